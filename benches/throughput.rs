@@ -0,0 +1,353 @@
+//! Throughput benchmarks against a RAM device
+//!
+//! Measures sequential reads, random reads, creating many files and reading
+//! a large directory. The read benchmarks run on several threads, each with
+//! its own read-only mount of one shared RAM device, and count how often a
+//! thread had to wait for the device lock held by another. Run with
+//!
+//! ```text
+//! cargo bench --bench throughput -- [threads]
+//! ```
+//!
+//! Mounts run without caches, so that every block read goes through the
+//! shared device.
+
+#[path = "../tests/images/mod.rs"]
+mod images;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use axdriver_block::{BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceType};
+use ext4rs::{Ext4FileSystem, File, InodeMode, MountOptions, EXT4_ROOT_INO};
+use images::{image, Image};
+
+/// 2 MiB ext3 image with 1 KiB blocks, also used by the compatibility tests
+static EXT3: Image = image!("../tests/images/ext3.img.packed");
+const BLOCK_SIZE: usize = 1024;
+/// Size of the file read by the read benchmarks
+const FILE_SIZE: usize = 512 * 1024;
+/// Entries of the directory read by the readdir benchmark
+const DIR_ENTRIES: usize = 200;
+/// Size of each random read
+const RANDOM_READ_SIZE: usize = 4096;
+/// Operations per thread of each read benchmark
+const READ_PASSES: usize = 20;
+const RANDOM_READS: usize = 2000;
+const READDIR_PASSES: usize = 200;
+/// Files created by each pass of the create benchmark
+const CREATE_FILES: usize = 200;
+const CREATE_PASSES: usize = 5;
+
+/// Lock acquisitions of a [`SharedDevice`]
+#[derive(Debug, Default)]
+struct Contention {
+    /// Times the lock was taken
+    acquired: AtomicU64,
+    /// Times it was held by another thread and had to be waited for
+    contended: AtomicU64,
+    /// Time spent waiting for it
+    waited_ns: AtomicU64,
+}
+
+impl Contention {
+    fn reset(&self) {
+        self.acquired.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.waited_ns.store(0, Ordering::Relaxed);
+    }
+}
+
+/// RAM device shared by mounts on different threads, behind a single lock
+#[derive(Clone)]
+struct SharedDevice {
+    data: Arc<Mutex<Vec<u8>>>,
+    contention: Arc<Contention>,
+}
+
+impl SharedDevice {
+    fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(data)),
+            contention: Arc::default(),
+        }
+    }
+
+    /// Run `f` on the device contents, counting whether the lock was free
+    fn with_data<T>(&self, f: impl FnOnce(&mut Vec<u8>) -> T) -> T {
+        self.contention.acquired.fetch_add(1, Ordering::Relaxed);
+        let mut data = match self.data.try_lock() {
+            Ok(data) => data,
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let data = self.data.lock().expect("device lock poisoned");
+                let waited = start.elapsed().as_nanos() as u64;
+                self.contention.contended.fetch_add(1, Ordering::Relaxed);
+                self.contention.waited_ns.fetch_add(waited, Ordering::Relaxed);
+                data
+            }
+            Err(TryLockError::Poisoned(_)) => panic!("device lock poisoned"),
+        };
+        f(&mut data)
+    }
+
+    /// Copy of the device contents
+    fn snapshot(&self) -> Vec<u8> {
+        self.with_data(|data| data.clone())
+    }
+}
+
+impl BaseDriverOps for SharedDevice {
+    fn device_name(&self) -> &str {
+        "shared-ram"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriverOps for SharedDevice {
+    fn num_blocks(&self) -> u64 {
+        self.with_data(|data| (data.len() / BLOCK_SIZE) as u64)
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let start = block_id as usize * BLOCK_SIZE;
+        self.with_data(|data| {
+            let src = data.get(start..start + buf.len()).ok_or(DevError::Io)?;
+            buf.copy_from_slice(src);
+            Ok(())
+        })
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let start = block_id as usize * BLOCK_SIZE;
+        self.with_data(|data| {
+            let dst = data.get_mut(start..start + buf.len()).ok_or(DevError::Io)?;
+            dst.copy_from_slice(buf);
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+}
+
+/// Mount `device`, read-only and without caches unless `writable`
+fn mount(device: SharedDevice, writable: bool) -> Ext4FileSystem<SharedDevice> {
+    let options = MountOptions {
+        read_only: !writable,
+        journaling: false,
+        cache_budget: 0,
+        inode_readahead_blks: 0,
+        ..MountOptions::default()
+    };
+    Ext4FileSystem::new(device, options).expect("Failed to mount image")
+}
+
+/// Build the benchmark image: `/bench.dat` of [`FILE_SIZE`] bytes and
+/// `/many` holding [`DIR_ENTRIES`] empty files
+fn build_image() -> Vec<u8> {
+    let device = SharedDevice::new(EXT3.to_vec());
+    let mut fs = mount(device.clone(), true);
+    let mode = InodeMode::from_bits_truncate(0o644);
+
+    let ino = fs
+        .create_file(EXT4_ROOT_INO, "bench.dat", mode)
+        .expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).expect("Failed to get inode"));
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    file.write(&data, &mut fs).expect("Failed to write file");
+    file.close(&mut fs).expect("Failed to close file");
+
+    let dir = fs
+        .create_dir(EXT4_ROOT_INO, "many", InodeMode::from_bits_truncate(0o755))
+        .expect("Failed to create directory");
+    for i in 0..DIR_ENTRIES {
+        fs.create_file(dir, &format!("f{:04}", i), mode)
+            .expect("Failed to create file");
+    }
+    fs.flush().expect("Failed to flush");
+    device.snapshot()
+}
+
+/// Outcome of one benchmark
+struct Report {
+    name: &'static str,
+    threads: usize,
+    ops: u64,
+    bytes: u64,
+    elapsed: Duration,
+    acquired: u64,
+    contended: u64,
+    waited: Duration,
+}
+
+impl Report {
+    fn print(&self) {
+        let secs = self.elapsed.as_secs_f64();
+        let rate = match self.bytes {
+            0 => format!("{:>10.0} ops/s", self.ops as f64 / secs),
+            bytes => format!("{:>10.1} MiB/s", bytes as f64 / secs / (1024.0 * 1024.0)),
+        };
+        let contended = match self.acquired {
+            0 => 0.0,
+            acquired => self.contended as f64 * 100.0 / acquired as f64,
+        };
+        println!(
+            "{:<14} {:>2} threads {:>9.3?} {}  lock: {} taken, \
+             {} contended ({:.1}%), {:.3?} waiting",
+            self.name, self.threads, self.elapsed, rate, self.acquired, self.contended, contended,
+            self.waited
+        );
+    }
+}
+
+/// Run `op` on `threads` threads, each with its own mount of `device`;
+/// `op` returns the operations and bytes it did
+fn run_threads(
+    name: &'static str,
+    device: &SharedDevice,
+    threads: usize,
+    op: fn(&mut Ext4FileSystem<SharedDevice>, usize) -> (u64, u64),
+) -> Report {
+    let mounts: Vec<_> = (0..threads).map(|_| mount(device.clone(), false)).collect();
+    device.contention.reset();
+
+    let start = Instant::now();
+    let handles: Vec<_> = mounts
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut fs)| thread::spawn(move || op(&mut fs, i)))
+        .collect();
+    let (ops, bytes) = handles
+        .into_iter()
+        .map(|h| h.join().expect("Benchmark thread panicked"))
+        .fold((0, 0), |(ops, bytes), (o, b)| (ops + o, bytes + b));
+    let elapsed = start.elapsed();
+
+    let contention = &device.contention;
+    Report {
+        name,
+        threads,
+        ops,
+        bytes,
+        elapsed,
+        acquired: contention.acquired.load(Ordering::Relaxed),
+        contended: contention.contended.load(Ordering::Relaxed),
+        waited: Duration::from_nanos(contention.waited_ns.load(Ordering::Relaxed)),
+    }
+}
+
+fn sequential_read(fs: &mut Ext4FileSystem<SharedDevice>, _thread: usize) -> (u64, u64) {
+    let inode = fs.find_inode("/bench.dat").expect("Failed to find file");
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut bytes = 0;
+    for _ in 0..READ_PASSES {
+        let mut file = File::new(inode.clone());
+        loop {
+            let n = file.read(&mut buf, fs).expect("Failed to read file");
+            if n == 0 {
+                break;
+            }
+            bytes += n as u64;
+        }
+    }
+    (READ_PASSES as u64, bytes)
+}
+
+fn random_read(fs: &mut Ext4FileSystem<SharedDevice>, thread: usize) -> (u64, u64) {
+    let inode = fs.find_inode("/bench.dat").expect("Failed to find file");
+    let mut file = File::new(inode);
+    let mut buf = vec![0u8; RANDOM_READ_SIZE];
+    // xorshift, seeded per thread so that threads read different offsets
+    let mut state = 0x9e37_79b9_7f4a_7c15u64 ^ thread as u64;
+    let mut bytes = 0;
+    for _ in 0..RANDOM_READS {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let offset = state % (FILE_SIZE - RANDOM_READ_SIZE) as u64;
+        file.seek(offset).expect("Failed to seek");
+        bytes += file.read(&mut buf, fs).expect("Failed to read file") as u64;
+    }
+    (RANDOM_READS as u64, bytes)
+}
+
+fn readdir(fs: &mut Ext4FileSystem<SharedDevice>, _thread: usize) -> (u64, u64) {
+    let dir = fs.find_inode("/many").expect("Failed to find directory");
+    let mut entries = 0;
+    for _ in 0..READDIR_PASSES {
+        entries += fs.read_dir(dir.ino).expect("Failed to read directory").len() as u64;
+    }
+    (entries, 0)
+}
+
+/// Create [`CREATE_FILES`] files in a new directory of a fresh copy of
+/// `image`, [`CREATE_PASSES`] times
+fn create_many(image: &[u8]) -> Report {
+    let mut elapsed = Duration::ZERO;
+    let mut acquired = 0;
+    for pass in 0..CREATE_PASSES {
+        let device = SharedDevice::new(image.to_vec());
+        let mut fs = mount(device.clone(), true);
+        let mode = InodeMode::from_bits_truncate(0o644);
+        device.contention.reset();
+
+        let start = Instant::now();
+        let dir = fs
+            .create_dir(
+                EXT4_ROOT_INO,
+                &format!("create{}", pass),
+                InodeMode::from_bits_truncate(0o755),
+            )
+            .expect("Failed to create directory");
+        for i in 0..CREATE_FILES {
+            fs.create_file(dir, &format!("f{:04}", i), mode)
+                .expect("Failed to create file");
+        }
+        fs.flush().expect("Failed to flush");
+        elapsed += start.elapsed();
+        acquired += device.contention.acquired.load(Ordering::Relaxed);
+    }
+
+    Report {
+        name: "create",
+        threads: 1,
+        ops: (CREATE_FILES * CREATE_PASSES) as u64,
+        bytes: 0,
+        elapsed,
+        acquired,
+        contended: 0,
+        waited: Duration::ZERO,
+    }
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`; the first number is the thread count
+    let threads = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(4usize)
+        .max(1);
+
+    let image = build_image();
+    let device = SharedDevice::new(image.clone());
+    for n in [1, threads] {
+        run_threads("sequential", &device, n, sequential_read).print();
+        run_threads("random", &device, n, random_read).print();
+        run_threads("readdir", &device, n, readdir).print();
+        if threads == 1 {
+            break;
+        }
+    }
+    create_many(&image).print();
+}
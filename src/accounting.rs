@@ -0,0 +1,59 @@
+//! Allocation accounting checks
+//!
+//! Every allocation and free updates both a bitmap and the free count of a
+//! group descriptor, and nothing else keeps the two in step. With the
+//! `verify_accounting` mount option, on by default in debug builds, the
+//! touched group is recounted from its bitmaps after each of them, so that a
+//! drift is caught where it starts rather than by `e2fsck` much later.
+
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{Bitmap, Ext4Error, Ext4FileSystem, Ext4Result};
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Recount the free blocks and inodes of `group` from its bitmaps and
+    /// compare them with its descriptor, if `verify_accounting` is set
+    ///
+    /// A mismatch is logged, recorded in the error log and returned as
+    /// `CorruptGroupDescriptor`.
+    pub(crate) fn check_group_accounting(&self, group: usize) -> Ext4Result<()> {
+        if !self.mount_options.verify_accounting {
+            return Ok(());
+        }
+        let (free_blocks, free_inodes) = self.count_group_free(group)?;
+        let bg = &self.block_groups[group];
+        if free_blocks == bg.free_blocks_count() && free_inodes == bg.free_inodes_count() {
+            return Ok(());
+        }
+
+        error!(
+            "Group {} counts {} free blocks and {} free inodes, bitmaps have {} and {}",
+            group,
+            bg.free_blocks_count(),
+            bg.free_inodes_count(),
+            free_blocks,
+            free_inodes
+        );
+        let err = Ext4Error::CorruptGroupDescriptor(group as u32);
+        self.record_error("check_group_accounting", line!(), 0, bg.block_bitmap(), &err);
+        Err(err)
+    }
+
+    /// Count the free blocks and inodes in the bitmaps of `group`, as the
+    /// allocator sees them in the bitmap cache
+    fn count_group_free(&self, group: usize) -> Ext4Result<(u32, u32)> {
+        let sb = &self.superblock;
+        let group_start = sb.group_first_block(group as u32);
+        let group_len = (sb.blocks_count() - group_start).min(sb.blocks_per_group() as u64);
+        let free_blocks = self.with_bitmap(group, false, |b| count_clear(b, group_len as usize))?;
+        let inodes = sb.inodes_per_group() as usize;
+        let free_inodes = self.with_bitmap(group, true, |b| count_clear(b, inodes))?;
+        Ok((free_blocks as u32, free_inodes as u32))
+    }
+}
+
+/// Count the clear bits among the first `len` of `bitmap`
+fn count_clear(bitmap: &Bitmap, len: usize) -> usize {
+    (0..len.min(bitmap.size())).filter(|&bit| !bitmap.is_set(bit)).count()
+}
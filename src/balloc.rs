@@ -0,0 +1,158 @@
+//! Block allocation hints
+//!
+//! The allocator remembers, for every block group, where its last bitmap scan
+//! stopped, and for recently written inodes the block they are expected to
+//! ask for next. New directories may also hold a reservation of free blocks
+//! that other inodes skip over, so the directory can grow contiguously. All
+//! of this is kept in memory only and merely steers where blocks are taken
+//! from, so losing it never affects correctness.
+//!
+//! [`AllocLog`] is different: it lists what the operation in progress has
+//! allocated, so that a failure halfway through can give it all back.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Maximum number of inodes with a remembered goal block
+const MAX_INODE_GOALS: usize = 256;
+/// Maximum number of inodes with reserved blocks
+const MAX_RESERVATIONS: usize = 64;
+
+/// In-memory allocation cursors and goals
+#[derive(Debug, Default)]
+pub(crate) struct AllocHints {
+    /// Bit just past the last block allocated in each group
+    cursors: Vec<u32>,
+    /// Block each inode is expected to allocate next
+    goals: BTreeMap<u32, u64>,
+    /// Free blocks set aside for each inode, as first block and length
+    reservations: BTreeMap<u32, (u64, u32)>,
+}
+
+impl AllocHints {
+    /// Create hints for a filesystem with `groups` block groups
+    pub(crate) fn new(groups: usize) -> Self {
+        Self {
+            cursors: vec![0; groups],
+            goals: BTreeMap::new(),
+            reservations: BTreeMap::new(),
+        }
+    }
+
+    /// Bit at which the next scan of `group` should start
+    pub(crate) fn cursor(&self, group: usize) -> u32 {
+        self.cursors.get(group).copied().unwrap_or(0)
+    }
+
+    /// Record that `bit` was just allocated in `group`
+    pub(crate) fn advance(&mut self, group: usize, bit: u32) {
+        if let Some(cursor) = self.cursors.get_mut(group) {
+            *cursor = bit + 1;
+        }
+    }
+
+    /// Block that inode `ino` is expected to allocate next
+    pub(crate) fn goal(&self, ino: u32) -> Option<u64> {
+        self.goals.get(&ino).copied()
+    }
+
+    /// Remember that inode `ino` is expected to allocate `block` next
+    pub(crate) fn set_goal(&mut self, ino: u32, block: u64) {
+        if self.goals.len() >= MAX_INODE_GOALS && !self.goals.contains_key(&ino) {
+            self.goals.pop_first();
+        }
+        self.goals.insert(ino, block);
+    }
+
+    /// Forget the goal and reservation of inode `ino`
+    pub(crate) fn forget(&mut self, ino: u32) {
+        self.goals.remove(&ino);
+        self.reservations.remove(&ino);
+    }
+
+    /// Set aside `len` blocks starting at `start` for inode `ino`
+    pub(crate) fn reserve(&mut self, ino: u32, start: u64, len: u32) {
+        if self.reservations.len() >= MAX_RESERVATIONS && !self.reservations.contains_key(&ino) {
+            self.reservations.pop_first();
+        }
+        self.reservations.insert(ino, (start, len));
+    }
+
+    /// Check if `block` is reserved for an inode other than `owner`
+    pub(crate) fn reserved_for_other(&self, block: u64, owner: Option<u32>) -> bool {
+        self.reservations
+            .iter()
+            .any(|(&ino, &(start, len))| {
+                Some(ino) != owner && block >= start && block - start < len as u64
+            })
+    }
+
+    /// Record that `block` was allocated, shrinking the reservation it is in
+    pub(crate) fn consume(&mut self, block: u64) {
+        self.reservations.retain(|_, (start, len)| {
+            if block >= *start && block - *start < *len as u64 {
+                *len -= (block + 1 - *start) as u32;
+                *start = block + 1;
+            }
+            *len > 0
+        });
+    }
+
+    /// Check if any blocks are reserved
+    pub(crate) fn has_reservations(&self) -> bool {
+        !self.reservations.is_empty()
+    }
+
+    /// Drop all reservations
+    pub(crate) fn clear_reservations(&mut self) {
+        self.reservations.clear();
+    }
+}
+
+/// A block or inode taken from the bitmaps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Allocation {
+    Block(u64),
+    Inode(u32),
+    /// Inode of a directory, also counted in the `used_dirs` of its group
+    Directory(u32),
+}
+
+/// Allocations made by the operation in progress
+#[derive(Debug, Default)]
+pub(crate) struct AllocLog {
+    /// Number of nested operations in progress
+    depth: usize,
+    allocations: Vec<Allocation>,
+}
+
+impl AllocLog {
+    /// Start an operation, nested in the current one if there is any
+    pub(crate) fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    /// Record an allocation made by the operation in progress, if any
+    pub(crate) fn record(&mut self, allocation: Allocation) {
+        if self.depth > 0 {
+            self.allocations.push(allocation);
+        }
+    }
+
+    /// End the innermost operation, returning the allocations to undo
+    ///
+    /// Nested operations belong to the outermost one, so only its end
+    /// returns anything, and only if `failed`.
+    pub(crate) fn end(&mut self, failed: bool) -> Vec<Allocation> {
+        self.depth -= 1;
+        if self.depth > 0 {
+            return Vec::new();
+        }
+        let allocations = core::mem::take(&mut self.allocations);
+        if failed {
+            allocations
+        } else {
+            Vec::new()
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use alloc::vec::Vec;
+#[cfg(not(feature = "read-only"))]
 use log::*;
 
 use crate::{Ext4Error, Ext4Result};
@@ -63,54 +64,165 @@ impl Bitmap {
         Ok(())
     }
 
-    /// Find the first free bit
-    pub fn find_first_free(&self) -> Option<usize> {
-        for (byte_index, &byte) in self.data.iter().enumerate() {
-            if byte != 0xFF {
-                for bit_index in 0..8 {
-                    let bit = byte_index * 8 + bit_index;
-                    if bit < self.size && !self.is_set(bit) {
-                        return Some(bit);
-                    }
-                }
+    /// Set `len` bits starting at `start`
+    pub fn set_range(&mut self, start: usize, len: usize) -> Ext4Result<()> {
+        self.fill_range(start, len, true)
+    }
+
+    /// Clear `len` bits starting at `start`
+    pub fn clear_range(&mut self, start: usize, len: usize) -> Ext4Result<()> {
+        self.fill_range(start, len, false)
+    }
+
+    /// Set or clear a range of bits, whole bytes at a time
+    fn fill_range(&mut self, start: usize, len: usize, value: bool) -> Ext4Result<()> {
+        let end = start.checked_add(len).ok_or(Ext4Error::InvalidInput)?;
+        if end > self.size {
+            return Err(Ext4Error::InvalidInput);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let first_byte = start / 8;
+        let last_byte = (end - 1) / 8;
+        let head_mask = 0xFFu8 << (start % 8);
+        let tail_mask = 0xFFu8 >> (7 - (end - 1) % 8);
+
+        let apply = |byte: &mut u8, mask: u8| {
+            if value {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
             }
+        };
+
+        if first_byte == last_byte {
+            apply(&mut self.data[first_byte], head_mask & tail_mask);
+        } else {
+            apply(&mut self.data[first_byte], head_mask);
+            self.data[first_byte + 1..last_byte].fill(if value { 0xFF } else { 0 });
+            apply(&mut self.data[last_byte], tail_mask);
         }
-        None
+        Ok(())
     }
 
-    /// Find the first set bit
-    pub fn find_first_set(&self) -> Option<usize> {
-        for (byte_index, &byte) in self.data.iter().enumerate() {
-            if byte != 0 {
-                for bit_index in 0..8 {
-                    let bit = byte_index * 8 + bit_index;
-                    if bit < self.size && self.is_set(bit) {
-                        return Some(bit);
-                    }
-                }
+    /// Number of 64-bit words covering the bitmap
+    fn word_count(&self) -> usize {
+        self.size.div_ceil(64)
+    }
+
+    /// Load 64-bit word `index`, zero-padded past the end of the data
+    fn word(&self, index: usize) -> u64 {
+        let start = index * 8;
+        let end = (start + 8).min(self.data.len());
+        let mut bytes = [0u8; 8];
+        if start < end {
+            bytes[..end - start].copy_from_slice(&self.data[start..end]);
+        }
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Mask of the bits of word `index` that lie inside the bitmap
+    fn word_mask(&self, index: usize) -> u64 {
+        let remaining = self.size - index * 64;
+        if remaining >= 64 {
+            u64::MAX
+        } else {
+            (1u64 << remaining) - 1
+        }
+    }
+
+    /// Find the first bit at or after `from` that is set (or free)
+    fn find_next(&self, from: usize, set: bool) -> Option<usize> {
+        if from >= self.size {
+            return None;
+        }
+
+        let load = |index: usize| {
+            let word = self.word(index);
+            let bits = if set { word } else { !word };
+            bits & self.word_mask(index)
+        };
+
+        let mut index = from / 64;
+        let mut bits = load(index) & (u64::MAX << (from % 64));
+        loop {
+            if bits != 0 {
+                return Some(index * 64 + bits.trailing_zeros() as usize);
             }
+            index += 1;
+            if index >= self.word_count() {
+                return None;
+            }
+            bits = load(index);
+        }
+    }
+
+    /// Find the first free bit at or after `from`
+    pub fn find_next_free(&self, from: usize) -> Option<usize> {
+        self.find_next(from, false)
+    }
+
+    /// Find the first set bit at or after `from`
+    pub fn find_next_set(&self, from: usize) -> Option<usize> {
+        self.find_next(from, true)
+    }
+
+    /// Find the first free bit in `from..to` for which `skip` returns false
+    pub fn find_free_filtered(
+        &self,
+        mut from: usize,
+        to: usize,
+        skip: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        while from < to {
+            let bit = self.find_next_free(from).filter(|&bit| bit < to)?;
+            if !skip(bit) {
+                return Some(bit);
+            }
+            from = bit + 1;
         }
         None
     }
 
-    /// Count the number of free bits
-    pub fn count_free(&self) -> usize {
-        let mut count = 0;
-        for &byte in &self.data {
-            count += (!byte).count_ones() as usize;
+    /// Find the first run of `len` consecutive free bits
+    pub fn find_free_run(&self, len: usize) -> Option<usize> {
+        if len == 0 {
+            return Some(0);
         }
-        // Adjust for the last byte if it's not fully used
-        let remainder = self.size % 8;
-        if remainder != 0 {
-            let mask = (1 << remainder) - 1;
-            count -= (self.data[self.data.len() - 1] & !mask).count_ones() as usize;
+
+        let mut from = 0;
+        loop {
+            let start = self.find_next_free(from)?;
+            let end = self.find_next_set(start).unwrap_or(self.size);
+            if end - start >= len {
+                return Some(start);
+            }
+            from = end;
         }
-        count
+    }
+
+    /// Find the first free bit
+    pub fn find_first_free(&self) -> Option<usize> {
+        self.find_next_free(0)
+    }
+
+    /// Find the first set bit
+    pub fn find_first_set(&self) -> Option<usize> {
+        self.find_next_set(0)
+    }
+
+    /// Count the number of free bits
+    pub fn count_free(&self) -> usize {
+        self.size - self.count_set()
     }
 
     /// Count the number of set bits
     pub fn count_set(&self) -> usize {
-        self.size - self.count_free()
+        (0..self.word_count())
+            .map(|index| (self.word(index) & self.word_mask(index)).count_ones() as usize)
+            .sum()
     }
 
     /// Get the bitmap data as bytes
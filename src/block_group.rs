@@ -1,41 +1,95 @@
 use alloc::vec::Vec;
+use crc::{Crc, CRC_16_MODBUS};
 use log::*;
 
-use crate::{Ext4Error, Ext4Result};
+use crate::{crc32c, Ext4Error, Ext4Result, SuperBlock};
+
+/// Inode table and bitmap are not initialized
+pub const EXT4_BG_INODE_UNINIT: u16 = 0x0001;
+/// Block bitmap is not initialized
+pub const EXT4_BG_BLOCK_UNINIT: u16 = 0x0002;
+/// Inode table is zeroed
+pub const EXT4_BG_INODE_ZEROED: u16 = 0x0004;
+
+/// Size of a block group descriptor without the 64bit feature
+const DESC_SIZE_32: usize = 32;
+/// Size of the part of a descriptor laid out by the 64bit feature; larger
+/// `s_desc_size` slots are reserved past it
+const DESC_SIZE_64: usize = 64;
+/// Offset of `bg_checksum`
+const CHECKSUM_OFFSET: usize = 30;
+
+/// CRC16 of `gdt_csum` descriptors, Linux's `crc16()` started from ~0
+const GDT_CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
+
+/// Checksum of descriptor slot `data` of group `group`, as Linux's
+/// `ext4_group_desc_csum()` computes it
+///
+/// Under `metadata_csum` it is the low half of a crc32c of the slot, with
+/// the checksum field itself counted as zeros; with `gdt_csum` a crc16
+/// that skips the field. Other filesystems have no descriptor checksums.
+pub(crate) fn desc_checksum(sb: &SuperBlock, group: u32, data: &[u8]) -> u16 {
+    let rest = &data[CHECKSUM_OFFSET + 2..];
+    if sb.has_metadata_csum() {
+        let csum = crc32c(sb.csum_seed(), &group.to_le_bytes());
+        let csum = crc32c(csum, &data[..CHECKSUM_OFFSET]);
+        let csum = crc32c(csum, &[0; 2]);
+        return crc32c(csum, rest) as u16;
+    }
+    if !sb.has_group_csum() {
+        return 0;
+    }
+    let mut digest = GDT_CRC16.digest();
+    digest.update(sb.uuid());
+    digest.update(&group.to_le_bytes());
+    digest.update(&data[..CHECKSUM_OFFSET]);
+    if sb.has_64bit() {
+        digest.update(rest);
+    }
+    digest.finalize()
+}
 
 /// Block group descriptor
+///
+/// Descriptors are 32 bytes, or `s_desc_size` bytes with the 64bit feature,
+/// whose second half holds the high halves of the locations, counts and
+/// bitmap checksums. Fields are kept whole here; a 32-byte descriptor has
+/// no high halves.
 #[derive(Debug, Clone)]
 pub struct BlockGroupDescriptor {
     /// Block bitmap
-    block_bitmap: u32,
+    block_bitmap: u64,
     /// Inode bitmap
-    inode_bitmap: u32,
+    inode_bitmap: u64,
     /// Inode table
-    inode_table: u32,
+    inode_table: u64,
     /// Free blocks count
-    free_blocks_count: u16,
+    free_blocks_count: u32,
     /// Free inodes count
-    free_inodes_count: u16,
+    free_inodes_count: u32,
     /// Used directories count
-    used_dirs_count: u16,
+    used_dirs_count: u32,
     /// Flags
     flags: u16,
     /// Exclude bitmap for snapshots
-    exclude_bitmap: u32,
+    exclude_bitmap: u64,
     /// Block bitmap checksum
-    block_bitmap_csum: u16,
+    block_bitmap_csum: u32,
     /// Inode bitmap checksum
-    inode_bitmap_csum: u16,
+    inode_bitmap_csum: u32,
     /// Unused inode count
-    itable_unused: u16,
+    itable_unused: u32,
     /// Checksum
     checksum: u16,
 }
 
 impl BlockGroupDescriptor {
     /// Parse block group descriptor from bytes
+    ///
+    /// `data` is the descriptor's slot: 32 bytes, or at least 64 for the
+    /// layout with high halves.
     pub fn from_bytes(data: &[u8]) -> Ext4Result<Self> {
-        if data.len() < 32 {
+        if data.len() < DESC_SIZE_32 {
             return Err(Ext4Error::InvalidInput);
         }
 
@@ -56,36 +110,32 @@ impl BlockGroupDescriptor {
             &data[..32.min(data.len())]
         );
 
-        let block_bitmap = read_u32(0);
-        let inode_bitmap = read_u32(4);
-        let inode_table = read_u32(8);
+        let wide = data.len() >= DESC_SIZE_64;
+        let hi_u32 = |offset: usize| if wide { read_u32(offset) } else { 0 };
+        let hi_u16 = |offset: usize| if wide { read_u16(offset) } else { 0 };
+        let join_u64 = |lo: u32, hi: u32| lo as u64 | (hi as u64) << 32;
+        let join_u32 = |lo: u16, hi: u16| lo as u32 | (hi as u32) << 16;
+
+        let block_bitmap = join_u64(read_u32(0), hi_u32(32));
+        let inode_bitmap = join_u64(read_u32(4), hi_u32(36));
+        let inode_table = join_u64(read_u32(8), hi_u32(40));
 
         debug!(
             "Block group descriptor: block_bitmap={}, inode_bitmap={}, inode_table={}",
             block_bitmap, inode_bitmap, inode_table
         );
-        if data.len() >= 16 {
-            debug!("First 16 bytes: {:x?}", &data[..16]);
-        }
-        let free_blocks_count = read_u16(12);
-        let free_inodes_count = read_u16(14);
-        let used_dirs_count = read_u16(16);
+        let free_blocks_count = join_u32(read_u16(12), hi_u16(44));
+        let free_inodes_count = join_u32(read_u16(14), hi_u16(46));
+        let used_dirs_count = join_u32(read_u16(16), hi_u16(48));
         let flags = read_u16(18);
 
-        // Extended fields (if available)
-        let mut exclude_bitmap = 0;
-        let mut block_bitmap_csum = 0;
-        let mut inode_bitmap_csum = 0;
-        let mut itable_unused = 0;
-        let mut checksum = 0;
-
-        if data.len() >= 64 {
-            exclude_bitmap = read_u32(20);
-            block_bitmap_csum = read_u16(24);
-            inode_bitmap_csum = read_u16(26);
-            itable_unused = read_u16(28);
-            checksum = read_u16(30);
-        }
+        // Also part of 32-byte descriptors, though only meaningful with
+        // group checksums
+        let exclude_bitmap = join_u64(read_u32(20), hi_u32(52));
+        let block_bitmap_csum = join_u32(read_u16(24), hi_u16(56));
+        let inode_bitmap_csum = join_u32(read_u16(26), hi_u16(58));
+        let itable_unused = join_u32(read_u16(28), hi_u16(50));
+        let checksum = read_u16(30);
 
         Ok(Self {
             block_bitmap,
@@ -103,61 +153,153 @@ impl BlockGroupDescriptor {
         })
     }
 
+    /// Check that the bitmaps and inode table of group `group` lie inside
+    /// the filesystem
+    ///
+    /// Without flex_bg they must also be inside the group, past its
+    /// superblock backup and descriptor blocks. With flex_bg they may be in
+    /// any group but must not overlap the primary descriptor table.
+    pub fn validate(&self, group: u32, sb: &SuperBlock) -> Ext4Result<()> {
+        let (first, end) = if sb.has_flex_bg() {
+            (sb.group_first_block(0) + sb.group_overhead_blocks(0) as u64, sb.blocks_count())
+        } else {
+            let start = sb.group_first_block(group);
+            let end = (start + sb.blocks_per_group() as u64).min(sb.blocks_count());
+            (start + sb.group_overhead_blocks(group) as u64, end)
+        };
+
+        let itable_blocks = (sb.inodes_per_group() as u64 * sb.inode_size() as u64)
+            .div_ceil(sb.block_size() as u64);
+        let regions = [
+            ("block bitmap", self.block_bitmap, 1),
+            ("inode bitmap", self.inode_bitmap, 1),
+            ("inode table", self.inode_table, itable_blocks),
+        ];
+        for (what, start, len) in regions {
+            if start < first || start + len > end {
+                error!(
+                    "Group {} {} at block {} is outside blocks {}..{}",
+                    group, what, start, first, end
+                );
+                return Err(Ext4Error::CorruptGroupDescriptor(group));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Getters
-    pub fn block_bitmap(&self) -> u32 {
+    pub fn block_bitmap(&self) -> u64 {
         self.block_bitmap
     }
-    pub fn inode_bitmap(&self) -> u32 {
+    pub fn inode_bitmap(&self) -> u64 {
         self.inode_bitmap
     }
-    pub fn inode_table(&self) -> u32 {
+    pub fn inode_table(&self) -> u64 {
         self.inode_table
     }
-    pub fn free_blocks_count(&self) -> u16 {
+    pub fn free_blocks_count(&self) -> u32 {
         self.free_blocks_count
     }
-    pub fn free_inodes_count(&self) -> u16 {
+    pub fn free_inodes_count(&self) -> u32 {
         self.free_inodes_count
     }
-    pub fn used_dirs_count(&self) -> u16 {
+    pub fn used_dirs_count(&self) -> u32 {
         self.used_dirs_count
     }
     pub fn flags(&self) -> u16 {
         self.flags
     }
-    pub fn exclude_bitmap(&self) -> u32 {
+    pub fn exclude_bitmap(&self) -> u64 {
         self.exclude_bitmap
     }
-    pub fn block_bitmap_csum(&self) -> u16 {
+    pub fn block_bitmap_csum(&self) -> u32 {
         self.block_bitmap_csum
     }
-    pub fn inode_bitmap_csum(&self) -> u16 {
+    pub fn inode_bitmap_csum(&self) -> u32 {
         self.inode_bitmap_csum
     }
-    pub fn itable_unused(&self) -> u16 {
+    pub fn itable_unused(&self) -> u32 {
         self.itable_unused
     }
     pub fn checksum(&self) -> u16 {
         self.checksum
     }
 
+    /// Check if the inode bitmap and table are uninitialized
+    pub fn inode_uninit(&self) -> bool {
+        self.flags & EXT4_BG_INODE_UNINIT != 0
+    }
+
+    /// Check if the block bitmap is uninitialized
+    pub fn block_uninit(&self) -> bool {
+        self.flags & EXT4_BG_BLOCK_UNINIT != 0
+    }
+
+    /// Check if the inode table has been zeroed
+    pub fn itable_zeroed(&self) -> bool {
+        self.flags & EXT4_BG_INODE_ZEROED != 0
+    }
+
     /// Setters for updating fields
-    pub fn set_free_inodes_count(&mut self, count: u16) {
+    pub fn set_free_inodes_count(&mut self, count: u32) {
         self.free_inodes_count = count;
     }
 
-    pub fn set_free_blocks_count(&mut self, count: u16) {
+    pub fn set_free_blocks_count(&mut self, count: u32) {
         self.free_blocks_count = count;
     }
 
-    pub fn set_used_dirs_count(&mut self, count: u16) {
+    pub fn set_used_dirs_count(&mut self, count: u32) {
         self.used_dirs_count = count;
     }
 
+    /// Mark the block bitmap as initialized
+    pub fn clear_block_uninit(&mut self) {
+        self.flags &= !EXT4_BG_BLOCK_UNINIT;
+    }
+
+    /// Mark the inode bitmap and table as initialized
+    pub fn clear_inode_uninit(&mut self) {
+        self.flags &= !EXT4_BG_INODE_UNINIT;
+    }
+
+    /// Mark the inode table as zeroed
+    pub fn set_itable_zeroed(&mut self) {
+        self.flags |= EXT4_BG_INODE_ZEROED;
+    }
+
+    pub fn set_itable_unused(&mut self, count: u32) {
+        self.itable_unused = count;
+    }
+
+    pub fn set_block_bitmap_csum(&mut self, csum: u32) {
+        self.block_bitmap_csum = csum;
+    }
+
+    pub fn set_inode_bitmap_csum(&mut self, csum: u32) {
+        self.inode_bitmap_csum = csum;
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.checksum = checksum;
+    }
+
     /// Convert block group descriptor back to bytes for writing to disk
+    ///
+    /// The result has the 64-byte layout.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut data = vec![0u8; 64]; // Use maximum size for descriptor
-        
+        let mut data = vec![0u8; DESC_SIZE_64];
+        self.write_to(&mut data);
+        data
+    }
+
+    /// Serialize the descriptor into an existing on-disk descriptor slot
+    ///
+    /// A slot of 64 bytes or more also gets the high halves; anything past
+    /// the first 64 bytes is preserved. A 32-byte slot only has room for
+    /// the low halves.
+    pub fn write_to(&self, data: &mut [u8]) {
         // Helper function to write little-endian values
         let write_u32 = |data: &mut [u8], offset: usize, value: u32| {
             data[offset] = (value & 0xFF) as u8;
@@ -171,19 +313,31 @@ impl BlockGroupDescriptor {
             data[offset + 1] = ((value >> 8) & 0xFF) as u8;
         };
 
-        write_u32(&mut data, 0, self.block_bitmap);
-        write_u32(&mut data, 4, self.inode_bitmap);
-        write_u32(&mut data, 8, self.inode_table);
-        write_u16(&mut data, 12, self.free_blocks_count);
-        write_u16(&mut data, 14, self.free_inodes_count);
-        write_u16(&mut data, 16, self.used_dirs_count);
-        write_u16(&mut data, 18, self.flags);
-        write_u32(&mut data, 20, self.exclude_bitmap);
-        write_u16(&mut data, 24, self.block_bitmap_csum);
-        write_u16(&mut data, 26, self.inode_bitmap_csum);
-        write_u16(&mut data, 28, self.itable_unused);
-        write_u16(&mut data, 30, self.checksum);
+        write_u32(data, 0, self.block_bitmap as u32);
+        write_u32(data, 4, self.inode_bitmap as u32);
+        write_u32(data, 8, self.inode_table as u32);
+        write_u16(data, 12, self.free_blocks_count as u16);
+        write_u16(data, 14, self.free_inodes_count as u16);
+        write_u16(data, 16, self.used_dirs_count as u16);
+        write_u16(data, 18, self.flags);
+        write_u32(data, 20, self.exclude_bitmap as u32);
+        write_u16(data, 24, self.block_bitmap_csum as u16);
+        write_u16(data, 26, self.inode_bitmap_csum as u16);
+        write_u16(data, 28, self.itable_unused as u16);
+        write_u16(data, CHECKSUM_OFFSET, self.checksum);
+        if data.len() < DESC_SIZE_64 {
+            return;
+        }
 
-        data
+        write_u32(data, 32, (self.block_bitmap >> 32) as u32);
+        write_u32(data, 36, (self.inode_bitmap >> 32) as u32);
+        write_u32(data, 40, (self.inode_table >> 32) as u32);
+        write_u16(data, 44, (self.free_blocks_count >> 16) as u16);
+        write_u16(data, 46, (self.free_inodes_count >> 16) as u16);
+        write_u16(data, 48, (self.used_dirs_count >> 16) as u16);
+        write_u16(data, 50, (self.itable_unused >> 16) as u16);
+        write_u32(data, 52, (self.exclude_bitmap >> 32) as u32);
+        write_u16(data, 56, (self.block_bitmap_csum >> 16) as u16);
+        write_u16(data, 58, (self.inode_bitmap_csum >> 16) as u16);
     }
 }
@@ -0,0 +1,388 @@
+//! Block, inode and directory entry caches
+//!
+//! The three caches share the memory budget of
+//! [`MountOptions::cache_budget`](crate::MountOptions::cache_budget): half of
+//! it for blocks, a quarter each for inodes and directory entries. They are
+//! write-through, so dropping any entry at any time is safe, which is what
+//! [`Ext4FileSystem::shrink`](crate::Ext4FileSystem::shrink) relies on.
+//!
+//! The allocator's bitmaps are kept apart, in a [`BitmapCache`] sized by
+//! [`MountOptions::bitmap_cache_blocks`](crate::MountOptions::bitmap_cache_blocks).
+//!
+//! A [`BufferPool`] keeps a few block-sized buffers for the temporary
+//! copies made on hot paths, such as file reads and writes and extent tree
+//! walks, so that they don't allocate for every block.
+
+use alloc::collections::BTreeMap;
+#[cfg(not(feature = "read-only"))]
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+#[cfg(not(feature = "read-only"))]
+use crate::Bitmap;
+use crate::Inode;
+
+/// Least recently used cache whose capacity is counted in bytes
+pub(crate) struct Lru<K, V> {
+    /// Value, last use, and charged size of each entry
+    entries: BTreeMap<K, (V, u64, usize)>,
+    /// Keys by last use
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    used: usize,
+    capacity: usize,
+}
+
+impl<K: Ord + Clone, V> Lru<K, V> {
+    /// Create an empty cache holding up to `capacity` bytes
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            used: 0,
+            capacity,
+        }
+    }
+
+    /// Look up `key`, marking it as recently used
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let (_, last_use, _) = self.entries.get_mut(key)?;
+        self.order.remove(last_use);
+        self.tick += 1;
+        *last_use = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.get(key).map(|(value, _, _)| value)
+    }
+
+    /// Insert `value` charged as `size` bytes, evicting old entries as needed
+    pub(crate) fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.capacity {
+            return;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick, size));
+        self.used += size;
+        self.evict_to(self.capacity);
+    }
+
+    /// Check if `key` is cached, without marking it as used
+    pub(crate) fn contains(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Drop `key`
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some((_, last_use, size)) = self.entries.remove(key) {
+            self.order.remove(&last_use);
+            self.used -= size;
+        }
+    }
+
+    /// Drop every entry for which `keep` returns false
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+        let dropped: Vec<K> = self.entries.keys().filter(|k| !keep(k)).cloned().collect();
+        for key in dropped {
+            self.remove(&key);
+        }
+    }
+
+    /// Drop least recently used entries until at most `target` bytes are used
+    pub(crate) fn evict_to(&mut self, target: usize) {
+        while self.used > target {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, _, size)) = self.entries.remove(&key) {
+                self.used -= size;
+            }
+        }
+    }
+
+    /// Bytes charged to the cached entries
+    pub(crate) fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Maximum number of bytes the cache holds
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Memory used by the filesystem caches, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheUsage {
+    /// Cached blocks
+    pub blocks: usize,
+    /// Cached inodes
+    pub inodes: usize,
+    /// Cached directory entries
+    pub dentries: usize,
+    /// Total budget shared by the caches
+    pub budget: usize,
+}
+
+impl CacheUsage {
+    /// Bytes used by all caches together
+    pub fn total(&self) -> usize {
+        self.blocks + self.inodes + self.dentries
+    }
+}
+
+/// The caches of a mounted filesystem
+pub(crate) struct Caches {
+    /// Block contents by block number
+    pub(crate) blocks: Lru<u64, Vec<u8>>,
+    /// Inodes by number
+    pub(crate) inodes: Lru<u32, Inode>,
+    /// Inode numbers by parent directory and name
+    pub(crate) dentries: Lru<(u32, Vec<u8>), u32>,
+}
+
+impl Caches {
+    /// Split `budget` bytes between the caches
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            blocks: Lru::new(budget / 2),
+            inodes: Lru::new(budget / 4),
+            dentries: Lru::new(budget / 4),
+        }
+    }
+
+    /// Bytes charged for a cached inode
+    pub(crate) fn inode_size() -> usize {
+        size_of::<Inode>()
+    }
+
+    /// Bytes charged for a cached directory entry named `name`
+    pub(crate) fn dentry_size(name: &[u8]) -> usize {
+        size_of::<(u32, Vec<u8>, u32)>() + name.len()
+    }
+
+    /// Forget the cached entries of directory `dir`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn invalidate_dir(&mut self, dir: u32) {
+        self.dentries.retain(|(parent, _)| *parent != dir);
+    }
+
+    /// Shrink the caches to at most `target` bytes in total
+    ///
+    /// Each cache gives up memory in proportion to its share of the budget.
+    pub(crate) fn shrink(&mut self, target: usize) {
+        let budget = self.budget();
+        if budget == 0 {
+            return;
+        }
+        let share = |capacity: usize| (target as u128 * capacity as u128 / budget as u128) as usize;
+        self.blocks.evict_to(share(self.blocks.capacity()));
+        self.inodes.evict_to(share(self.inodes.capacity()));
+        self.dentries.evict_to(share(self.dentries.capacity()));
+    }
+
+    /// Total budget shared by the caches
+    pub(crate) fn budget(&self) -> usize {
+        self.blocks.capacity() + self.inodes.capacity() + self.dentries.capacity()
+    }
+
+    /// Memory currently used by the caches
+    pub(crate) fn usage(&self) -> CacheUsage {
+        CacheUsage {
+            blocks: self.blocks.used(),
+            inodes: self.inodes.used(),
+            dentries: self.dentries.used(),
+            budget: self.budget(),
+        }
+    }
+}
+
+/// Block-sized buffers kept for reuse
+pub(crate) struct BufferPool {
+    block_size: usize,
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// Most buffers kept at a time; more than hot paths hold at once
+    const CAPACITY: usize = 8;
+
+    /// Create an empty pool of `block_size` buffers
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            free: Vec::new(),
+        }
+    }
+
+    /// Take a zeroed buffer, reusing a kept one if there is any
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        match self.free.pop() {
+            Some(mut buf) => {
+                buf.fill(0);
+                buf
+            }
+            None => vec![0u8; self.block_size],
+        }
+    }
+
+    /// Give `buf` back for reuse
+    ///
+    /// Buffers of another size, and any beyond the capacity, are dropped.
+    pub(crate) fn put(&mut self, buf: Vec<u8>) {
+        if buf.len() == self.block_size && self.free.len() < Self::CAPACITY {
+            self.free.push(buf);
+        }
+    }
+}
+
+/// A bitmap held by [`BitmapCache`]
+#[cfg(not(feature = "read-only"))]
+struct CachedBitmap {
+    bitmap: Bitmap,
+    /// Whether the bitmap was changed since it was last written
+    dirty: bool,
+    last_use: u64,
+}
+
+/// Block and inode bitmaps used by the allocator, by block number
+///
+/// Unlike the other caches, bitmaps are changed in the cache and written
+/// back later, along with the descriptors of the groups changed with them,
+/// which are tracked here too. Only clean bitmaps are evicted, so a bitmap
+/// is never lost before it is written.
+#[cfg(not(feature = "read-only"))]
+pub(crate) struct BitmapCache {
+    entries: BTreeMap<u64, CachedBitmap>,
+    /// Groups whose descriptors were changed since they were last written
+    dirty_groups: BTreeSet<u32>,
+    tick: u64,
+    capacity: usize,
+}
+
+#[cfg(not(feature = "read-only"))]
+impl BitmapCache {
+    /// Create an empty cache holding up to `capacity` clean bitmaps
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            dirty_groups: BTreeSet::new(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    /// Look up the bitmap in `block`, marking it as recently used
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn get(&mut self, block: u64) -> Option<&Bitmap> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&block)?;
+        entry.last_use = self.tick;
+        Some(&entry.bitmap)
+    }
+
+    /// Look up the bitmap in `block` to change it in place, marking it as
+    /// recently used
+    ///
+    /// Changes are only written back once [`Self::mark_dirty`] is called.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn get_mut(&mut self, block: u64) -> Option<&mut Bitmap> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&block)?;
+        entry.last_use = self.tick;
+        Some(&mut entry.bitmap)
+    }
+
+    /// Mark the bitmap in `block` as changed since it was last written
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn mark_dirty(&mut self, block: u64) {
+        if let Some(entry) = self.entries.get_mut(&block) {
+            entry.dirty = true;
+        }
+    }
+
+    /// Note that the descriptor of `group` changed and must be written
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn mark_group_dirty(&mut self, group: u32) {
+        self.dirty_groups.insert(group);
+    }
+
+    /// Take the groups whose descriptors must be written, marking them clean
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn take_dirty_groups(&mut self) -> BTreeSet<u32> {
+        core::mem::take(&mut self.dirty_groups)
+    }
+
+    /// Note that the descriptor of `group` was written
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn group_written(&mut self, group: u32) {
+        self.dirty_groups.remove(&group);
+    }
+
+    /// Check if bitmaps are cached at all
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Cache `bitmap` as the contents of `block`, dirty if it differs from
+    /// what is on disk
+    ///
+    /// Nothing is kept if the cache is disabled.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn insert(&mut self, block: u64, bitmap: Bitmap, dirty: bool) {
+        if !self.enabled() {
+            return;
+        }
+        self.tick += 1;
+        let dirty = dirty || self.entries.get(&block).is_some_and(|e| e.dirty);
+        let last_use = self.tick;
+        self.entries.insert(block, CachedBitmap { bitmap, dirty, last_use });
+        self.evict();
+    }
+
+    /// Take the dirty bitmap in `block` to be written, marking it clean
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn take_dirty(&mut self, block: u64) -> Option<Bitmap> {
+        let entry = self.entries.get_mut(&block).filter(|e| e.dirty)?;
+        entry.dirty = false;
+        Some(entry.bitmap.clone())
+    }
+
+    /// Blocks of all dirty bitmaps
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn dirty_blocks(&self) -> Vec<u64> {
+        self.entries.iter().filter(|(_, e)| e.dirty).map(|(&b, _)| b).collect()
+    }
+
+    /// Note that `data` was written to `block` by other means than
+    /// [`Self::take_dirty`]
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn written(&mut self, block: u64, data: &[u8]) {
+        if let Some(entry) = self.entries.get_mut(&block) {
+            entry.bitmap = Bitmap::from_bytes(data);
+            entry.dirty = false;
+        }
+    }
+
+    /// Drop the least recently used clean bitmaps beyond the capacity
+    #[cfg(not(feature = "read-only"))]
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, e)| !e.dirty)
+                .min_by_key(|(_, e)| e.last_use)
+                .map(|(&block, _)| block);
+            match oldest {
+                Some(block) => self.entries.remove(&block),
+                None => break,
+            };
+        }
+    }
+}
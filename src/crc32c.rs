@@ -0,0 +1,89 @@
+//! CRC32c (Castagnoli) as used by ext4 metadata checksums
+//!
+//! [`crc32c`] works like the Linux kernel's `crc32c()`: it continues a raw
+//! checksum without inverting its input or output, so ext4's seeds and
+//! chained checksums can be passed straight through.
+//!
+//! With the `hw-crc32c` feature, the SSE4.2 or ARMv8 CRC instructions are
+//! used when the target is compiled with them (`-C target-feature=+sse4.2`
+//! or `+crc`). Other targets use a table-driven implementation.
+
+use crc::{Algorithm, Crc, Table};
+
+/// CRC32c without the usual initial and final inversion
+const CRC32C_RAW: Algorithm<u32> = Algorithm {
+    width: 32,
+    poly: 0x1edc6f41,
+    init: 0,
+    refin: true,
+    refout: true,
+    xorout: 0,
+    check: 0x58e3fa20,
+    residue: 0,
+};
+
+static PORTABLE: Crc<u32, Table<16>> = Crc::<u32, Table<16>>::new(&CRC32C_RAW);
+
+/// Continue the CRC32c `crc` over `data`
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(all(
+        feature = "hw-crc32c",
+        any(
+            all(target_arch = "x86_64", target_feature = "sse4.2"),
+            all(target_arch = "aarch64", target_feature = "crc")
+        )
+    ))]
+    return hw::crc32c(crc, data);
+
+    #[allow(unreachable_code)]
+    portable_crc32c(crc, data)
+}
+
+/// Table-driven CRC32c, available on every target
+fn portable_crc32c(crc: u32, data: &[u8]) -> u32 {
+    // The table implementation reflects the initial value it is given
+    let mut digest = PORTABLE.digest_with_initial(crc.reverse_bits());
+    digest.update(data);
+    digest.finalize()
+}
+
+#[cfg(all(feature = "hw-crc32c", target_arch = "x86_64", target_feature = "sse4.2"))]
+mod hw {
+    use core::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    /// CRC32c with the SSE4.2 `crc32` instruction
+    pub(super) fn crc32c(crc: u32, data: &[u8]) -> u32 {
+        let mut crc = crc as u64;
+        let mut words = data.chunks_exact(8);
+        for word in &mut words {
+            // SAFETY: sse4.2 is enabled for the whole build
+            crc = unsafe { _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap())) };
+        }
+
+        let mut crc = crc as u32;
+        for &byte in words.remainder() {
+            // SAFETY: as above
+            crc = unsafe { _mm_crc32_u8(crc, byte) };
+        }
+        crc
+    }
+}
+
+#[cfg(all(feature = "hw-crc32c", target_arch = "aarch64", target_feature = "crc"))]
+mod hw {
+    use core::arch::aarch64::{__crc32cb, __crc32cd};
+
+    /// CRC32c with the ARMv8 `crc32c` instructions
+    pub(super) fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+        let mut words = data.chunks_exact(8);
+        for word in &mut words {
+            // SAFETY: the crc extension is enabled for the whole build
+            crc = unsafe { __crc32cd(crc, u64::from_le_bytes(word.try_into().unwrap())) };
+        }
+        for &byte in words.remainder() {
+            // SAFETY: as above
+            crc = unsafe { __crc32cb(crc, byte) };
+        }
+        crc
+    }
+}
@@ -0,0 +1,790 @@
+//! Block device adapters
+//!
+//! In-memory devices and wrappers implementing [`BlockDriverOps`] on top of
+//! another device.
+
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use alloc::vec::Vec;
+use axdriver::prelude::*;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::partition::read_partitions;
+use crate::{Ext4Error, Ext4Result};
+
+/// Kind of a failed block device request
+///
+/// [`DevError`] is narrowed down to what a caller can act on: whether
+/// trying again may help, or the request can never succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceErrorKind {
+    /// The device was busy or did not answer in time; the request may
+    /// succeed if retried
+    Timeout,
+    /// The medium failed to read or write the blocks
+    Media,
+    /// The request reached past the end of the device
+    OutOfRange,
+    /// The device can't serve this request at all, such as a write to a
+    /// read-only device
+    Unsupported,
+    /// Any other failure, including running out of memory
+    Other,
+}
+
+impl DeviceErrorKind {
+    /// Check if retrying the request may succeed
+    pub fn is_transient(self) -> bool {
+        self == DeviceErrorKind::Timeout
+    }
+}
+
+impl From<DevError> for DeviceErrorKind {
+    fn from(err: DevError) -> Self {
+        match err {
+            DevError::Again | DevError::ResourceBusy => DeviceErrorKind::Timeout,
+            DevError::Io => DeviceErrorKind::Media,
+            // The devices of this crate reject requests past their end
+            // with `InvalidParam`
+            DevError::InvalidParam => DeviceErrorKind::OutOfRange,
+            DevError::Unsupported => DeviceErrorKind::Unsupported,
+            _ => DeviceErrorKind::Other,
+        }
+    }
+}
+
+impl From<DevError> for Ext4Error {
+    fn from(err: DevError) -> Self {
+        Ext4Error::Device(err.into())
+    }
+}
+
+/// Read `buf.len()` bytes starting at byte `offset` of `device`
+///
+/// The transfer does not need to line up with device blocks, so filesystem
+/// blocks can be smaller or larger than the device's sectors.
+pub(crate) fn read_bytes<D: BlockDriverOps>(
+    device: &mut D,
+    offset: u64,
+    buf: &mut [u8],
+) -> DevResult {
+    let block_size = device.block_size();
+    if offset.is_multiple_of(block_size as u64) && buf.len().is_multiple_of(block_size) {
+        return device.read_block(offset / block_size as u64, buf);
+    }
+
+    let mut tmp = vec![0u8; block_size];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let block = pos / block_size as u64;
+        let start = (pos % block_size as u64) as usize;
+        let len = (block_size - start).min(buf.len() - done);
+
+        device.read_block(block, &mut tmp)?;
+        buf[done..done + len].copy_from_slice(&tmp[start..start + len]);
+        done += len;
+    }
+    Ok(())
+}
+
+/// Write `buf` starting at byte `offset` of `device`
+///
+/// Device blocks only partially covered by the transfer are read, modified
+/// and written back.
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn write_bytes<D: BlockDriverOps>(
+    device: &mut D,
+    offset: u64,
+    buf: &[u8],
+) -> DevResult {
+    let block_size = device.block_size();
+    if offset.is_multiple_of(block_size as u64) && buf.len().is_multiple_of(block_size) {
+        return device.write_block(offset / block_size as u64, buf);
+    }
+
+    let mut tmp = vec![0u8; block_size];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let block = pos / block_size as u64;
+        let start = (pos % block_size as u64) as usize;
+        let len = (block_size - start).min(buf.len() - done);
+
+        if len == block_size {
+            device.write_block(block, &buf[done..done + len])?;
+        } else {
+            device.read_block(block, &mut tmp)?;
+            tmp[start..start + len].copy_from_slice(&buf[done..done + len]);
+            device.write_block(block, &tmp)?;
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+/// Byte range covered by a transfer of `len` bytes starting at `block_id`
+fn block_range(
+    block_id: u64,
+    len: usize,
+    block_size: usize,
+    device_len: usize,
+) -> DevResult<Range<usize>> {
+    let start = usize::try_from(block_id)
+        .ok()
+        .and_then(|block| block.checked_mul(block_size))
+        .ok_or(DevError::InvalidParam)?;
+    let end = start.checked_add(len).ok_or(DevError::InvalidParam)?;
+    if end > device_len {
+        return Err(DevError::InvalidParam);
+    }
+    Ok(start..end)
+}
+
+/// Check that `block_size` can be the block size of an in-memory device
+fn check_block_size(block_size: usize) -> Ext4Result<()> {
+    if !block_size.is_power_of_two() {
+        warn!("Block size {} is not a power of two", block_size);
+        return Err(Ext4Error::InvalidArg);
+    }
+    Ok(())
+}
+
+/// Read-only block device over a byte slice
+///
+/// Useful for mounting an image embedded in the binary with
+/// `include_bytes!`. Writes fail with [`DevError::Unsupported`]; wrap the
+/// device in an [`OverlayDevice`] to get a writable view.
+pub struct SliceBlockDevice<'a> {
+    data: &'a [u8],
+    block_size: usize,
+}
+
+impl<'a> SliceBlockDevice<'a> {
+    /// Create a device over `data` with the given block size
+    ///
+    /// A trailing partial block is ignored. Fails with `InvalidArg` unless
+    /// the block size is a power of two.
+    pub fn new(data: &'a [u8], block_size: usize) -> Ext4Result<Self> {
+        check_block_size(block_size)?;
+        Ok(Self { data, block_size })
+    }
+
+    /// Get the underlying bytes
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl BaseDriverOps for SliceBlockDevice<'_> {
+    fn device_name(&self) -> &str {
+        "slice-block"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriverOps for SliceBlockDevice<'_> {
+    fn num_blocks(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let len = self.num_blocks() as usize * self.block_size;
+        let range = block_range(block_id, buf.len(), self.block_size, len)?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, _block_id: u64, _buf: &[u8]) -> DevResult {
+        Err(DevError::Unsupported)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+}
+
+/// Writable block device backed by an in-memory buffer (RAM disk)
+pub struct VecBlockDevice {
+    data: Vec<u8>,
+    block_size: usize,
+}
+
+impl VecBlockDevice {
+    /// Create a device over `data` with the given block size
+    ///
+    /// A trailing partial block is ignored. Fails with `InvalidArg` unless
+    /// the block size is a power of two.
+    pub fn new(data: Vec<u8>, block_size: usize) -> Ext4Result<Self> {
+        check_block_size(block_size)?;
+        Ok(Self { data, block_size })
+    }
+
+    /// Create a zero-filled device of `num_blocks` blocks
+    pub fn zeroed(num_blocks: usize, block_size: usize) -> Ext4Result<Self> {
+        check_block_size(block_size)?;
+        let len = num_blocks.checked_mul(block_size).ok_or(Ext4Error::InvalidArg)?;
+        Self::new(vec![0u8; len], block_size)
+    }
+
+    /// Get the device contents
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the device contents
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl BaseDriverOps for VecBlockDevice {
+    fn device_name(&self) -> &str {
+        "vec-block"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriverOps for VecBlockDevice {
+    fn num_blocks(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let len = self.num_blocks() as usize * self.block_size;
+        let range = block_range(block_id, buf.len(), self.block_size, len)?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let len = self.num_blocks() as usize * self.block_size;
+        let range = block_range(block_id, buf.len(), self.block_size, len)?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+}
+
+/// View of a contiguous range of blocks of another device
+///
+/// Block 0 of the wrapper is block `start` of the underlying device, which
+/// makes a filesystem stored inside a partition mountable directly.
+pub struct OffsetDevice<D: BlockDriverOps> {
+    inner: D,
+    start: u64,
+    num_blocks: u64,
+}
+
+impl<D: BlockDriverOps> OffsetDevice<D> {
+    /// Expose `num_blocks` blocks of `inner` starting at block `start`
+    pub fn new(inner: D, start: u64, num_blocks: u64) -> Ext4Result<Self> {
+        let end = start.checked_add(num_blocks).ok_or(Ext4Error::InvalidArg)?;
+        if end > inner.num_blocks() {
+            warn!(
+                "Range {}..{} exceeds device of {} blocks",
+                start,
+                end,
+                inner.num_blocks()
+            );
+            return Err(Ext4Error::InvalidArg);
+        }
+
+        Ok(Self {
+            inner,
+            start,
+            num_blocks,
+        })
+    }
+
+    /// Expose partition `number` (1-based) of the MBR or GPT on `inner`
+    pub fn partition(mut inner: D, number: u32) -> Ext4Result<Self> {
+        let partition = read_partitions(&mut inner)?
+            .into_iter()
+            .find(|p| p.number == number)
+            .ok_or(Ext4Error::InvalidArg)?;
+        debug!(
+            "Partition {}: start block {}, {} blocks",
+            number, partition.start, partition.num_blocks
+        );
+        Self::new(inner, partition.start, partition.num_blocks)
+    }
+
+    /// First block of the range on the underlying device
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Get the underlying device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return the underlying device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Translate a transfer to the underlying device
+    fn translate(&self, block_id: u64, len: usize) -> DevResult<u64> {
+        let count = len.div_ceil(self.inner.block_size()) as u64;
+        match block_id.checked_add(count) {
+            Some(end) if end <= self.num_blocks => Ok(self.start + block_id),
+            _ => Err(DevError::InvalidParam),
+        }
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for OffsetDevice<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for OffsetDevice<D> {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block = self.translate(block_id, buf.len())?;
+        self.inner.read_block(block, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block = self.translate(block_id, buf.len())?;
+        self.inner.write_block(block, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.flush()
+    }
+}
+
+/// Copy-on-write overlay over a block device
+///
+/// Reads are served from the overlay when a block has been written and from
+/// the underlying device otherwise. Writes only ever land in memory, so the
+/// underlying device is never modified and all changes disappear when the
+/// overlay is dropped.
+pub struct OverlayDevice<D: BlockDriverOps> {
+    inner: D,
+    /// Modified device blocks, keyed by device block number
+    overlay: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<D: BlockDriverOps> OverlayDevice<D> {
+    /// Create an overlay over `inner`
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            overlay: BTreeMap::new(),
+        }
+    }
+
+    /// Get the underlying device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Number of device blocks currently held in the overlay
+    pub fn overlay_blocks(&self) -> usize {
+        self.overlay.len()
+    }
+
+    /// Drop all in-memory modifications
+    pub fn discard(&mut self) {
+        debug!("Discarding {} overlay blocks", self.overlay.len());
+        self.overlay.clear();
+    }
+
+    /// Discard the overlay and return the untouched underlying device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for OverlayDevice<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for OverlayDevice<D> {
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_size = self.inner.block_size();
+        let count = buf.len().div_ceil(block_size) as u64;
+
+        // Fast path: nothing in the range has been modified
+        if self.overlay.range(block_id..block_id + count).next().is_none() {
+            return self.inner.read_block(block_id, buf);
+        }
+
+        for (i, chunk) in buf.chunks_mut(block_size).enumerate() {
+            let id = block_id + i as u64;
+            match self.overlay.get(&id) {
+                Some(data) => chunk.copy_from_slice(&data[..chunk.len()]),
+                None => {
+                    if chunk.len() == block_size {
+                        self.inner.read_block(id, chunk)?;
+                    } else {
+                        let mut tmp = vec![0u8; block_size];
+                        self.inner.read_block(id, &mut tmp)?;
+                        chunk.copy_from_slice(&tmp[..chunk.len()]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_size = self.inner.block_size();
+        if block_id + buf.len().div_ceil(block_size) as u64 > self.inner.num_blocks() {
+            return Err(DevError::InvalidParam);
+        }
+
+        for (i, chunk) in buf.chunks(block_size).enumerate() {
+            let id = block_id + i as u64;
+            if chunk.len() == block_size {
+                self.overlay.insert(id, chunk.to_vec());
+            } else {
+                // Partial block: merge with the current contents
+                let mut data = vec![0u8; block_size];
+                self.read_block(id, &mut data)?;
+                data[..chunk.len()].copy_from_slice(chunk);
+                self.overlay.insert(id, data);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        // Overlay contents are never written back
+        Ok(())
+    }
+}
+
+/// A block changed on a [`CopyOnWriteDevice`]
+#[cfg(feature = "test-utils")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDiff {
+    /// Device block number
+    pub block: u64,
+    /// Contents before the first write
+    pub original: Vec<u8>,
+    /// Current contents
+    pub current: Vec<u8>,
+}
+
+#[cfg(feature = "test-utils")]
+impl BlockDiff {
+    /// Byte ranges of the block that differ
+    pub fn changed_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        let changed = self.original.iter().zip(&self.current).map(|(a, b)| a != b);
+        for (i, changed) in changed.enumerate() {
+            match ranges.last_mut() {
+                Some(range) if changed && range.end == i => range.end = i + 1,
+                _ if changed => ranges.push(i..i + 1),
+                _ => {}
+            }
+        }
+        ranges
+    }
+}
+
+/// Block device keeping the original contents of the blocks it overwrites
+///
+/// Writes go through to the underlying device, but the first write to each
+/// block since the last [checkpoint](Self::checkpoint) saves what it held
+/// before. The changes can then be compared with [`Self::diff`] and undone
+/// with [`Self::rollback`], which helps track down which write corrupted an
+/// image. Only built with the `test-utils` feature.
+#[cfg(feature = "test-utils")]
+pub struct CopyOnWriteDevice<D: BlockDriverOps> {
+    inner: D,
+    /// Contents at the last checkpoint of the blocks written since, keyed by
+    /// device block number
+    originals: BTreeMap<u64, Vec<u8>>,
+}
+
+#[cfg(feature = "test-utils")]
+impl<D: BlockDriverOps> CopyOnWriteDevice<D> {
+    /// Start recording the writes to `inner`
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            originals: BTreeMap::new(),
+        }
+    }
+
+    /// Get the underlying device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Return the underlying device, with the changes still applied
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Device blocks written since the last checkpoint, in order
+    pub fn changed_blocks(&self) -> Vec<u64> {
+        self.originals.keys().copied().collect()
+    }
+
+    /// Contents of `block` at the last checkpoint, if it was written since
+    pub fn original(&self, block: u64) -> Option<&[u8]> {
+        self.originals.get(&block).map(Vec::as_slice)
+    }
+
+    /// Compare the blocks written since the last checkpoint with their
+    /// original contents
+    ///
+    /// Blocks written back with the contents they had are left out.
+    pub fn diff(&mut self) -> DevResult<Vec<BlockDiff>> {
+        let mut diffs = Vec::new();
+        for (&block, original) in &self.originals {
+            let mut current = vec![0u8; original.len()];
+            self.inner.read_block(block, &mut current)?;
+            if current != *original {
+                diffs.push(BlockDiff {
+                    block,
+                    original: original.clone(),
+                    current,
+                });
+            }
+        }
+        Ok(diffs)
+    }
+
+    /// Restore the blocks written since the last checkpoint
+    pub fn rollback(&mut self) -> DevResult {
+        debug!("Rolling back {} blocks", self.originals.len());
+        while let Some((block, original)) = self.originals.pop_first() {
+            self.inner.write_block(block, &original)?;
+        }
+        Ok(())
+    }
+
+    /// Accept the changes so far: later diffs and rollbacks start from here
+    pub fn checkpoint(&mut self) {
+        self.originals.clear();
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl<D: BlockDriverOps> BaseDriverOps for CopyOnWriteDevice<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl<D: BlockDriverOps> BlockDriverOps for CopyOnWriteDevice<D> {
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_size = self.inner.block_size();
+        let count = buf.len().div_ceil(block_size) as u64;
+        if block_id + count > self.inner.num_blocks() {
+            return Err(DevError::InvalidParam);
+        }
+
+        for id in block_id..block_id + count {
+            if !self.originals.contains_key(&id) {
+                let mut original = vec![0u8; block_size];
+                self.inner.read_block(id, &mut original)?;
+                self.originals.insert(id, original);
+            }
+        }
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.flush()
+    }
+}
+
+/// How [`RetryDevice`] retries requests failing with a transient error
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Most times a request is tried, including the first; 0 and 1 don't
+    /// retry
+    pub attempts: u32,
+    /// Called before each retry with its number, starting at 1, typically
+    /// to sleep for a while
+    pub backoff: Option<fn(u32)>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: None,
+        }
+    }
+}
+
+/// Requests retried by a [`RetryDevice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryStats {
+    /// Retries made
+    pub retries: u64,
+    /// Requests that succeeded after failing at least once
+    pub recovered: u64,
+    /// Requests still failing with a transient error after every attempt
+    pub exhausted: u64,
+}
+
+/// Block device retrying requests that fail with a transient error
+///
+/// Errors whose [`DeviceErrorKind`] is transient, such as a busy virtio
+/// queue, are retried as set by a [`RetryPolicy`]; any other error is
+/// returned right away.
+pub struct RetryDevice<D: BlockDriverOps> {
+    inner: D,
+    policy: RetryPolicy,
+    stats: RetryStats,
+}
+
+impl<D: BlockDriverOps> RetryDevice<D> {
+    /// Retry the requests to `inner` as set by `policy`
+    pub fn new(inner: D, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            stats: RetryStats::default(),
+        }
+    }
+
+    /// Get the underlying device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Requests retried so far
+    pub fn stats(&self) -> RetryStats {
+        self.stats
+    }
+
+    /// Return the underlying device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Run `op` on the underlying device until it succeeds, fails with an
+    /// error that isn't transient or runs out of attempts
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut D) -> DevResult<T>) -> DevResult<T> {
+        let mut attempt = 1;
+        loop {
+            match op(&mut self.inner) {
+                Ok(value) => {
+                    if attempt > 1 {
+                        self.stats.recovered += 1;
+                    }
+                    return Ok(value);
+                }
+                Err(err) if DeviceErrorKind::from(err).is_transient() => {
+                    if attempt >= self.policy.attempts {
+                        warn!("Device request still failing after {} attempts: {:?}", attempt, err);
+                        self.stats.exhausted += 1;
+                        return Err(err);
+                    }
+                    debug!("Retrying device request after {:?}, attempt {}", err, attempt);
+                    if let Some(backoff) = self.policy.backoff {
+                        backoff(attempt);
+                    }
+                    self.stats.retries += 1;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl<D: BlockDriverOps> BaseDriverOps for RetryDevice<D> {
+    fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+}
+
+impl<D: BlockDriverOps> BlockDriverOps for RetryDevice<D> {
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.retry(|inner| inner.read_block(block_id, buf))
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.retry(|inner| inner.write_block(block_id, buf))
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.retry(|inner| inner.flush())
+    }
+}
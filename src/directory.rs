@@ -1,8 +1,159 @@
+use alloc::borrow::Cow;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::fmt;
 use log::*;
 
-use crate::{Ext4Error, Ext4Result, InodeType};
+#[cfg(not(feature = "read-only"))]
+use crate::crc32c;
+use crate::{Ext4Error, Ext4Result, Inode, InodeMode, InodeTimes, InodeType};
+
+/// Maximum length of a file name in bytes
+pub const EXT4_NAME_LEN: usize = 255;
+
+/// Size of the `ext4_dir_entry_tail` ending each directory leaf block under
+/// `metadata_csum`
+pub(crate) const DIRENT_TAIL_SIZE: usize = 12;
+
+/// File type of a dirent tail, which looks like an unused entry to readers
+/// that don't know about it
+pub(crate) const DIRENT_TAIL_FT: u8 = 0xDE;
+
+/// End directory leaf block `block` with a dirent tail holding the checksum
+/// of the entries before it, seeded with the directory's checksum seed
+///
+/// The entries must leave the last [`DIRENT_TAIL_SIZE`] bytes free.
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn set_dirent_tail(block: &mut [u8], seed: u32) {
+    let tail = block.len() - DIRENT_TAIL_SIZE;
+    block[tail..tail + 4].fill(0);
+    block[tail + 4..tail + 6].copy_from_slice(&(DIRENT_TAIL_SIZE as u16).to_le_bytes());
+    block[tail + 6] = 0;
+    block[tail + 7] = DIRENT_TAIL_FT;
+    let csum = crc32c(seed, &block[..tail]);
+    block[tail + 8..].copy_from_slice(&csum.to_le_bytes());
+}
+
+/// Check that `name` can be used as a new directory entry name
+///
+/// A valid name is 1 to [`EXT4_NAME_LEN`] bytes long, is not `.` or `..`,
+/// and contains neither `/` nor NUL. Every operation that creates a name in
+/// a directory goes through this check.
+pub fn validate_name(name: &[u8]) -> Ext4Result<()> {
+    if name.is_empty() || name.len() > EXT4_NAME_LEN {
+        return Err(Ext4Error::InvalidArg);
+    }
+
+    if name == b"." || name == b".." {
+        return Err(Ext4Error::InvalidArg);
+    }
+
+    if name.iter().any(|&b| b == b'/' || b == 0) {
+        return Err(Ext4Error::InvalidArg);
+    }
+
+    Ok(())
+}
+
+/// Raw file name as stored on disk
+///
+/// Linux places no encoding requirements on file names, so names are kept
+/// as bytes. Use [`to_str`](Self::to_str) or
+/// [`to_string_lossy`](Self::to_string_lossy) to display them.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileName(Vec<u8>);
+
+impl FileName {
+    /// Create a name from raw bytes
+    pub fn new(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+
+    /// Get the raw bytes of the name
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Get the name length in bytes
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if the name is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the name as a string if it is valid UTF-8
+    pub fn to_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.0).ok()
+    }
+
+    /// Get the name as a string, replacing invalid UTF-8 sequences
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl fmt::Display for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+impl fmt::Debug for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string_lossy())
+    }
+}
+
+impl From<&str> for FileName {
+    fn from(name: &str) -> Self {
+        Self(name.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for FileName {
+    fn from(name: String) -> Self {
+        Self(name.into_bytes())
+    }
+}
+
+impl From<&[u8]> for FileName {
+    fn from(name: &[u8]) -> Self {
+        Self(name.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for FileName {
+    fn from(name: Vec<u8>) -> Self {
+        Self(name)
+    }
+}
+
+impl AsRef<[u8]> for FileName {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for FileName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for FileName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<[u8]> for FileName {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
 
 /// Directory entry
 #[derive(Debug, Clone)]
@@ -16,7 +167,7 @@ pub struct DirectoryEntry {
     /// File type
     pub file_type: u8,
     /// Name
-    pub name: String,
+    pub name: FileName,
 }
 
 impl DirectoryEntry {
@@ -54,8 +205,7 @@ impl DirectoryEntry {
             return Err(Ext4Error::InvalidInput);
         }
 
-        let name_bytes = &data[8..8 + name_len as usize];
-        let name = String::from_utf8(name_bytes.to_vec()).map_err(|_| Ext4Error::InvalidInput)?;
+        let name = FileName::new(&data[8..8 + name_len as usize]);
 
         Ok(Self {
             ino,
@@ -86,6 +236,43 @@ impl DirectoryEntry {
     }
 }
 
+/// Directory entry bundled with the metadata of the inode it refers to
+#[derive(Debug, Clone)]
+pub struct DirEntryPlus {
+    /// The directory entry
+    pub entry: DirectoryEntry,
+    /// Inode type
+    pub inode_type: InodeType,
+    /// File mode
+    pub mode: InodeMode,
+    /// File size
+    pub size: u64,
+    /// Links count
+    pub links_count: u16,
+    /// User ID
+    pub uid: u16,
+    /// Group ID
+    pub gid: u16,
+    /// Timestamps
+    pub times: InodeTimes,
+}
+
+impl DirEntryPlus {
+    /// Bundle an entry with its inode
+    pub fn new(entry: DirectoryEntry, inode: &Inode) -> Self {
+        Self {
+            entry,
+            inode_type: inode.inode_type(),
+            mode: inode.mode,
+            size: inode.size,
+            links_count: inode.links_count,
+            uid: inode.uid,
+            gid: inode.gid,
+            times: inode.times(),
+        }
+    }
+}
+
 /// Directory iterator
 pub struct DirectoryIterator<'a> {
     data: &'a [u8],
@@ -175,6 +362,62 @@ impl<'a> Iterator for DirectoryIterator<'a> {
     }
 }
 
+/// Summary of a directory from [`Ext4FileSystem::dir_stats`]
+///
+/// [`Ext4FileSystem::dir_stats`]: crate::Ext4FileSystem::dir_stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirStats {
+    /// Entries in use, "." and ".." included
+    pub entries: u64,
+    /// Size of the directory in bytes
+    pub size: u64,
+    /// Whether lookups use a hashed index
+    pub indexed: bool,
+    /// Leaf blocks listed by the hashed index, if the directory has one
+    pub index_leaves: Option<u64>,
+}
+
+/// Count the entries in use in directory block `block`, without parsing
+/// their names
+pub(crate) fn count_entries(block: &[u8]) -> u64 {
+    let mut count = 0;
+    let mut offset = 0;
+    while offset + 8 <= block.len() {
+        let ino = u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        let rec_len = u16::from_le_bytes([block[offset + 4], block[offset + 5]]) as usize;
+        if rec_len < 8 || offset + rec_len > block.len() {
+            break;
+        }
+        if ino != 0 && block[offset + 6] != 0 {
+            count += 1;
+        }
+        offset += rec_len;
+    }
+    count
+}
+
+/// Find the entry named `name` in directory block `block`, returning its
+/// inode number
+///
+/// Entries are compared in place, without parsing them or copying their
+/// names. The scan stops at the first malformed record.
+pub fn find_entry_in_block(block: &[u8], name: &[u8]) -> Option<u32> {
+    let mut offset = 0;
+    while offset + 8 <= block.len() {
+        let ino = u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+        let rec_len = u16::from_le_bytes([block[offset + 4], block[offset + 5]]) as usize;
+        let name_len = block[offset + 6] as usize;
+        if rec_len < 8 || offset + rec_len > block.len() || 8 + name_len > rec_len {
+            return None;
+        }
+        if ino != 0 && &block[offset + 8..offset + 8 + name_len] == name {
+            return Some(ino);
+        }
+        offset += rec_len;
+    }
+    None
+}
+
 /// Directory operations
 pub struct Directory {
     entries: Vec<DirectoryEntry>,
@@ -219,14 +462,25 @@ impl Directory {
     }
 
     /// Remove an entry by name
-    pub fn remove_entry(&mut self, name: &str) -> Option<DirectoryEntry> {
-        let index = self.entries.iter().position(|e| e.name == name)?;
+    pub fn remove_entry<N: AsRef<[u8]> + ?Sized>(&mut self, name: &N) -> Option<DirectoryEntry> {
+        let name = name.as_ref();
+        let index = self.entries.iter().position(|e| e.name.as_bytes() == name)?;
         Some(self.entries.remove(index))
     }
 
     /// Find an entry by name
-    pub fn find_entry(&self, name: &str) -> Option<&DirectoryEntry> {
-        self.entries.iter().find(|e| e.name == name)
+    pub fn find_entry<N: AsRef<[u8]> + ?Sized>(&self, name: &N) -> Option<&DirectoryEntry> {
+        let name = name.as_ref();
+        self.entries.iter().find(|e| e.name.as_bytes() == name)
+    }
+
+    /// Find an entry by name for modification
+    pub fn find_entry_mut<N: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        name: &N,
+    ) -> Option<&mut DirectoryEntry> {
+        let name = name.as_ref();
+        self.entries.iter_mut().find(|e| e.name.as_bytes() == name)
     }
 
     /// Get all entries
@@ -277,6 +531,57 @@ impl Directory {
         Ok(data)
     }
 
+    /// Serialize directory into blocks of `block_size` bytes
+    ///
+    /// Entries never straddle a block boundary and the last entry of each
+    /// block extends to the end of that block, so the result is always a
+    /// whole number of blocks.
+    pub fn to_blocks(&self, block_size: usize) -> Ext4Result<Vec<u8>> {
+        let sizes: Vec<usize> = self
+            .entries
+            .iter()
+            .map(|e| (8 + e.name.len() + 3) & !3)
+            .collect();
+
+        let mut data = Vec::new();
+        let mut start = 0;
+        let mut used = 0;
+        for (i, &size) in sizes.iter().enumerate() {
+            if used + size > block_size && i > start {
+                self.write_block_entries(&mut data, start..i, &sizes, block_size)?;
+                start = i;
+                used = 0;
+            }
+            used += size;
+        }
+        if start < sizes.len() {
+            self.write_block_entries(&mut data, start..sizes.len(), &sizes, block_size)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Append one directory block holding the entries in `range`
+    fn write_block_entries(
+        &self,
+        data: &mut Vec<u8>,
+        range: core::ops::Range<usize>,
+        sizes: &[usize],
+        block_size: usize,
+    ) -> Ext4Result<()> {
+        let block_start = data.len();
+        for i in range.clone() {
+            let rec_len = if i + 1 == range.end {
+                block_size - (data.len() - block_start)
+            } else {
+                sizes[i]
+            };
+            let entry = self.entry_to_bytes_with_rec_len(&self.entries[i], rec_len as u16)?;
+            data.extend_from_slice(&entry);
+        }
+        Ok(())
+    }
+
     /// Convert an entry to bytes with specified record length
     fn entry_to_bytes_with_rec_len(&self, entry: &DirectoryEntry, rec_len: u16) -> Ext4Result<Vec<u8>> {
         let mut data = Vec::new();
@@ -0,0 +1,287 @@
+//! Ext4 directory index (htree) hash functions
+//!
+//! Hashed directories order their leaf blocks by a 32-bit hash of each
+//! entry name. The hash algorithm is selected by `s_def_hash_version` and
+//! seeded with `s_hash_seed`. The low bit of every hash is reserved: in index
+//! entries it marks a leaf block that continues a run of colliding hashes from
+//! the previous block.
+
+use crate::{SuperBlock, SuperBlockFlags};
+
+/// Directory hash algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashVersion {
+    /// Original ext3 hash, characters treated as signed
+    Legacy,
+    /// Half MD4, characters treated as signed
+    HalfMd4,
+    /// TEA, characters treated as signed
+    Tea,
+    /// Original ext3 hash, characters treated as unsigned
+    LegacyUnsigned,
+    /// Half MD4, characters treated as unsigned
+    HalfMd4Unsigned,
+    /// TEA, characters treated as unsigned
+    TeaUnsigned,
+}
+
+impl HashVersion {
+    /// Parse an on-disk hash version number
+    pub fn from_raw(version: u8) -> Option<Self> {
+        match version {
+            0 => Some(Self::Legacy),
+            1 => Some(Self::HalfMd4),
+            2 => Some(Self::Tea),
+            3 => Some(Self::LegacyUnsigned),
+            4 => Some(Self::HalfMd4Unsigned),
+            5 => Some(Self::TeaUnsigned),
+            _ => None,
+        }
+    }
+
+    /// Get the on-disk hash version number
+    pub fn to_raw(self) -> u8 {
+        match self {
+            Self::Legacy => 0,
+            Self::HalfMd4 => 1,
+            Self::Tea => 2,
+            Self::LegacyUnsigned => 3,
+            Self::HalfMd4Unsigned => 4,
+            Self::TeaUnsigned => 5,
+        }
+    }
+
+    /// Get the hash algorithm used by a filesystem
+    ///
+    /// Signed versions are upgraded to their unsigned counterpart when the
+    /// superblock says the filesystem was created with unsigned `char`.
+    /// Filesystems flagged neither way are taken as signed.
+    pub fn from_superblock(sb: &SuperBlock) -> Option<Self> {
+        Some(Self::from_raw(sb.def_hash_version())?.signedness_of(sb))
+    }
+
+    /// Get the variant of this algorithm used on a filesystem, which is the
+    /// unsigned one if it was created with unsigned `char`
+    pub(crate) fn signedness_of(self, sb: &SuperBlock) -> Self {
+        if sb.super_flags().contains(SuperBlockFlags::UNSIGNED_HASH) {
+            self.to_unsigned()
+        } else {
+            self
+        }
+    }
+
+    /// Get the variant of this algorithm that treats characters as unsigned
+    pub fn to_unsigned(self) -> Self {
+        match self {
+            Self::Legacy => Self::LegacyUnsigned,
+            Self::HalfMd4 => Self::HalfMd4Unsigned,
+            Self::Tea => Self::TeaUnsigned,
+            other => other,
+        }
+    }
+
+    /// Check if characters are treated as unsigned
+    pub fn is_unsigned(self) -> bool {
+        matches!(
+            self,
+            Self::LegacyUnsigned | Self::HalfMd4Unsigned | Self::TeaUnsigned
+        )
+    }
+}
+
+/// Hash value of a directory entry name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DxHash {
+    /// Major hash, used to order entries; the low bit is always clear
+    pub major: u32,
+    /// Minor hash, used to order entries with equal major hashes
+    pub minor: u32,
+}
+
+/// Largest 32-bit hash, reserved as the end-of-directory marker for readdir
+const EXT4_HTREE_EOF_32BIT: u32 = 0x7fff_ffff;
+
+/// Default hash buffer used when the seed is all zeros
+const DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+/// Compute the directory hash of `name`
+pub fn dx_hash(name: &[u8], version: HashVersion, seed: &[u32; 4]) -> DxHash {
+    let mut buf = if seed.iter().any(|&s| s != 0) {
+        *seed
+    } else {
+        DEFAULT_SEED
+    };
+    let unsigned = version.is_unsigned();
+
+    let (major, minor) = match version {
+        HashVersion::Legacy | HashVersion::LegacyUnsigned => (legacy_hash(name, unsigned), 0),
+        HashVersion::HalfMd4 | HashVersion::HalfMd4Unsigned => {
+            let mut input = [0u32; 8];
+            for (i, chunk) in name.chunks(32).enumerate() {
+                str_to_hash_buf(chunk, name.len() - i * 32, &mut input, unsigned);
+                half_md4_transform(&mut buf, &input);
+            }
+            (buf[1], buf[2])
+        }
+        HashVersion::Tea | HashVersion::TeaUnsigned => {
+            let mut input = [0u32; 4];
+            for (i, chunk) in name.chunks(16).enumerate() {
+                str_to_hash_buf(chunk, name.len() - i * 16, &mut input, unsigned);
+                tea_transform(&mut buf, &input);
+            }
+            (buf[0], buf[1])
+        }
+    };
+
+    let mut major = major & !1;
+    if major == EXT4_HTREE_EOF_32BIT << 1 {
+        major = (EXT4_HTREE_EOF_32BIT - 1) << 1;
+    }
+
+    DxHash { major, minor }
+}
+
+/// Hash to store in the index entry of a newly split leaf block
+///
+/// `continued` is set when the first hash of the new block equals the last
+/// hash of the previous one, so lookups must scan both blocks.
+pub fn split_hash(hash: u32, continued: bool) -> u32 {
+    (hash & !1) | continued as u32
+}
+
+/// Check if a lookup for `hash` must continue into the leaf block whose
+/// index entry carries `next_hash`
+///
+/// This is the case only when the next block continues a collision run of
+/// exactly this hash.
+pub fn continues_into(hash: u32, next_hash: u32) -> bool {
+    next_hash & 1 != 0 && (next_hash & !1) == (hash & !1)
+}
+
+/// Convert a character to the value used by the hash functions
+fn hash_char(c: u8, unsigned: bool) -> u32 {
+    if unsigned {
+        c as u32
+    } else {
+        c as i8 as i32 as u32
+    }
+}
+
+/// The original ext3 directory hash
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32 {
+    let mut hash0: u32 = 0x12a3_fe2d;
+    let mut hash1: u32 = 0x37ab_e8f9;
+
+    for &c in name {
+        let mut hash =
+            hash1.wrapping_add(hash0 ^ hash_char(c, unsigned).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+
+    hash0 << 1
+}
+
+/// Pack up to `4 * out.len()` bytes of `msg` into words, padding with a
+/// value derived from `len`, the number of name bytes not yet hashed
+fn str_to_hash_buf(msg: &[u8], len: usize, out: &mut [u32], unsigned: bool) {
+    let mut pad = (len as u32) | ((len as u32) << 8);
+    pad |= pad << 16;
+
+    let mut val = pad;
+    let mut words = 0;
+    for (i, &c) in msg.iter().take(out.len() * 4).enumerate() {
+        val = hash_char(c, unsigned).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            out[words] = val;
+            words += 1;
+            val = pad;
+        }
+    }
+
+    if words < out.len() {
+        out[words] = val;
+        words += 1;
+    }
+    for word in &mut out[words..] {
+        *word = pad;
+    }
+}
+
+/// TEA block cipher step used by the TEA hash
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E37_79B9;
+    let mut sum: u32 = 0;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ (b1.wrapping_add(sum)) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ (b0.wrapping_add(sum)) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// Reduced MD4 compression function used by the half MD4 hash
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K1: u32 = 0;
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+    let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+
+    macro_rules! round {
+        ($f:expr, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s);
+        };
+    }
+
+    // Round 1
+    round!(f, a, b, c, d, input[0].wrapping_add(K1), 3);
+    round!(f, d, a, b, c, input[1].wrapping_add(K1), 7);
+    round!(f, c, d, a, b, input[2].wrapping_add(K1), 11);
+    round!(f, b, c, d, a, input[3].wrapping_add(K1), 19);
+    round!(f, a, b, c, d, input[4].wrapping_add(K1), 3);
+    round!(f, d, a, b, c, input[5].wrapping_add(K1), 7);
+    round!(f, c, d, a, b, input[6].wrapping_add(K1), 11);
+    round!(f, b, c, d, a, input[7].wrapping_add(K1), 19);
+
+    // Round 2
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    // Round 3
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
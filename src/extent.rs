@@ -8,10 +8,25 @@ use log::*;
 
 use crate::{Ext4Error, Ext4Result};
 
+/// Magic number of extent tree nodes
+pub const EXT4_EXT_MAGIC: u16 = 0xF30A;
+
+/// Number of extents that fit in the root node in `i_block`
+#[cfg(not(feature = "read-only"))]
+const EXT4_INLINE_EXTENTS: u16 = 4;
+
+/// Longest initialized extent
+const EXT4_EXT_INIT_MAX_LEN: u16 = 32768;
+
+/// Deepest extent tree allowed, as in Linux: 4 levels of index nodes
+/// suffice to map 2^32 blocks
+pub const EXT4_MAX_EXTENT_DEPTH: u16 = 5;
+
 /// Extent header structure
 #[derive(Debug, Clone)]
 pub struct ExtentHeader {
     /// Magic number (0xF30A)
+    #[cfg(not(feature = "read-only"))]
     pub magic: u16,
     /// Number of valid entries
     pub entries: u16,
@@ -20,6 +35,7 @@ pub struct ExtentHeader {
     /// Depth of extent tree
     pub depth: u16,
     /// Generation
+    #[cfg(not(feature = "read-only"))]
     pub generation: u32,
 }
 
@@ -28,10 +44,11 @@ pub struct ExtentHeader {
 pub struct Extent {
     /// First logical block
     pub block: u32,
-    /// Number of blocks covered by this extent
+    /// Number of blocks covered by this extent, plus 32768 if it is
+    /// unwritten
     pub len: u16,
-    /// Starting physical block
-    pub start: u32,
+    /// Starting physical block, 48 bits from `ee_start_hi` and `ee_start_lo`
+    pub start: u64,
 }
 
 /// Extent index structure for internal nodes
@@ -39,8 +56,8 @@ pub struct Extent {
 pub struct ExtentIndex {
     /// First logical block covered by this index
     pub block: u32,
-    /// Leaf node block number
-    pub leaf: u32,
+    /// Leaf node block number, 48 bits from `ei_leaf_hi` and `ei_leaf_lo`
+    pub leaf: u64,
 }
 
 /// Extent node (either leaf or index)
@@ -60,15 +77,17 @@ impl ExtentHeader {
         }
 
         let magic = u16::from_le_bytes([data[0], data[1]]);
-        if magic != 0xF30A {
+        if magic != EXT4_EXT_MAGIC {
             return Err(Ext4Error::InvalidInput);
         }
 
         Ok(Self {
+            #[cfg(not(feature = "read-only"))]
             magic,
             entries: u16::from_le_bytes([data[2], data[3]]),
             max_entries: u16::from_le_bytes([data[4], data[5]]),
             depth: u16::from_le_bytes([data[6], data[7]]),
+            #[cfg(not(feature = "read-only"))]
             generation: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
         })
     }
@@ -77,6 +96,16 @@ impl ExtentHeader {
     pub fn is_leaf(&self) -> bool {
         self.depth == 0
     }
+
+    /// Serialize the header into the first 12 bytes of `data`
+    #[cfg(not(feature = "read-only"))]
+    pub fn to_bytes(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&self.magic.to_le_bytes());
+        data[2..4].copy_from_slice(&self.entries.to_le_bytes());
+        data[4..6].copy_from_slice(&self.max_entries.to_le_bytes());
+        data[6..8].copy_from_slice(&self.depth.to_le_bytes());
+        data[8..12].copy_from_slice(&self.generation.to_le_bytes());
+    }
 }
 
 impl Extent {
@@ -86,12 +115,42 @@ impl Extent {
             return Err(Ext4Error::InvalidInput);
         }
 
+        let start_hi = u16::from_le_bytes([data[6], data[7]]);
+        let start_lo = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
         Ok(Self {
             block: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
             len: u16::from_le_bytes([data[4], data[5]]),
-            start: u32::from_le_bytes([data[6], data[7], data[8], data[9]]),
+            start: (start_hi as u64) << 32 | start_lo as u64,
         })
     }
+
+    /// Serialize the extent into the first 12 bytes of `data`
+    pub fn to_bytes(&self, data: &mut [u8]) {
+        data[0..4].copy_from_slice(&self.block.to_le_bytes());
+        data[4..6].copy_from_slice(&self.len.to_le_bytes());
+        data[6..8].copy_from_slice(&((self.start >> 32) as u16).to_le_bytes());
+        data[8..12].copy_from_slice(&(self.start as u32).to_le_bytes());
+    }
+
+    /// Whether the extent is unwritten: its blocks are allocated, but read
+    /// as zeros
+    pub fn is_unwritten(&self) -> bool {
+        self.len > EXT4_EXT_INIT_MAX_LEN
+    }
+
+    /// Number of blocks covered by this extent
+    pub fn length(&self) -> u16 {
+        if self.is_unwritten() {
+            self.len - EXT4_EXT_INIT_MAX_LEN
+        } else {
+            self.len
+        }
+    }
+
+    /// Logical block just past the extent
+    fn end(&self) -> u32 {
+        self.block + self.length() as u32
+    }
 }
 
 impl ExtentIndex {
@@ -101,9 +160,11 @@ impl ExtentIndex {
             return Err(Ext4Error::InvalidInput);
         }
 
+        let leaf_lo = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let leaf_hi = u16::from_le_bytes([data[8], data[9]]);
         Ok(Self {
             block: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
-            leaf: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            leaf: (leaf_hi as u64) << 32 | leaf_lo as u64,
         })
     }
 }
@@ -145,111 +206,291 @@ pub fn parse_extent_node(data: &[u8]) -> Ext4Result<ExtentNode> {
     }
 }
 
+/// Turn `i_block` into the 60 bytes it holds on disk
+pub(crate) fn inline_bytes(inode_block: &[u32; 15]) -> [u8; 60] {
+    let mut bytes = [0u8; 60];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(inode_block) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Write the extents of a leaf root into `i_block`
+#[cfg(not(feature = "read-only"))]
+fn store_inline_leaf(inode_block: &mut [u32; 15], extents: &[Extent]) {
+    let mut bytes = [0u8; 60];
+    let header = ExtentHeader {
+        magic: EXT4_EXT_MAGIC,
+        entries: extents.len() as u16,
+        max_entries: EXT4_INLINE_EXTENTS,
+        depth: 0,
+        generation: 0,
+    };
+    header.to_bytes(&mut bytes);
+    for (i, extent) in extents.iter().enumerate() {
+        extent.to_bytes(&mut bytes[12 + i * 12..]);
+    }
+    for (word, chunk) in inode_block.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+}
+
+/// Initialize `i_block` with an empty extent tree: a leaf root without
+/// extents
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn init_inline_root(inode_block: &mut [u32; 15]) {
+    store_inline_leaf(inode_block, &[]);
+}
+
+/// Map logical block `logical` to physical block `physical` in the extent
+/// tree rooted in `i_block`, or unmap it if `physical` is 0
+///
+/// The block joins a neighbouring extent when it is contiguous with it and
+/// written; the rest of an unwritten extent it is cut out of stays
+/// unwritten. Only trees that are a single leaf in the inode are supported; a mapping
+/// that needs more than four extents fails with `NoSpaceLeft`.
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn set_inline_block(
+    inode_block: &mut [u32; 15],
+    logical: u32,
+    physical: u64,
+) -> Ext4Result<()> {
+    let bytes = inline_bytes(inode_block);
+    let header = ExtentHeader::from_bytes(&bytes)?;
+    if !header.is_leaf() {
+        warn!("Mapping blocks in extent trees of depth {} is not supported", header.depth);
+        return Err(Ext4Error::NotSupported);
+    }
+    let ExtentNode::Leaf(old) = parse_extent_node(&bytes)? else {
+        return Err(Ext4Error::InvalidState);
+    };
+
+    // Cut `logical` out of the extent holding it
+    let mut extents = Vec::with_capacity(old.len() + 1);
+    for extent in old {
+        if logical < extent.block || logical >= extent.end() {
+            extents.push(extent);
+            continue;
+        }
+        let unwritten = extent.len - extent.length();
+        let before = (logical - extent.block) as u16;
+        if before > 0 {
+            extents.push(Extent { len: unwritten + before, ..extent.clone() });
+        }
+        if logical + 1 < extent.end() {
+            extents.push(Extent {
+                block: logical + 1,
+                len: unwritten + extent.length() - before - 1,
+                start: extent.start + before as u64 + 1,
+            });
+        }
+    }
+
+    if physical != 0 {
+        // Unwritten extents have lengths past the limit, so they never join
+        let joins_end = |e: &Extent| {
+            e.end() == logical
+                && e.start + e.len as u64 == physical
+                && e.len < EXT4_EXT_INIT_MAX_LEN
+        };
+        let joins_start = |e: &Extent| {
+            e.block == logical + 1 && e.start == physical + 1 && e.len < EXT4_EXT_INIT_MAX_LEN
+        };
+        if let Some(extent) = extents.iter_mut().find(|e| joins_end(e)) {
+            extent.len += 1;
+        } else if let Some(extent) = extents.iter_mut().find(|e| joins_start(e)) {
+            extent.block = logical;
+            extent.start = physical;
+            extent.len += 1;
+        } else {
+            extents.push(Extent { block: logical, len: 1, start: physical });
+        }
+    }
+
+    if extents.len() > EXT4_INLINE_EXTENTS as usize {
+        warn!("Mapping logical block {} needs more extents than fit in the inode", logical);
+        return Err(Ext4Error::NoSpaceLeft);
+    }
+    extents.sort_by_key(|e| e.block);
+    store_inline_leaf(inode_block, &extents);
+    Ok(())
+}
+
+/// Collect the nodes below the root of the extent tree of `inode`, which
+/// hold its block map rather than its data
+///
+/// Leaves are listed by their parents and never read.
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn extent_node_blocks<D>(
+    fs: &crate::Ext4FileSystem<D>,
+    inode: &crate::Inode,
+) -> Ext4Result<Vec<u64>>
+where
+    D: axdriver_block::BlockDriverOps,
+{
+    let root = inline_bytes(&inode.block);
+    let depth = ExtentHeader::from_bytes(&root)?.depth;
+    if depth > EXT4_MAX_EXTENT_DEPTH {
+        return Err(Ext4Error::InvalidState);
+    }
+    let csum_seed = Some(fs.inode_csum_seed(inode));
+    let mut buf = fs.take_block_buf();
+    let mut blocks = Vec::new();
+    let mut level = vec![root.to_vec()];
+    for remaining in (0..depth).rev() {
+        let mut children = Vec::new();
+        for node in &level {
+            let ExtentNode::Index(indices) = parse_extent_node(node)? else {
+                return Err(Ext4Error::InvalidState);
+            };
+            for index in indices {
+                blocks.push(index.leaf);
+                if remaining > 0 {
+                    fs.read_extent_block(index.leaf, csum_seed, &mut buf)?;
+                    children.push(buf.clone());
+                }
+            }
+        }
+        level = children;
+    }
+    fs.put_block_buf(buf);
+    Ok(blocks)
+}
+
 /// Find physical block for a given logical block in an extent tree
+///
+/// `i_block` is read as the 60 bytes it holds on disk, whose root node is
+/// parsed like any other.
 pub fn find_block_in_extent_tree<D>(
     fs: &crate::Ext4FileSystem<D>,
     inode_block: &[u32; 15],
     logical_block: u32,
-) -> Ext4Result<u32>
+) -> Ext4Result<u64>
 where
     D: axdriver_block::BlockDriverOps,
 {
-    // The first block (block[0]) contains the extent tree root block number
-    // when extents are enabled, it's not a direct block pointer
-    let extent_root = inode_block[0];
-    
-    // Check if this is an inline extent (magic in first 2 bytes)
-    if (extent_root & 0xFFFF) == 0xF30A {
-        // This is an inline extent - extract from inode block array
-        let entries = ((extent_root >> 16) & 0xFFFF) as u16;
-        let depth = ((extent_root as u64 >> 32) & 0xFFFF) as u16;
-        
-        debug!("Found inline extent: entries={}, depth={}", entries, depth);
-        
-        if depth == 0 && entries > 0 {
-            // Leaf node with inline extents
-            // The extent data is in the remaining block array entries
-            for i in 0..entries.min(4) {
-                let idx = 1 + i * 3; // Each extent uses 3 u32 values
-                if idx + 2 < 15 {
-                    let block = inode_block[idx as usize];
-                    let len = (inode_block[(idx + 1) as usize] & 0xFFFF) as u16;
-                    let start_hi = ((inode_block[(idx + 1) as usize] >> 16) & 0xFFFF) as u16;
-                    let start_lo = inode_block[(idx + 2) as usize];
-                    let start = ((start_hi as u32) << 16) | start_lo;
-                    
-                    debug!("Extent[{}]: block={}, len={}, start={}", i, block, len, start);
-                    
-                    // Check if this is a valid extent
-                    if len == 0 {
-                        // If len is 0, this might be an uninitialized extent
-                        // For existing directories, this might mean the extent is not properly formatted
-                        // Let's try to use the block number directly
-                        if logical_block == 0 {
-                            return Ok(block);
-                        }
-                        continue;
-                    }
-                    
-                    if logical_block >= block && logical_block < block + len as u32 {
-                        return Ok(start + (logical_block - block));
-                    }
-                }
-            }
-        }
+    // Some tools leave the tree of an inode without blocks all zeros
+    if inode_block.iter().all(|&word| word == 0) {
         return Err(Ext4Error::BlockNotFound);
     }
-    
-    // For larger files, the extent tree is stored in a separate block
-    // The first block contains the block number of the extent tree root
-    if extent_root == 0 {
+    let root = inline_bytes(inode_block);
+    find_block_in_extent_node(fs, &root, None, logical_block).map(|(block, _)| block)
+}
+
+/// Find physical block for a given logical block in the extent tree of
+/// `inode`, whose nodes are also checked against its checksum seed when
+/// `verify_reads` is set, and whether it is in an unwritten extent
+pub(crate) fn find_block_in_inode_extents<D>(
+    fs: &crate::Ext4FileSystem<D>,
+    inode: &crate::Inode,
+    logical_block: u32,
+) -> Ext4Result<(u64, bool)>
+where
+    D: axdriver_block::BlockDriverOps,
+{
+    if inode.block.iter().all(|&word| word == 0) {
         return Err(Ext4Error::BlockNotFound);
     }
-    
-    // Traverse the extent tree starting at the root block
-    find_block_in_extent_node(fs, extent_root, logical_block)
+    let root = inline_bytes(&inode.block);
+    let seed = fs.superblock.has_metadata_csum().then(|| fs.inode_csum_seed(inode));
+    find_block_in_extent_node(fs, &root, seed, logical_block)
 }
 
-/// Recursively search for a block in an extent node
+/// Search for a block in the extent tree whose root node is `root`
+///
+/// The tree is walked with a loop rather than recursion. Every child must be
+/// a block of the filesystem one level below its parent, and no node may be
+/// deeper than [`EXT4_MAX_EXTENT_DEPTH`], so an index pointing back at
+/// itself or at an ancestor is caught as corruption instead of looping.
+/// `csum_seed` is the checksum seed of the inode owning the tree, if known.
+/// Also returns whether the block is in an unwritten extent.
 fn find_block_in_extent_node<D>(
     fs: &crate::Ext4FileSystem<D>,
-    block_num: u32,
+    root: &[u8],
+    csum_seed: Option<u32>,
     logical_block: u32,
-) -> Ext4Result<u32>
+) -> Ext4Result<(u64, bool)>
 where
     D: axdriver_block::BlockDriverOps,
 {
-    let mut buf = vec![0u8; fs.superblock.block_size() as usize];
-    fs.read_block(block_num, &mut buf)?;
-    
-    let node = parse_extent_node(&buf)?;
-    
-    match node {
-        ExtentNode::Leaf(extents) => {
-            // Search through extents
-            for extent in extents {
-                if logical_block >= extent.block && logical_block < extent.block + extent.len as u32 {
-                    return Ok(extent.start + (logical_block - extent.block));
-                }
+    let mut buf = Vec::new();
+    let result = search_extent_tree(fs, root, csum_seed, logical_block, &mut buf);
+    fs.put_block_buf(buf);
+    result
+}
+
+/// Loop of [`find_block_in_extent_node`], reading nodes below the root into
+/// `buf`, which is only taken from the buffer pool once a node is read
+fn search_extent_tree<D>(
+    fs: &crate::Ext4FileSystem<D>,
+    root: &[u8],
+    csum_seed: Option<u32>,
+    logical_block: u32,
+    buf: &mut Vec<u8>,
+) -> Ext4Result<(u64, bool)>
+where
+    D: axdriver_block::BlockDriverOps,
+{
+    let corrupt = |block: u64, line: u32| {
+        fs.record_error("find_block_in_extent_node", line, 0, block, &Ext4Error::InvalidState);
+        Ext4Error::InvalidState
+    };
+    let first_data_block = fs.superblock.first_data_block();
+    let blocks_count = fs.superblock.blocks_count();
+
+    // Block of the node being searched, 0 for the root in the inode
+    let mut block_num = 0;
+    let mut expected_depth = None;
+    loop {
+        let data = if block_num == 0 {
+            root
+        } else {
+            if block_num < first_data_block as u64 || block_num >= blocks_count {
+                warn!("Extent tree node at block {} is outside the filesystem", block_num);
+                return Err(corrupt(block_num, line!()));
             }
-            Err(Ext4Error::BlockNotFound)
+            if buf.is_empty() {
+                *buf = fs.take_block_buf();
+            }
+            fs.read_extent_block(block_num, csum_seed, buf)?;
+            &buf[..]
+        };
+
+        let header = ExtentHeader::from_bytes(data).inspect_err(|e| {
+            fs.record_error("find_block_in_extent_node", line!(), 0, block_num, e);
+        })?;
+        if header.depth > EXT4_MAX_EXTENT_DEPTH
+            || expected_depth.is_some_and(|depth| header.depth != depth)
+        {
+            warn!(
+                "Extent tree node at block {} has depth {}, expected {:?}",
+                block_num, header.depth, expected_depth
+            );
+            return Err(corrupt(block_num, line!()));
         }
-        ExtentNode::Index(indices) => {
-            // Find the right index to follow
-            for i in 0..indices.len() {
-                let index = &indices[i];
-                let next_logical = if i + 1 < indices.len() {
-                    indices[i + 1].block
-                } else {
-                    u32::MAX
+
+        match parse_extent_node(data)? {
+            ExtentNode::Leaf(extents) => {
+                return extents
+                    .iter()
+                    .find(|e| logical_block >= e.block && logical_block < e.end())
+                    .map(|e| (e.start + (logical_block - e.block) as u64, e.is_unwritten()))
+                    .ok_or(Ext4Error::BlockNotFound);
+            }
+            ExtentNode::Index(indices) => {
+                // Follow the last index starting at or before the block
+                let Some(index) = indices.iter().take_while(|i| i.block <= logical_block).last()
+                else {
+                    return Err(Ext4Error::BlockNotFound);
                 };
-                
-                if logical_block >= index.block && logical_block < next_logical {
-                    // Recurse into child node
-                    return find_block_in_extent_node(fs, index.leaf, logical_block);
+                // Block 0 is never part of a tree, and would be taken
+                // for the root
+                if index.leaf == 0 {
+                    return Err(corrupt(block_num, line!()));
                 }
+                block_num = index.leaf;
+                expected_depth = Some(header.depth - 1);
             }
-            Err(Ext4Error::BlockNotFound)
         }
     }
-}
\ No newline at end of file
+}
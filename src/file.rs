@@ -1,19 +1,102 @@
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use axdriver_block::BlockDriverOps;
+use bitflags::bitflags;
 use log::*;
 
-use crate::{Ext4Error, Ext4Result, Inode};
+#[cfg(not(feature = "read-only"))]
+use crate::inode::EXT4_GOOD_OLD_INODE_SIZE;
+#[cfg(not(feature = "read-only"))]
+use crate::xattr::{self, EXT4_XATTR_MAGIC};
+#[cfg(not(feature = "read-only"))]
+use crate::Change;
+use crate::{Ext4Error, Ext4Result, Inode, ResolveFlags, TraceOp, EXT4_ROOT_INO};
 
 /// File operations
 pub struct File {
     inode: Inode,
     position: u64,
+    /// Staging buffer of [`File::buffered`] handles
+    staged: Option<StagedWrite>,
+    /// Whether data bypasses the block cache, for [`File::direct`] handles
+    direct: bool,
+    /// Share of the open file table entry of the inode, for files from
+    /// [`open`](crate::Ext4FileSystem::open); dropping it closes the file
+    open: Option<Arc<()>>,
+}
+
+bitflags! {
+    /// Access requested from [`Ext4FileSystem::open`](crate::Ext4FileSystem::open)
+    #[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+    pub struct OpenFlags: u32 {
+        /// Open for writing
+        const WRITE = 1 << 0;
+        /// Open for writing at the end of the file only
+        const APPEND = 1 << 1;
+    }
+}
+
+/// Sequential small writes not written to disk yet
+#[derive(Default)]
+struct StagedWrite {
+    /// File offset of the first staged byte
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl StagedWrite {
+    /// File offset just past the staged bytes
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
 }
 
 impl File {
     /// Create a new file from an inode
     pub fn new(inode: Inode) -> Self {
-        Self { inode, position: 0 }
+        Self {
+            inode,
+            position: 0,
+            staged: None,
+            direct: false,
+            open: None,
+        }
+    }
+
+    /// Create a new file from an inode, coalescing small sequential writes
+    ///
+    /// Writes shorter than a block are staged in memory and written out once
+    /// they reach the end of a block, so a run of them costs one block write
+    /// instead of a read-modify-write each. Staged data is also written out
+    /// by the next operation after a seek, by [`Self::sync`] and by
+    /// [`Self::close`]; until then other handles don't see it, and it is lost
+    /// if the file is dropped.
+    pub fn buffered(inode: Inode) -> Self {
+        Self {
+            inode,
+            position: 0,
+            staged: Some(StagedWrite::default()),
+            direct: false,
+            open: None,
+        }
+    }
+
+    /// Create a new file from an inode, transferring data without the block
+    /// cache, like `O_DIRECT`
+    ///
+    /// Reads and writes go straight between the caller's buffer and the
+    /// device, one request per physically contiguous run of whole blocks,
+    /// so that streaming a large file doesn't evict cached metadata. Written
+    /// blocks are dropped from the cache. Partial blocks at either end of a
+    /// transfer still go through a bounce buffer.
+    pub fn direct(inode: Inode) -> Self {
+        Self {
+            inode,
+            position: 0,
+            staged: None,
+            direct: true,
+            open: None,
+        }
     }
 
     /// Get the inode
@@ -21,9 +104,12 @@ impl File {
         &self.inode
     }
 
-    /// Get the file size
+    /// Get the file size, including staged writes
     pub fn size(&self) -> u64 {
-        self.inode.size
+        match &self.staged {
+            Some(staged) if !staged.data.is_empty() => self.inode.size.max(staged.end()),
+            _ => self.inode.size,
+        }
     }
 
     /// Get the current position
@@ -33,7 +119,7 @@ impl File {
 
     /// Seek to a position
     pub fn seek(&mut self, offset: u64) -> Ext4Result<u64> {
-        if offset > self.inode.size {
+        if offset > self.size() {
             return Err(Ext4Error::InvalidInput);
         }
 
@@ -50,7 +136,7 @@ impl File {
         };
 
         match new_pos {
-            Some(pos) if pos <= self.inode.size => {
+            Some(pos) if pos <= self.size() => {
                 self.position = pos;
                 Ok(pos)
             }
@@ -61,9 +147,9 @@ impl File {
     /// Seek from end
     pub fn seek_from_end(&mut self, offset: i64) -> Ext4Result<u64> {
         let new_pos = if offset >= 0 {
-            self.inode.size.checked_add(offset as u64)
+            self.size().checked_add(offset as u64)
         } else {
-            self.inode.size.checked_sub((-offset) as u64)
+            self.size().checked_sub((-offset) as u64)
         };
 
         match new_pos {
@@ -84,16 +170,39 @@ impl File {
     where
         D: axdriver_block::BlockDriverOps,
     {
+        let span = fs.span_enter(TraceOp::Read, self.inode.ino);
+        let result = self.read_untraced(buf, fs);
+        fs.span_exit(span, &result);
+        result
+    }
+
+    /// [`Self::read`] without its span
+    fn read_untraced<D>(
+        &mut self,
+        buf: &mut [u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        self.write_staged(fs)?;
         if self.position >= self.inode.size {
             return Ok(0);
         }
+        if self.inode.has_inline_data() {
+            return self.read_inline(buf, fs);
+        }
+        if self.direct {
+            return self.read_direct(buf, fs);
+        }
 
         let block_size = fs.superblock().block_size();
         let mut bytes_read = 0;
         let mut offset = self.position;
+        let mut block_buf = fs.take_block_buf();
 
         while bytes_read < buf.len() && offset < self.inode.size {
-            let block_num = self.inode.get_block_number(offset, block_size, fs)?;
+            let block_num = self.inode.get_data_block_number(offset, block_size, fs)?;
             if block_num == 0 {
                 // Sparse file - zero block
                 let block_offset = (offset % block_size as u64) as usize;
@@ -110,7 +219,7 @@ impl File {
             }
 
             // Check if block number is valid
-            if block_num >= fs.superblock().blocks_count() as u32 {
+            if block_num >= fs.superblock().blocks_count() {
                 warn!("Invalid block number {} in file inode {}, treating as zero", block_num, self.inode.ino);
                 // Treat as sparse block
                 let block_offset = (offset % block_size as u64) as usize;
@@ -130,17 +239,12 @@ impl File {
             let remaining_in_block =
                 (block_size as usize - block_offset).min(buf.len() - bytes_read);
 
-            let mut block_buf = vec![0u8; block_size as usize];
+            // Nothing counts as read if a block fails, so that the caller
+            // can retry from the same position
             if let Err(e) = fs.read_block(block_num, &mut block_buf) {
                 warn!("Failed to read block {} for file inode {}: {:?}", block_num, self.inode.ino, e);
-                // Treat as sparse block
-                for i in 0..remaining_in_block {
-                    buf[bytes_read + i] = 0;
-                }
-
-                bytes_read += remaining_in_block;
-                offset += remaining_in_block as u64;
-                continue;
+                fs.put_block_buf(block_buf);
+                return Err(e);
             }
 
             buf[bytes_read..bytes_read + remaining_in_block]
@@ -149,61 +253,519 @@ impl File {
             bytes_read += remaining_in_block;
             offset += remaining_in_block as u64;
         }
+        fs.put_block_buf(block_buf);
 
         self.position = offset;
+        fs.update_atime(&mut self.inode)?;
         Ok(bytes_read)
     }
 
+    /// Read from the current position to the end of the file, appending
+    /// to `buf`
+    ///
+    /// Fails with `FileTooLarge`, reading nothing, if that is more than the
+    /// `max_read_to_end` mount option allows, and with `NoMemory` if memory
+    /// can't be reserved for it. Returns the number of bytes read.
+    pub fn read_to_end<D>(
+        &mut self,
+        buf: &mut Vec<u8>,
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        let limit = match fs.mount_options.max_read_to_end {
+            0 => u64::MAX,
+            limit => limit,
+        };
+        self.read_to_end_limit(buf, limit, fs)
+    }
+
+    /// [`Self::read_to_end`] with a limit of `limit` bytes instead of the
+    /// one of the mount options
+    pub fn read_to_end_limit<D>(
+        &mut self,
+        buf: &mut Vec<u8>,
+        limit: u64,
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        self.write_staged(fs)?;
+        let remaining = self.size().saturating_sub(self.position);
+        if remaining > limit {
+            warn!(
+                "Inode {} has {} bytes left to read, more than the limit of {}",
+                self.inode.ino, remaining, limit
+            );
+            return Err(Ext4Error::FileTooLarge);
+        }
+        let len = usize::try_from(remaining).map_err(|_| Ext4Error::FileTooLarge)?;
+        buf.try_reserve_exact(len).map_err(|_| Ext4Error::NoMemory)?;
+
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        let mut done = 0;
+        while done < len {
+            match self.read(&mut buf[start + done..], fs) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(e) => {
+                    buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+        buf.truncate(start + done);
+        Ok(done)
+    }
+
     /// Write data to the file
+    ///
+    /// Returns the number of bytes written. This is less than `buf.len()`
+    /// when an error ends a write after some of it was committed; the error
+    /// is returned only when nothing was written.
+    #[cfg(not(feature = "read-only"))]
     pub fn write<D>(&mut self, buf: &[u8], fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<usize>
     where
         D: BlockDriverOps,
     {
-        let block_size = fs.superblock().block_size();
-        let mut bytes_written = 0;
-        let mut offset = self.position;
+        let span = fs.span_enter(TraceOp::Write, self.inode.ino);
+        let result = self.write_untraced(buf, fs);
+        fs.span_exit(span, &result);
+        if result.is_ok() {
+            fs.notify(self.inode.ino, Change::Write);
+        }
+        result
+    }
+
+    /// [`Self::write`] without its span
+    #[cfg(not(feature = "read-only"))]
+    fn write_untraced<D>(
+        &mut self,
+        buf: &[u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        self.inode.check_write_at(self.position)?;
+        let block_size = fs.superblock().block_size() as usize;
+        if let Some(staged) = &self.staged {
+            if !staged.data.is_empty() && staged.end() != self.position {
+                self.write_staged(fs)?;
+            }
+            if buf.len() < block_size {
+                return self.stage(buf, block_size, fs);
+            }
+            self.write_staged(fs)?;
+        }
+
+        // Commit large writes in steps so they never leave more than
+        // max_dirty_blocks unflushed. Once a step is committed, a later
+        // failure ends the write short instead of failing it.
+        let step = Self::dirty_limit(fs).unwrap_or(buf.len()).max(1);
+        let mut written = 0;
+        for part in buf.chunks(step) {
+            if let Err(e) = self.commit_part(part, fs) {
+                if written == 0 {
+                    return Err(e);
+                }
+                warn!(
+                    "Write to inode {} stopped after {} bytes: {:?}",
+                    self.inode.ino, written, e
+                );
+                return Ok(written);
+            }
+            written += part.len();
+            if written < buf.len() {
+                debug!("Committed {} bytes of a write to inode {}", part.len(), self.inode.ino);
+            }
+        }
+        Ok(written)
+    }
+
+    /// Write `part` at the current position and commit it with the inode
+    #[cfg(not(feature = "read-only"))]
+    fn commit_part<D>(&mut self, part: &[u8], fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
         let mut inode = self.inode.clone();
+        let offset = self.write_data(&mut inode, self.position, part, fs)?;
 
-        while bytes_written < buf.len() {
-            let block_index = offset / block_size as u64;
-            let block_num = match inode.get_block_number(offset, block_size, fs) {
-                Ok(0) => {
-                    // Need to allocate a new block
-                    let new_block = fs.alloc_block()?;
-                    inode.set_block(block_index, new_block, block_size, fs)?;
-                    new_block
+        // Update file size if needed
+        if offset > inode.size {
+            inode.size = offset;
+        }
+
+        // Write updated inode
+        fs.note_file_size(&inode)?;
+        fs.order_data()?;
+        fs.write_inode(&inode)?;
+        self.inode = inode;
+        self.position = offset;
+        Ok(())
+    }
+
+    /// Most bytes written before an intermediate commit, if limited
+    #[cfg(not(feature = "read-only"))]
+    fn dirty_limit<D>(fs: &crate::Ext4FileSystem<D>) -> Option<usize>
+    where
+        D: BlockDriverOps,
+    {
+        let blocks = fs.mount_options.max_dirty_blocks as usize;
+        (blocks != 0).then(|| blocks * fs.superblock().block_size() as usize)
+    }
+
+    /// Append `buf` to the end of the file, returning the new size
+    ///
+    /// The data goes to the blocks past the current end of file, which is
+    /// read again from disk so that appends through other handles are not
+    /// overwritten. Only once the data is flushed does an inode update
+    /// publish the new size, so readers never see a size covering data not
+    /// yet written. With a journal, the inode update goes through the log
+    /// first, so a crash leaves either the old size or the new one, never
+    /// a torn inode.
+    #[cfg(not(feature = "read-only"))]
+    pub fn append<D>(&mut self, buf: &[u8], fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<u64>
+    where
+        D: BlockDriverOps,
+    {
+        self.write_staged(fs)?;
+        let mut inode = fs.get_inode(self.inode.ino)?;
+        let start = inode.size;
+        inode.check_write_at(start)?;
+        // The size can't change before all of buf is written, but the data
+        // is still flushed in steps
+        let step = Self::dirty_limit(fs).unwrap_or(buf.len()).max(1);
+        let mut end = start;
+        for (i, part) in buf.chunks(step).enumerate() {
+            if i > 0 {
+                fs.flush()?;
+            }
+            end = self.write_data(&mut inode, end, part, fs)?;
+        }
+        inode.size = end;
+
+        fs.note_file_size(&inode)?;
+        fs.flush()?;
+        fs.write_inode_journaled(&inode)?;
+        self.inode = inode;
+        self.position = end;
+        fs.notify(self.inode.ino, Change::Write);
+        Ok(end)
+    }
+
+    /// Stage `buf`, shorter than a block, at the current position
+    #[cfg(not(feature = "read-only"))]
+    fn stage<D>(
+        &mut self,
+        buf: &[u8],
+        block_size: usize,
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let staged = self.staged.get_or_insert_with(Default::default);
+            if staged.data.is_empty() {
+                staged.offset = self.position;
+            }
+            let room = block_size - (staged.end() % block_size as u64) as usize;
+            let len = room.min(rest.len());
+            staged.data.extend_from_slice(&rest[..len]);
+            self.position += len as u64;
+            rest = &rest[len..];
+
+            if len == room {
+                self.write_staged(fs)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Write out the staged data, if any
+    #[cfg(not(feature = "read-only"))]
+    fn write_staged<D>(&mut self, fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        let Some(staged) = self.staged.as_mut().filter(|s| !s.data.is_empty()) else {
+            return Ok(());
+        };
+        let offset = staged.offset;
+        let data = core::mem::take(&mut staged.data);
+
+        let mut inode = self.inode.clone();
+        let end = Self::write_blocks(&mut inode, offset, &data, fs)?;
+        if end > inode.size {
+            inode.size = end;
+        }
+        fs.note_file_size(&inode)?;
+        fs.order_data()?;
+        fs.write_inode(&inode)?;
+        self.inode = inode;
+
+        // Keep the allocation of the buffer for the next writes
+        if let Some(staged) = self.staged.as_mut() {
+            staged.data = data;
+            staged.data.clear();
+        }
+        Ok(())
+    }
+
+    /// Nothing is staged with the read-only feature
+    #[cfg(feature = "read-only")]
+    fn write_staged<D>(&mut self, _fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        Ok(())
+    }
+
+    /// Write out staged data and flush the device
+    pub fn sync<D>(&mut self, fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        self.write_staged(fs)?;
+        fs.flush()
+    }
+
+    /// Write out staged data and close the file
+    ///
+    /// A file from [`open`](crate::Ext4FileSystem::open) no longer counts as
+    /// open afterwards, even if writing fails. Closing the last file of an
+    /// inode whose last link is gone frees the inode.
+    pub fn close<D>(mut self, fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        let result = self.write_staged(fs);
+        if self.open.take().is_none() {
+            return result;
+        }
+        let released = fs.release_closed();
+        result.and(released)
+    }
+
+    /// Read from the current position of a file whose data is in its inode
+    fn read_inline<D>(
+        &mut self,
+        buf: &mut [u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        let data = fs.inline_file_data(&self.inode)?;
+        let start = usize::try_from(self.position).map_or(data.len(), |pos| pos.min(data.len()));
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+
+        self.position += len as u64;
+        fs.update_atime(&mut self.inode)?;
+        Ok(len)
+    }
+
+    /// Read from the current position without the block cache
+    fn read_direct<D>(
+        &mut self,
+        buf: &mut [u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<usize>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size() as usize;
+        let start = self.position;
+        let end = self.inode.size.min(start + buf.len() as u64);
+        let mut offset = start;
+        let mut block_buf = vec![0u8; block_size];
+
+        while offset < end {
+            let done = (offset - start) as usize;
+            let block_offset = (offset % block_size as u64) as usize;
+            let len = (block_size - block_offset).min((end - offset) as usize);
+            let block = self.inode.get_data_block_number(offset, block_size as u32, fs)?;
+            if block == 0 || block >= fs.superblock().blocks_count() {
+                buf[done..done + len].fill(0);
+                offset += len as u64;
+                continue;
+            }
+
+            if len < block_size {
+                fs.read_blocks(block, &mut block_buf)?;
+                buf[done..done + len].copy_from_slice(&block_buf[block_offset..block_offset + len]);
+                offset += len as u64;
+                continue;
+            }
+
+            // Whole blocks are read into the caller's buffer, as many at a
+            // time as are contiguous on disk
+            let whole = (end - offset) / block_size as u64;
+            let mut count = 1;
+            while count < whole {
+                let next = offset + count * block_size as u64;
+                if self.inode.get_data_block_number(next, block_size as u32, fs)? != block + count {
+                    break;
                 }
-                Ok(block) => {
-                    if block >= fs.superblock().blocks_count() as u32 {
-                        warn!("Invalid block number {} in file inode {}, allocating new block", block, inode.ino);
-                        // Allocate a new block
-                        let new_block = fs.alloc_block()?;
-                        inode.set_block(block_index, new_block, block_size, fs)?;
-                        new_block
-                    } else {
-                        block
-                    }
+                count += 1;
+            }
+            let len = count as usize * block_size;
+            fs.read_blocks(block, &mut buf[done..done + len])?;
+            offset += len as u64;
+        }
+
+        self.position = end;
+        fs.update_atime(&mut self.inode)?;
+        Ok((end - start) as usize)
+    }
+
+    /// Write `buf` at `offset` of `inode` the way this handle transfers data
+    #[cfg(not(feature = "read-only"))]
+    fn write_data<D>(
+        &self,
+        inode: &mut Inode,
+        offset: u64,
+        buf: &[u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<u64>
+    where
+        D: BlockDriverOps,
+    {
+        match self.direct {
+            true => Self::write_direct(inode, offset, buf, fs),
+            false => Self::write_blocks(inode, offset, buf, fs),
+        }
+    }
+
+    /// Write `buf` at `offset` of `inode` without the block cache
+    ///
+    /// As [`Self::write_blocks`], but whole blocks are written from `buf` as
+    /// many at a time as are contiguous on disk.
+    #[cfg(not(feature = "read-only"))]
+    fn write_direct<D>(
+        inode: &mut Inode,
+        offset: u64,
+        buf: &[u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<u64>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size() as usize;
+        let mut done = 0;
+        let mut offset = offset;
+
+        while done < buf.len() {
+            let block_index = offset / block_size as u64;
+            let (block, fresh) = Self::map_for_write(inode, block_index, fs)?;
+            let block_offset = (offset % block_size as u64) as usize;
+            let len = (block_size - block_offset).min(buf.len() - done);
+
+            if len < block_size {
+                let mut block_buf = vec![0u8; block_size];
+                if !fresh {
+                    fs.read_blocks(block, &mut block_buf)?;
                 }
-                Err(_) => {
-                    // Need to allocate a new block
-                    let new_block = fs.alloc_block()?;
-                    inode.set_block(block_index, new_block, block_size, fs)?;
-                    new_block
+                block_buf[block_offset..block_offset + len].copy_from_slice(&buf[done..done + len]);
+                fs.write_blocks_uncached(block, &block_buf)?;
+                done += len;
+                offset += len as u64;
+                continue;
+            }
+
+            let whole = ((buf.len() - done) / block_size) as u64;
+            let mut count = 1;
+            while count < whole
+                && Self::map_for_write(inode, block_index + count, fs)?.0 == block + count
+            {
+                count += 1;
+            }
+            let len = count as usize * block_size;
+            fs.write_blocks_uncached(block, &buf[done..done + len])?;
+            done += len;
+            offset += len as u64;
+        }
+
+        Ok(offset)
+    }
+
+    /// Physical block backing logical block `block_index` of `inode`,
+    /// allocating one if there is none or the mapping is out of range
+    ///
+    /// Blocks of unwritten extents are marked written. Also returns whether
+    /// the block is new or was unwritten, so that its old contents aren't
+    /// part of the file.
+    #[cfg(not(feature = "read-only"))]
+    fn map_for_write<D>(
+        inode: &mut Inode,
+        block_index: u64,
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<(u64, bool)>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size();
+        let offset = block_index * block_size as u64;
+        match inode.get_block_number(offset, block_size, fs) {
+            Ok(block) if block != 0 && block < fs.superblock().blocks_count() => {
+                if !inode.is_unwritten(offset, block_size, fs)? {
+                    return Ok((block, false));
                 }
-            };
+                inode.set_block(block_index, block, block_size, fs)?;
+                return Ok((block, true));
+            }
+            Ok(0) | Err(_) => {}
+            Ok(block) => {
+                warn!(
+                    "Invalid block number {} in file inode {}, allocating new block",
+                    block, inode.ino
+                );
+            }
+        }
+        let new_block = fs.alloc_data_block(inode)?;
+        inode.charge_block(block_size);
+        inode.set_block(block_index, new_block, block_size, fs)?;
+        Ok((new_block, true))
+    }
+
+    /// Write `buf` at `offset` of `inode`, allocating blocks as needed
+    ///
+    /// Only `inode` itself is left to be written. Returns the offset just
+    /// past the data.
+    #[cfg(not(feature = "read-only"))]
+    fn write_blocks<D>(
+        inode: &mut Inode,
+        offset: u64,
+        buf: &[u8],
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<u64>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size();
+        let mut bytes_written = 0;
+        let mut offset = offset;
+        let mut block_buf = fs.take_block_buf();
+
+        while bytes_written < buf.len() {
+            let (block_num, fresh) = Self::map_for_write(inode, offset / block_size as u64, fs)?;
 
             let block_offset = (offset % block_size as u64) as usize;
             let remaining_in_block =
                 (block_size as usize - block_offset).min(buf.len() - bytes_written);
 
-            let mut block_buf = vec![0u8; block_size as usize];
-
             // Read existing block if not writing to a new block
-            if block_offset > 0 || remaining_in_block < block_size as usize {
-                if let Err(e) = fs.read_block(block_num, &mut block_buf) {
-                    warn!("Failed to read block {} for file inode {}: {:?}", block_num, inode.ino, e);
-                    // Continue with zero-filled block
-                }
+            if fresh {
+                block_buf.fill(0);
+            } else if block_offset > 0 || remaining_in_block < block_size as usize {
+                fs.read_block(block_num, &mut block_buf)?;
             }
 
             block_buf[block_offset..block_offset + remaining_in_block]
@@ -214,24 +776,134 @@ impl File {
             bytes_written += remaining_in_block;
             offset += remaining_in_block as u64;
         }
+        fs.put_block_buf(block_buf);
 
-        self.position = offset;
+        Ok(offset)
+    }
 
-        // Update file size if needed
-        if offset > inode.size {
-            inode.size = offset;
-            // Update block count
-            inode.blocks = (offset + block_size as u64 - 1) / block_size as u64;
+    /// Iterate over the physical block runs backing this file
+    ///
+    /// Each item covers a maximal run of logically and physically contiguous
+    /// blocks. Holes and unwritten extents, which read as zeros, are
+    /// skipped, and a file whose data is in its inode has no runs.
+    pub fn blocks<'a, D>(&self, fs: &'a crate::Ext4FileSystem<D>) -> FileBlocks<'a, D>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size();
+        let end = if self.inode.has_inline_data() {
+            0
+        } else {
+            self.inode.block_count(block_size)
+        };
+        FileBlocks {
+            fs,
+            inode: self.inode.clone(),
+            next: 0,
+            end,
         }
+    }
 
-        // Write updated inode
-        fs.write_inode(&inode)?;
-        self.inode = inode;
+    /// Number of bytes from `offset` stored in physically contiguous blocks
+    ///
+    /// Callers able to transfer straight from the device can read that many
+    /// bytes with one request instead of copying block by block; the run's
+    /// location comes from [`blocks`](Self::blocks). The length is capped at
+    /// the end of the file and stops at an unwritten extent. It is 0 in a
+    /// hole, in an unwritten extent, past the end, and for data stored in
+    /// the inode.
+    pub fn read_contiguous_hint<D>(
+        &self,
+        offset: u64,
+        fs: &crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<u64>
+    where
+        D: BlockDriverOps,
+    {
+        let size = self.size();
+        if offset >= size || self.inode.has_inline_data() {
+            return Ok(0);
+        }
 
-        Ok(bytes_written)
+        let block_size = fs.superblock().block_size() as u64;
+        let logical = offset / block_size;
+        let mut blocks = self.blocks(fs);
+        if blocks.map_block(logical)? == 0 {
+            return Ok(0);
+        }
+        blocks.next = logical;
+        let run = match blocks.next().transpose()? {
+            Some(run) => run,
+            None => return Ok(0),
+        };
+        let run_end = (run.logical + run.len as u64) * block_size;
+        Ok(run_end.min(size) - offset)
+    }
+
+    /// Export the contents of the file as data segments and holes
+    ///
+    /// Unallocated ranges and unwritten extents come out as
+    /// [`SparseSegment::Hole`] instead of zeros, so a sparse file can be
+    /// copied without filling it in. Data stored in the inode comes out as
+    /// a single [`SparseSegment::Data`].
+    pub fn export_sparse<'a, D>(&self, fs: &'a crate::Ext4FileSystem<D>) -> SparseSegments<'a, D>
+    where
+        D: BlockDriverOps,
+    {
+        SparseSegments {
+            fs,
+            blocks: self.blocks(fs),
+            run: None,
+            position: 0,
+            size: self.inode.size,
+        }
+    }
+
+    /// Replace the contents of the file with `segments`, as produced by
+    /// [`Self::export_sparse`]
+    ///
+    /// Holes are skipped rather than written, so they stay unallocated. The
+    /// file is truncated first and ends up as long as all the segments.
+    /// Returns the new size.
+    #[cfg(not(feature = "read-only"))]
+    pub fn import_sparse<I, D>(
+        &mut self,
+        segments: I,
+        fs: &mut crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<u64>
+    where
+        I: IntoIterator<Item = Ext4Result<SparseSegment>>,
+        D: BlockDriverOps,
+    {
+        self.truncate(0, fs)?;
+        let mut position = 0u64;
+        for segment in segments {
+            match segment? {
+                SparseSegment::Data(data) => {
+                    self.position = position;
+                    self.write(&data, fs)?;
+                    position += data.len() as u64;
+                }
+                SparseSegment::Hole(len) => {
+                    position = position.checked_add(len).ok_or(Ext4Error::InvalidInput)?;
+                }
+            }
+        }
+
+        // A trailing hole only extends the size
+        if position > self.inode.size {
+            self.inode.check_modify_data()?;
+            self.inode.size = position;
+            fs.note_file_size(&self.inode)?;
+            fs.write_inode(&self.inode)?;
+        }
+        self.position = position;
+        debug!("Imported {} bytes into inode {}", position, self.inode.ino);
+        Ok(position)
     }
 
     /// Truncate the file
+    #[cfg(not(feature = "read-only"))]
     pub fn truncate<D>(
         &mut self,
         new_size: u64,
@@ -240,6 +912,9 @@ impl File {
     where
         D: BlockDriverOps,
     {
+        self.inode.check_remove()?;
+        self.inode.check_modify_data()?;
+        self.write_staged(fs)?;
         let block_size = fs.superblock().block_size();
         let old_block_count = (self.inode.size + block_size as u64 - 1) / block_size as u64;
         let new_block_count = (new_size + block_size as u64 - 1) / block_size as u64;
@@ -247,7 +922,8 @@ impl File {
         if new_size > self.inode.size {
             // Expand file - allocate blocks as needed
             for block_index in old_block_count..new_block_count {
-                let new_block = fs.alloc_block()?;
+                let new_block = fs.alloc_data_block(&self.inode)?;
+                self.inode.charge_block(block_size);
                 self.inode
                     .set_block(block_index, new_block, block_size, fs)?;
 
@@ -256,20 +932,31 @@ impl File {
                 fs.write_block(new_block, &zero_buf)?;
             }
         } else if new_size < self.inode.size {
-            // Shrink file - free blocks that are no longer needed
+            // Shrink file - unmap the blocks past the new end and write the
+            // new size before freeing them, with the inode in the orphan
+            // file in between, so that a crash never leaves freed blocks
+            // mapped
+            fs.alloc_hints.forget(self.inode.ino);
+            fs.add_orphan(self.inode.ino)?;
+            let mut freed = Vec::new();
             for block_index in new_block_count..old_block_count {
                 if let Ok(block_num) =
                     self.inode
                         .get_block_number(block_index * block_size as u64, block_size, fs)
                 {
                     if block_num != 0 {
-                        // Free the block
-                        // Note: In a complete implementation, we would need to update the block bitmap
-                        // For now, we just set the block pointer to 0
                         self.inode.set_block(block_index, 0, block_size, fs)?;
+                        self.inode.uncharge_block(block_size);
+                        freed.push(block_num);
                     }
                 }
             }
+            self.inode.size = new_size;
+            fs.write_inode(&self.inode)?;
+            for block_num in freed {
+                fs.free_block(block_num)?;
+            }
+            fs.remove_orphan(self.inode.ino)?;
         }
 
         // Update the inode size
@@ -280,6 +967,487 @@ impl File {
             self.position = new_size;
         }
 
+        fs.notify(self.inode.ino, Change::Write);
         Ok(())
     }
 }
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Some(staged) = self.staged.as_ref().filter(|s| !s.data.is_empty()) {
+            warn!(
+                "Dropping {} staged bytes of inode {} that were never written",
+                staged.data.len(),
+                self.inode.ino
+            );
+        }
+    }
+}
+
+impl<D: BlockDriverOps> crate::Ext4FileSystem<D> {
+    /// Open the regular file with inode number `ino` for the access in
+    /// `flags`
+    ///
+    /// Fails with `IsADirectory` for a directory, with `InvalidArg` for
+    /// other inodes that aren't regular files, and with `InodeNotFound` for
+    /// reserved and deleted inodes. Opening for writing fails with
+    /// `PermissionDenied` for immutable and fs-verity files, and for
+    /// append-only files without [`OpenFlags::APPEND`]. The permission bits
+    /// of the mode aren't checked, as the filesystem doesn't know who opens
+    /// the file.
+    ///
+    /// The file counts as open, see [`is_open`](Self::is_open), until it is
+    /// closed with [`File::close`] or dropped.
+    pub fn open_inode(&self, ino: u32, flags: OpenFlags) -> Ext4Result<File> {
+        if ino == 0 || ino > self.superblock().inodes_count() || self.is_reserved_inode(ino) {
+            return Err(Ext4Error::InodeNotFound);
+        }
+        let inode = self.get_inode(ino)?;
+        self.open_checked(inode, flags)
+    }
+
+    /// Open the regular file at `path`, following symbolic links
+    ///
+    /// Fails like [`open_inode`](Self::open_inode) if `path` doesn't lead to
+    /// a regular file, or if the file can't be opened for `flags`.
+    pub fn open(&self, path: &str, flags: OpenFlags) -> Ext4Result<File> {
+        let inode = self.resolve_at(EXT4_ROOT_INO, path.as_bytes(), ResolveFlags::empty())?;
+        self.open_checked(inode, flags)
+    }
+
+    /// Check that `inode` can be opened for `flags` and count it as open
+    fn open_checked(&self, inode: Inode, flags: OpenFlags) -> Ext4Result<File> {
+        if inode.links_count == 0 || inode.mode.bits() == 0 || inode.dtime != 0 {
+            return Err(Ext4Error::InodeNotFound);
+        }
+        if inode.is_dir() {
+            return Err(Ext4Error::IsADirectory);
+        }
+        if !inode.is_file() {
+            return Err(Ext4Error::InvalidArg);
+        }
+        if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            self.check_writable()?;
+            inode.check_modify_data()?;
+            let append_only = inode.inode_flags().contains(crate::InodeFlags::APPEND);
+            if append_only && !flags.contains(OpenFlags::APPEND) {
+                return Err(Ext4Error::PermissionDenied);
+            }
+        }
+
+        let token = self
+            .open_files
+            .borrow_mut()
+            .entry(inode.ino)
+            .or_insert_with(|| Arc::new(()))
+            .clone();
+        let mut file = File::new(inode);
+        file.open = Some(token);
+        Ok(file)
+    }
+
+    /// Check if inode `ino` has files from [`open`](Self::open) or
+    /// [`open_inode`](Self::open_inode) that weren't closed or dropped yet
+    pub fn is_open(&self, ino: u32) -> bool {
+        let open_files = self.open_files.borrow();
+        open_files.get(&ino).is_some_and(|token| Arc::strong_count(token) > 1)
+    }
+
+    /// Forget the inodes whose files were all closed or dropped, and free
+    /// those whose last link is gone
+    ///
+    /// This runs on every close and unlink, so an unlinked inode whose last
+    /// file was dropped rather than closed is freed by the next one, or by
+    /// orphan cleanup at the next mount.
+    pub(crate) fn release_closed(&mut self) -> Ext4Result<()> {
+        let open_files = self.open_files.get_mut();
+        let closed: Vec<u32> = open_files
+            .iter()
+            .filter(|(_, token)| Arc::strong_count(token) == 1)
+            .map(|(&ino, _)| ino)
+            .collect();
+        for ino in closed {
+            self.open_files.get_mut().remove(&ino);
+            if !self.unlinked_open.remove(&ino) {
+                continue;
+            }
+            #[cfg(not(feature = "read-only"))]
+            {
+                debug!("Releasing unlinked inode {} on its last close", ino);
+                let mut inode = self.get_inode(ino)?;
+                self.free_unlinked_inode(&mut inode)?;
+                self.remove_orphan(ino)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `len` bytes of `src` at `src_off` into `dst` at `dst_off`
+    ///
+    /// Whole blocks go straight from block to block, and holes in the source
+    /// stay holes in the destination where it has no block yet. The range is
+    /// cut short at the end of `src`, and the positions of both files are
+    /// left alone. Returns the number of bytes copied.
+    #[cfg(not(feature = "read-only"))]
+    pub fn copy_file_range(
+        &mut self,
+        src: &File,
+        src_off: u64,
+        dst: &mut File,
+        dst_off: u64,
+        len: u64,
+    ) -> Ext4Result<u64> {
+        self.check_writable()?;
+        dst.inode.check_write_at(dst_off)?;
+        let len = len.min(src.inode.size.saturating_sub(src_off));
+        if src.inode.ino == dst.inode.ino && src_off < dst_off + len && dst_off < src_off + len {
+            return Err(Ext4Error::InvalidArg);
+        }
+
+        let block_size = self.superblock().block_size();
+        let bs = block_size as u64;
+        let mut inode = dst.inode.clone();
+        let mut src_buf = vec![0u8; block_size as usize];
+        let mut dst_buf = vec![0u8; block_size as usize];
+        let mut copied = 0;
+        while copied < len {
+            let s = src_off + copied;
+            let d = dst_off + copied;
+            let chunk = (bs - s % bs).min(bs - d % bs).min(len - copied);
+            let src_block = src.inode.get_data_block_number(s, block_size, self)?;
+            let mut dst_block = inode.get_block_number(d, block_size, self)?;
+            let dst_unwritten = inode.is_unwritten(d, block_size, self)?;
+            copied += chunk;
+            if src_block == 0 && (dst_block == 0 || dst_unwritten) {
+                continue;
+            }
+
+            if src_block == 0 {
+                src_buf.fill(0);
+            } else {
+                self.read_block(src_block, &mut src_buf)?;
+            }
+            if dst_block == 0 {
+                dst_block = self.alloc_data_block(&inode)?;
+                inode.charge_block(block_size);
+                inode.set_block(d / bs, dst_block, block_size, self)?;
+                dst_buf.fill(0);
+            } else if dst_unwritten {
+                inode.set_block(d / bs, dst_block, block_size, self)?;
+                dst_buf.fill(0);
+            } else if chunk < bs {
+                self.read_block(dst_block, &mut dst_buf)?;
+            }
+
+            let s_in = (s % bs) as usize;
+            let d_in = (d % bs) as usize;
+            let n = chunk as usize;
+            dst_buf[d_in..d_in + n].copy_from_slice(&src_buf[s_in..s_in + n]);
+            self.write_block(dst_block, &dst_buf)?;
+        }
+
+        if dst_off + len > inode.size {
+            inode.size = dst_off + len;
+        }
+        self.note_file_size(&inode)?;
+        self.order_data()?;
+        self.write_inode(&inode)?;
+        dst.inode = inode;
+        self.notify(dst.inode.ino, Change::Write);
+
+        debug!(
+            "Copied {} bytes from inode {} to inode {}",
+            len, src.inode.ino, dst.inode.ino
+        );
+        Ok(len)
+    }
+
+    /// Copy the regular file at `src` to the new file `dst`
+    ///
+    /// Data is copied with [`copy_file_range`](Self::copy_file_range), so
+    /// holes are kept. The copy gets the mode, owner, access and modification
+    /// times, and extended attributes of the source. Returns the inode number
+    /// of the copy.
+    #[cfg(not(feature = "read-only"))]
+    pub fn copy(&mut self, src: &str, dst: &str) -> Ext4Result<u32> {
+        let src_inode = self.find_inode(src)?;
+        if src_inode.is_dir() {
+            return Err(Ext4Error::IsADirectory);
+        }
+        if !src_inode.is_file() {
+            return Err(Ext4Error::NotSupported);
+        }
+
+        let (parent, name) = match dst.trim_end_matches('/').rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((parent, name)) => (parent, name),
+            None => ("/", dst),
+        };
+        let parent = self.find_inode(parent)?;
+        let ino = self.create_file(parent.ino, name, src_inode.mode)?;
+
+        let src_file = File::new(src_inode.clone());
+        let mut dst_file = File::new(self.get_inode(ino)?);
+        self.copy_file_range(&src_file, 0, &mut dst_file, 0, src_inode.size)?;
+
+        let mut inode = dst_file.inode.clone();
+        inode.mode = src_inode.mode;
+        inode.uid = src_inode.uid;
+        inode.uid_high = src_inode.uid_high;
+        inode.gid = src_inode.gid;
+        inode.gid_high = src_inode.gid_high;
+        inode.atime = src_inode.atime;
+        inode.atime_extra = src_inode.atime_extra;
+        inode.mtime = src_inode.mtime;
+        inode.mtime_extra = src_inode.mtime_extra;
+        self.copy_xattrs(&src_inode, &mut inode)?;
+        self.copy_inline_xattrs(&src_inode, &mut inode)?;
+        self.write_inode(&inode)?;
+
+        debug!("Copied {} to {} (inode {})", src, dst, ino);
+        Ok(ino)
+    }
+
+    /// Give `dst` its own copy of the extended attribute block of `src`
+    #[cfg(not(feature = "read-only"))]
+    fn copy_xattrs(&mut self, src: &Inode, dst: &mut Inode) -> Ext4Result<()> {
+        let block_size = self.superblock().block_size();
+        if src.xattr_block() == 0 {
+            return Ok(());
+        }
+
+        let mut buf = vec![0u8; block_size as usize];
+        self.read_block(src.xattr_block(), &mut buf)?;
+        if u32::from_le_bytes(buf[0..4].try_into().unwrap()) != EXT4_XATTR_MAGIC {
+            warn!("Inode {} has an invalid xattr block {}", src.ino, src.xattr_block());
+            return Ok(());
+        }
+
+        // The copy is referenced by `dst` alone, but shares value inodes
+        buf[4..8].copy_from_slice(&1u32.to_le_bytes());
+        for inum in xattr::block_value_inodes(&buf)? {
+            self.ref_xattr_value_inode(inum, dst)?;
+        }
+        let block = self.alloc_block_for(dst.ino)?;
+        if self.superblock().has_metadata_csum() {
+            xattr::set_block_csum(self.superblock().csum_seed(), block, &mut buf);
+        }
+        self.write_block(block, &buf)?;
+        dst.set_xattr_block(block);
+        dst.charge_block(block_size);
+        Ok(())
+    }
+
+    /// Copy the extended attributes stored inside the inode of `src` to `dst`
+    ///
+    /// Attributes are only copied when `dst` has at least as much room for
+    /// them as `src`, since value offsets are relative to the area start.
+    #[cfg(not(feature = "read-only"))]
+    fn copy_inline_xattrs(&mut self, src: &Inode, dst: &mut Inode) -> Ext4Result<()> {
+        let inode_size = self.superblock().inode_size() as usize;
+        let src_start = EXT4_GOOD_OLD_INODE_SIZE + src.extra_isize as usize;
+        let dst_start = EXT4_GOOD_OLD_INODE_SIZE + dst.extra_isize as usize;
+        if src_start + 4 > inode_size {
+            return Ok(());
+        }
+
+        let (block, offset) = self.inode_location(src.ino)?;
+        let mut buf = vec![0u8; self.superblock().block_size() as usize];
+        self.read_block(block, &mut buf)?;
+        let area = buf[offset + src_start..offset + inode_size].to_vec();
+        if u32::from_le_bytes(area[0..4].try_into().unwrap()) != EXT4_XATTR_MAGIC {
+            return Ok(());
+        }
+        if dst_start > src_start {
+            warn!("No room to copy in-inode xattrs of inode {}", src.ino);
+            return Ok(());
+        }
+        for inum in xattr::inline_value_inodes(&area)? {
+            self.ref_xattr_value_inode(inum, dst)?;
+        }
+
+        let (block, offset) = self.inode_location(dst.ino)?;
+        self.read_block(block, &mut buf)?;
+        let dst_area = &mut buf[offset + dst_start..offset + inode_size];
+        dst_area.fill(0);
+        dst_area[..area.len()].copy_from_slice(&area);
+        self.write_block(block, &buf)
+    }
+}
+
+/// A run of physically contiguous blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRun {
+    /// First logical block of the run
+    pub logical: u64,
+    /// First physical block of the run
+    pub physical: u64,
+    /// Number of blocks in the run
+    pub len: u32,
+}
+
+/// Iterator over the block runs of a file, created by [`File::blocks`]
+pub struct FileBlocks<'a, D: BlockDriverOps> {
+    fs: &'a crate::Ext4FileSystem<D>,
+    inode: Inode,
+    next: u64,
+    end: u64,
+}
+
+impl<'a, D: BlockDriverOps> FileBlocks<'a, D> {
+    /// Map a logical block, returning 0 for holes and unwritten blocks
+    fn map_block(&self, logical: u64) -> Ext4Result<u64> {
+        let block_size = self.fs.superblock().block_size();
+        match self
+            .inode
+            .get_data_block_number(logical * block_size as u64, block_size, self.fs)
+        {
+            Ok(block) => Ok(block),
+            Err(Ext4Error::BlockNotFound) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<'a, D: BlockDriverOps> Iterator for FileBlocks<'a, D> {
+    type Item = Ext4Result<BlockRun>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Skip holes
+        let mut physical = 0;
+        while self.next < self.end {
+            match self.map_block(self.next) {
+                Ok(0) => self.next += 1,
+                Ok(block) => {
+                    physical = block;
+                    break;
+                }
+                Err(e) => {
+                    self.next = self.end;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        if physical == 0 {
+            return None;
+        }
+
+        let mut run = BlockRun {
+            logical: self.next,
+            physical,
+            len: 1,
+        };
+        self.next += 1;
+
+        // Extend the run while blocks stay physically contiguous
+        while self.next < self.end && run.len < u32::MAX {
+            match self.map_block(self.next) {
+                Ok(block) if block != 0 && block == run.physical + run.len as u64 => {
+                    run.len += 1;
+                    self.next += 1;
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    self.next = self.end;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        debug!(
+            "File inode {} block run: logical={}, physical={}, len={}",
+            self.inode.ino, run.logical, run.physical, run.len
+        );
+        Some(Ok(run))
+    }
+}
+
+/// Most blocks in one data segment of a sparse export
+const SPARSE_SEGMENT_BLOCKS: u32 = 64;
+
+/// Piece of a file exported by [`File::export_sparse`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SparseSegment {
+    /// File contents
+    Data(Vec<u8>),
+    /// Unallocated or unwritten range of this many bytes, reading as zeros
+    Hole(u64),
+}
+
+/// Iterator over the data segments and holes of a file, created by
+/// [`File::export_sparse`]
+///
+/// Data segments cover at most 64 blocks, so memory use doesn't grow with
+/// the file.
+pub struct SparseSegments<'a, D: BlockDriverOps> {
+    fs: &'a crate::Ext4FileSystem<D>,
+    blocks: FileBlocks<'a, D>,
+    /// Part of the current block run not yet exported
+    run: Option<BlockRun>,
+    position: u64,
+    size: u64,
+}
+
+impl<'a, D: BlockDriverOps> Iterator for SparseSegments<'a, D> {
+    type Item = Ext4Result<SparseSegment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.size {
+            return None;
+        }
+        if self.blocks.inode.has_inline_data() {
+            self.position = self.size;
+            return Some(
+                self.fs
+                    .inline_file_data(&self.blocks.inode)
+                    .map(SparseSegment::Data),
+            );
+        }
+        let block_size = self.fs.superblock().block_size() as u64;
+        let run = match self.run {
+            Some(run) => run,
+            None => match self.blocks.next() {
+                Some(Ok(run)) => run,
+                Some(Err(e)) => {
+                    self.position = self.size;
+                    return Some(Err(e));
+                }
+                None => {
+                    let hole = self.size - self.position;
+                    self.position = self.size;
+                    return Some(Ok(SparseSegment::Hole(hole)));
+                }
+            },
+        };
+
+        let start = run.logical * block_size;
+        if self.position < start {
+            self.run = Some(run);
+            let hole = start - self.position;
+            self.position = start;
+            return Some(Ok(SparseSegment::Hole(hole)));
+        }
+
+        let count = run.len.min(SPARSE_SEGMENT_BLOCKS);
+        let mut data = match crate::try_zeroed(count as usize * block_size as usize) {
+            Ok(data) => data,
+            Err(e) => {
+                self.position = self.size;
+                return Some(Err(e));
+            }
+        };
+        if let Err(e) = self.fs.read_blocks(run.physical, &mut data) {
+            self.position = self.size;
+            return Some(Err(e));
+        }
+        data.truncate(data.len().min((self.size - self.position) as usize));
+        self.position += data.len() as u64;
+        self.run = (count < run.len).then(|| BlockRun {
+            logical: run.logical + count as u64,
+            physical: run.physical + count as u64,
+            len: run.len - count,
+        });
+        Some(Ok(SparseSegment::Data(data)))
+    }
+}
@@ -0,0 +1,161 @@
+//! Shell-style name patterns
+//!
+//! Patterns follow `fnmatch(3)` without flags other than `FNM_PERIOD`: `*`
+//! matches any run of bytes, `?` any single byte, and `[...]` one byte of a
+//! class of bytes and ranges, negated by a leading `!` or `^`. A backslash
+//! makes the next byte literal, and a `[` without its closing `]` is literal.
+//! As in shells, a leading "." must be matched literally, so "*" skips
+//! hidden names and "." and "..".
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{DirectoryEntry, DirectoryIterator, Ext4Error, Ext4FileSystem, Ext4Result};
+
+/// One element of a pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    /// `*`
+    Star,
+    /// `?`
+    Any,
+    /// `[...]`, holding the bytes between the brackets and whether the
+    /// class is negated
+    Class(&'a [u8], bool),
+    /// Any other byte, or one escaped by a backslash
+    Byte(u8),
+}
+
+/// Split the first token off `pattern`, returning it with its length
+fn token(pattern: &[u8]) -> (Token<'_>, usize) {
+    match pattern[0] {
+        b'*' => (Token::Star, 1),
+        b'?' => (Token::Any, 1),
+        b'\\' if pattern.len() > 1 => (Token::Byte(pattern[1]), 2),
+        b'[' => {
+            let negated = matches!(pattern.get(1), Some(b'!' | b'^'));
+            let start = 1 + negated as usize;
+            // A "]" right after the opening bracket is a member
+            let mut i = start + 1;
+            while i < pattern.len() && pattern[i] != b']' {
+                i += 1 + (pattern[i] == b'\\') as usize;
+            }
+            match i < pattern.len() {
+                true => (Token::Class(&pattern[start..i], negated), i + 1),
+                false => (Token::Byte(b'['), 1),
+            }
+        }
+        b => (Token::Byte(b), 1),
+    }
+}
+
+/// Check if byte `b` is a member of class `class`
+fn class_contains(class: &[u8], b: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        i += (class[i] == b'\\' && i + 1 < class.len()) as usize;
+        let low = class[i];
+        if class.get(i + 1) == Some(&b'-') && i + 2 < class.len() {
+            let high = class[i + 2];
+            if (low..=high).contains(&b) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if low == b {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Check if token `token` matches byte `b`
+fn token_matches(token: Token<'_>, b: u8) -> bool {
+    match token {
+        Token::Star | Token::Any => true,
+        Token::Class(class, negated) => class_contains(class, b) != negated,
+        Token::Byte(c) => c == b,
+    }
+}
+
+/// Check if `name` matches the shell pattern `pattern`
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    if name.starts_with(b".") && !pattern.starts_with(b".") {
+        return false;
+    }
+
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last "*": the pattern past it and the name
+    // byte it swallows next
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() {
+            let (token, len) = token(&pattern[p..]);
+            if token == Token::Star {
+                star = Some((p + len, n));
+                p += len;
+                continue;
+            }
+            if token_matches(token, name[n]) {
+                p += len;
+                n += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((after, swallowed)) => {
+                star = Some((after, swallowed + 1));
+                p = after;
+                n = swallowed + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// List the entries of directory `dir_ino` whose names match the shell
+    /// pattern `pattern`, as with [`glob_match`]
+    ///
+    /// The directory is scanned one block at a time and only matching
+    /// entries are kept, so a large directory is never listed in full.
+    pub fn find_matching(&self, dir_ino: u32, pattern: &[u8]) -> Ext4Result<Vec<DirectoryEntry>> {
+        let dir = self.get_inode(dir_ino)?;
+        if !dir.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+
+        let mut matches = Vec::new();
+        let mut add_matches = |data: &[u8]| {
+            for entry in DirectoryIterator::new(data) {
+                match entry {
+                    Ok(entry) if glob_match(pattern, entry.name.as_bytes()) => {
+                        matches.push(entry)
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Error parsing entry of directory {}: {:?}", dir_ino, e),
+                }
+            }
+        };
+        if let Some(data) = self.inline_dir_data(&dir)? {
+            add_matches(&data);
+            return Ok(matches);
+        }
+
+        let block_size = self.superblock.block_size();
+        let mut block = vec![0u8; block_size as usize];
+        for i in 0..dir.block_count(block_size) {
+            let block_num = dir.get_block_number(i * block_size as u64, block_size, self)?;
+            if block_num == 0 {
+                continue;
+            }
+            self.read_block(block_num, &mut block)?;
+            add_matches(&block);
+        }
+        Ok(matches)
+    }
+}
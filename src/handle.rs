@@ -0,0 +1,39 @@
+//! Persistent file handles for NFS-style exports
+//!
+//! A handle names an inode by number and generation. Inode numbers are
+//! reused once a file is deleted, but the new file gets a different
+//! generation, so a handle held across the deletion is detected as stale
+//! instead of silently resolving to an unrelated file.
+
+/// Size of an encoded file handle
+pub const FILE_HANDLE_SIZE: usize = 8;
+
+/// Handle identifying one incarnation of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileHandle {
+    /// Inode number
+    pub ino: u32,
+    /// Generation of the inode when the handle was created
+    pub generation: u32,
+}
+
+impl FileHandle {
+    /// Encode the handle as opaque bytes
+    pub fn to_bytes(&self) -> [u8; FILE_HANDLE_SIZE] {
+        let mut bytes = [0u8; FILE_HANDLE_SIZE];
+        bytes[..4].copy_from_slice(&self.ino.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.generation.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a handle produced by [`FileHandle::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != FILE_HANDLE_SIZE {
+            return None;
+        }
+        Some(Self {
+            ino: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            generation: u32::from_le_bytes(bytes[4..].try_into().unwrap()),
+        })
+    }
+}
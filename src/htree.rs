@@ -0,0 +1,674 @@
+//! Hashed directory index (htree)
+//!
+//! An indexed directory keeps its index in blocks that look empty to linear
+//! readers: the root in block 0 after "." and "..", and interior nodes in
+//! blocks holding a single unused record. Every node is an array of
+//! `(hash, block)` entries sorted by hash, whose first entry holds the limit
+//! and count of the array instead of a hash. Under `metadata_csum`, a
+//! `dx_tail` right after the last possible entry holds the checksum of the
+//! node, seeded with the directory's inode number and generation.
+//!
+//! The root is followed by at most one level of interior nodes, or two with
+//! the `large_dir` feature, which Linux uses once a directory outgrows one
+//! level.
+//!
+//! Rewriting an indexed directory rebuilds its index from scratch: the root
+//! in block 0, then the interior nodes, then the leaves sorted by hash.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+#[cfg(not(feature = "read-only"))]
+use crate::directory::DIRENT_TAIL_SIZE;
+#[cfg(not(feature = "read-only"))]
+use crate::{split_hash, Directory, DirectoryEntry};
+use crate::{
+    continues_into, crc32c, dx_hash, find_entry_in_block, Ext4Error, Ext4FileSystem, Ext4Result,
+    HashVersion, Inode, InodeFlags,
+};
+
+/// Offset of `dx_root_info` in the root block, after "." and ".."
+const DX_ROOT_INFO: usize = 0x18;
+
+/// Offset of the entry array of a root built here, after `dx_root_info`
+#[cfg(not(feature = "read-only"))]
+const DX_ROOT_ENTRIES: usize = DX_ROOT_INFO + 8;
+
+/// Offset of the entry array of an interior node, after its unused record
+const DX_NODE_ENTRIES: usize = 8;
+
+/// Size of an index entry
+const DX_ENTRY_SIZE: usize = 8;
+
+/// Size of the checksum tail
+const DX_TAIL_SIZE: usize = 8;
+
+/// Most index levels, the root included, without the `large_dir` feature
+const DX_MAX_LEVELS: u8 = 2;
+
+/// Most index levels, the root included, with the `large_dir` feature
+const DX_MAX_LEVELS_LARGE_DIR: u8 = 3;
+
+/// Where the entry array of an index node starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DxKind {
+    /// The root, in logical block 0 of the directory
+    Root,
+    /// An interior node
+    Node,
+}
+
+/// Offset of the limit and count of the entry array of `block`
+fn count_offset(block: &[u8], kind: DxKind) -> Ext4Result<usize> {
+    match kind {
+        DxKind::Root => {
+            let info_length = block[DX_ROOT_INFO + 5] as usize;
+            if info_length < 8 || DX_ROOT_INFO + info_length + 4 > block.len() {
+                return Err(Ext4Error::InvalidInput);
+            }
+            Ok(DX_ROOT_INFO + info_length)
+        }
+        DxKind::Node => {
+            let rec_len = u16::from_le_bytes([block[4], block[5]]) as usize;
+            if block[0..4] != [0; 4] || rec_len != block.len() {
+                return Err(Ext4Error::InvalidInput);
+            }
+            Ok(DX_NODE_ENTRIES)
+        }
+    }
+}
+
+/// Limit and count of the entry array at `offset`
+fn limit_count(block: &[u8], offset: usize) -> (usize, usize) {
+    let limit = u16::from_le_bytes([block[offset], block[offset + 1]]) as usize;
+    let count = u16::from_le_bytes([block[offset + 2], block[offset + 3]]) as usize;
+    (limit, count)
+}
+
+/// Hash and logical block of entry `i` of the array at `offset`
+///
+/// Entry 0 has no hash of its own and covers everything below entry 1.
+fn entry(block: &[u8], offset: usize, i: usize) -> (u32, u32) {
+    let pos = offset + i * DX_ENTRY_SIZE;
+    let hash = match i {
+        0 => 0,
+        _ => u32::from_le_bytes(block[pos..pos + 4].try_into().unwrap()),
+    };
+    let child = u32::from_le_bytes(block[pos + 4..pos + 8].try_into().unwrap());
+    (hash, child)
+}
+
+/// Store `entries` as the array at `offset` of a node of at most `limit`
+/// entries
+///
+/// The hash of the first entry isn't stored: the limit and count take its
+/// place.
+#[cfg(not(feature = "read-only"))]
+fn store_entries(block: &mut [u8], offset: usize, limit: usize, entries: &[(u32, u32)]) {
+    block[offset..offset + 2].copy_from_slice(&(limit as u16).to_le_bytes());
+    block[offset + 2..offset + 4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (i, &(hash, child)) in entries.iter().enumerate() {
+        let pos = offset + i * DX_ENTRY_SIZE;
+        if i > 0 {
+            block[pos..pos + 4].copy_from_slice(&hash.to_le_bytes());
+        }
+        block[pos + 4..pos + 8].copy_from_slice(&child.to_le_bytes());
+    }
+    block[offset + entries.len() * DX_ENTRY_SIZE..offset + limit * DX_ENTRY_SIZE].fill(0);
+}
+
+/// Write the directory entry `entry` at the start of `block`, with a
+/// record length of `rec_len`
+#[cfg(not(feature = "read-only"))]
+fn store_dirent(block: &mut [u8], entry: &DirectoryEntry, rec_len: usize) {
+    let name = entry.name.as_bytes();
+    block[0..4].copy_from_slice(&entry.ino.to_le_bytes());
+    block[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    block[6] = name.len() as u8;
+    block[7] = entry.file_type;
+    block[8..8 + name.len()].copy_from_slice(name);
+}
+
+/// Space a directory entry takes in a leaf block
+#[cfg(not(feature = "read-only"))]
+fn dirent_size(entry: &DirectoryEntry) -> usize {
+    (entry.entry_size() + 3) & !3
+}
+
+/// Index levels below the root of directory root block `root`
+fn root_levels(root: &[u8]) -> u8 {
+    root[DX_ROOT_INFO + 6]
+}
+
+/// Count of the entry array at `offset`, after checking it fits its node
+fn checked_count(dir: &Inode, block: &[u8], offset: usize) -> Ext4Result<usize> {
+    let (limit, count) = limit_count(block, offset);
+    if count == 0 || count > limit || offset + count * DX_ENTRY_SIZE > block.len() {
+        warn!("Directory {} has an index node with {} of {} entries", dir.ino, count, limit);
+        return Err(Ext4Error::InvalidInput);
+    }
+    Ok(count)
+}
+
+/// Offset of the checksum tail of an index node, if it has room for one
+fn tail_offset(block: &[u8], offset: usize) -> Option<usize> {
+    let (limit, _) = limit_count(block, offset);
+    let tail = offset + limit * DX_ENTRY_SIZE;
+    (tail + DX_TAIL_SIZE <= block.len()).then_some(tail)
+}
+
+/// Compute the checksum of an index node as Linux's `ext4_dx_csum()` does
+fn dx_csum(seed: u32, block: &[u8], offset: usize, tail: usize) -> u32 {
+    let (_, count) = limit_count(block, offset);
+    let csum = crc32c(seed, &block[..offset + count * DX_ENTRY_SIZE]);
+    let csum = crc32c(csum, &block[tail..tail + 4]);
+    crc32c(csum, &[0; 4])
+}
+
+/// Check the checksum tail of index node `block`
+pub(crate) fn verify_dx_csum(seed: u32, block: &[u8], kind: DxKind) -> Ext4Result<()> {
+    let offset = count_offset(block, kind)?;
+    let tail = tail_offset(block, offset).ok_or(Ext4Error::InvalidInput)?;
+    let stored = u32::from_le_bytes(block[tail + 4..tail + 8].try_into().unwrap());
+    if stored != dx_csum(seed, block, offset, tail) {
+        return Err(Ext4Error::BadChecksum);
+    }
+    Ok(())
+}
+
+/// Recompute the checksum tail of index node `block` after changing it
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn set_dx_csum(seed: u32, block: &mut [u8], kind: DxKind) -> Ext4Result<()> {
+    let offset = count_offset(block, kind)?;
+    let tail = tail_offset(block, offset).ok_or(Ext4Error::InvalidInput)?;
+    let csum = dx_csum(seed, block, offset, tail);
+    block[tail + 4..tail + 8].copy_from_slice(&csum.to_le_bytes());
+    Ok(())
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Check if lookups in directory `dir` may use its hashed index
+    pub(crate) fn is_indexed(&self, dir: &Inode) -> bool {
+        self.superblock.has_dir_index() && dir.inode_flags().contains(InodeFlags::INDEX)
+    }
+
+    /// Most index levels of a hashed index, the root included
+    fn dx_max_levels(&self) -> u8 {
+        match self.superblock.has_large_dir() {
+            true => DX_MAX_LEVELS_LARGE_DIR,
+            false => DX_MAX_LEVELS,
+        }
+    }
+
+    /// Most entries of the root or an interior node, leaving room for the
+    /// checksum tail under `metadata_csum`
+    #[cfg(not(feature = "read-only"))]
+    fn dx_limit(&self, kind: DxKind) -> usize {
+        let start = match kind {
+            DxKind::Root => DX_ROOT_ENTRIES,
+            DxKind::Node => DX_NODE_ENTRIES,
+        };
+        let tail = match self.superblock.has_metadata_csum() {
+            true => DX_TAIL_SIZE,
+            false => 0,
+        };
+        (self.superblock.block_size() as usize - start - tail) / DX_ENTRY_SIZE
+    }
+
+    /// Space for entries in a leaf block, before its dirent tail under
+    /// `metadata_csum`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn dir_leaf_space(&self) -> usize {
+        let tail = match self.superblock.has_metadata_csum() {
+            true => DIRENT_TAIL_SIZE,
+            false => 0,
+        };
+        self.superblock.block_size() as usize - tail
+    }
+
+    /// Seed of the checksums of the metadata of `inode`
+    pub(crate) fn inode_csum_seed(&self, inode: &Inode) -> u32 {
+        let csum = crc32c(self.superblock.csum_seed(), &inode.ino.to_le_bytes());
+        crc32c(csum, &inode.generation.to_le_bytes())
+    }
+
+    /// Hash algorithm of the index whose root is `root`, or `None` if the
+    /// root is not one this code can use
+    fn dx_root_version(&self, dir: &Inode, root: &[u8]) -> Option<HashVersion> {
+        let hash_version = root[DX_ROOT_INFO + 4];
+        let Some(version) = HashVersion::from_raw(hash_version) else {
+            warn!(
+                "Directory {} has unknown hash version {}, scanning it linearly",
+                dir.ino, hash_version
+            );
+            return None;
+        };
+        let levels = root_levels(root);
+        if root[DX_ROOT_INFO..DX_ROOT_INFO + 4] != [0; 4] || levels >= self.dx_max_levels() {
+            warn!("Directory {} has an unsupported index root, scanning it linearly", dir.ino);
+            return None;
+        }
+        Some(version.signedness_of(&self.superblock))
+    }
+
+    /// Look up `name` in directory `dir` through its hashed index
+    ///
+    /// Returns `None` if the directory has no usable index, so the caller
+    /// falls back to a linear scan. Under `metadata_csum`, index nodes with a
+    /// bad checksum fail the lookup with `BadChecksum`.
+    pub(crate) fn dx_lookup(&self, dir: &Inode, name: &[u8]) -> Ext4Result<Option<u32>> {
+        if !self.is_indexed(dir) {
+            return Ok(None);
+        }
+        // "." and ".." are the first entries of the root block
+        if name == b"." || name == b".." {
+            return self.search_leaves(dir, &[0], name).map(Some);
+        }
+
+        let mut block = self.read_dir_block(dir, 0)?;
+        let Some(version) = self.dx_root_version(dir, &block) else {
+            return Ok(None);
+        };
+        let hash = dx_hash(name, version, self.superblock.hash_seed());
+        let mut kind = DxKind::Root;
+        let mut levels = root_levels(&block) as usize + 1;
+        loop {
+            self.check_dx_node(dir, &block, kind)?;
+            let offset = count_offset(&block, kind)?;
+            let count = checked_count(dir, &block, offset)?;
+
+            // The last entry whose hash is not above the one looked up
+            let i = (1..count)
+                .take_while(|&i| entry(&block, offset, i).0 <= hash.major)
+                .last()
+                .unwrap_or(0);
+            let (_, child) = entry(&block, offset, i);
+
+            levels -= 1;
+            if levels > 0 {
+                block = self.read_dir_block(dir, child)?;
+                kind = DxKind::Node;
+                continue;
+            }
+
+            // Colliding hashes may continue into the following leaves
+            let mut leaves = vec![child];
+            for j in i + 1..count {
+                let (next_hash, next) = entry(&block, offset, j);
+                if !continues_into(hash.major, next_hash) {
+                    break;
+                }
+                leaves.push(next);
+            }
+            return self.search_leaves(dir, &leaves, name).map(Some);
+        }
+    }
+
+    /// Count the leaf blocks listed by the hashed index of directory `dir`
+    ///
+    /// Only index nodes are read. Returns `None` if the index root is not one
+    /// [`Self::dx_lookup`] would use.
+    pub(crate) fn dx_leaf_count(&self, dir: &Inode) -> Ext4Result<Option<u64>> {
+        let root = self.read_dir_block(dir, 0)?;
+        let levels = root_levels(&root);
+        if root[DX_ROOT_INFO..DX_ROOT_INFO + 4] != [0; 4] || levels >= self.dx_max_levels() {
+            return Ok(None);
+        }
+
+        let mut nodes = vec![(root, DxKind::Root)];
+        for _ in 0..levels {
+            let mut children = Vec::new();
+            for (block, kind) in &nodes {
+                self.check_dx_node(dir, block, *kind)?;
+                let offset = count_offset(block, *kind)?;
+                for i in 0..checked_count(dir, block, offset)? {
+                    let (_, child) = entry(block, offset, i);
+                    children.push((self.read_dir_block(dir, child)?, DxKind::Node));
+                }
+            }
+            nodes = children;
+        }
+
+        let mut leaves = 0;
+        for (block, kind) in &nodes {
+            self.check_dx_node(dir, block, *kind)?;
+            leaves += checked_count(dir, block, count_offset(block, *kind)?)? as u64;
+        }
+        Ok(Some(leaves))
+    }
+
+    /// Lay out the entries of `dir` as a hashed index of directory
+    /// `dir_inode`, over at least `min_blocks` blocks
+    ///
+    /// Leaves are filled in hash order, a new one starting where an entry
+    /// doesn't fit. Blocks past those the entries need are indexed as empty
+    /// leaves under hash 0, where lookups never stop, so none of them is left
+    /// unreferenced. Returns `None` if the default hash of the filesystem is
+    /// unknown or `dir` has no "." or "..", and fails with `NoSpaceLeft` if
+    /// the index would need more levels than the filesystem allows.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn dx_blocks(
+        &self,
+        dir_inode: &Inode,
+        dir: &Directory,
+        min_blocks: usize,
+    ) -> Ext4Result<Option<Vec<u8>>> {
+        let Some(version) = HashVersion::from_superblock(&self.superblock) else {
+            return Ok(None);
+        };
+        let (Some(dot), Some(dotdot)) = (dir.find_entry("."), dir.find_entry("..")) else {
+            return Ok(None);
+        };
+        let seed = self.superblock.hash_seed();
+        let mut entries: Vec<_> = dir
+            .entries()
+            .iter()
+            .filter(|e| e.name.as_bytes() != b"." && e.name.as_bytes() != b"..")
+            .map(|e| (dx_hash(e.name.as_bytes(), version, seed), e))
+            .collect();
+        entries.sort_by_key(|(hash, _)| (hash.major, hash.minor));
+
+        let space = self.dir_leaf_space();
+        let mut leaves = vec![(0, Directory::new())];
+        let mut used = 0;
+        let mut last_hash = 0;
+        for (hash, entry) in entries {
+            let size = dirent_size(entry);
+            if used + size > space {
+                leaves.push((split_hash(hash.major, hash.major == last_hash), Directory::new()));
+                used = 0;
+            }
+            used += size;
+            last_hash = hash.major;
+            leaves.last_mut().unwrap().1.add_entry(entry.clone());
+        }
+
+        // Nodes per level, from the one right above the leaves
+        let root_limit = self.dx_limit(DxKind::Root);
+        let node_limit = self.dx_limit(DxKind::Node);
+        let node_counts = |mut entries: usize| {
+            let mut counts = Vec::new();
+            while entries > root_limit {
+                entries = entries.div_ceil(node_limit);
+                counts.push(entries);
+            }
+            counts
+        };
+        let mut empty = 0;
+        let counts = loop {
+            let counts = node_counts(leaves.len() + empty);
+            let total = 1 + counts.iter().sum::<usize>() + leaves.len() + empty;
+            if total >= min_blocks {
+                break counts;
+            }
+            empty += min_blocks - total;
+        };
+        if counts.len() >= self.dx_max_levels() as usize {
+            warn!("Index of directory {} would need {} levels", dir_inode.ino, counts.len() + 1);
+            return Err(Ext4Error::NoSpaceLeft);
+        }
+
+        let block_size = self.superblock.block_size() as usize;
+        let first_leaf = 1 + counts.iter().sum::<usize>();
+        let mut data = vec![0u8; first_leaf * block_size];
+        let mut level = Vec::new();
+        let empty_leaves = (0..empty).map(|_| (0, Directory::new()));
+        for (i, (hash, leaf)) in empty_leaves.chain(leaves).enumerate() {
+            level.push((hash, (first_leaf + i) as u32));
+            data.extend_from_slice(&self.dir_blocks(dir_inode, &leaf)?);
+        }
+
+        let csum_seed = self.inode_csum_seed(dir_inode);
+        let mut next_node = 1;
+        for _ in &counts {
+            let mut upper = Vec::new();
+            for chunk in level.chunks(node_limit) {
+                let node = &mut data[next_node * block_size..(next_node + 1) * block_size];
+                node[4..6].copy_from_slice(&(block_size as u16).to_le_bytes());
+                store_entries(node, DX_NODE_ENTRIES, node_limit, chunk);
+                if self.superblock.has_metadata_csum() {
+                    set_dx_csum(csum_seed, node, DxKind::Node)?;
+                }
+                upper.push((chunk[0].0, next_node as u32));
+                next_node += 1;
+            }
+            level = upper;
+        }
+
+        let root = &mut data[..block_size];
+        store_dirent(root, dot, 12);
+        store_dirent(&mut root[12..], dotdot, block_size - 12);
+        root[DX_ROOT_INFO + 4] = self.superblock.def_hash_version();
+        root[DX_ROOT_INFO + 5] = 8;
+        root[DX_ROOT_INFO + 6] = counts.len() as u8;
+        store_entries(root, DX_ROOT_ENTRIES, root_limit, &level);
+        if self.superblock.has_metadata_csum() {
+            set_dx_csum(csum_seed, root, DxKind::Root)?;
+        }
+        Ok(Some(data))
+    }
+
+    /// Add `new_entry` to directory `dir` through its hashed index
+    ///
+    /// The entry goes in the leaf its hash selects. A full leaf is split in
+    /// two halves of its entries sorted by hash, the upper one moving to a
+    /// new block with an index entry of its own. A full interior node is
+    /// split the same way, and a full root moves its entries to a new node
+    /// one level down, as long as the filesystem allows another level;
+    /// otherwise the insert fails with `NoSpaceLeft` before anything is
+    /// changed. Returns `false` if the directory has no usable index.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn dx_add_entry(
+        &mut self,
+        dir: &mut Inode,
+        new_entry: DirectoryEntry,
+    ) -> Ext4Result<bool> {
+        let root = self.read_dir_block(dir, 0)?;
+        let Some(version) = self.dx_root_version(dir, &root) else {
+            return Ok(false);
+        };
+        let seed = *self.superblock.hash_seed();
+        let hash_of = |entry: &DirectoryEntry| dx_hash(entry.name.as_bytes(), version, &seed);
+        let hash = hash_of(&new_entry);
+
+        // Index nodes from the root down, with the entry followed in each
+        let levels = root_levels(&root);
+        let mut path = Vec::new();
+        let mut block = root;
+        let mut index = 0;
+        let mut kind = DxKind::Root;
+        for _ in 0..=levels {
+            self.check_dx_node(dir, &block, kind)?;
+            let offset = count_offset(&block, kind)?;
+            let count = checked_count(dir, &block, offset)?;
+            let i = (1..count)
+                .take_while(|&i| entry(&block, offset, i).0 <= hash.major)
+                .last()
+                .unwrap_or(0);
+            let (_, child) = entry(&block, offset, i);
+            path.push((index, block, kind, i));
+            block = self.read_dir_block(dir, child)?;
+            index = child;
+            kind = DxKind::Node;
+        }
+
+        let space = self.dir_leaf_space();
+        let mut leaf = Directory::from_bytes(&block[..space])?;
+        leaf.add_entry(new_entry);
+        if leaf.entries().iter().map(dirent_size).sum::<usize>() <= space {
+            let data = self.dir_blocks(dir, &leaf)?;
+            return self.write_dir_block(dir, index, &data).map(|_| true);
+        }
+
+        // One new block for the leaf and each full node above it, plus one
+        // for a new level if the root is full too
+        let mut needed = 1;
+        for (_, node, kind, _) in path.iter().rev() {
+            let (limit, count) = limit_count(node, count_offset(node, *kind)?);
+            if count < limit {
+                break;
+            }
+            if *kind == DxKind::Root && levels + 1 >= self.dx_max_levels() {
+                warn!("Index of directory {} is full", dir.ino);
+                return Err(Ext4Error::NoSpaceLeft);
+            }
+            needed += 1;
+        }
+        let mut new_blocks = self.grow_dir(dir, needed)?.into_iter();
+
+        // Split the leaf where the upper half of its entries starts
+        let mut entries: Vec<_> =
+            leaf.entries().iter().map(|e| (hash_of(e), e.clone())).collect();
+        entries.sort_by_key(|(hash, _)| (hash.major, hash.minor));
+        let total: usize = entries.iter().map(|(_, e)| dirent_size(e)).sum();
+        let mut used = 0;
+        let mut split = entries.len() - 1;
+        for (i, (_, e)) in entries.iter().enumerate() {
+            if i > 0 && used * 2 >= total {
+                split = i;
+                break;
+            }
+            used += dirent_size(e);
+        }
+        let split_major = entries[split].0.major;
+        let continued = entries[split - 1].0.major == split_major;
+        let (mut lower, mut upper) = (Directory::new(), Directory::new());
+        for (i, (_, e)) in entries.into_iter().enumerate() {
+            match i < split {
+                true => lower.add_entry(e),
+                false => upper.add_entry(e),
+            }
+        }
+        let new_leaf = new_blocks.next().ok_or(Ext4Error::InvalidState)?;
+        self.write_dir_block(dir, index, &self.dir_blocks(dir, &lower)?)?;
+        self.write_dir_block(dir, new_leaf, &self.dir_blocks(dir, &upper)?)?;
+
+        // Add the new block to its parent, splitting full nodes on the way up
+        let block_size = self.superblock.block_size() as usize;
+        let mut pending = (split_hash(split_major, continued), new_leaf);
+        for (index, mut node, kind, i) in path.into_iter().rev() {
+            let offset = count_offset(&node, kind)?;
+            let (limit, count) = limit_count(&node, offset);
+            let mut entries: Vec<_> = (0..count).map(|j| entry(&node, offset, j)).collect();
+            entries.insert(i + 1, pending);
+            if count < limit {
+                store_entries(&mut node, offset, limit, &entries);
+                return self.write_dx_node(dir, index, &mut node, kind).map(|_| true);
+            }
+
+            let child_block = new_blocks.next().ok_or(Ext4Error::InvalidState)?;
+            let mut child = vec![0u8; block_size];
+            child[4..6].copy_from_slice(&(block_size as u16).to_le_bytes());
+            let node_limit = self.dx_limit(DxKind::Node);
+            if kind == DxKind::Root {
+                // The root keeps a single entry, for its entries one level down
+                store_entries(&mut child, DX_NODE_ENTRIES, node_limit, &entries);
+                self.write_dx_node(dir, child_block, &mut child, DxKind::Node)?;
+                store_entries(&mut node, offset, limit, &[(0, child_block)]);
+                node[DX_ROOT_INFO + 6] += 1;
+                return self.write_dx_node(dir, index, &mut node, kind).map(|_| true);
+            }
+            let half = entries.len() / 2;
+            store_entries(&mut node, offset, limit, &entries[..half]);
+            store_entries(&mut child, DX_NODE_ENTRIES, node_limit, &entries[half..]);
+            self.write_dx_node(dir, index, &mut node, kind)?;
+            self.write_dx_node(dir, child_block, &mut child, DxKind::Node)?;
+            pending = (entries[half].0, child_block);
+        }
+        Err(Ext4Error::InvalidState)
+    }
+
+    /// Add `count` blocks to the end of directory `dir`, returning their
+    /// logical block numbers
+    ///
+    /// All of them are allocated before any is mapped, so that running out
+    /// of space leaves the directory untouched.
+    #[cfg(not(feature = "read-only"))]
+    fn grow_dir(&mut self, dir: &mut Inode, count: usize) -> Ext4Result<Vec<u32>> {
+        let block_size = self.superblock.block_size();
+        let first = dir.block_count(block_size);
+        let size = (first + count as u64) * block_size as u64;
+        if !self.superblock.has_large_dir() && size > u32::MAX as u64 {
+            warn!("Directory {} would outgrow 4 GiB without large_dir", dir.ino);
+            return Err(Ext4Error::NoSpaceLeft);
+        }
+        let mut new_blocks = Vec::new();
+        for _ in 0..count {
+            new_blocks.push(self.alloc_data_block(dir)?);
+        }
+        for (i, block) in (first..).zip(new_blocks) {
+            dir.charge_block(block_size);
+            dir.set_block(i, block, block_size, self)?;
+        }
+        dir.size = size;
+        self.write_inode(dir)?;
+        Ok((first..first + count as u64).map(|i| i as u32).collect())
+    }
+
+    /// Write index node `node` of directory `dir` to its logical block
+    /// `index`, with a new checksum under `metadata_csum`
+    #[cfg(not(feature = "read-only"))]
+    fn write_dx_node(
+        &mut self,
+        dir: &Inode,
+        index: u32,
+        node: &mut [u8],
+        kind: DxKind,
+    ) -> Ext4Result<()> {
+        if self.superblock.has_metadata_csum() {
+            set_dx_csum(self.inode_csum_seed(dir), node, kind)?;
+        }
+        self.write_dir_block(dir, index, node)
+    }
+
+    /// Write `data` to logical block `index` of directory `dir`
+    #[cfg(not(feature = "read-only"))]
+    fn write_dir_block(&mut self, dir: &Inode, index: u32, data: &[u8]) -> Ext4Result<()> {
+        let block_size = self.superblock.block_size();
+        let block_num = dir.get_block_number(index as u64 * block_size as u64, block_size, self)?;
+        if block_num == 0 {
+            return Err(Ext4Error::BlockNotFound);
+        }
+        self.write_block(block_num, data)?;
+        self.caches.borrow_mut().invalidate_dir(dir.ino);
+        Ok(())
+    }
+
+    /// Search the leaf blocks `leaves` of directory `dir` for `name`
+    fn search_leaves(&self, dir: &Inode, leaves: &[u32], name: &[u8]) -> Ext4Result<u32> {
+        for &leaf in leaves {
+            let block = self.read_dir_block(dir, leaf)?;
+            if let Some(ino) = find_entry_in_block(&block, name) {
+                return Ok(ino);
+            }
+        }
+        Err(Ext4Error::InodeNotFound)
+    }
+
+    /// Verify the checksum of index node `block` of directory `dir`, if the
+    /// filesystem has metadata checksums
+    pub(crate) fn check_dx_node(&self, dir: &Inode, block: &[u8], kind: DxKind) -> Ext4Result<()> {
+        if !self.superblock.has_metadata_csum() {
+            return Ok(());
+        }
+        verify_dx_csum(self.inode_csum_seed(dir), block, kind).inspect_err(|e| {
+            warn!("Index node of directory {} failed verification: {:?}", dir.ino, e);
+        })
+    }
+
+    /// Read logical block `index` of directory `dir`
+    pub(crate) fn read_dir_block(&self, dir: &Inode, index: u32) -> Ext4Result<Vec<u8>> {
+        let block_size = self.superblock.block_size();
+        if index as u64 >= dir.block_count(block_size) {
+            return Err(Ext4Error::InvalidInput);
+        }
+        let block_num = dir.get_block_number(index as u64 * block_size as u64, block_size, self)?;
+        if block_num == 0 {
+            return Err(Ext4Error::BlockNotFound);
+        }
+        let mut buf = vec![0u8; block_size as usize];
+        self.read_dir_data_block(dir, block_num, &mut buf)?;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,159 @@
+//! User and group ID mapping
+//!
+//! An [`IdMap`] in [`MountOptions::id_map`] translates the owner of every
+//! inode read from disk into the ID space seen by the user of the
+//! filesystem, and back when the inode is written, as Linux does for
+//! user-namespaced and ID-mapped mounts. The image itself is left alone: an
+//! inode whose owner wasn't changed keeps its on-disk IDs even when they have
+//! no mapping.
+//!
+//! [`MountOptions::id_map`]: crate::MountOptions::id_map
+
+use alloc::vec::Vec;
+
+use crate::{Ext4Error, Ext4Result, Inode};
+
+/// ID shown for on-disk IDs without a mapping, and stored for visible IDs
+/// without one, as Linux's `overflowuid` and `overflowgid`
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A range of `count` consecutive IDs, starting at `disk` on disk and at
+/// `visible` as seen through the mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    /// First ID on disk
+    pub disk: u32,
+    /// First ID as seen through the mapping
+    pub visible: u32,
+    /// Number of IDs
+    pub count: u32,
+}
+
+impl IdRange {
+    /// Check that both sides of the range fit below `u32::MAX`
+    fn new(disk: u32, visible: u32, count: u32) -> Ext4Result<Self> {
+        let last = count.saturating_sub(1);
+        if disk.checked_add(last).is_none() || visible.checked_add(last).is_none() {
+            return Err(Ext4Error::InvalidArg);
+        }
+        Ok(Self { disk, visible, count })
+    }
+
+    /// Map `id` from the `from` side of the range to the `to` side
+    fn translate(from: u32, to: u32, count: u32, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(from)?;
+        if offset < count {
+            to.checked_add(offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Translation tables between on-disk and visible user and group IDs
+///
+/// IDs outside every range of their table appear as [`OVERFLOW_ID`], and
+/// visible IDs outside every range are stored as it. An empty map thus
+/// squashes every owner.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IdMap {
+    uids: Vec<IdRange>,
+    gids: Vec<IdRange>,
+}
+
+impl IdMap {
+    /// Create a map with no ranges
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map every ID but root to itself, so that root is squashed both ways
+    pub fn root_squash() -> Self {
+        let all_but_root = IdRange {
+            disk: 1,
+            visible: 1,
+            count: u32::MAX - 1,
+        };
+        Self {
+            uids: vec![all_but_root],
+            gids: vec![all_but_root],
+        }
+    }
+
+    /// Add a range of user IDs
+    ///
+    /// Fails with `InvalidArg` if either side of the range runs past
+    /// `u32::MAX`.
+    pub fn with_uids(mut self, disk: u32, visible: u32, count: u32) -> Ext4Result<Self> {
+        self.uids.push(IdRange::new(disk, visible, count)?);
+        Ok(self)
+    }
+
+    /// Add a range of group IDs
+    ///
+    /// Fails with `InvalidArg` if either side of the range runs past
+    /// `u32::MAX`.
+    pub fn with_gids(mut self, disk: u32, visible: u32, count: u32) -> Ext4Result<Self> {
+        self.gids.push(IdRange::new(disk, visible, count)?);
+        Ok(self)
+    }
+
+    /// Visible user ID of on-disk user ID `uid`
+    pub fn uid_to_visible(&self, uid: u32) -> u32 {
+        to_visible(&self.uids, uid)
+    }
+
+    /// On-disk user ID of visible user ID `uid`
+    pub fn uid_to_disk(&self, uid: u32) -> u32 {
+        to_disk(&self.uids, uid)
+    }
+
+    /// Visible group ID of on-disk group ID `gid`
+    pub fn gid_to_visible(&self, gid: u32) -> u32 {
+        to_visible(&self.gids, gid)
+    }
+
+    /// On-disk group ID of visible group ID `gid`
+    pub fn gid_to_disk(&self, gid: u32) -> u32 {
+        to_disk(&self.gids, gid)
+    }
+
+    /// Replace the on-disk owner of `inode` with the visible one
+    pub(crate) fn map_inode(&self, inode: &mut Inode) {
+        inode.set_full_uid(self.uid_to_visible(inode.full_uid()));
+        inode.set_full_gid(self.gid_to_visible(inode.full_gid()));
+    }
+
+    /// Replace the visible owner of `inode` with the on-disk one
+    ///
+    /// `stored` is the inode currently on disk, if any: its IDs are kept
+    /// when they still map to the visible ones.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn unmap_inode(&self, inode: &mut Inode, stored: Option<&Inode>) {
+        let (uid, gid) = (inode.full_uid(), inode.full_gid());
+        let stored_uid = stored.map(|s| s.full_uid());
+        let stored_gid = stored.map(|s| s.full_gid());
+        inode.set_full_uid(match stored_uid {
+            Some(disk) if self.uid_to_visible(disk) == uid => disk,
+            _ => self.uid_to_disk(uid),
+        });
+        inode.set_full_gid(match stored_gid {
+            Some(disk) if self.gid_to_visible(disk) == gid => disk,
+            _ => self.gid_to_disk(gid),
+        });
+    }
+}
+
+fn to_visible(ranges: &[IdRange], id: u32) -> u32 {
+    ranges
+        .iter()
+        .find_map(|r| IdRange::translate(r.disk, r.visible, r.count, id))
+        .unwrap_or(OVERFLOW_ID)
+}
+
+fn to_disk(ranges: &[IdRange], id: u32) -> u32 {
+    ranges
+        .iter()
+        .find_map(|r| IdRange::translate(r.visible, r.disk, r.count, id))
+        .unwrap_or(OVERFLOW_ID)
+}
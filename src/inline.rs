@@ -0,0 +1,174 @@
+//! Inline directories
+//!
+//! With the `inline_data` feature, a small directory keeps its entries in
+//! its inode rather than in blocks. `i_block` starts with the inode number
+//! of the parent, standing for "..", followed by 56 bytes of entries; "."
+//! is implied. Entries that don't fit there may continue in the value of
+//! the `system.data` extended attribute, and `i_size` covers both parts.
+//! Each part is laid out like a directory block of its own size, its last
+//! entry extending to its end.
+//!
+//! Entries are added and removed in place as long as they fit in the room
+//! the directory already has. Once they don't, the directory moves to a
+//! block of its own and loses its `system.data` attribute, as in Linux.
+//!
+//! Small regular files keep their data the same way: the first 60 bytes in
+//! `i_block` and the rest in `system.data`. They are read but not written.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+#[cfg(not(feature = "read-only"))]
+use log::*;
+
+#[cfg(not(feature = "read-only"))]
+use crate::extent;
+use crate::extent::inline_bytes;
+#[cfg(not(feature = "read-only"))]
+use crate::{Directory, DirectoryEntry, InodeFlags};
+use crate::{Ext4FileSystem, Ext4Result, Inode, InodeType};
+
+/// Size of `i_block`
+#[cfg(not(feature = "read-only"))]
+const INLINE_SIZE: usize = 60;
+
+/// Size of the parent inode number at the start of `i_block`
+const PARENT_SIZE: usize = 4;
+
+/// Size of the record of an entry named `name`
+fn record_len(name: &[u8]) -> usize {
+    (8 + name.len() + 3) & !3
+}
+
+/// Append a record for `ino` named `name`, `rec_len` bytes long, to `data`
+fn push_record(data: &mut Vec<u8>, ino: u32, rec_len: usize, file_type: u8, name: &[u8]) {
+    let start = data.len();
+    data.extend_from_slice(&ino.to_le_bytes());
+    data.extend_from_slice(&(rec_len as u16).to_le_bytes());
+    data.push(name.len() as u8);
+    data.push(file_type);
+    data.extend_from_slice(name);
+    data.resize(start + rec_len, 0);
+}
+
+/// Lay out `entries` in parts of `sizes` bytes, each like a directory block
+/// of that size, or return `None` if they don't fit
+#[cfg(not(feature = "read-only"))]
+fn pack_entries(entries: &[&DirectoryEntry], sizes: &[usize]) -> Option<Vec<Vec<u8>>> {
+    let mut parts = Vec::new();
+    let mut rest = entries;
+    for &size in sizes {
+        let mut used = 0;
+        let count = rest
+            .iter()
+            .take_while(|entry| {
+                used += record_len(entry.name.as_bytes());
+                used <= size
+            })
+            .count();
+
+        let mut part = Vec::with_capacity(size);
+        for (i, entry) in rest[..count].iter().enumerate() {
+            let rec_len = match i + 1 == count {
+                true => size - part.len(),
+                false => record_len(entry.name.as_bytes()),
+            };
+            push_record(&mut part, entry.ino, rec_len, entry.file_type, entry.name.as_bytes());
+        }
+        // An empty part holds a single unused record
+        if count == 0 && size >= 8 {
+            push_record(&mut part, 0, size, 0, b"");
+        }
+        part.resize(size, 0);
+        parts.push(part);
+        rest = &rest[count..];
+    }
+    rest.is_empty().then_some(parts)
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Entries of inline directory `dir` as consecutive directory records,
+    /// starting with "." and "..", or `None` if `dir` is not inline
+    pub(crate) fn inline_dir_data(&self, dir: &Inode) -> Ext4Result<Option<Vec<u8>>> {
+        if !dir.has_inline_data() {
+            return Ok(None);
+        }
+        let block = inline_bytes(&dir.block);
+        let parent = u32::from_le_bytes(block[..PARENT_SIZE].try_into().unwrap());
+        let file_type = self.dir_file_type(InodeType::Directory);
+
+        let mut data = Vec::new();
+        push_record(&mut data, dir.ino, record_len(b"."), file_type, b".");
+        push_record(&mut data, parent, record_len(b".."), file_type, b"..");
+        data.extend_from_slice(&block[PARENT_SIZE..]);
+        if let Some(value) = self.inline_data_xattr(dir)? {
+            data.extend_from_slice(&value);
+        }
+        Ok(Some(data))
+    }
+
+    /// Data of inline file `inode`: `i_block` followed by the value of
+    /// `system.data`, cut to the size of the file
+    pub(crate) fn inline_file_data(&self, inode: &Inode) -> Ext4Result<Vec<u8>> {
+        let mut data = inline_bytes(&inode.block).to_vec();
+        if let Some(value) = self.inline_data_xattr(inode)? {
+            data.extend_from_slice(&value);
+        }
+        data.truncate(usize::try_from(inode.size).unwrap_or(usize::MAX));
+        Ok(data)
+    }
+
+    /// Write `dir` back as the contents of inline directory `dir_inode`
+    ///
+    /// The entries stay in the inode if they fit in `i_block` and the
+    /// current `system.data` value. Otherwise the directory is written to
+    /// blocks by [`write_directory`](Self::write_directory) first, and only
+    /// then is `system.data` removed.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_inline_directory(
+        &mut self,
+        dir_inode: &mut Inode,
+        dir: &Directory,
+    ) -> Ext4Result<()> {
+        let block = inline_bytes(&dir_inode.block);
+        let parent = match dir.find_entry("..") {
+            Some(entry) => entry.ino,
+            None => u32::from_le_bytes(block[..PARENT_SIZE].try_into().unwrap()),
+        };
+        let entries: Vec<&DirectoryEntry> = dir
+            .entries()
+            .iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .collect();
+        let xattr_size = self.inline_data_xattr(dir_inode)?.map_or(0, |value| value.len());
+
+        let Some(parts) = pack_entries(&entries, &[INLINE_SIZE - PARENT_SIZE, xattr_size]) else {
+            debug!("Moving the entries of inline directory {} to a block", dir_inode.ino);
+            let mut moved = dir_inode.clone();
+            moved.flags &= !InodeFlags::INLINE_DATA.bits();
+            moved.block = [0; 15];
+            if self.superblock.has_extents() {
+                moved.flags |= InodeFlags::EXTENTS.bits();
+                extent::init_inline_root(&mut moved.block);
+            }
+            moved.size = 0;
+            self.write_directory(&mut moved, dir)?;
+            if xattr_size > 0 {
+                self.write_inline_data_xattr(&moved, None)?;
+            }
+            *dir_inode = moved;
+            return Ok(());
+        };
+
+        if xattr_size > 0 {
+            self.write_inline_data_xattr(dir_inode, Some(&parts[1]))?;
+        }
+        let mut bytes = [0u8; INLINE_SIZE];
+        bytes[..PARENT_SIZE].copy_from_slice(&parent.to_le_bytes());
+        bytes[PARENT_SIZE..].copy_from_slice(&parts[0]);
+        for (word, chunk) in dir_inode.block.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        self.caches.borrow_mut().invalidate_dir(dir_inode.ino);
+        self.write_inode(dir_inode)
+    }
+}
@@ -1,6 +1,5 @@
 use alloc::vec::Vec;
 use bitflags::bitflags;
-use core::time::Duration;
 use log::*;
 
 use crate::{Ext4Error, Ext4Result};
@@ -46,6 +45,79 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Inode flags (`i_flags`), as shown by `lsattr`
+    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+    pub struct InodeFlags: u32 {
+        /// Secure deletion
+        const SECRM = 0x0000_0001;
+        /// Keep a copy for undeletion
+        const UNRM = 0x0000_0002;
+        /// Compress file
+        const COMPR = 0x0000_0004;
+        /// Synchronous updates
+        const SYNC = 0x0000_0008;
+        /// Immutable file
+        const IMMUTABLE = 0x0000_0010;
+        /// Writes may only append
+        const APPEND = 0x0000_0020;
+        /// Do not dump file
+        const NODUMP = 0x0000_0040;
+        /// Do not update access time
+        const NOATIME = 0x0000_0080;
+        /// Encrypted inode
+        const ENCRYPT = 0x0000_0800;
+        /// Hashed directory index
+        const INDEX = 0x0000_1000;
+        /// AFS magic directory
+        const IMAGIC = 0x0000_2000;
+        /// Journal file data
+        const JOURNAL_DATA = 0x0000_4000;
+        /// Do not merge file tails
+        const NOTAIL = 0x0000_8000;
+        /// Synchronous directory updates
+        const DIRSYNC = 0x0001_0000;
+        /// Top of a directory hierarchy for the allocator
+        const TOPDIR = 0x0002_0000;
+        /// `i_blocks` counts filesystem blocks instead of 512-byte sectors
+        const HUGE_FILE = 0x0004_0000;
+        /// Blocks are mapped by an extent tree
+        const EXTENTS = 0x0008_0000;
+        /// fs-verity protected file
+        const VERITY = 0x0010_0000;
+        /// Inode stores a large extended attribute value
+        const EA_INODE = 0x0020_0000;
+        /// Direct access to the file's pages, bypassing the page cache
+        const DAX = 0x0200_0000;
+        /// Data stored inside the inode
+        const INLINE_DATA = 0x1000_0000;
+        /// Children inherit the project ID
+        const PROJINHERIT = 0x2000_0000;
+        /// Case-insensitive directory
+        const CASEFOLD = 0x4000_0000;
+    }
+}
+
+impl InodeFlags {
+    /// Flags that may be changed through [`Ext4FileSystem::set_flags`]
+    ///
+    /// [`Ext4FileSystem::set_flags`]: crate::Ext4FileSystem::set_flags
+    pub const USER_MODIFIABLE: Self = Self::SECRM
+        .union(Self::UNRM)
+        .union(Self::COMPR)
+        .union(Self::SYNC)
+        .union(Self::IMMUTABLE)
+        .union(Self::APPEND)
+        .union(Self::NODUMP)
+        .union(Self::NOATIME)
+        .union(Self::JOURNAL_DATA)
+        .union(Self::NOTAIL)
+        .union(Self::DIRSYNC)
+        .union(Self::TOPDIR)
+        .union(Self::DAX)
+        .union(Self::PROJINHERIT);
+}
+
 impl InodeMode {
     pub const DEFAULT_FILE: Self = Self::IFREG
         .union(Self::IRUSR)
@@ -62,6 +134,60 @@ impl InodeMode {
         .union(Self::IXOTH);
 }
 
+/// Size of the original (revision 0) inode
+pub const EXT4_GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// `i_extra_isize` used for new inodes, covering all timestamp extensions
+/// and the project ID
+pub const EXT4_INODE_EXTRA_ISIZE: u16 = 32;
+
+/// A point in time with nanosecond precision
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch
+    pub sec: i64,
+    /// Nanoseconds within the second
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    /// Create a timestamp
+    pub const fn new(sec: i64, nsec: u32) -> Self {
+        Self { sec, nsec }
+    }
+
+    /// Decode an on-disk `(seconds, *_extra)` pair
+    ///
+    /// The low two bits of `extra` extend the signed 32-bit seconds value,
+    /// the upper 30 bits hold nanoseconds.
+    pub fn from_raw(sec: u32, extra: u32) -> Self {
+        let epoch = (extra & 0x3) as i64;
+        Self {
+            sec: (sec as i32 as i64) + (epoch << 32),
+            nsec: extra >> 2,
+        }
+    }
+
+    /// Encode into an on-disk `(seconds, *_extra)` pair
+    pub fn to_raw(&self) -> (u32, u32) {
+        let epoch = ((self.sec - (self.sec as i32 as i64)) >> 32) as u32 & 0x3;
+        (self.sec as u32, (self.nsec << 2) | epoch)
+    }
+}
+
+/// Timestamps of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodeTimes {
+    /// Last access time
+    pub atime: Timestamp,
+    /// Last data modification time
+    pub mtime: Timestamp,
+    /// Last status change time
+    pub ctime: Timestamp,
+    /// Creation time, only available on inodes with extended fields
+    pub crtime: Option<Timestamp>,
+}
+
 /// Ext4 inode structure
 #[derive(Clone)]
 pub struct Inode {
@@ -69,9 +195,9 @@ pub struct Inode {
     pub ino: u32,
     /// File mode
     pub mode: InodeMode,
-    /// User ID
+    /// User ID (low 16 bits)
     pub uid: u16,
-    /// File size
+    /// File size, stored as i_size_lo and i_size_high
     pub size: u64,
     /// Access time
     pub atime: u32,
@@ -81,7 +207,7 @@ pub struct Inode {
     pub mtime: u32,
     /// Deletion time
     pub dtime: u32,
-    /// Group ID
+    /// Group ID (low 16 bits)
     pub gid: u16,
     /// Links count
     pub links_count: u16,
@@ -93,8 +219,6 @@ pub struct Inode {
     pub version: u32,
     /// File ACL
     pub file_acl: u32,
-    /// Directory ACL or upper 16 bits of file size for large files
-    pub dir_acl: u32,
     /// Fragment address
     pub faddr: u32,
     /// Direct block pointers
@@ -105,8 +229,10 @@ pub struct Inode {
     pub faddr_ext: u32,
     /// File ACL (high 32 bits)
     pub file_acl_high: u32,
-    /// Upper 32 bits of size if needed
-    pub size_high: u32,
+    /// User ID (high 16 bits)
+    pub uid_high: u16,
+    /// Group ID (high 16 bits)
+    pub gid_high: u16,
     /// Obsoleted fragment address
     pub obso_faddr: u32,
     /// Extra inode size
@@ -189,94 +315,38 @@ impl Inode {
 
         let generation = read_u32(100);
         let file_acl = read_u32(104);
-        let dir_acl = read_u32(108);
+        let size_high = read_u32(108);
         let faddr = read_u32(112);
 
-        // Skip to extended fields if needed
-        let mut extra_isize = 0;
-        let mut checksum = 0;
-        let mut ctime_extra = 0;
-        let mut mtime_extra = 0;
-        let mut atime_extra = 0;
-        let mut crtime = 0;
-        let mut crtime_extra = 0;
-        let mut size_high = 0;
-        let mut file_acl_high = 0;
-        let mut obso_faddr = 0;
-        let mut projid = 0;
-        let mut faddr_ext = 0;
-
-        // Check if we have extended fields
-        if data.len() >= 128 {
-            extra_isize = read_u16(116);
-            checksum = read_u16(118);
-
-            if data.len() >= 156 {
-                ctime_extra = read_u32(120);
-                mtime_extra = read_u32(124);
-                atime_extra = read_u32(128);
-                crtime = read_u32(132);
-                crtime_extra = read_u32(136);
-                size_high = read_u32(140);
-                file_acl_high = read_u32(144);
-                obso_faddr = read_u32(148);
-
-                if data.len() >= 160 {
-                    projid = read_u32(152);
-                    faddr_ext = read_u32(156);
-                }
-            }
-        }
+        // Linux-specific osd2 fields
+        let blocks_high = read_u16(116);
+        let file_acl_high = read_u16(118) as u32;
+        let uid_high = read_u16(120);
+        let gid_high = read_u16(122);
+        let checksum = read_u16(124);
 
-        // Combine high and low parts for 64-bit values
-        // Check for potential corruption in size_high
-        let size_high = if size_high > 0xFFFF {
-            warn!("Potential corruption in size_high for inode {}: {}, treating as 0", ino, size_high);
-            0
-        } else {
-            size_high
+        // Fields past the 128-byte base inode are only valid when covered by
+        // i_extra_isize
+        let extra_isize = if data.len() >= 130 { read_u16(128) } else { 0 };
+        let extra_end = (128 + extra_isize as usize).min(data.len());
+        let read_extra = |offset: usize| -> u32 {
+            if offset + 4 <= extra_end {
+                read_u32(offset)
+            } else {
+                0
+            }
         };
-        
+
+        let ctime_extra = read_extra(132);
+        let mtime_extra = read_extra(136);
+        let atime_extra = read_extra(140);
+        let crtime = read_extra(144);
+        let crtime_extra = read_extra(148);
+        let projid = read_extra(156);
+
+        // i_size_high took over the slot of the old i_dir_acl field
         let size = ((size_high as u64) << 32) | (size_lo as u64);
-        let blocks = blocks_lo as u64; // blocks is actually 32-bit in ext4
-        
-        // Sanity check for size
-        if size > 0x100000000 { // 4GB limit for sanity
-            warn!("Potential corruption in size for inode {}: {}, treating as 0", ino, size);
-            // For now, we'll use size_lo only
-            return Ok(Self {
-                ino,
-                mode: InodeMode::from_bits_truncate(mode),
-                uid,
-                size: size_lo as u64,
-                atime,
-                ctime,
-                mtime,
-                dtime,
-                gid,
-                links_count,
-                blocks,
-                flags,
-                version,
-                file_acl,
-                dir_acl,
-                faddr,
-                block,
-                generation,
-                faddr_ext,
-                file_acl_high,
-                size_high: 0,
-                obso_faddr,
-                extra_isize,
-                checksum,
-                ctime_extra,
-                mtime_extra,
-                atime_extra,
-                crtime,
-                crtime_extra,
-                projid,
-            });
-        }
+        let blocks = ((blocks_high as u64) << 32) | (blocks_lo as u64);
 
         Ok(Self {
             ino,
@@ -293,14 +363,14 @@ impl Inode {
             flags,
             version,
             file_acl,
-            dir_acl,
             faddr,
             block,
             generation,
-            faddr_ext,
+            faddr_ext: 0,
             file_acl_high,
-            size_high,
-            obso_faddr,
+            uid_high,
+            gid_high,
+            obso_faddr: faddr,
             extra_isize,
             checksum,
             ctime_extra,
@@ -349,6 +419,39 @@ impl Inode {
         self.inode_type() == InodeType::SymLink
     }
 
+    /// Check if this is a symbolic link keeping its target in `i_block`
+    ///
+    /// As in the kernel, a symlink is fast if no blocks besides an external
+    /// xattr block of `cluster_size` bytes are allocated to it; its size
+    /// alone doesn't tell, since a short target may still be stored in a
+    /// block. Inline data symlinks are not fast symlinks.
+    pub fn is_fast_symlink(&self, block_size: u32, cluster_size: u32) -> bool {
+        let flags = self.inode_flags();
+        if !self.is_symlink() || flags.contains(InodeFlags::INLINE_DATA) {
+            return false;
+        }
+        if flags.contains(InodeFlags::EA_INODE) {
+            return self.size != 0 && self.size < self.block.len() as u64 * 4;
+        }
+
+        let has_xattr_block = self.xattr_block() != 0;
+        let ea_sectors = if has_xattr_block { cluster_size as u64 / 512 } else { 0 };
+        self.sectors(block_size) == ea_sectors
+    }
+
+    /// Block holding the extended attributes of the inode, 0 if none
+    ///
+    /// The block number is 48 bits, its high half in `file_acl_high`.
+    pub fn xattr_block(&self) -> u64 {
+        ((self.file_acl_high as u64) << 32) | self.file_acl as u64
+    }
+
+    /// Set the block holding the extended attributes of the inode
+    pub fn set_xattr_block(&mut self, block: u64) {
+        self.file_acl = block as u32;
+        self.file_acl_high = (block >> 32) as u32;
+    }
+
     /// Get file permissions
     pub fn permissions(&self) -> u16 {
         (self.mode
@@ -364,31 +467,79 @@ impl Inode {
             .bits()
     }
 
+    /// Get the block holding the data at a given file offset, or 0 for
+    /// holes and for blocks of unwritten extents, which read as zeros
+    pub(crate) fn get_data_block_number<D>(
+        &self,
+        offset: u64,
+        block_size: u32,
+        fs: &crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<u64>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        if self.has_inline_data() || !self.inode_flags().contains(InodeFlags::EXTENTS) {
+            return self.get_block_number(offset, block_size, fs);
+        }
+        let block_index = offset / block_size as u64;
+        match crate::extent::find_block_in_inode_extents(fs, self, block_index as u32) {
+            Ok((_, true)) | Err(Ext4Error::BlockNotFound) => Ok(0),
+            result => result.map(|(block, _)| block),
+        }
+    }
+
+    /// Whether the block at a given file offset is in an unwritten extent
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn is_unwritten<D>(
+        &self,
+        offset: u64,
+        block_size: u32,
+        fs: &crate::Ext4FileSystem<D>,
+    ) -> Ext4Result<bool>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        if self.has_inline_data() || !self.inode_flags().contains(InodeFlags::EXTENTS) {
+            return Ok(false);
+        }
+        let block_index = offset / block_size as u64;
+        match crate::extent::find_block_in_inode_extents(fs, self, block_index as u32) {
+            Ok((_, unwritten)) => Ok(unwritten),
+            Err(Ext4Error::BlockNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Get block number for a given file offset
     pub fn get_block_number<D>(
         &self,
         offset: u64,
         block_size: u32,
         fs: &crate::Ext4FileSystem<D>,
-    ) -> Ext4Result<u32>
+    ) -> Ext4Result<u64>
     where
         D: axdriver_block::BlockDriverOps,
     {
+        if self.has_inline_data() {
+            warn!("Inode {} has inline data, not blocks", self.ino);
+            return Err(Ext4Error::NotSupported);
+        }
         let block_index = offset / block_size as u64;
 
-        // Check if filesystem uses extents
-        debug!("inode {}: feature_incompat=0x{:x}, block[0]=0x{:x}", 
-               self.ino, fs.superblock.feature_incompat(), self.block[0]);
-        if fs.superblock.feature_incompat() & 0x0040 != 0 {
-            // EXT4_FEATURE_INCOMPAT_EXTENTS - use extent tree
-            crate::extent::find_block_in_extent_tree(fs, &self.block, block_index as u32)
+        debug!("inode {}: flags=0x{:x}, block[0]=0x{:x}", self.ino, self.flags, self.block[0]);
+        if self.inode_flags().contains(InodeFlags::EXTENTS) {
+            // Blocks outside every extent are holes
+            match crate::extent::find_block_in_inode_extents(fs, self, block_index as u32) {
+                Err(Ext4Error::BlockNotFound) => Ok(0),
+                result => result.map(|(block, _)| block),
+            }
         } else {
             // Traditional block mapping
             if block_index < 12 {
                 // Direct block
-                let block_num = self.block[block_index as usize];
+                let block_num = self.block[block_index as usize] as u64;
                 // Validate block number
-                if block_num == 0 || block_num >= fs.superblock().blocks_count() as u32 {
+                if block_num == 0 || block_num >= fs.superblock().blocks_count() {
                     return Ok(0);
                 }
                 Ok(block_num)
@@ -398,9 +549,14 @@ impl Inode {
                 if self.block[12] == 0 {
                     return Ok(0);
                 }
-                let block_num = self.get_indirect_block(self.block[12], indirect_index as u32, block_size, fs)?;
+                let block_num = self.get_indirect_block(
+                    self.block[12] as u64,
+                    indirect_index as u32,
+                    block_size,
+                    fs,
+                )?;
                 // Validate block number
-                if block_num == 0 || block_num >= fs.superblock().blocks_count() as u32 {
+                if block_num == 0 || block_num >= fs.superblock().blocks_count() {
                     return Ok(0);
                 }
                 Ok(block_num)
@@ -416,15 +572,19 @@ impl Inode {
                     return Ok(0);
                 }
                 
-                let indirect_block =
-                    self.get_indirect_block(self.block[13], first_level as u32, block_size, fs)?;
+                let indirect_block = self.get_indirect_block(
+                    self.block[13] as u64,
+                    first_level as u32,
+                    block_size,
+                    fs,
+                )?;
                 if indirect_block == 0 {
                     return Ok(0);
                 }
                 
                 let block_num = self.get_indirect_block(indirect_block, second_level as u32, block_size, fs)?;
                 // Validate block number
-                if block_num == 0 || block_num >= fs.superblock().blocks_count() as u32 {
+                if block_num == 0 || block_num >= fs.superblock().blocks_count() {
                     return Ok(0);
                 }
                 Ok(block_num)
@@ -443,8 +603,12 @@ impl Inode {
                     return Ok(0);
                 }
 
-                let indirect_block =
-                    self.get_indirect_block(self.block[14], first_level as u32, block_size, fs)?;
+                let indirect_block = self.get_indirect_block(
+                    self.block[14] as u64,
+                    first_level as u32,
+                    block_size,
+                    fs,
+                )?;
                 if indirect_block == 0 {
                     return Ok(0);
                 }
@@ -457,7 +621,7 @@ impl Inode {
                 
                 let block_num = self.get_indirect_block(doubly_indirect, third_level as u32, block_size, fs)?;
                 // Validate block number
-                if block_num == 0 || block_num >= fs.superblock().blocks_count() as u32 {
+                if block_num == 0 || block_num >= fs.superblock().blocks_count() {
                     return Ok(0);
                 }
                 Ok(block_num)
@@ -468,11 +632,11 @@ impl Inode {
     /// Get block from indirect block
     fn get_indirect_block<D>(
         &self,
-        indirect_block: u32,
+        indirect_block: u64,
         index: u32,
         block_size: u32,
         fs: &crate::Ext4FileSystem<D>,
-    ) -> Ext4Result<u32>
+    ) -> Ext4Result<u64>
     where
         D: axdriver_block::BlockDriverOps,
     {
@@ -480,28 +644,26 @@ impl Inode {
             return Ok(0); // Sparse block
         }
 
-        let mut buf = vec![0u8; block_size as usize];
-        fs.read_block(indirect_block, &mut buf)?;
-
         let offset = index as usize * 4;
-        if offset + 4 > buf.len() {
+        if offset + 4 > block_size as usize {
             return Err(crate::Ext4Error::InvalidInput);
         }
 
-        let block_num = (buf[offset] as u32)
-            | ((buf[offset + 1] as u32) << 8)
-            | ((buf[offset + 2] as u32) << 16)
-            | ((buf[offset + 3] as u32) << 24);
+        let mut buf = fs.take_block_buf();
+        fs.read_block(indirect_block, &mut buf)?;
+        let block_num = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        fs.put_block_buf(buf);
 
-        Ok(block_num)
+        Ok(block_num as u64)
     }
 
     /// Set block in indirect block
+    #[cfg(not(feature = "read-only"))]
     fn set_indirect_block<D>(
         &mut self,
-        indirect_block: u32,
+        indirect_block: u64,
         index: u32,
-        block_num: u32,
+        block_num: u64,
         block_size: u32,
         fs: &mut crate::Ext4FileSystem<D>,
     ) -> Ext4Result<()>
@@ -520,44 +682,50 @@ impl Inode {
             return Err(crate::Ext4Error::InvalidInput);
         }
 
-        // Write the block number in little-endian format
-        buf[offset] = (block_num & 0xFF) as u8;
-        buf[offset + 1] = ((block_num >> 8) & 0xFF) as u8;
-        buf[offset + 2] = ((block_num >> 16) & 0xFF) as u8;
-        buf[offset + 3] = ((block_num >> 24) & 0xFF) as u8;
+        let block_num = self.indirect_block_number(block_num)?;
+        buf[offset..offset + 4].copy_from_slice(&block_num.to_le_bytes());
 
         fs.write_block(indirect_block, &buf)?;
         Ok(())
     }
 
     /// Set block number for a given block index, handling indirect blocks
+    ///
+    /// Inodes with the `EXTENTS` flag get the block in their extent tree
+    /// instead; a block number of 0 unmaps the block.
+    #[cfg(not(feature = "read-only"))]
     pub fn set_block<D>(
         &mut self,
         block_index: u64,
-        block_num: u32,
+        block_num: u64,
         block_size: u32,
         fs: &mut crate::Ext4FileSystem<D>,
     ) -> Ext4Result<()>
     where
         D: axdriver_block::BlockDriverOps,
     {
+        if self.inode_flags().contains(InodeFlags::EXTENTS) {
+            let logical = u32::try_from(block_index).map_err(|_| Ext4Error::InvalidArg)?;
+            return crate::extent::set_inline_block(&mut self.block, logical, block_num);
+        }
         if block_index < 12 {
             // Direct block
-            self.block[block_index as usize] = block_num;
+            self.block[block_index as usize] = self.indirect_block_number(block_num)?;
             Ok(())
         } else if block_index < 12 + (block_size as u64 / 4) {
             // Singly indirect block
             let indirect_index = block_index - 12;
             if self.block[12] == 0 {
                 // Allocate indirect block if needed
-                let new_indirect = fs.alloc_block()?;
-                self.block[12] = new_indirect;
+                let new_indirect = fs.alloc_indirect_block()?;
+                self.charge_block(block_size);
+                self.block[12] = self.indirect_block_number(new_indirect)?;
                 // Initialize the indirect block with zeros
                 let zero_buf = vec![0u8; block_size as usize];
                 fs.write_block(new_indirect, &zero_buf)?;
             }
             self.set_indirect_block(
-                self.block[12],
+                self.block[12] as u64,
                 indirect_index as u32,
                 block_num,
                 block_size,
@@ -573,8 +741,9 @@ impl Inode {
 
             if self.block[13] == 0 {
                 // Allocate doubly indirect block if needed
-                let new_doubly = fs.alloc_block()?;
-                self.block[13] = new_doubly;
+                let new_doubly = fs.alloc_indirect_block()?;
+                self.charge_block(block_size);
+                self.block[13] = self.indirect_block_number(new_doubly)?;
                 // Initialize the doubly indirect block with zeros
                 let zero_buf = vec![0u8; block_size as usize];
                 fs.write_block(new_doubly, &zero_buf)?;
@@ -582,12 +751,13 @@ impl Inode {
 
             // Get or allocate the singly indirect block
             let indirect_block =
-                self.get_indirect_block(self.block[13], first_level as u32, block_size, fs)?;
+                self.get_indirect_block(self.block[13] as u64, first_level as u32, block_size, fs)?;
             if indirect_block == 0 {
                 // Allocate singly indirect block if needed
-                let new_indirect = fs.alloc_block()?;
+                let new_indirect = fs.alloc_indirect_block()?;
+                self.charge_block(block_size);
                 self.set_indirect_block(
-                    self.block[13],
+                    self.block[13] as u64,
                     first_level as u32,
                     new_indirect,
                     block_size,
@@ -625,8 +795,9 @@ impl Inode {
 
             if self.block[14] == 0 {
                 // Allocate triply indirect block if needed
-                let new_triply = fs.alloc_block()?;
-                self.block[14] = new_triply;
+                let new_triply = fs.alloc_indirect_block()?;
+                self.charge_block(block_size);
+                self.block[14] = self.indirect_block_number(new_triply)?;
                 // Initialize the triply indirect block with zeros
                 let zero_buf = vec![0u8; block_size as usize];
                 fs.write_block(new_triply, &zero_buf)?;
@@ -634,12 +805,13 @@ impl Inode {
 
             // Get or allocate the doubly indirect block
             let doubly_block =
-                self.get_indirect_block(self.block[14], first_level as u32, block_size, fs)?;
+                self.get_indirect_block(self.block[14] as u64, first_level as u32, block_size, fs)?;
             let doubly_indirect = if doubly_block == 0 {
                 // Allocate doubly indirect block if needed
-                let new_doubly = fs.alloc_block()?;
+                let new_doubly = fs.alloc_indirect_block()?;
+                self.charge_block(block_size);
                 self.set_indirect_block(
-                    self.block[14],
+                    self.block[14] as u64,
                     first_level as u32,
                     new_doubly,
                     block_size,
@@ -658,7 +830,8 @@ impl Inode {
                 self.get_indirect_block(doubly_indirect, second_level as u32, block_size, fs)?;
             let singly_indirect = if singly_block == 0 {
                 // Allocate singly indirect block if needed
-                let new_singly = fs.alloc_block()?;
+                let new_singly = fs.alloc_indirect_block()?;
+                self.charge_block(block_size);
                 self.set_indirect_block(
                     doubly_indirect,
                     second_level as u32,
@@ -684,6 +857,62 @@ impl Inode {
         }
     }
 
+    /// Collect the indirect blocks of this inode, which hold its block map
+    /// rather than its data
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn indirect_blocks<D>(&self, fs: &crate::Ext4FileSystem<D>) -> Ext4Result<Vec<u64>>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        let mut blocks = Vec::new();
+        let mut buf = fs.take_block_buf();
+        for (slot, depth) in [(12, 1), (13, 2), (14, 3)] {
+            let mut level = vec![self.block[slot] as u64];
+            for remaining in (0..depth).rev() {
+                let mut children = Vec::new();
+                for &block in level.iter().filter(|&&block| block != 0) {
+                    blocks.push(block);
+                    if remaining > 0 {
+                        fs.read_block(block, &mut buf)?;
+                        let entries = buf.chunks_exact(4);
+                        children.extend(entries.map(|e| {
+                            u32::from_le_bytes(e.try_into().unwrap()) as u64
+                        }));
+                    }
+                }
+                level = children;
+            }
+        }
+        fs.put_block_buf(buf);
+        Ok(blocks)
+    }
+
+    /// Convert `block` to the 32-bit form the indirect block map stores
+    ///
+    /// Only extent-mapped files can reach blocks past 2^32; this fails with
+    /// `NotSupported` for such blocks.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn indirect_block_number(&self, block: u64) -> Ext4Result<u32> {
+        u32::try_from(block).map_err(|_| {
+            warn!("Inode {} can't map block {} without extents", self.ino, block);
+            Ext4Error::NotSupported
+        })
+    }
+
+    /// Account one newly allocated filesystem block in `blocks`
+    ///
+    /// `blocks` counts 512-byte sectors and includes indirect blocks.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn charge_block(&mut self, block_size: u32) {
+        self.blocks += block_size as u64 / 512;
+    }
+
+    /// Stop accounting a freed filesystem block in `blocks`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn uncharge_block(&mut self, block_size: u32) {
+        self.blocks = self.blocks.saturating_sub(block_size as u64 / 512);
+    }
+
     /// Get the number of blocks this inode uses
     pub fn block_count(&self, block_size: u32) -> u64 {
         (self.size + block_size as u64 - 1) / block_size as u64
@@ -706,13 +935,13 @@ impl Inode {
             flags: 0,
             version: 0,
             file_acl: 0,
-            dir_acl: 0,
             faddr: 0,
             block: [0; 15],
             generation: 0,
             faddr_ext: 0,
             file_acl_high: 0,
-            size_high: 0,
+            uid_high: 0,
+            gid_high: 0,
             obso_faddr: 0,
             extra_isize: 0,
             checksum: 0,
@@ -728,7 +957,17 @@ impl Inode {
     /// Convert inode to bytes
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut data = vec![0u8; 256]; // Maximum inode size
+        self.write_to(&mut data);
+        data
+    }
 
+    /// Serialize the inode into an existing on-disk inode slot
+    ///
+    /// Only fields modelled by [`Inode`] are written; everything else in
+    /// `data` (in-inode extended attributes, reserved fields) is preserved.
+    /// Fields past the 128-byte base inode are written only when both the
+    /// slot and `extra_isize` cover them.
+    pub fn write_to(&self, data: &mut [u8]) {
         // Helper function to write little-endian values
         let write_u16 = |data: &mut [u8], offset: usize, value: u16| {
             data[offset] = (value & 0xFF) as u8;
@@ -743,44 +982,375 @@ impl Inode {
         };
 
         // Write basic inode fields
-        write_u16(&mut data, 0, self.mode.bits());
-        write_u16(&mut data, 2, self.uid);
-        write_u32(&mut data, 4, self.size as u32);
-        write_u32(&mut data, 8, self.atime);
-        write_u32(&mut data, 12, self.ctime);
-        write_u32(&mut data, 16, self.mtime);
-        write_u32(&mut data, 20, self.dtime);
-        write_u16(&mut data, 24, self.gid);
-        write_u16(&mut data, 26, self.links_count);
-        write_u32(&mut data, 28, self.blocks as u32);
-        write_u32(&mut data, 32, self.flags);
-        write_u32(&mut data, 36, self.version);
+        write_u16(data, 0, self.mode.bits());
+        write_u16(data, 2, self.uid);
+        write_u32(data, 4, self.size as u32);
+        write_u32(data, 8, self.atime);
+        write_u32(data, 12, self.ctime);
+        write_u32(data, 16, self.mtime);
+        write_u32(data, 20, self.dtime);
+        write_u16(data, 24, self.gid);
+        write_u16(data, 26, self.links_count);
+        write_u32(data, 28, self.blocks as u32);
+        write_u32(data, 32, self.flags);
+        write_u32(data, 36, self.version);
 
         // Write block pointers
         for i in 0..15 {
-            write_u32(&mut data, 40 + i * 4, self.block[i]);
+            write_u32(data, 40 + i * 4, self.block[i]);
         }
 
-        write_u32(&mut data, 100, self.generation);
-        write_u32(&mut data, 104, self.file_acl);
-        write_u32(&mut data, 108, self.dir_acl);
-        write_u32(&mut data, 112, self.faddr);
+        write_u32(data, 100, self.generation);
+        write_u32(data, 104, self.file_acl);
+        write_u32(data, 108, (self.size >> 32) as u32);
+        write_u32(data, 112, self.faddr);
+        write_u16(data, 116, (self.blocks >> 32) as u16);
+        write_u16(data, 118, self.file_acl_high as u16);
+        write_u16(data, 120, self.uid_high);
+        write_u16(data, 122, self.gid_high);
+        write_u16(data, 124, self.checksum);
 
         // Write extended fields
-        write_u16(&mut data, 116, self.extra_isize);
-        write_u16(&mut data, 118, self.checksum);
-        write_u32(&mut data, 120, self.ctime_extra);
-        write_u32(&mut data, 124, self.mtime_extra);
-        write_u32(&mut data, 128, self.atime_extra);
-        write_u32(&mut data, 132, self.crtime);
-        write_u32(&mut data, 136, self.crtime_extra);
-        write_u32(&mut data, 140, self.size_high);
-        write_u32(&mut data, 144, self.file_acl_high);
-        write_u32(&mut data, 148, self.obso_faddr);
-
-        // Don't truncate - we need full 256 bytes for ext4 inodes
-        // data.truncate(128 + self.extra_isize as usize);
-        data
+        if data.len() < 130 {
+            return;
+        }
+        write_u16(data, 128, self.extra_isize);
+
+        let extra_end = (128 + self.extra_isize as usize).min(data.len());
+        let mut write_extra = |offset: usize, value: u32| {
+            if offset + 4 <= extra_end {
+                write_u32(data, offset, value);
+            }
+        };
+        write_extra(132, self.ctime_extra);
+        write_extra(136, self.mtime_extra);
+        write_extra(140, self.atime_extra);
+        write_extra(144, self.crtime);
+        write_extra(148, self.crtime_extra);
+        write_extra(156, self.projid);
+    }
+
+    /// Initialize all timestamps of a newly created inode
+    ///
+    /// `inode_size` is the on-disk inode size; when it has room for the
+    /// extended fields, `extra_isize` is set so that nanoseconds and the
+    /// creation time are stored.
+    pub fn init_timestamps(&mut self, now: Timestamp, inode_size: u16) {
+        if inode_size as usize >= EXT4_GOOD_OLD_INODE_SIZE + EXT4_INODE_EXTRA_ISIZE as usize {
+            self.extra_isize = EXT4_INODE_EXTRA_ISIZE;
+        }
+
+        let (sec, extra) = now.to_raw();
+        self.atime = sec;
+        self.ctime = sec;
+        self.mtime = sec;
+        self.crtime = sec;
+        self.atime_extra = extra;
+        self.ctime_extra = extra;
+        self.mtime_extra = extra;
+        self.crtime_extra = extra;
+    }
+
+    /// Set the inode change time
+    pub fn set_ctime(&mut self, time: Timestamp) {
+        let (sec, extra) = time.to_raw();
+        self.ctime = sec;
+        self.ctime_extra = extra;
+    }
+
+    /// Set the last access time
+    pub fn set_atime(&mut self, time: Timestamp) {
+        let (sec, extra) = time.to_raw();
+        self.atime = sec;
+        self.atime_extra = extra;
+    }
+
+    /// Set the data modification time
+    pub fn set_mtime(&mut self, time: Timestamp) {
+        let (sec, extra) = time.to_raw();
+        self.mtime = sec;
+        self.mtime_extra = extra;
+    }
+
+    /// Creation (birth) time, if the inode is large enough to record it
+    pub fn crtime(&self) -> Option<Timestamp> {
+        if self.extra_isize >= 24 {
+            Some(Timestamp::from_raw(self.crtime, self.crtime_extra))
+        } else {
+            None
+        }
+    }
+
+    /// Set the creation (birth) time
+    pub fn set_crtime(&mut self, time: Timestamp) {
+        let (sec, extra) = time.to_raw();
+        self.crtime = sec;
+        self.crtime_extra = extra;
+    }
+
+    /// Inode flags
+    pub fn inode_flags(&self) -> InodeFlags {
+        InodeFlags::from_bits_retain(self.flags)
+    }
+
+    /// Fail if the inode's data or metadata may not be modified
+    pub fn check_modify(&self) -> Ext4Result<()> {
+        if self.inode_flags().contains(InodeFlags::IMMUTABLE) {
+            return Err(Ext4Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Check if the file is protected by fs-verity
+    ///
+    /// The data of such a file is covered by a Merkle tree stored past its
+    /// end, and can't change once verity is enabled. The data is read
+    /// without being checked against the tree.
+    pub fn is_verity(&self) -> bool {
+        self.inode_flags().contains(InodeFlags::VERITY)
+    }
+
+    /// Check if the inode keeps its data in `i_block` and the
+    /// `system.data` extended attribute rather than in blocks
+    pub fn has_inline_data(&self) -> bool {
+        self.inode_flags().contains(InodeFlags::INLINE_DATA)
+    }
+
+    /// Fail if the inode's data may not be changed
+    ///
+    /// Besides immutable inodes, this covers fs-verity files, whose metadata
+    /// may still change, and files with inline data, which can't be written.
+    pub fn check_modify_data(&self) -> Ext4Result<()> {
+        self.check_modify()?;
+        if self.is_verity() {
+            return Err(Ext4Error::PermissionDenied);
+        }
+        if self.has_inline_data() {
+            return Err(Ext4Error::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Fail unless data may be written at `offset`
+    ///
+    /// Append-only inodes only accept writes at end of file.
+    pub fn check_write_at(&self, offset: u64) -> Ext4Result<()> {
+        self.check_modify_data()?;
+        if self.inode_flags().contains(InodeFlags::APPEND) && offset != self.size {
+            return Err(Ext4Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Fail if the inode may not be truncated, unlinked, or renamed
+    ///
+    /// This applies to both immutable and append-only inodes. For
+    /// directories it also guards removing entries.
+    pub fn check_remove(&self) -> Ext4Result<()> {
+        if self
+            .inode_flags()
+            .intersects(InodeFlags::IMMUTABLE | InodeFlags::APPEND)
+        {
+            return Err(Ext4Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Upper 32 bits of the size, which took over the slot of `i_dir_acl`
+    #[deprecated(note = "use the upper half of `size`")]
+    pub fn dir_acl(&self) -> u32 {
+        (self.size >> 32) as u32
+    }
+
+    /// Upper 32 bits of the size
+    #[deprecated(note = "use the upper half of `size`")]
+    pub fn size_high(&self) -> u32 {
+        (self.size >> 32) as u32
+    }
+
+    /// Full 32-bit user ID
+    pub fn full_uid(&self) -> u32 {
+        ((self.uid_high as u32) << 16) | self.uid as u32
+    }
+
+    /// Full 32-bit group ID
+    pub fn full_gid(&self) -> u32 {
+        ((self.gid_high as u32) << 16) | self.gid as u32
+    }
+
+    /// Set the full 32-bit user ID
+    pub fn set_full_uid(&mut self, uid: u32) {
+        self.uid = uid as u16;
+        self.uid_high = (uid >> 16) as u16;
+    }
+
+    /// Set the full 32-bit group ID
+    pub fn set_full_gid(&mut self, gid: u32) {
+        self.gid = gid as u16;
+        self.gid_high = (gid >> 16) as u16;
+    }
+
+    /// Number of 512-byte sectors allocated to the inode
+    ///
+    /// Inodes flagged `HUGE_FILE` count filesystem blocks instead.
+    pub fn sectors(&self, block_size: u32) -> u64 {
+        if self.inode_flags().contains(InodeFlags::HUGE_FILE) {
+            self.blocks * (block_size as u64 / 512)
+        } else {
+            self.blocks
+        }
+    }
+
+    /// All timestamps of the inode
+    pub fn times(&self) -> InodeTimes {
+        // Extra fields the inode's extra space doesn't reach read as 0
+        let extra = |end: u16, value: u32| if self.extra_isize >= end { value } else { 0 };
+        InodeTimes {
+            atime: Timestamp::from_raw(self.atime, extra(16, self.atime_extra)),
+            mtime: Timestamp::from_raw(self.mtime, extra(12, self.mtime_extra)),
+            ctime: Timestamp::from_raw(self.ctime, extra(8, self.ctime_extra)),
+            crtime: self.crtime(),
+        }
+    }
+}
+
+/// Description of a new inode: its type, permissions, owner and flags
+///
+/// `InodeBuilder::file().mode(0o640).uid(1000)` describes a file readable
+/// by its group and owned by user 1000. The creating operation allocates the
+/// inode and fills in everything else, such as its number, generation and
+/// timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodeBuilder {
+    kind: InodeMode,
+    perm: InodeMode,
+    uid: u32,
+    gid: u32,
+    flags: InodeFlags,
+    /// Major and minor device number of device nodes
+    rdev: (u32, u32),
+}
+
+impl InodeBuilder {
+    /// Start an inode of type `kind` with permissions `perm`
+    fn new(kind: InodeMode, perm: u16) -> Self {
+        Self {
+            kind,
+            perm: InodeMode::from_bits_truncate(perm),
+            uid: 0,
+            gid: 0,
+            flags: InodeFlags::empty(),
+            rdev: (0, 0),
+        }
+    }
+
+    /// A regular file, mode 0644 by default
+    pub fn file() -> Self {
+        Self::new(InodeMode::IFREG, 0o644)
+    }
+
+    /// A directory, mode 0755 by default
+    pub fn dir() -> Self {
+        Self::new(InodeMode::IFDIR, 0o755)
+    }
+
+    /// A symbolic link, mode 0777 by default
+    pub fn symlink() -> Self {
+        Self::new(InodeMode::IFLNK, 0o777)
+    }
+
+    /// A named pipe, mode 0644 by default
+    pub fn fifo() -> Self {
+        Self::new(InodeMode::IFIFO, 0o644)
+    }
+
+    /// A Unix domain socket, mode 0644 by default
+    pub fn socket() -> Self {
+        Self::new(InodeMode::IFSOCK, 0o644)
+    }
+
+    /// A character device node, mode 0644 by default
+    pub fn char_device(major: u32, minor: u32) -> Self {
+        Self {
+            rdev: (major, minor),
+            ..Self::new(InodeMode::IFCHR, 0o644)
+        }
+    }
+
+    /// A block device node, mode 0644 by default
+    pub fn block_device(major: u32, minor: u32) -> Self {
+        Self {
+            rdev: (major, minor),
+            ..Self::new(InodeMode::IFBLK, 0o644)
+        }
+    }
+
+    /// Set the permission bits, including setuid, setgid and sticky; type
+    /// bits are ignored
+    pub fn mode(mut self, perm: u16) -> Self {
+        self.perm = InodeMode::from_bits_truncate(perm) - InodeMode::IFMT;
+        self
+    }
+
+    /// Set the owner
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    /// Set the group
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// Set the inode flags
+    pub fn flags(mut self, flags: InodeFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Type of the inode being built
+    pub fn inode_type(&self) -> InodeType {
+        self.build().inode_type()
+    }
+
+    /// Build the inode, with number 0 and no timestamps
+    ///
+    /// Directories start with two links, for their entry and ".", and
+    /// other inodes with one. Device numbers are stored in the old format
+    /// when they fit, and in the new one in the next block slot otherwise,
+    /// as Linux does.
+    pub fn build(&self) -> Inode {
+        let mut inode = Inode::new(0);
+        inode.mode = self.kind | self.perm;
+        inode.links_count = if self.kind == InodeMode::IFDIR { 2 } else { 1 };
+        inode.set_full_uid(self.uid);
+        inode.set_full_gid(self.gid);
+        inode.flags = self.flags.bits();
+
+        let (major, minor) = self.rdev;
+        if matches!(self.kind, InodeMode::IFCHR | InodeMode::IFBLK) {
+            if major < 256 && minor < 256 {
+                inode.block[0] = (major << 8) | minor;
+            } else {
+                inode.block[1] = (minor & 0xFF) | (major << 8) | ((minor & !0xFF) << 12);
+            }
+        }
+        inode
+    }
+}
+
+impl Inode {
+    /// Major and minor device number of a device node
+    pub fn device_number(&self) -> (u32, u32) {
+        match self.block[0] {
+            0 => {
+                let dev = self.block[1];
+                ((dev >> 8) & 0xFFF, (dev & 0xFF) | ((dev >> 12) & 0xFFF00))
+            }
+            dev => ((dev >> 8) & 0xFF, dev & 0xFF),
+        }
     }
 }
 
@@ -801,13 +1371,11 @@ impl core::fmt::Debug for Inode {
             .field("flags", &self.flags)
             .field("version", &self.version)
             .field("file_acl", &self.file_acl)
-            .field("dir_acl", &self.dir_acl)
             .field("faddr", &self.faddr)
             .field("block", &self.block)
             .field("generation", &self.generation)
             .field("faddr_ext", &self.faddr_ext)
             .field("file_acl_high", &self.file_acl_high)
-            .field("size_high", &self.size_high)
             .field("obso_faddr", &self.obso_faddr)
             .field("extra_isize", &self.extra_isize)
             .field("checksum", &self.checksum)
@@ -820,3 +1388,131 @@ impl core::fmt::Debug for Inode {
             .finish()
     }
 }
+
+/// Iterator over all allocated inodes, created by
+/// [`Ext4FileSystem::iter_inodes`](crate::Ext4FileSystem::iter_inodes)
+pub struct InodeIter<'a, D: axdriver_block::BlockDriverOps> {
+    fs: &'a crate::Ext4FileSystem<D>,
+    /// Block group currently being scanned
+    group: usize,
+    /// Next inode index within the group
+    index: u32,
+    /// Number of initialized inodes in the group
+    limit: u32,
+    /// Inode bitmap of the current group
+    bitmap: Option<crate::Bitmap>,
+    /// Most recently read inode table block
+    table_block: Option<(u64, Vec<u8>)>,
+}
+
+impl<'a, D: axdriver_block::BlockDriverOps> InodeIter<'a, D> {
+    pub(crate) fn new(fs: &'a crate::Ext4FileSystem<D>) -> Self {
+        Self {
+            fs,
+            group: 0,
+            index: 0,
+            limit: 0,
+            bitmap: None,
+            table_block: None,
+        }
+    }
+
+    /// Load the inode bitmap of the current group, skipping uninitialized groups
+    fn load_group(&mut self) -> Ext4Result<bool> {
+        let inodes_per_group = self.fs.superblock.inodes_per_group();
+
+        while self.group < self.fs.block_groups.len() {
+            let bg = &self.fs.block_groups[self.group];
+            if bg.inode_uninit() {
+                debug!("Skipping uninitialized inode group {}", self.group);
+                self.group += 1;
+                continue;
+            }
+
+            // itable_unused is only maintained when group checksums are enabled
+            // (RO_COMPAT_GDT_CSUM or RO_COMPAT_METADATA_CSUM)
+            let limit = if self.fs.superblock.has_group_csum() {
+                inodes_per_group.saturating_sub(bg.itable_unused())
+            } else {
+                inodes_per_group
+            };
+
+            // The allocator's copy may not be written yet
+            #[cfg(not(feature = "read-only"))]
+            let bitmap = self.fs.with_bitmap(self.group, true, crate::Bitmap::clone)?;
+            #[cfg(feature = "read-only")]
+            let bitmap = {
+                let mut buf = vec![0u8; self.fs.superblock.block_size() as usize];
+                self.fs.read_block(bg.inode_bitmap(), &mut buf)?;
+                crate::Bitmap::from_bytes(&buf)
+            };
+
+            self.bitmap = Some(bitmap);
+            self.limit = limit;
+            self.index = 0;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Read an inode, reusing the cached inode table block when possible
+    fn read_inode(&mut self, ino: u32) -> Ext4Result<Inode> {
+        let (block, offset) = self.fs.inode_location(ino)?;
+        let cached = matches!(&self.table_block, Some((b, _)) if *b == block);
+        if !cached {
+            let mut buf = vec![0u8; self.fs.superblock.block_size() as usize];
+            self.fs.read_block(block, &mut buf)?;
+            self.table_block = Some((block, buf));
+        }
+
+        let inode_size = self.fs.superblock.inode_size() as usize;
+        let (_, buf) = self.table_block.as_ref().ok_or(Ext4Error::IoError)?;
+        self.fs.parse_inode(&buf[offset..offset + inode_size], ino)
+    }
+}
+
+impl<'a, D: axdriver_block::BlockDriverOps> Iterator for InodeIter<'a, D> {
+    type Item = Ext4Result<(u32, Inode)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.bitmap.is_none() {
+                match self.load_group() {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(e) => {
+                        self.group = self.fs.block_groups.len();
+                        return Some(Err(e));
+                    }
+                }
+            }
+
+            let bitmap = self.bitmap.as_ref()?;
+            while self.index < self.limit && !bitmap.is_set(self.index as usize) {
+                self.index += 1;
+            }
+
+            if self.index >= self.limit {
+                self.bitmap = None;
+                self.group += 1;
+                continue;
+            }
+
+            let ino = self.group as u32 * self.fs.superblock.inodes_per_group() + self.index + 1;
+            self.index += 1;
+            if self.fs.is_reserved_inode(ino) {
+                continue;
+            }
+
+            return Some(match self.read_inode(ino) {
+                Ok(inode) => Ok((ino, inode)),
+                Err(e) => {
+                    self.group = self.fs.block_groups.len();
+                    self.bitmap = None;
+                    Err(e)
+                }
+            });
+        }
+    }
+}
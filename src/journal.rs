@@ -1,13 +1,109 @@
+#[cfg(not(feature = "read-only"))]
 use alloc::vec::Vec;
 use axdriver_block::BlockDriverOps;
 use log::*;
 
-use crate::{Ext4Error, Ext4Result};
+#[cfg(not(feature = "read-only"))]
+use crate::{crc32c, Ext4Error};
+use crate::Ext4Result;
+
+/// Magic number of every jbd2 metadata block
+const JBD2_MAGIC: u32 = 0xC03B_3998;
+/// Block type of a descriptor block, listing the blocks logged after it
+#[cfg(not(feature = "read-only"))]
+const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+/// Block type of a commit block, ending a transaction
+#[cfg(not(feature = "read-only"))]
+const JBD2_COMMIT_BLOCK: u32 = 2;
+/// Block type of a version 1 journal superblock
+const JBD2_SUPERBLOCK_V1: u32 = 3;
+/// Block type of a version 2 journal superblock
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+/// Size of the header starting every jbd2 metadata block
+#[cfg(not(feature = "read-only"))]
+const JBD2_HEADER_SIZE: usize = 12;
+
+/// Offset of `s_first`, the first block of the log, in the journal
+/// superblock
+#[cfg(not(feature = "read-only"))]
+const JBD2_FIRST_OFFSET: usize = 0x14;
+/// Offset of `s_sequence`, the ID of the first transaction in the log
+#[cfg(not(feature = "read-only"))]
+const JBD2_SEQUENCE_OFFSET: usize = 0x18;
+/// Offset of `s_start`, the block the log starts at or 0 if it is empty
+#[cfg(not(feature = "read-only"))]
+const JBD2_START_OFFSET: usize = 0x1C;
+/// Offset of `s_errno` in the journal superblock
+const JBD2_ERRNO_OFFSET: usize = 0x20;
+/// Offset of `s_feature_compat` in the journal superblock
+#[cfg(not(feature = "read-only"))]
+const JBD2_COMPAT_OFFSET: usize = 0x24;
+/// Offset of `s_feature_incompat` in the journal superblock
+#[cfg(not(feature = "read-only"))]
+const JBD2_INCOMPAT_OFFSET: usize = 0x28;
+/// Offset of `s_uuid` in the journal superblock
+#[cfg(not(feature = "read-only"))]
+const JBD2_UUID_OFFSET: usize = 0x30;
+/// Offset of `s_checksum` in the journal superblock
+#[cfg(not(feature = "read-only"))]
+const JBD2_CHECKSUM_OFFSET: usize = 0xFC;
+/// Size of the journal superblock covered by its checksum
+#[cfg(not(feature = "read-only"))]
+const JBD2_SUPERBLOCK_SIZE: usize = 1024;
+
+/// Compatible feature: commit blocks hold a crc32 of the transaction
+#[cfg(not(feature = "read-only"))]
+const JBD2_FEATURE_COMPAT_CHECKSUM: u32 = 0x1;
+/// Incompatible feature: the log may hold revoke blocks
+#[cfg(not(feature = "read-only"))]
+const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 0x1;
+/// Incompatible feature: block tags hold 64-bit block numbers
+#[cfg(not(feature = "read-only"))]
+const JBD2_FEATURE_INCOMPAT_64BIT: u32 = 0x2;
+/// Incompatible feature: commit blocks may be written without a barrier
+#[cfg(not(feature = "read-only"))]
+const JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+/// Incompatible feature: version 2 checksums, 16 bits in block tags
+#[cfg(not(feature = "read-only"))]
+const JBD2_FEATURE_INCOMPAT_CSUM_V2: u32 = 0x8;
+/// Incompatible feature: version 3 checksums, 32 bits in block tags
+#[cfg(not(feature = "read-only"))]
+const JBD2_FEATURE_INCOMPAT_CSUM_V3: u32 = 0x10;
+/// Incompatible features transactions can be logged with
+#[cfg(not(feature = "read-only"))]
+const JBD2_INCOMPAT_LOGGABLE: u32 = JBD2_FEATURE_INCOMPAT_REVOKE
+    | JBD2_FEATURE_INCOMPAT_64BIT
+    | JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT
+    | JBD2_FEATURE_INCOMPAT_CSUM_V2
+    | JBD2_FEATURE_INCOMPAT_CSUM_V3;
+
+/// Tag flag: the logged block started with the jbd2 magic, zeroed in the log
+#[cfg(not(feature = "read-only"))]
+const JBD2_FLAG_ESCAPE: u32 = 0x1;
+/// Tag flag: no UUID follows the tag, the one of the previous tag applies
+#[cfg(not(feature = "read-only"))]
+const JBD2_FLAG_SAME_UUID: u32 = 0x2;
+/// Tag flag: last tag of the descriptor block
+#[cfg(not(feature = "read-only"))]
+const JBD2_FLAG_LAST_TAG: u32 = 0x8;
+
+/// Error number recorded in the journal superblock on abort
+pub(crate) const EIO: i32 = 5;
+
+fn read_be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn write_be32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
 
 /// Journaling support for ext4
 #[derive(Debug)]
 pub struct Journal {
     /// Journal inode number
+    #[cfg(not(feature = "read-only"))]
     journal_inum: u32,
     /// Journal size in blocks
     journal_size: u32,
@@ -15,11 +111,30 @@ pub struct Journal {
     journal_block_size: u32,
     /// Maximum transaction size
     max_transaction_size: u32,
+    /// First block of the log, after the journal superblock
+    #[cfg(not(feature = "read-only"))]
+    first: u32,
+    /// ID of the next transaction
+    #[cfg(not(feature = "read-only"))]
+    sequence: u32,
+    /// Compatible features (`s_feature_compat`)
+    #[cfg(not(feature = "read-only"))]
+    feature_compat: u32,
+    /// Incompatible features (`s_feature_incompat`)
+    #[cfg(not(feature = "read-only"))]
+    feature_incompat: u32,
+    /// UUID of the journal, which seeds its checksums
+    #[cfg(not(feature = "read-only"))]
+    uuid: [u8; 16],
     /// Current transaction
+    #[cfg(not(feature = "read-only"))]
     current_transaction: Option<Transaction>,
+    /// Negative error number once the journal is aborted, 0 otherwise
+    errno: i32,
 }
 
 /// Journal transaction
+#[cfg(not(feature = "read-only"))]
 #[derive(Debug)]
 pub struct Transaction {
     /// Transaction ID
@@ -31,6 +146,7 @@ pub struct Transaction {
 }
 
 /// Transaction state
+#[cfg(not(feature = "read-only"))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransactionState {
     Running,
@@ -40,10 +156,11 @@ pub enum TransactionState {
 }
 
 /// Transaction block
+#[cfg(not(feature = "read-only"))]
 #[derive(Debug, Clone)]
 pub struct TransactionBlock {
     /// Block number
-    block_num: u32,
+    block_num: u64,
     /// Block data
     data: Vec<u8>,
     /// Block type
@@ -51,6 +168,7 @@ pub struct TransactionBlock {
 }
 
 /// Block type in journal
+#[cfg(not(feature = "read-only"))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BlockType {
     Descriptor,
@@ -62,22 +180,136 @@ pub enum BlockType {
 impl Journal {
     /// Create a new journal
     pub fn new(journal_inum: u32, journal_size: u32, journal_block_size: u32) -> Self {
+        // Only the write paths go back to the journal inode
+        #[cfg(feature = "read-only")]
+        let _ = journal_inum;
         Self {
+            #[cfg(not(feature = "read-only"))]
             journal_inum,
             journal_size,
             journal_block_size,
             max_transaction_size: journal_size / 4, // Conservative estimate
+            #[cfg(not(feature = "read-only"))]
+            first: 1,
+            #[cfg(not(feature = "read-only"))]
+            sequence: 1,
+            #[cfg(not(feature = "read-only"))]
+            feature_compat: 0,
+            #[cfg(not(feature = "read-only"))]
+            feature_incompat: 0,
+            #[cfg(not(feature = "read-only"))]
+            uuid: [0; 16],
+            #[cfg(not(feature = "read-only"))]
             current_transaction: None,
+            errno: 0,
         }
     }
 
+    /// Load the journal stored in inode `journal_inum`
+    ///
+    /// A journal superblock with a bad magic or block type means the log is
+    /// corrupt and the returned journal is already aborted. So is a journal
+    /// whose superblock records the error of an earlier abort.
+    pub fn load<D>(fs: &crate::Ext4FileSystem<D>, journal_inum: u32) -> Ext4Result<Self>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size();
+        let inode = fs.get_inode(journal_inum)?;
+        let block = inode.get_block_number(0, block_size, fs)?;
+        let mut buf = vec![0u8; block_size as usize];
+        fs.read_block(block, &mut buf)?;
+
+        let mut journal = Self::new(journal_inum, inode.block_count(block_size) as u32, block_size);
+        let blocktype = read_be32(&buf, 4);
+        if read_be32(&buf, 0) != JBD2_MAGIC
+            || !(blocktype == JBD2_SUPERBLOCK_V1 || blocktype == JBD2_SUPERBLOCK_V2)
+        {
+            warn!("Journal superblock in block {} is corrupt", block);
+            journal.abort(-EIO);
+            return Ok(journal);
+        }
+
+        journal.journal_block_size = read_be32(&buf, 0x0C);
+        journal.journal_size = read_be32(&buf, 0x10);
+        journal.max_transaction_size = journal.journal_size / 4;
+        #[cfg(not(feature = "read-only"))]
+        {
+            journal.first = read_be32(&buf, JBD2_FIRST_OFFSET);
+            journal.sequence = read_be32(&buf, JBD2_SEQUENCE_OFFSET);
+            if blocktype == JBD2_SUPERBLOCK_V2 {
+                journal.feature_compat = read_be32(&buf, JBD2_COMPAT_OFFSET);
+                journal.feature_incompat = read_be32(&buf, JBD2_INCOMPAT_OFFSET);
+                journal.uuid = buf[JBD2_UUID_OFFSET..JBD2_UUID_OFFSET + 16].try_into().unwrap();
+            }
+        }
+        let errno = read_be32(&buf, JBD2_ERRNO_OFFSET) as i32;
+        if errno != 0 {
+            warn!("Journal was aborted with error {}", errno);
+            journal.abort(errno);
+        }
+
+        Ok(journal)
+    }
+
+    /// Cap transactions at `blocks` blocks, if non-zero and below the
+    /// default of a quarter of the journal
+    pub fn limit_transaction_size(&mut self, blocks: u32) {
+        if blocks != 0 && blocks < self.max_transaction_size {
+            self.max_transaction_size = blocks;
+        }
+    }
+
+    /// Abort the journal with error `errno`
+    ///
+    /// The running transaction is dropped and every later operation fails
+    /// with `JournalAborted`. Only the first error is kept.
+    pub fn abort(&mut self, errno: i32) {
+        if self.errno == 0 {
+            error!("Aborting journal: error {}", errno);
+            self.errno = if errno == 0 { -EIO } else { errno };
+        }
+        #[cfg(not(feature = "read-only"))]
+        {
+            if let Some(transaction) = self.current_transaction.as_mut() {
+                transaction.state = TransactionState::Aborted;
+            }
+            self.current_transaction = None;
+        }
+    }
+
+    /// Check if the journal has been aborted
+    pub fn is_aborted(&self) -> bool {
+        self.errno != 0
+    }
+
+    /// Error the journal was aborted with, 0 if it is running
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    /// Record the abort error in the on-disk journal superblock
+    #[cfg(not(feature = "read-only"))]
+    pub fn record_error<D>(&self, fs: &crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        self.edit_superblock(fs, |buf| {
+            write_be32(buf, JBD2_ERRNO_OFFSET, self.errno as u32);
+        })
+    }
+
     /// Start a new transaction
+    #[cfg(not(feature = "read-only"))]
     pub fn begin_transaction(&mut self) -> Ext4Result<u32> {
+        if self.is_aborted() {
+            return Err(Ext4Error::JournalAborted);
+        }
         if self.current_transaction.is_some() {
             return Err(Ext4Error::InvalidInput);
         }
 
-        let id = self.generate_transaction_id();
+        let id = self.sequence;
         self.current_transaction = Some(Transaction {
             id,
             blocks: Vec::new(),
@@ -88,12 +320,16 @@ impl Journal {
     }
 
     /// Add a block to the current transaction
+    #[cfg(not(feature = "read-only"))]
     pub fn add_block(
         &mut self,
-        block_num: u32,
+        block_num: u64,
         data: Vec<u8>,
         block_type: BlockType,
     ) -> Ext4Result<()> {
+        if self.is_aborted() {
+            return Err(Ext4Error::JournalAborted);
+        }
         let transaction = self
             .current_transaction
             .as_mut()
@@ -117,35 +353,69 @@ impl Journal {
     }
 
     /// Commit the current transaction
-    pub fn commit_transaction<D>(&mut self, _fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    ///
+    /// Its blocks are written to the log, followed by a commit block once
+    /// they are durable, and the journal superblock points at them. From
+    /// then on, a crash leaves a transaction that the next mount or `fsck`
+    /// replays, until [`checkpoint`](Self::checkpoint) is called once the
+    /// blocks are written in place. A failed write to the log aborts the
+    /// journal.
+    #[cfg(not(feature = "read-only"))]
+    pub fn commit_transaction<D>(&mut self, fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
     where
         D: BlockDriverOps,
     {
-        {
-            let transaction = self
-                .current_transaction
-                .as_mut()
-                .ok_or(Ext4Error::InvalidInput)?;
-
-            if transaction.state != TransactionState::Running {
-                return Err(Ext4Error::InvalidInput);
-            }
-
-            transaction.state = TransactionState::Committing;
+        if self.is_aborted() {
+            return Err(Ext4Error::JournalAborted);
+        }
 
-            // Write transaction to journal
-            // Note: In a real implementation, this would write to journal
-            // For now, we just skip the journaling
+        let mut transaction = self
+            .current_transaction
+            .take()
+            .ok_or(Ext4Error::InvalidInput)?;
+        if transaction.state != TransactionState::Running {
+            self.current_transaction = Some(transaction);
+            return Err(Ext4Error::InvalidInput);
+        }
 
-            transaction.state = TransactionState::Committed;
-            self.current_transaction = None;
+        transaction.state = TransactionState::Committing;
+        if let Err(e) = self.write_transaction_to_journal(fs, &transaction) {
+            warn!("Failed to write transaction {} to journal: {:?}", transaction.id, e);
+            self.abort(-EIO);
+            return Err(Ext4Error::JournalAborted);
         }
+        transaction.state = TransactionState::Committed;
+        self.sequence = self.sequence.wrapping_add(1);
 
         Ok(())
     }
 
+    /// Empty the log once the blocks of the committed transaction are
+    /// written in place
+    ///
+    /// The blocks are flushed first, then the journal superblock is marked
+    /// empty with the ID of the next transaction, and the `needs_recovery`
+    /// feature cleared.
+    #[cfg(not(feature = "read-only"))]
+    pub fn checkpoint<D>(&self, fs: &crate::Ext4FileSystem<D>) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        if self.is_aborted() {
+            return Err(Ext4Error::JournalAborted);
+        }
+        fs.flush()?;
+        self.write_log_state(fs, 0, self.sequence)?;
+        fs.set_needs_recovery(false)?;
+        fs.flush()
+    }
+
     /// Abort the current transaction
+    #[cfg(not(feature = "read-only"))]
     pub fn abort_transaction(&mut self) -> Ext4Result<()> {
+        if self.is_aborted() {
+            return Err(Ext4Error::JournalAborted);
+        }
         let transaction = self
             .current_transaction
             .as_mut()
@@ -162,23 +432,112 @@ impl Journal {
     }
 
     /// Check if journaling is enabled
+    #[cfg(not(feature = "read-only"))]
     pub fn is_enabled(&self) -> bool {
         self.journal_inum != 0
     }
 
-    /// Generate a transaction ID
-    fn generate_transaction_id(&self) -> u32 {
-        // Simple implementation - in a real filesystem this would be more sophisticated
-        // For now, just return a simple counter
-        static mut COUNTER: u32 = 1;
-        unsafe {
-            let id = COUNTER;
-            COUNTER += 1;
-            id
+    /// Check if transactions can be written to the log
+    ///
+    /// Logs with features whose records aren't written here, such as the
+    /// old crc32 commit checksums, can't be.
+    #[cfg(not(feature = "read-only"))]
+    pub fn is_loggable(&self) -> bool {
+        self.feature_compat & JBD2_FEATURE_COMPAT_CHECKSUM == 0
+            && self.feature_incompat & !JBD2_INCOMPAT_LOGGABLE == 0
+    }
+
+    /// Check if the journal uses version 2 or 3 checksums
+    #[cfg(not(feature = "read-only"))]
+    fn has_csum(&self) -> bool {
+        self.feature_incompat & (JBD2_FEATURE_INCOMPAT_CSUM_V2 | JBD2_FEATURE_INCOMPAT_CSUM_V3) != 0
+    }
+
+    /// Size of a block tag in a descriptor block
+    #[cfg(not(feature = "read-only"))]
+    fn tag_size(&self) -> usize {
+        if self.feature_incompat & JBD2_FEATURE_INCOMPAT_CSUM_V3 != 0 {
+            return 16;
         }
+        let mut size = 8;
+        if self.feature_incompat & JBD2_FEATURE_INCOMPAT_CSUM_V2 != 0 {
+            size += 2;
+        }
+        if self.feature_incompat & JBD2_FEATURE_INCOMPAT_64BIT != 0 {
+            size += 4;
+        }
+        size
+    }
+
+    /// Block of the filesystem holding block `index` of the log
+    #[cfg(not(feature = "read-only"))]
+    fn log_block<D>(&self, fs: &crate::Ext4FileSystem<D>, index: u32) -> Ext4Result<u64>
+    where
+        D: BlockDriverOps,
+    {
+        let block_size = fs.superblock().block_size();
+        let inode = fs.get_inode(self.journal_inum)?;
+        match inode.get_block_number(index as u64 * block_size as u64, block_size, fs)? {
+            0 => Err(Ext4Error::BlockNotFound),
+            block => Ok(block),
+        }
+    }
+
+    /// Store the start of the log and the ID of its first transaction in
+    /// the journal superblock
+    #[cfg(not(feature = "read-only"))]
+    fn write_log_state<D>(
+        &self,
+        fs: &crate::Ext4FileSystem<D>,
+        start: u32,
+        sequence: u32,
+    ) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        self.edit_superblock(fs, |buf| {
+            write_be32(buf, JBD2_START_OFFSET, start);
+            write_be32(buf, JBD2_SEQUENCE_OFFSET, sequence);
+        })
     }
 
-    /// Write transaction to journal
+    /// Apply `edit` to the on-disk journal superblock, updating its
+    /// checksum if the journal has one
+    #[cfg(not(feature = "read-only"))]
+    fn edit_superblock<D>(
+        &self,
+        fs: &crate::Ext4FileSystem<D>,
+        edit: impl FnOnce(&mut [u8]),
+    ) -> Ext4Result<()>
+    where
+        D: BlockDriverOps,
+    {
+        let block = self.log_block(fs, 0)?;
+        let mut buf = vec![0u8; fs.superblock().block_size() as usize];
+        fs.read_block(block, &mut buf)?;
+        if read_be32(&buf, 0) != JBD2_MAGIC {
+            // Never scribble over a block that is not a journal superblock
+            return Err(Ext4Error::InvalidState);
+        }
+
+        edit(&mut buf);
+        if self.has_csum() {
+            write_be32(&mut buf, JBD2_CHECKSUM_OFFSET, 0);
+            let csum = crc32c(!0, &buf[..JBD2_SUPERBLOCK_SIZE]);
+            write_be32(&mut buf, JBD2_CHECKSUM_OFFSET, csum);
+        }
+        fs.write_block_raw(block, &buf)
+    }
+
+    /// Write the blocks of `transaction` to the log, from its first block,
+    /// then its commit block
+    ///
+    /// Every block is written to the log with the jbd2 layout, so that
+    /// Linux and e2fsck can replay it: descriptor blocks tagging the home
+    /// location of the blocks following them, escaped if they start with
+    /// the jbd2 magic, and checksums if the journal has them. The commit
+    /// block is written once the rest is durable, and flushed in turn.
+    #[cfg(not(feature = "read-only"))]
     fn write_transaction_to_journal<D>(
         &self,
         fs: &mut crate::Ext4FileSystem<D>,
@@ -187,23 +546,110 @@ impl Journal {
     where
         D: BlockDriverOps,
     {
-        // This is a simplified implementation
-        // In a real implementation, we would:
-        // 1. Find the journal inode
-        // 2. Write the transaction blocks to the journal
-        // 3. Write a commit record
-        // 4. Update the journal superblock
+        if !self.is_loggable() {
+            return Err(Ext4Error::NotSupported);
+        }
+        let block_size = fs.superblock().block_size() as usize;
+        let seed = crc32c(!0, &self.uuid);
+        let tail = if self.has_csum() { 4 } else { 0 };
+        let tag_size = self.tag_size();
+        // The first tag of each descriptor is followed by the UUID
+        let tags_per_block = (block_size - JBD2_HEADER_SIZE - tail - 16) / tag_size;
+        let descriptors = transaction.blocks.len().div_ceil(tags_per_block);
+        let len = descriptors + transaction.blocks.len() + 1;
+        if len as u64 > self.journal_size.saturating_sub(self.first) as u64 {
+            return Err(Ext4Error::NoSpaceLeft);
+        }
 
-        for block in &transaction.blocks {
-            // Write block to journal
-            // This is a placeholder - actual implementation would write to journal blocks
-            debug!("Writing block {} to journal", block.block_num);
+        // Point the log at the transaction first: without a commit block,
+        // a replay ignores it
+        self.write_log_state(fs, self.first, transaction.id)?;
+        fs.set_needs_recovery(true)?;
+
+        let header = |block_type: u32| {
+            let mut buf = vec![0u8; block_size];
+            write_be32(&mut buf, 0, JBD2_MAGIC);
+            write_be32(&mut buf, 4, block_type);
+            write_be32(&mut buf, 8, transaction.id);
+            buf
+        };
+        let mut index = self.first;
+        for chunk in transaction.blocks.chunks(tags_per_block) {
+            let mut descriptor = header(JBD2_DESCRIPTOR_BLOCK);
+            let descriptor_index = index;
+            index += 1;
+            let mut at = JBD2_HEADER_SIZE;
+            for (i, block) in chunk.iter().enumerate() {
+                let mut data = block.data.clone();
+                let mut flags = 0;
+                if read_be32(&data, 0) == JBD2_MAGIC {
+                    data[..4].fill(0);
+                    flags |= JBD2_FLAG_ESCAPE;
+                }
+                if i > 0 {
+                    flags |= JBD2_FLAG_SAME_UUID;
+                }
+                if i == chunk.len() - 1 {
+                    flags |= JBD2_FLAG_LAST_TAG;
+                }
+                self.write_tag(&mut descriptor[at..at + tag_size], block.block_num, flags, || {
+                    crc32c(crc32c(seed, &transaction.id.to_be_bytes()), &data)
+                });
+                at += tag_size;
+                if i == 0 {
+                    descriptor[at..at + 16].copy_from_slice(&self.uuid);
+                    at += 16;
+                }
+                fs.write_block_raw(self.log_block(fs, index)?, &data)?;
+                index += 1;
+            }
+            if self.has_csum() {
+                let csum = crc32c(seed, &descriptor);
+                write_be32(&mut descriptor, block_size - 4, csum);
+            }
+            fs.write_block_raw(self.log_block(fs, descriptor_index)?, &descriptor)?;
         }
+        fs.flush()?;
 
+        let mut commit = header(JBD2_COMMIT_BLOCK);
+        if self.has_csum() {
+            // h_chksum[0], after the zero checksum type and size
+            let csum = crc32c(seed, &commit);
+            write_be32(&mut commit, 16, csum);
+        }
+        fs.write_block_raw(self.log_block(fs, index)?, &commit)?;
+        fs.flush()?;
+        debug!(
+            "Wrote transaction {} to journal blocks {} to {}",
+            transaction.id, self.first, index
+        );
         Ok(())
     }
 
+    /// Fill descriptor tag `tag` for the block logged for `block_num`
+    ///
+    /// `csum` computes the checksum of the block as logged, only called if
+    /// the journal has checksums.
+    #[cfg(not(feature = "read-only"))]
+    fn write_tag(&self, tag: &mut [u8], block_num: u64, flags: u32, csum: impl FnOnce() -> u32) {
+        write_be32(tag, 0, block_num as u32);
+        if self.feature_incompat & JBD2_FEATURE_INCOMPAT_CSUM_V3 != 0 {
+            write_be32(tag, 4, flags);
+            write_be32(tag, 8, (block_num >> 32) as u32);
+            write_be32(tag, 12, csum());
+            return;
+        }
+        if self.feature_incompat & JBD2_FEATURE_INCOMPAT_CSUM_V2 != 0 {
+            tag[4..6].copy_from_slice(&(csum() as u16).to_be_bytes());
+        }
+        tag[6..8].copy_from_slice(&(flags as u16).to_be_bytes());
+        if self.feature_incompat & JBD2_FEATURE_INCOMPAT_64BIT != 0 {
+            write_be32(tag, 8, (block_num >> 32) as u32);
+        }
+    }
+
     /// Replay the journal (for recovery)
+    #[cfg(not(feature = "read-only"))]
     pub fn replay<D>(&self, _fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<()>
     where
         D: BlockDriverOps,
@@ -11,29 +11,110 @@ extern crate alloc;
 use core::fmt;
 use log::*;
 
+#[cfg(not(feature = "read-only"))]
+mod accounting;
+#[cfg(not(feature = "read-only"))]
+mod balloc;
 mod bitmap;
 mod block_group;
+mod cache;
+mod crc32c;
+mod device;
 mod directory;
+mod dirhash;
 mod extent;
 mod file;
+mod glob;
+mod handle;
+mod htree;
+mod idmap;
+mod inline;
 mod inode;
 mod journal;
+mod lock;
+mod metadata;
+mod notify;
+mod orphan;
+mod partition;
+mod path;
+#[cfg(not(feature = "read-only"))]
+mod rename;
+mod resize;
 mod superblock;
 mod symlink;
+mod trace;
+mod uuid;
+mod verify;
+mod walk;
+mod writeback;
+mod xattr;
 
 pub use bitmap::Bitmap;
 pub use block_group::BlockGroupDescriptor;
-pub use directory::{Directory, DirectoryEntry, DirectoryIterator};
-pub use extent::{parse_extent_node, find_block_in_extent_tree};
-pub use file::File;
-pub use inode::{Inode, InodeMode, InodeType};
-pub use superblock::SuperBlock;
-
+pub use cache::CacheUsage;
+pub use crc32c::crc32c;
+pub use device::{
+    DeviceErrorKind, OffsetDevice, OverlayDevice, RetryDevice, RetryPolicy, RetryStats,
+    SliceBlockDevice, VecBlockDevice,
+};
+#[cfg(feature = "test-utils")]
+pub use device::{BlockDiff, CopyOnWriteDevice};
+pub use directory::{
+    find_entry_in_block, validate_name, DirEntryPlus, DirStats, Directory, DirectoryEntry,
+    DirectoryIterator, FileName, EXT4_NAME_LEN,
+};
+pub use dirhash::{continues_into, dx_hash, split_hash, DxHash, HashVersion};
+pub use extent::{parse_extent_node, find_block_in_extent_tree, EXT4_MAX_EXTENT_DEPTH};
+pub use file::{BlockRun, File, FileBlocks, OpenFlags, SparseSegment, SparseSegments};
+pub use glob::glob_match;
+pub use handle::{FileHandle, FILE_HANDLE_SIZE};
+pub use idmap::{IdMap, IdRange, OVERFLOW_ID};
+pub use inode::{
+    Inode, InodeBuilder, InodeFlags, InodeIter, InodeMode, InodeTimes, InodeType, Timestamp,
+};
+pub use lock::{FileLock, LockKind};
+pub use metadata::{Metadata, StatxAttributes, StatxMask};
+pub use notify::{Change, WatchId, WatchMask, Watcher};
+pub use partition::{read_partitions, Partition, PartitionKind};
+pub use path::ResolveFlags;
+#[cfg(not(feature = "read-only"))]
+pub use rename::RenameFlags;
+pub use resize::ReservedGdtBlock;
+pub use superblock::{
+    ErrorLog, ErrorRecord, FeatureCompat, FeatureIncompat, FeatureRoCompat, SuperBlock,
+    SuperBlockError, SuperBlockFlags,
+};
+pub use trace::{BlockCounts, TraceOp};
+#[cfg(feature = "tracing")]
+pub use trace::Tracer;
+pub use uuid::Uuid;
+pub use walk::{DiskUsage, SymlinkPolicy, Walk, WalkOptions};
+pub use xattr::Xattr;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+#[cfg(not(feature = "read-only"))]
+use balloc::{AllocHints, AllocLog, Allocation};
+#[cfg(not(feature = "read-only"))]
+use cache::BitmapCache;
+use cache::{BufferPool, Caches};
+#[cfg(not(feature = "read-only"))]
+use journal::BlockType;
+use journal::Journal;
+use lock::LockTable;
+use notify::Watches;
+use trace::Span;
+use writeback::Unflushed;
+#[cfg(not(feature = "read-only"))]
+use directory::{set_dirent_tail, DIRENT_TAIL_SIZE};
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+#[cfg(not(feature = "read-only"))]
 use axdriver::prelude::*;
 use axdriver_block::BlockDriverOps;
-use axerrno::{AxError, LinuxError};
+use axerrno::AxError;
+#[cfg(not(feature = "read-only"))]
+use axerrno::LinuxError;
 
 /// Ext4 filesystem error type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -68,6 +149,39 @@ pub enum Ext4Error {
     InvalidArg,
     /// Operation not supported
     NotSupported,
+    /// The journal was aborted and the filesystem is read-only
+    JournalAborted,
+    /// The superblock describes an impossible filesystem
+    CorruptSuperblock(SuperBlockError),
+    /// The descriptor of this block group points outside its bounds
+    CorruptGroupDescriptor(u32),
+    /// Path too deeply nested, or a directory cycle
+    Loop,
+    /// File handle refers to a deleted or reused inode
+    StaleHandle,
+    /// Operation forbidden by the inode's immutable or append-only flag
+    PermissionDenied,
+    /// Directory has too many subdirectories
+    TooManyLinks,
+    /// Path lookup would leave the directory it is confined to
+    CrossDevice,
+    /// The inode has no extended attribute of that name
+    NoAttribute,
+    /// Metadata does not match its checksum
+    BadChecksum,
+    /// The filesystem has features that can't be written safely, given as
+    /// their incompatible and read-only compatible feature bits
+    UnsupportedForWrite(FeatureIncompat, FeatureRoCompat),
+    /// File too large for the operation
+    FileTooLarge,
+    /// The block device failed a request
+    Device(DeviceErrorKind),
+    /// A memory allocation failed
+    NoMemory,
+    /// A conflicting lock is held by another owner
+    WouldBlock,
+    /// Waiting for the lock would deadlock
+    Deadlock,
 }
 
 impl fmt::Display for Ext4Error {
@@ -88,6 +202,28 @@ impl fmt::Display for Ext4Error {
             Ext4Error::ReadOnly => write!(f, "Read-only filesystem"),
             Ext4Error::InvalidArg => write!(f, "Invalid argument"),
             Ext4Error::NotSupported => write!(f, "Operation not supported"),
+            Ext4Error::JournalAborted => write!(f, "Journal aborted"),
+            Ext4Error::CorruptSuperblock(e) => write!(f, "Corrupt superblock: {}", e),
+            Ext4Error::Loop => write!(f, "Too many levels of directories"),
+            Ext4Error::StaleHandle => write!(f, "Stale file handle"),
+            Ext4Error::PermissionDenied => write!(f, "Operation not permitted"),
+            Ext4Error::TooManyLinks => write!(f, "Too many links"),
+            Ext4Error::CrossDevice => write!(f, "Path escapes the starting directory"),
+            Ext4Error::NoAttribute => write!(f, "No such attribute"),
+            Ext4Error::BadChecksum => write!(f, "Metadata checksum mismatch"),
+            Ext4Error::FileTooLarge => write!(f, "File too large"),
+            Ext4Error::Device(kind) => write!(f, "Block device error: {:?}", kind),
+            Ext4Error::NoMemory => write!(f, "Out of memory"),
+            Ext4Error::WouldBlock => write!(f, "Lock held by another owner"),
+            Ext4Error::Deadlock => write!(f, "Lock would deadlock"),
+            Ext4Error::CorruptGroupDescriptor(group) => {
+                write!(f, "Corrupt descriptor of block group {}", group)
+            }
+            Ext4Error::UnsupportedForWrite(incompat, ro_compat) => write!(
+                f,
+                "Writing not supported with features {:?} and {:?}",
+                incompat, ro_compat
+            ),
         }
     }
 }
@@ -97,6 +233,8 @@ impl From<Ext4Error> for AxError {
         let code = match err {
             Ext4Error::InvalidMagic
             | Ext4Error::InvalidState
+            | Ext4Error::CorruptSuperblock(_)
+            | Ext4Error::CorruptGroupDescriptor(_)
             | Ext4Error::InvalidPath
             | Ext4Error::InvalidInput
             | Ext4Error::InvalidArg => -(axerrno::LinuxError::EINVAL as i32),
@@ -109,8 +247,23 @@ impl From<Ext4Error> for AxError {
             Ext4Error::IsADirectory => -(axerrno::LinuxError::EISDIR as i32),
             Ext4Error::IoError => -(axerrno::LinuxError::EIO as i32),
             Ext4Error::NoSpaceLeft => -(axerrno::LinuxError::ENOSPC as i32),
-            Ext4Error::ReadOnly => -(axerrno::LinuxError::EROFS as i32),
+            Ext4Error::ReadOnly
+            | Ext4Error::JournalAborted
+            | Ext4Error::UnsupportedForWrite(..) => -(axerrno::LinuxError::EROFS as i32),
             Ext4Error::NotSupported => -(axerrno::LinuxError::ENOSYS as i32),
+            Ext4Error::Loop => -(axerrno::LinuxError::ELOOP as i32),
+            Ext4Error::StaleHandle => -(axerrno::LinuxError::ESTALE as i32),
+            Ext4Error::PermissionDenied => -(axerrno::LinuxError::EPERM as i32),
+            Ext4Error::TooManyLinks => -(axerrno::LinuxError::EMLINK as i32),
+            Ext4Error::CrossDevice => -(axerrno::LinuxError::EXDEV as i32),
+            Ext4Error::NoAttribute => -(axerrno::LinuxError::ENODATA as i32),
+            Ext4Error::BadChecksum => -(axerrno::LinuxError::EBADMSG as i32),
+            Ext4Error::FileTooLarge => -(axerrno::LinuxError::EFBIG as i32),
+            Ext4Error::Device(DeviceErrorKind::Timeout) => -(axerrno::LinuxError::ETIMEDOUT as i32),
+            Ext4Error::Device(_) => -(axerrno::LinuxError::EIO as i32),
+            Ext4Error::NoMemory => -(axerrno::LinuxError::ENOMEM as i32),
+            Ext4Error::WouldBlock => -(axerrno::LinuxError::EAGAIN as i32),
+            Ext4Error::Deadlock => -(axerrno::LinuxError::EDEADLK as i32),
         };
         unsafe { core::mem::transmute::<i32, AxError>(code) }
     }
@@ -125,91 +278,434 @@ pub struct Ext4FileSystem<D: BlockDriverOps> {
     superblock: SuperBlock,
     block_groups: Vec<BlockGroupDescriptor>,
     mount_options: MountOptions,
+    #[cfg(not(feature = "read-only"))]
+    alloc_hints: AllocHints,
+    #[cfg(not(feature = "read-only"))]
+    alloc_log: AllocLog,
+    journal: Option<Journal>,
+    caches: core::cell::RefCell<Caches>,
+    #[cfg(not(feature = "read-only"))]
+    bitmaps: core::cell::RefCell<BitmapCache>,
+    buffers: core::cell::RefCell<BufferPool>,
+    error_log: core::cell::RefCell<ErrorLog>,
+    unflushed: core::cell::RefCell<Unflushed>,
+    /// Files opened with `open`, by inode: every open file holds a clone
+    /// of the token of its inode
+    open_files: core::cell::RefCell<BTreeMap<u32, Arc<()>>>,
+    /// Open inodes whose last link is gone, released when their last file
+    /// is closed
+    unlinked_open: BTreeSet<u32>,
+    /// Advisory locks held on files
+    locks: core::cell::RefCell<LockTable>,
+    /// Watches for changes to inodes
+    watches: core::cell::RefCell<Watches>,
+    #[cfg(feature = "tracing")]
+    block_counts: core::cell::Cell<BlockCounts>,
+    /// Generation for the next allocated inode
+    next_generation: u32,
 }
 
 /// Mount options for ext4 filesystem
 #[derive(Debug, Clone)]
 pub struct MountOptions {
-    /// Read-only mount
+    /// Read-only mount; always on with the `read-only` feature
     pub read_only: bool,
     /// Enable journaling
     pub journaling: bool,
     /// Enable execute permission check
     pub exec_check: bool,
+    /// Clock used to stamp created and modified inodes
+    ///
+    /// Without one, every timestamp written is the epoch, 1970-01-01, and
+    /// access times are never updated. Writable mounts should always set
+    /// it; mounting one without it logs a warning.
+    pub time_source: Option<fn() -> Timestamp>,
+    /// Ordering of file data against the metadata referencing it
+    pub data_mode: DataMode,
+    /// Memory in bytes shared by the block, inode and directory entry
+    /// caches; 0 disables caching
+    pub cache_budget: usize,
+    /// Most block and inode bitmaps the allocator keeps in memory, apart
+    /// from `cache_budget`; changed bitmaps and the descriptors of their
+    /// groups are written on the next flush. 0 reads and writes them on
+    /// every allocation
+    pub bitmap_cache_blocks: usize,
+    /// Most inode table blocks prefetched into the block cache when a
+    /// directory is read, as the `inode_readahead_blks` mount option; 0
+    /// disables readahead
+    pub inode_readahead_blks: u32,
+    /// Most data blocks a single write leaves unflushed: larger writes are
+    /// committed in steps of this many blocks, each flushed and, when the
+    /// new size may be published early, followed by an inode update; 0
+    /// disables the limit
+    pub max_dirty_blocks: u32,
+    /// Most blocks in one journal transaction; 0 keeps the default of a
+    /// quarter of the journal
+    pub max_transaction_blocks: u32,
+    /// When reads update the access time of files; never without a
+    /// `time_source`, nor for inodes flagged `NOATIME`
+    pub atime: AtimeMode,
+    /// Translation of inode owners between the image and its users; `None`
+    /// shows the on-disk IDs
+    pub id_map: Option<IdMap>,
+    /// Recount the bitmaps of every group an allocation or free touches and
+    /// check the descriptor agrees, failing the operation with
+    /// `CorruptGroupDescriptor` if not; on by default in debug builds
+    pub verify_accounting: bool,
+    /// Mount read-only when the filesystem has features that can't be
+    /// written safely, rather than failing each write with
+    /// `UnsupportedForWrite`
+    pub read_only_if_unsupported: bool,
+    /// Most bytes [`File::read_to_end`] reads into memory, so that a corrupt
+    /// size can't exhaust it; 0 disables the limit
+    pub max_read_to_end: u64,
+    /// Read inodes, extent tree nodes and directory blocks from the device
+    /// on every access and check their checksums and invariants again,
+    /// rather than trusting the copy checked when they were first read.
+    /// Bitmaps are checked when read into the bitmap cache, and group
+    /// descriptors at mount.
+    pub verify_reads: bool,
+    /// Receiver of operation spans
+    #[cfg(feature = "tracing")]
+    pub tracer: Option<&'static dyn Tracer>,
+}
+
+/// Access time maintenance (the `strictatime`, `relatime` and `noatime`
+/// mount options)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AtimeMode {
+    /// Every read updates the access time
+    Strict,
+    /// Reads update the access time only if it is not after the
+    /// modification or change time, or is more than a day old
+    #[default]
+    Relatime,
+    /// Reads never update the access time
+    NoAtime,
+}
+
+/// Journaling mode for file data (the `data=` mount option)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DataMode {
+    /// File data is flushed to the device before the inode that references
+    /// it is written
+    #[default]
+    Ordered,
+    /// No ordering between file data and metadata; a crash may expose stale
+    /// block contents
+    Writeback,
+    /// File data is written through the journal; not supported
+    Journal,
 }
 
 impl Default for MountOptions {
     fn default() -> Self {
         Self {
-            read_only: false,
+            read_only: cfg!(feature = "read-only"),
             journaling: true,
             exec_check: false,
+            time_source: None,
+            data_mode: DataMode::Ordered,
+            cache_budget: 0,
+            bitmap_cache_blocks: 32,
+            inode_readahead_blks: 32,
+            max_dirty_blocks: 1024,
+            max_transaction_blocks: 0,
+            atime: AtimeMode::Relatime,
+            id_map: None,
+            verify_accounting: cfg!(debug_assertions),
+            read_only_if_unsupported: false,
+            max_read_to_end: 64 * 1024 * 1024,
+            verify_reads: false,
+            #[cfg(feature = "tracing")]
+            tracer: None,
         }
     }
 }
 
 impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
     /// Create a new ext4 filesystem instance
-    pub fn new(mut device: D, options: MountOptions) -> Ext4Result<Self> {
+    pub fn new(device: D, options: MountOptions) -> Ext4Result<Self> {
+        let span = Span::start(&options, TraceOp::Mount, 0, BlockCounts::default());
+        match Self::mount(device, options) {
+            Ok(fs) => {
+                fs.span_exit(span, &Ok(()));
+                Ok(fs)
+            }
+            Err(e) => {
+                span.finish(BlockCounts::default(), false);
+                Err(e)
+            }
+        }
+    }
+
+    /// Mount the filesystem on `device`, as [`Self::new`]
+    fn mount(mut device: D, options: MountOptions) -> Ext4Result<Self> {
         info!("Initializing ext4 filesystem");
+        #[cfg(feature = "read-only")]
+        let options = MountOptions {
+            read_only: true,
+            ..options
+        };
+
+        if options.data_mode == DataMode::Journal {
+            warn!("data=journal is not supported");
+            return Err(Ext4Error::NotSupported);
+        }
 
         // Read and validate superblock
         let superblock = SuperBlock::read_from_device(&mut device)?;
         superblock.validate()?;
+        if superblock.has_verity() {
+            info!("Reading fs-verity files without checking their Merkle trees");
+        }
 
         // Read block group descriptors
-        let block_groups = Self::read_block_groups(&mut device, &superblock)?;
-
-        Ok(Self {
+        let verify = options.verify_reads;
+        let block_groups = match Self::read_block_groups(&mut device, &superblock, verify) {
+            Err(e @ Ext4Error::CorruptGroupDescriptor(group)) => {
+                let mut log = superblock.error_log().clone();
+                let now = options.time_source.map(|clock| clock().sec).unwrap_or_default();
+                let block = superblock.group_desc_block(group / superblock.descs_per_block());
+                log.record(ErrorRecord::new(now, "read_block_groups", line!(), 0, block, &e));
+                #[cfg(not(feature = "read-only"))]
+                if !options.read_only {
+                    let _ = superblock.write_error_log(&mut device, &log);
+                }
+                return Err(e);
+            }
+            result => result?,
+        };
+        let error_log = superblock.error_log().clone();
+        #[cfg(not(feature = "read-only"))]
+        let alloc_hints = AllocHints::new(block_groups.len());
+        let caches = Caches::new(options.cache_budget);
+        #[cfg(not(feature = "read-only"))]
+        let bitmaps = BitmapCache::new(options.bitmap_cache_blocks);
+        let buffers = BufferPool::new(superblock.block_size() as usize);
+
+        let mut fs = Self {
             device: core::cell::RefCell::new(device),
             superblock,
             block_groups,
             mount_options: options,
-        })
+            #[cfg(not(feature = "read-only"))]
+            alloc_hints,
+            #[cfg(not(feature = "read-only"))]
+            alloc_log: AllocLog::default(),
+            journal: None,
+            caches: core::cell::RefCell::new(caches),
+            #[cfg(not(feature = "read-only"))]
+            bitmaps: core::cell::RefCell::new(bitmaps),
+            buffers: core::cell::RefCell::new(buffers),
+            error_log: core::cell::RefCell::new(error_log),
+            unflushed: core::cell::RefCell::new(Unflushed::default()),
+            open_files: core::cell::RefCell::new(BTreeMap::new()),
+            unlinked_open: BTreeSet::new(),
+            locks: core::cell::RefCell::new(LockTable::default()),
+            watches: core::cell::RefCell::new(Watches::default()),
+            #[cfg(feature = "tracing")]
+            block_counts: core::cell::Cell::new(BlockCounts::default()),
+            next_generation: 0,
+        };
+        // The superblock and descriptor table were read before blocks were
+        // counted
+        fs.count_blocks(1 + fs.superblock.desc_blocks() as u64, 0);
+        // Older kernels seed s_next_generation randomly; the clock will do
+        let now = fs.now();
+        fs.next_generation = now.sec as u32 ^ now.nsec;
+        fs.check_write_features();
+        if fs.mount_options.time_source.is_none() && fs.check_writable().is_ok() {
+            warn!("Mounted writable without a time source, timestamps will be the epoch");
+        }
+        fs.load_journal();
+        fs.check_resize_inode();
+        fs.process_orphans();
+        Ok(fs)
+    }
+
+    /// Load the journal if the filesystem has one and journaling is enabled
+    ///
+    /// A journal that cannot be read is treated as corrupt: the filesystem
+    /// still mounts, but in the read-only error state of an aborted journal.
+    fn load_journal(&mut self) {
+        let journal_inum = self.superblock.journal_inum();
+        if cfg!(feature = "read-only")
+            || !self.mount_options.journaling
+            || !self.superblock.has_journal()
+            || journal_inum == 0
+        {
+            return;
+        }
+
+        let mut journal = Journal::load(self, journal_inum).unwrap_or_else(|e| {
+            warn!("Failed to load journal inode {}: {:?}", journal_inum, e);
+            let mut journal = Journal::new(journal_inum, 0, self.superblock.block_size());
+            journal.abort(-journal::EIO);
+            journal
+        });
+        journal.limit_transaction_size(self.mount_options.max_transaction_blocks);
+        if journal.is_aborted() {
+            #[cfg(not(feature = "read-only"))]
+            self.mark_errors();
+            self.record_error("load_journal", line!(), journal_inum, 0, &Ext4Error::JournalAborted);
+            warn!("Journal aborted, filesystem is read-only");
+        }
+        self.journal = Some(journal);
+    }
+
+    /// Abort the journal after an unrecoverable error
+    ///
+    /// Like jbd2, the error is recorded in the journal superblock and the
+    /// filesystem turns read-only: every later modification fails with
+    /// `JournalAborted`. Fails with `NotSupported` without a journal.
+    #[cfg(not(feature = "read-only"))]
+    pub fn abort_journal(&mut self, errno: i32) -> Ext4Result<()> {
+        let journal = self.journal.as_mut().ok_or(Ext4Error::NotSupported)?;
+        journal.abort(errno);
+        self.mark_errors();
+
+        let journal = self.journal.as_ref().unwrap();
+        if let Err(e) = journal.record_error(self) {
+            warn!("Failed to record journal error: {:?}", e);
+        }
+        Ok(())
     }
 
-    /// Read block group descriptors
+    /// Set or clear the `needs_recovery` feature in the superblock on the
+    /// device, around a transaction in the journal
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_needs_recovery(&self, recover: bool) -> Ext4Result<()> {
+        self.superblock
+            .write_needs_recovery(&mut *self.device.borrow_mut(), recover)?;
+        let sb_block = self.superblock.superblock_block();
+        self.caches.borrow_mut().blocks.remove(&sb_block);
+        Ok(())
+    }
+
+    /// Mark the filesystem as having errors, in the superblock on the device
+    /// too unless the mount is read-only, so that the next mount or fsck
+    /// sees it
+    #[cfg(not(feature = "read-only"))]
+    fn mark_errors(&mut self) {
+        self.superblock.mark_errors();
+        if self.mount_options.read_only {
+            return;
+        }
+        if let Err(e) = self.superblock.write_state(&mut *self.device.borrow_mut()) {
+            warn!("Failed to mark the superblock as having errors: {:?}", e);
+        }
+        let sb_block = self.superblock.superblock_block();
+        self.caches.borrow_mut().blocks.remove(&sb_block);
+    }
+
+    /// Errors recorded in the superblock, including those detected since mount
+    pub fn error_log(&self) -> ErrorLog {
+        self.error_log.borrow().clone()
+    }
+
+    /// Record corruption detected at `line` of `func` in the superblock
+    ///
+    /// The error count and the first and last error fields are written right
+    /// away, so they survive even if the filesystem is never unmounted
+    /// cleanly. Read-only mounts only keep the record in memory.
+    pub(crate) fn record_error(
+        &self,
+        func: &str,
+        line: u32,
+        ino: u32,
+        block: u64,
+        error: &Ext4Error,
+    ) {
+        warn!("{}:{}: inode {}, block {}: {:?}", func, line, ino, block, error);
+        let mut log = self.error_log.borrow_mut();
+        log.record(ErrorRecord::new(self.now().sec, func, line, ino, block, error));
+
+        // Mounts are always read-only with the read-only feature
+        #[cfg(not(feature = "read-only"))]
+        if !self.mount_options.read_only {
+            let result = self.superblock.write_error_log(&mut *self.device.borrow_mut(), &log);
+            if let Err(e) = result {
+                warn!("Failed to record error in the superblock: {:?}", e);
+            }
+            let sb_block = self.superblock.superblock_block();
+            self.caches.borrow_mut().blocks.remove(&sb_block);
+        }
+    }
+
+    /// Check if the journal has been aborted
+    pub fn is_journal_aborted(&self) -> bool {
+        self.journal.as_ref().is_some_and(Journal::is_aborted)
+    }
+
+    /// Fail unless the filesystem may be modified
+    ///
+    /// With the `read-only` feature this always fails, and the APIs that
+    /// write are compiled out.
+    fn check_writable(&self) -> Ext4Result<()> {
+        if cfg!(feature = "read-only") || self.mount_options.read_only {
+            return Err(Ext4Error::ReadOnly);
+        }
+        let (incompat, ro_compat) = self.superblock.write_blockers();
+        if !incompat.is_empty() || !ro_compat.is_empty() {
+            return Err(Ext4Error::UnsupportedForWrite(incompat, ro_compat));
+        }
+        if self.is_journal_aborted() {
+            return Err(Ext4Error::JournalAborted);
+        }
+        Ok(())
+    }
+
+    /// Warn about features that keep the filesystem from being written,
+    /// and mount read-only for them if `read_only_if_unsupported` is set
+    fn check_write_features(&mut self) {
+        let (incompat, ro_compat) = self.superblock.write_blockers();
+        if self.mount_options.read_only || (incompat.is_empty() && ro_compat.is_empty()) {
+            return;
+        }
+        warn!("Writing not supported with features {:?} and {:?}", incompat, ro_compat);
+        if self.mount_options.read_only_if_unsupported {
+            info!("Mounting read-only");
+            self.mount_options.read_only = true;
+        }
+    }
+
+    /// Read block group descriptors, also checking their checksums if
+    /// `verify` is set
     fn read_block_groups(
         device: &mut D,
         superblock: &SuperBlock,
+        verify: bool,
     ) -> Ext4Result<Vec<BlockGroupDescriptor>> {
         let block_size = superblock.block_size();
-        let blocks_count = superblock.blocks_count();
-        let blocks_per_group = superblock.blocks_per_group();
-
-        // Handle small filesystems where blocks_count < blocks_per_group
-        let groups_count = if blocks_count == 0 {
-            0
-        } else {
-            // Ensure at least one group for non-empty filesystems
-            ((blocks_count + blocks_per_group as u64 - 1) / blocks_per_group as u64).max(1)
-        };
-
-        let desc_size = if superblock.rev_level() >= 1 { 64 } else { 32 };
-        let blocks_per_desc = block_size / desc_size;
-        let desc_blocks = (groups_count + blocks_per_desc as u64 - 1) / blocks_per_desc as u64;
+        let groups_count = superblock.groups_count() as u64;
+        let desc_size = superblock.group_desc_size();
+        let blocks_per_desc = superblock.descs_per_block();
+        let desc_blocks = groups_count.div_ceil(blocks_per_desc as u64);
 
-        debug!("Reading block groups: blocks_count={}, blocks_per_group={}, groups_count={}, desc_size={}, blocks_per_desc={}, desc_blocks={}", 
-                blocks_count, blocks_per_group, groups_count, desc_size, blocks_per_desc, desc_blocks);
+        debug!(
+            "Reading block groups: blocks_count={}, blocks_per_group={}, groups_count={}, \
+             desc_size={}, blocks_per_desc={}, desc_blocks={}",
+            superblock.blocks_count(),
+            superblock.blocks_per_group(),
+            groups_count,
+            desc_size,
+            blocks_per_desc,
+            desc_blocks
+        );
 
-        let mut descriptors = Vec::with_capacity(groups_count as usize);
+        let mut descriptors = try_with_capacity(groups_count as usize)?;
         let mut buf = vec![0u8; block_size as usize];
 
         for i in 0..desc_blocks {
-            // In ext4, block group descriptors are typically at block 1 (or block 0 if first_data_block is 0)
-            let block = if superblock.first_data_block() == 0 {
-                1 + i
-            } else {
-                (superblock.first_data_block() as u64) + 1 + i
-            };
+            let block = superblock.group_desc_block(i as u32);
             debug!("Reading block group descriptor block {}", block);
 
             // Clear buffer before reading
             buf.fill(0);
 
-            device
-                .read_block(block, &mut buf)
-                .map_err(|_| Ext4Error::IoError)?;
+            device::read_bytes(device, block * block_size as u64, &mut buf)
+                .map_err(Ext4Error::from)?;
 
             debug!(
                 "Block group descriptor block {} first 64 bytes: {:x?}",
@@ -230,9 +726,21 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
             let base = i * blocks_per_desc as u64;
             for j in 0..blocks_per_desc.min((groups_count - base) as u32) {
                 let offset = j * desc_size;
-                let desc = BlockGroupDescriptor::from_bytes(
-                    &buf[offset as usize..(offset + desc_size) as usize],
-                )?;
+                let raw = &buf[offset as usize..(offset + desc_size) as usize];
+                let desc = BlockGroupDescriptor::from_bytes(raw)?;
+                let group = descriptors.len() as u32;
+                if verify && superblock.has_group_csum() {
+                    let expected = block_group::desc_checksum(superblock, group, raw);
+                    if desc.checksum() != expected {
+                        warn!(
+                            "Group {} descriptor has checksum {:#x}, expected {:#x}",
+                            group,
+                            desc.checksum(),
+                            expected
+                        );
+                        return Err(Ext4Error::CorruptGroupDescriptor(group));
+                    }
+                }
                 debug!(
                     "Block group {}: block_bitmap={}, inode_bitmap={}, inode_table={}",
                     descriptors.len(),
@@ -240,6 +748,7 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
                     desc.inode_bitmap(),
                     desc.inode_table()
                 );
+                desc.validate(descriptors.len() as u32, superblock)?;
                 descriptors.push(desc);
             }
         }
@@ -248,36 +757,104 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
         Ok(descriptors)
     }
 
-    /// Write a block group descriptor to disk
-    fn write_block_group_descriptor(&mut self, group_index: usize) -> Ext4Result<()> {
+    /// Get the descriptor of block group `index`
+    pub fn block_group(&self, index: u32) -> Ext4Result<&BlockGroupDescriptor> {
+        self.block_groups
+            .get(index as usize)
+            .ok_or(Ext4Error::InvalidArg)
+    }
+
+    /// Get the descriptor of block group `index` for changing it
+    ///
+    /// Changes stay in memory until [`Self::write_block_group`].
+    pub fn block_group_mut(&mut self, index: u32) -> Ext4Result<&mut BlockGroupDescriptor> {
+        self.block_groups
+            .get_mut(index as usize)
+            .ok_or(Ext4Error::InvalidArg)
+    }
+
+    /// Write the descriptor of block group `index` back to its slot in the
+    /// group descriptor table
+    ///
+    /// Slots are `s_desc_size` bytes apart on 64-bit filesystems and 32
+    /// otherwise, and with `meta_bg` the table block is the one of the
+    /// group's meta group. Only the primary table is written; use
+    /// [`sync_backups`](Self::sync_backups) to update the backups.
+    ///
+    /// The descriptor checksum is recomputed, and under `metadata_csum` so
+    /// are the checksums of the group's bitmaps.
+    #[cfg(not(feature = "read-only"))]
+    pub fn write_block_group(&mut self, index: u32) -> Ext4Result<()> {
+        self.check_writable()?;
+        self.block_group(index)?;
+        self.write_group_bitmaps(index as usize)?;
+        self.update_bitmap_csums(index as usize)?;
         let block_size = self.superblock.block_size();
-        let desc_size = if self.superblock.rev_level() >= 1 { 64 } else { 32 };
-        let blocks_per_desc = block_size / desc_size;
-        
-        // Calculate which block contains this descriptor
-        let desc_block_index = group_index / blocks_per_desc as usize;
-        let desc_offset_in_block = (group_index % blocks_per_desc as usize) * desc_size as usize;
-        
-        // In ext4, block group descriptors are typically at block 1 (or block 0 if first_data_block is 0)
-        let block = if self.superblock.first_data_block() == 0 {
-            1 + desc_block_index as u64
-        } else {
-            (self.superblock.first_data_block() as u64) + 1 + desc_block_index as u64
-        };
-        
-        // Read entire descriptor block
+        let desc_size = self.superblock.group_desc_size() as usize;
+        let descs_per_block = self.superblock.descs_per_block();
+
+        let block = self.superblock.group_desc_block(index / descs_per_block);
+        let offset = (index % descs_per_block) as usize * desc_size;
         let mut buf = vec![0u8; block_size as usize];
-        self.read_block(block as u32, &mut buf)?;
-        
-        // Convert descriptor to bytes and update buffer
-        let desc_bytes = self.block_groups[group_index].to_bytes();
-        buf[desc_offset_in_block..desc_offset_in_block + desc_size as usize]
-            .copy_from_slice(&desc_bytes[..desc_size as usize]);
-        
-        // Write updated block back to disk
-        self.write_block(block as u32, &buf)?;
-        
-        debug!("Wrote block group descriptor {} at block {} offset {}", group_index, block, desc_offset_in_block);
+        self.read_block(block, &mut buf)?;
+        let slot = &mut buf[offset..offset + desc_size];
+        let bg = &mut self.block_groups[index as usize];
+        bg.write_to(slot);
+        bg.set_checksum(block_group::desc_checksum(&self.superblock, index, slot));
+        bg.write_to(slot);
+        self.write_block(block, &buf)?;
+        self.bitmaps.borrow_mut().group_written(index);
+
+        debug!("Wrote block group descriptor {} at block {} offset {}", index, block, offset);
+        Ok(())
+    }
+
+    /// Current time according to the configured time source
+    fn now(&self) -> Timestamp {
+        self.mount_options
+            .time_source
+            .map(|clock| clock())
+            .unwrap_or_default()
+    }
+
+    /// Update the access time of `inode` after reading it, as the `atime`
+    /// mount option says
+    ///
+    /// `inode` may be an older copy, such as the one held by a [`File`], so
+    /// the inode is read again and only its access time is changed on disk.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn update_atime(&mut self, inode: &mut Inode) -> Ext4Result<()> {
+        if self.mount_options.time_source.is_none() || self.check_writable().is_err() {
+            return Ok(());
+        }
+        let mut current = self.get_inode(inode.ino)?;
+        if current.inode_flags().contains(InodeFlags::NOATIME) {
+            return Ok(());
+        }
+
+        let now = self.now();
+        let times = current.times();
+        let update = match self.mount_options.atime {
+            AtimeMode::Strict => times.atime != now,
+            AtimeMode::Relatime => {
+                times.atime <= times.mtime
+                    || times.atime <= times.ctime
+                    || now.sec - times.atime.sec >= RELATIME_INTERVAL
+            }
+            AtimeMode::NoAtime => false,
+        };
+        if !update {
+            return Ok(());
+        }
+        current.set_atime(now);
+        self.write_inode(&current)?;
+        inode.set_atime(now);
+        Ok(())
+    }
+
+    /// Access times are never updated with the read-only feature
+    #[cfg(feature = "read-only")]
+    pub(crate) fn update_atime(&mut self, _inode: &mut Inode) -> Ext4Result<()> {
         Ok(())
     }
 
@@ -291,8 +868,125 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
         &self.superblock
     }
 
+    /// Set the volume label
+    ///
+    /// The label is stored in the primary superblock and all its backups.
+    /// Fails with `InvalidArg` if it is longer than 16 bytes or contains a
+    /// NUL byte.
+    #[cfg(not(feature = "read-only"))]
+    pub fn set_label(&mut self, label: &str) -> Ext4Result<()> {
+        self.check_writable()?;
+        let mut name = [0u8; 16];
+        if label.len() > name.len() || label.contains('\0') {
+            return Err(Ext4Error::InvalidArg);
+        }
+        name[..label.len()].copy_from_slice(label.as_bytes());
+
+        let mut superblock = self.superblock.clone();
+        superblock.set_volume_name(name);
+        self.write_superblock_copies(superblock)?;
+        debug!("Set volume label to {:?}", label);
+        Ok(())
+    }
+
+    /// Set the filesystem UUID
+    ///
+    /// The UUID is stored in the primary superblock and all its backups.
+    /// Metadata checksums are seeded from the UUID unless the `csum_seed`
+    /// feature keeps the seed apart, and they aren't recomputed here: such
+    /// filesystems fail with `NotSupported`.
+    #[cfg(not(feature = "read-only"))]
+    pub fn set_uuid(&mut self, uuid: Uuid) -> Ext4Result<()> {
+        self.check_writable()?;
+        let seeded = self
+            .superblock
+            .feature_incompat()
+            .contains(FeatureIncompat::CSUM_SEED);
+        if self.superblock.has_group_csum() && !seeded {
+            return Err(Ext4Error::NotSupported);
+        }
+
+        let mut superblock = self.superblock.clone();
+        superblock.set_uuid(uuid);
+        self.write_superblock_copies(superblock)?;
+        debug!("Set filesystem UUID to {}", uuid);
+        Ok(())
+    }
+
+    /// Write the fields of `superblock` shared by all copies to every
+    /// superblock copy and make it the current superblock
+    ///
+    /// Backups that don't look like superblocks are skipped.
+    #[cfg(not(feature = "read-only"))]
+    fn write_superblock_copies(&mut self, superblock: SuperBlock) -> Ext4Result<()> {
+        let block_size = superblock.block_size() as u64;
+        for (i, offset) in superblock.copy_offsets().into_iter().enumerate() {
+            let result = superblock.write_shared_fields(&mut *self.device.borrow_mut(), offset);
+            match result {
+                Err(Ext4Error::InvalidMagic) if i > 0 => {}
+                result => result?,
+            }
+            self.caches.borrow_mut().blocks.remove(&(offset / block_size));
+        }
+        self.superblock = superblock;
+        Ok(())
+    }
+
+    /// Set the `large_file` feature once regular file `inode` grows to 2 GiB
+    /// or more, as Linux does when it writes such an inode
+    ///
+    /// Revision 0 filesystems have no feature flags and are left alone.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn note_file_size(&mut self, inode: &Inode) -> Ext4Result<()> {
+        let large = inode.size > 0x7FFF_FFFF && inode.is_file();
+        let sb = &self.superblock;
+        let has_feature = sb.feature_ro_compat().contains(FeatureRoCompat::LARGE_FILE);
+        if !large || sb.rev_level() == 0 || has_feature {
+            return Ok(());
+        }
+        let mut superblock = self.superblock.clone();
+        superblock.set_large_file();
+        self.write_superblock_copies(superblock)?;
+        debug!("Set the large_file feature for inode {}", inode.ino);
+        Ok(())
+    }
+
+    /// Bring the superblock backups and backup group descriptor tables in
+    /// line with the primary ones, as `tune2fs` does after a change
+    ///
+    /// Backups get the geometry, features, UUID and label of the primary
+    /// superblock, and a copy of the current descriptor table, so that
+    /// recovering from them doesn't restore stale geometry. The free counts
+    /// of the copied descriptors are only as current as the primary ones.
+    #[cfg(not(feature = "read-only"))]
+    pub fn sync_backups(&mut self) -> Ext4Result<()> {
+        self.check_writable()?;
+        self.flush()?;
+        self.write_superblock_copies(self.superblock.clone())?;
+
+        let mut buf = vec![0u8; self.superblock.block_size() as usize];
+        for (primary, backup) in self.superblock.desc_backup_blocks() {
+            self.read_block(primary, &mut buf)?;
+            self.write_block(backup, &buf)?;
+        }
+        debug!("Synced {} superblock backups", self.superblock.copy_offsets().len() - 1);
+        Ok(())
+    }
+
     /// Get an inode by number
+    ///
+    /// With `verify_reads`, the inode is read from the device and checked
+    /// every time.
     pub fn get_inode(&self, ino: u32) -> Ext4Result<Inode> {
+        if ino == 0 {
+            return Err(Ext4Error::InodeNotFound);
+        }
+        let verify = self.mount_options.verify_reads;
+        if !verify {
+            if let Some(inode) = self.caches.borrow_mut().inodes.get(&ino) {
+                return Ok(inode.clone());
+            }
+        }
         debug!(
             "Getting inode {} with inodes_per_group={}",
             ino,
@@ -330,198 +1024,1546 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
             "Reading inode table block {} + {} = {}",
             inode_table_block,
             block_offset,
-            inode_table_block + block_offset
+            inode_table_block + block_offset as u64
         );
 
-        let mut buf = vec![0u8; self.superblock.block_size() as usize];
-        self.device
-            .borrow_mut()
-            .read_block((inode_table_block + block_offset) as u64, &mut buf)
-            .map_err(|_| Ext4Error::IoError)?;
+        let mut buf = self.take_block_buf();
+        if verify {
+            self.read_blocks(inode_table_block + block_offset as u64, &mut buf)?;
+        } else {
+            self.read_block(inode_table_block + block_offset as u64, &mut buf)?;
+        }
 
         debug!(
             "Reading inode at offset {} size {}",
             inode_offset, inode_size
         );
-        Inode::from_bytes(
+        let inode = self.parse_inode(
             &buf[inode_offset as usize..(inode_offset + inode_size as u32) as usize],
             ino,
-        )
+        )?;
+        self.put_block_buf(buf);
+        self.caches
+            .borrow_mut()
+            .inodes
+            .insert(ino, inode.clone(), Caches::inode_size());
+        Ok(inode)
     }
 
-    /// Read a block from the filesystem
-    pub fn read_block(&self, block: u32, buf: &mut [u8]) -> Ext4Result<()> {
-        if buf.len() != self.superblock.block_size() as usize {
-            return Err(Ext4Error::InvalidInput);
+    /// Get several inodes at once, in the order of `inos`
+    ///
+    /// Each inode table block is read only once, and runs of adjacent table
+    /// blocks are fetched with a single device request.
+    pub fn get_inodes(&self, inos: &[u32]) -> Ext4Result<Vec<Inode>> {
+        let block_size = self.superblock.block_size() as usize;
+        let inode_size = self.superblock.inode_size() as usize;
+        let locations = inos
+            .iter()
+            .map(|&ino| self.inode_location(ino))
+            .collect::<Ext4Result<Vec<_>>>()?;
+
+        let mut blocks: Vec<u64> = locations.iter().map(|&(block, _)| block).collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+
+        let mut table_blocks: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let mut requests = 0;
+        for run in blocks.chunk_by(|a, b| a + 1 == *b) {
+            for chunk in run.chunks(MAX_BATCH_BLOCKS) {
+                let mut buf = try_zeroed(chunk.len() * block_size)?;
+                self.read_blocks(chunk[0], &mut buf)?;
+                for (&block, data) in chunk.iter().zip(buf.chunks(block_size)) {
+                    table_blocks.insert(block, data.to_vec());
+                }
+                requests += 1;
+            }
         }
 
-        self.device
-            .borrow_mut()
-            .read_block(block as u64, buf)
-            .map_err(|_| Ext4Error::IoError)?;
-        Ok(())
+        debug!(
+            "get_inodes: {} inodes from {} table blocks in {} requests",
+            inos.len(),
+            table_blocks.len(),
+            requests
+        );
+        inos.iter()
+            .zip(locations)
+            .map(|(&ino, (block, offset))| {
+                self.parse_inode(&table_blocks[&block][offset..offset + inode_size], ino)
+            })
+            .collect()
     }
 
-    /// Write a block to the filesystem
-    pub fn write_block(&self, block: u32, buf: &[u8]) -> Ext4Result<()> {
-        if self.mount_options.read_only {
-            return Err(Ext4Error::ReadOnly);
+    /// Parse the on-disk inode `ino` from `data`, with its owner mapped
+    /// through the `id_map` mount option and checked if `verify_reads` is set
+    pub(crate) fn parse_inode(&self, data: &[u8], ino: u32) -> Ext4Result<Inode> {
+        self.verify_inode(data, ino)?;
+        let mut inode = Inode::from_bytes(data, ino)?;
+        // As in Linux, only regular files and, with large_dir, directories
+        // use i_size_high
+        if !inode.is_file() && !self.superblock.has_large_dir() {
+            inode.size &= u32::MAX as u64;
         }
-
-        if buf.len() != self.superblock.block_size() as usize {
-            return Err(Ext4Error::InvalidInput);
+        if let Some(map) = &self.mount_options.id_map {
+            map.map_inode(&mut inode);
         }
+        Ok(inode)
+    }
 
-        self.device
-            .borrow_mut()
-            .write_block(block as u64, buf)
-            .map_err(|_| Ext4Error::IoError)?;
-        Ok(())
+    /// Store `inode` into its on-disk slot `data`, with its owner mapped
+    /// back through the `id_map` mount option and, under `metadata_csum`,
+    /// its checksum updated
+    ///
+    /// Open files keep the link count their inode had when opened, so an
+    /// inode released on its last close is always stored without links.
+    #[cfg(not(feature = "read-only"))]
+    fn encode_inode(&self, inode: &Inode, data: &mut [u8]) {
+        let unlinked = self.unlinked_open.contains(&inode.ino) && inode.links_count != 0;
+        if self.mount_options.id_map.is_none() && !unlinked {
+            inode.write_to(data);
+        } else {
+            let mut inode = inode.clone();
+            if unlinked {
+                inode.links_count = 0;
+            }
+            if let Some(map) = &self.mount_options.id_map {
+                let stored = Inode::from_bytes(data, inode.ino).ok();
+                map.unmap_inode(&mut inode, stored.as_ref());
+            }
+            inode.write_to(data);
+        }
+        self.set_inode_csum(data, inode.ino);
     }
 
-    /// Allocate a new block
-    pub fn alloc_block(&self) -> Ext4Result<u32> {
-        if self.mount_options.read_only {
-            return Err(Ext4Error::ReadOnly);
+    /// Locate an inode on disk, returning its inode table block and byte offset
+    fn inode_location(&self, ino: u32) -> Ext4Result<(u64, usize)> {
+        if ino == 0 {
+            return Err(Ext4Error::InodeNotFound);
         }
 
-        // Simple block allocation - find first free block
-        for (i, bg) in self.block_groups.iter().enumerate() {
-            if bg.free_blocks_count() > 0 {
-                let block_bitmap = bg.block_bitmap();
-                let mut buf = vec![0u8; self.superblock.block_size() as usize];
-                self.read_block(block_bitmap, &mut buf)?;
+        let block_group = (ino - 1) / self.superblock.inodes_per_group();
+        let index = (ino - 1) % self.superblock.inodes_per_group();
 
-                let bitmap = Bitmap::from_bytes(&buf);
-                if let Some(bit) = bitmap.find_first_free() {
-                    let block = i as u32 * self.superblock.blocks_per_group() + bit as u32;
-                    return Ok(block);
-                }
-            }
+        if block_group as usize >= self.block_groups.len() {
+            return Err(Ext4Error::InodeNotFound);
         }
 
-        Err(Ext4Error::NoSpaceLeft)
+        let inode_table_block = self.block_groups[block_group as usize].inode_table();
+        let inode_size = self.superblock.inode_size() as u32;
+        let inodes_per_block = self.superblock.block_size() / inode_size;
+        let block_offset = index / inodes_per_block;
+        let inode_offset = (index % inodes_per_block) * inode_size;
+
+        Ok((inode_table_block + block_offset as u64, inode_offset as usize))
     }
 
-    /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> Ext4Result<u32> {
-        if self.mount_options.read_only {
-            return Err(Ext4Error::ReadOnly);
+    /// Iterate over every allocated inode in the filesystem
+    ///
+    /// Reserved inodes other than the root are skipped. Groups are scanned
+    /// in order using their inode bitmaps. Groups flagged `INODE_UNINIT` are
+    /// skipped and, when group descriptor checksums are enabled, the
+    /// never-used tail of each inode table (`itable_unused`) is not read.
+    /// Each inode table block is read at most once.
+    pub fn iter_inodes(&self) -> InodeIter<'_, D> {
+        InodeIter::new(self)
+    }
+
+    /// Read a block from the filesystem
+    pub fn read_block(&self, block: u64, buf: &mut [u8]) -> Ext4Result<()> {
+        if buf.len() != self.superblock.block_size() as usize {
+            return Err(Ext4Error::InvalidInput);
+        }
+        if let Some(data) = self.caches.borrow_mut().blocks.get(&block) {
+            buf.copy_from_slice(data);
+            return Ok(());
         }
 
-        // Simple inode allocation - find first free inode
-        let groups_count = self.block_groups.len();
-        for i in 0..groups_count {
-            // Check if this group has free inodes
-            if self.block_groups[i].free_inodes_count() > 0 {
-                let inode_bitmap = self.block_groups[i].inode_bitmap();
-                let mut buf = vec![0u8; self.superblock.block_size() as usize];
-                self.read_block(inode_bitmap, &mut buf)?;
+        let offset = self.block_offset(block)?;
+        device::read_bytes(&mut *self.device.borrow_mut(), offset, buf)
+            .map_err(Ext4Error::from)?;
+        self.count_blocks(1, 0);
+        self.caches
+            .borrow_mut()
+            .blocks
+            .insert(block, buf.to_vec(), buf.len());
+        Ok(())
+    }
 
-                let mut bitmap = Bitmap::from_bytes(&buf);
-                if let Some(bit) = bitmap.find_first_free() {
-                    let ino = i as u32 * self.superblock.inodes_per_group() + bit as u32 + 1;
-                    
-                    // Mark inode as used in bitmap
-                    bitmap.set(bit)?;
-                    buf.copy_from_slice(bitmap.as_bytes());
-                    self.write_block(inode_bitmap, &buf)?;
-                    
-                    // Update free inodes count in block group descriptor
-                    let new_free_count = self.block_groups[i].free_inodes_count() - 1;
-                    self.block_groups[i].set_free_inodes_count(new_free_count);
-                    
-                    // Write updated block group descriptor to disk
-                    self.write_block_group_descriptor(i)?;
-                    
-                    debug!("Allocated inode {} in block group {}, free inodes now: {}", ino, i, new_free_count);
-                    return Ok(ino);
-                }
-            }
-        }
+    /// Byte offset of `block` on the device
+    ///
+    /// Fails with `InvalidArg` for block numbers too large to address.
+    fn block_offset(&self, block: u64) -> Ext4Result<u64> {
+        block
+            .checked_mul(self.superblock.block_size() as u64)
+            .ok_or(Ext4Error::InvalidArg)
+    }
 
-        Err(Ext4Error::NoSpaceLeft)
+    /// Take a zeroed block-sized buffer, from the buffer pool if it has one
+    pub(crate) fn take_block_buf(&self) -> Vec<u8> {
+        self.buffers.borrow_mut().take()
     }
 
-    /// Get filesystem statistics
-    pub fn stats(&self) -> Ext4Result<FilesystemStats> {
-        Ok(FilesystemStats {
-            block_size: self.superblock.block_size(),
-            total_blocks: self.superblock.blocks_count(),
-            free_blocks: self.superblock.free_blocks_count(),
-            total_inodes: self.superblock.inodes_count() as u64,
-            free_inodes: self.superblock.free_inodes_count() as u64,
-        })
+    /// Give a buffer from [`take_block_buf`](Self::take_block_buf) back to
+    /// the buffer pool
+    pub(crate) fn put_block_buf(&self, buf: Vec<u8>) {
+        self.buffers.borrow_mut().put(buf);
     }
-}
 
-/// Filesystem statistics
-#[derive(Debug, Clone)]
-pub struct FilesystemStats {
-    pub block_size: u32,
-    pub total_blocks: u64,
-    pub free_blocks: u64,
-    pub total_inodes: u64,
-    pub free_inodes: u64,
-}
+    /// Read consecutive blocks starting at `block` with one device request
+    ///
+    /// `buf` must hold a whole number of blocks.
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Ext4Result<()> {
+        let block_size = self.superblock.block_size() as usize;
+        if buf.is_empty() || !buf.len().is_multiple_of(block_size) {
+            return Err(Ext4Error::InvalidInput);
+        }
 
-/// Root inode number
-pub const EXT4_ROOT_INO: u32 = 2;
+        let offset = self.block_offset(block)?;
+        device::read_bytes(&mut *self.device.borrow_mut(), offset, buf)
+            .map_err(Ext4Error::from)?;
+        self.count_blocks((buf.len() / block_size) as u64, 0);
+        Ok(())
+    }
 
-/// Invalid inode number
-pub const EXT4_BAD_INO: u32 = 1;
+    /// Write consecutive blocks starting at `block` with one device request,
+    /// dropping them from the block cache
+    ///
+    /// `buf` must hold a whole number of blocks.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_blocks_uncached(&self, block: u64, buf: &[u8]) -> Ext4Result<()> {
+        self.check_writable()?;
+        let block_size = self.superblock.block_size() as usize;
+        if buf.is_empty() || !buf.len().is_multiple_of(block_size) {
+            return Err(Ext4Error::InvalidInput);
+        }
 
-/// Ext4 filesystem operations
-impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
-    /// Find an inode by path
-    pub fn find_inode(&self, path: &str) -> Ext4Result<Inode> {
-        if path == "/" || path.is_empty() {
-            return self.root_inode();
+        let offset = self.block_offset(block)?;
+        let result = device::write_bytes(&mut *self.device.borrow_mut(), offset, buf);
+        let count = (buf.len() / block_size) as u64;
+        self.unflushed.borrow_mut().record(count, self.now());
+        self.count_blocks(0, count);
+        let mut caches = self.caches.borrow_mut();
+        for i in 0..count {
+            caches.blocks.remove(&(block + i));
         }
+        result.map_err(Ext4Error::from)
+    }
 
-        let path = path.trim_start_matches('/');
-        let components: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    /// Write a block to the filesystem
+    #[cfg(not(feature = "read-only"))]
+    pub fn write_block(&self, block: u64, buf: &[u8]) -> Ext4Result<()> {
+        self.check_writable()?;
+        self.write_block_raw(block, buf)
+    }
 
-        let mut current_ino = EXT4_ROOT_INO;
+    /// Write a block even if the filesystem is read-only
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_block_raw(&self, block: u64, buf: &[u8]) -> Ext4Result<()> {
+        if buf.len() != self.superblock.block_size() as usize {
+            return Err(Ext4Error::InvalidInput);
+        }
 
-        for component in components {
-            let current_inode = self.get_inode(current_ino)?;
-            if !current_inode.mode.contains(InodeMode::IFDIR) {
-                return Err(Ext4Error::NotADirectory);
+        let offset = self.block_offset(block)?;
+        let result = device::write_bytes(&mut *self.device.borrow_mut(), offset, buf);
+        self.unflushed.borrow_mut().record(1, self.now());
+        self.count_blocks(0, 1);
+        let mut caches = self.caches.borrow_mut();
+        match result {
+            Ok(()) => {
+                caches.blocks.insert(block, buf.to_vec(), buf.len());
+                self.bitmaps.borrow_mut().written(block, buf);
             }
+            Err(_) => caches.blocks.remove(&block),
+        }
+        result.map_err(Ext4Error::from)
+    }
 
-            // Read directory data
-            let block_size = self.superblock.block_size();
-            let mut dir_data = Vec::new();
+    /// Flush the device's write cache, after writing any changed bitmaps
+    /// and group descriptors
+    pub fn flush(&self) -> Ext4Result<()> {
+        #[cfg(not(feature = "read-only"))]
+        {
+            let dirty = self.bitmaps.borrow().dirty_blocks();
+            for block in dirty {
+                self.write_bitmap(block)?;
+            }
+            self.write_dirty_groups()?;
+        }
+        self.device
+            .borrow_mut()
+            .flush()
+            .map_err(Ext4Error::from)?;
+        *self.unflushed.borrow_mut() = Unflushed::default();
+        Ok(())
+    }
 
-            for i in 0..current_inode.block_count(block_size) {
-                let block_num =
-                    current_inode.get_block_number(i * block_size as u64, block_size, self)?;
-                if block_num == 0 {
-                    continue;
-                }
+    /// Make file data durable before writing metadata that references it,
+    /// as required by the data mode
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn order_data(&self) -> Ext4Result<()> {
+        match self.mount_options.data_mode {
+            DataMode::Ordered => self.flush(),
+            DataMode::Writeback | DataMode::Journal => Ok(()),
+        }
+    }
 
-                let mut block_buf = vec![0u8; block_size as usize];
-                self.read_block(block_num, &mut block_buf)?;
-                dir_data.extend_from_slice(&block_buf);
-            }
+    /// Allocate a new block
+    ///
+    /// Moves the cursor of the group it allocates from, hence `&mut self`;
+    /// see the changelog for callers of the old `&self` version.
+    #[cfg(not(feature = "read-only"))]
+    pub fn alloc_block(&mut self) -> Ext4Result<u64> {
+        self.alloc_block_near(None)
+    }
 
-            // Parse directory entries
-            let dir = Directory::from_bytes(&dir_data)?;
-            let entry = dir.find_entry(component).ok_or(Ext4Error::InodeNotFound)?;
+    /// Allocate the next block for inode `ino`
+    ///
+    /// Continues right after the block last allocated for the same inode, so
+    /// sequential writers get physically contiguous blocks.
+    #[cfg(not(feature = "read-only"))]
+    pub fn alloc_block_for(&mut self, ino: u32) -> Ext4Result<u64> {
+        self.alloc_block_owned(ino, self.block_groups.len())
+    }
 
-            current_ino = entry.ino;
-        }
+    /// Allocate the next data block for `inode`
+    ///
+    /// Like [`alloc_block_for`](Self::alloc_block_for), but block-mapped
+    /// inodes only get blocks they can map.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn alloc_data_block(&mut self, inode: &Inode) -> Ext4Result<u64> {
+        self.alloc_block_owned(inode.ino, self.alloc_groups(inode))
+    }
 
-        self.get_inode(current_ino)
+    /// Allocate a block for the indirect block map of a block-mapped inode
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn alloc_indirect_block(&mut self) -> Ext4Result<u64> {
+        self.alloc_block_in(None, None, self.blockfile_groups())
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    fn alloc_block_owned(&mut self, ino: u32, groups: usize) -> Ext4Result<u64> {
+        let goal = self.alloc_hints.goal(ino);
+        let block = self.alloc_block_in(goal, Some(ino), groups)?;
+        self.alloc_hints.set_goal(ino, block + 1);
+        Ok(block)
+    }
+
+    /// Number of leading block groups `inode` may get blocks from
+    ///
+    /// The indirect block map stores 32-bit block numbers, so block-mapped
+    /// inodes are kept to the groups below 2^32.
+    #[cfg(not(feature = "read-only"))]
+    fn alloc_groups(&self, inode: &Inode) -> usize {
+        if inode.inode_flags().contains(InodeFlags::EXTENTS) {
+            self.block_groups.len()
+        } else {
+            self.blockfile_groups()
+        }
+    }
+
+    /// Number of block groups lying wholly below block 2^32, like Linux's
+    /// `s_blockfile_groups`
+    #[cfg(not(feature = "read-only"))]
+    fn blockfile_groups(&self) -> usize {
+        let first_data_block = self.superblock.first_data_block() as u64;
+        let blocks_per_group = self.superblock.blocks_per_group() as u64;
+        let groups = (u32::MAX as u64 - first_data_block) / blocks_per_group;
+        self.block_groups.len().min(groups as usize)
+    }
+
+    /// Allocate a new block, preferring `goal` or the first free block after it
+    ///
+    /// Without a goal, each group is scanned from where its previous
+    /// allocation left off rather than from its first block.
+    #[cfg(not(feature = "read-only"))]
+    pub fn alloc_block_near(&mut self, goal: Option<u64>) -> Ext4Result<u64> {
+        self.alloc_block_in(goal, None, self.block_groups.len())
+    }
+
+    /// Allocate a block for `owner` from the first `groups` block groups,
+    /// skipping blocks reserved for others
+    ///
+    /// Reservations are dropped instead of failing when no other block is
+    /// free.
+    #[cfg(not(feature = "read-only"))]
+    fn alloc_block_in(
+        &mut self,
+        goal: Option<u64>,
+        owner: Option<u32>,
+        groups: usize,
+    ) -> Ext4Result<u64> {
+        match self.scan_for_block(goal, owner, groups) {
+            Err(Ext4Error::NoSpaceLeft) if self.alloc_hints.has_reservations() => {
+                debug!("Dropping block reservations to satisfy an allocation");
+                self.alloc_hints.clear_reservations();
+                self.scan_for_block(goal, owner, groups)
+            }
+            result => result,
+        }
+    }
+
+    /// Take the first free block at or after `goal` from the bitmaps of the
+    /// first `groups_count` block groups
+    #[cfg(not(feature = "read-only"))]
+    fn scan_for_block(
+        &mut self,
+        goal: Option<u64>,
+        owner: Option<u32>,
+        groups_count: usize,
+    ) -> Ext4Result<u64> {
+        self.check_writable()?;
+
+        let first_data_block = self.superblock.first_data_block() as u64;
+        let blocks_per_group = self.superblock.blocks_per_group() as u64;
+        let blocks_count = self.superblock.blocks_count();
+        let end = first_data_block + groups_count as u64 * blocks_per_group;
+
+        let (start_group, start_bit) = match goal {
+            Some(goal) if goal >= first_data_block && goal < blocks_count.min(end) => {
+                let rel = goal - first_data_block;
+                ((rel / blocks_per_group) as usize, Some((rel % blocks_per_group) as u32))
+            }
+            _ => (0, None),
+        };
+
+        for n in 0..groups_count {
+            let i = (start_group + n) % groups_count;
+            if self.block_groups[i].free_blocks_count() == 0 {
+                continue;
+            }
+
+            let group_start = first_data_block + i as u64 * blocks_per_group;
+            let limit = (blocks_count - group_start).min(blocks_per_group) as usize;
+            let start = match start_bit {
+                Some(bit) if n == 0 => bit,
+                _ => self.alloc_hints.cursor(i),
+            } as usize;
+
+            let uninit = self.block_groups[i].block_uninit();
+            // Scan forward from the cursor, then wrap around to the group start
+            let reserved = |bit: usize| {
+                self.alloc_hints
+                    .reserved_for_other(group_start + bit as u64, owner)
+            };
+            let found = self.change_bitmap(i, false, |bitmap| {
+                let limit = limit.min(bitmap.size());
+                let start = start.min(limit);
+                let Some(bit) = bitmap
+                    .find_free_filtered(start, limit, reserved)
+                    .or_else(|| bitmap.find_free_filtered(0, start, reserved))
+                else {
+                    return Ok(None);
+                };
+                bitmap.set(bit)?;
+                Ok(Some(bit))
+            })?;
+            let Some(bit) = found else {
+                continue;
+            };
+
+            let new_free_count = self.block_groups[i].free_blocks_count() - 1;
+            self.block_groups[i].set_free_blocks_count(new_free_count);
+            if uninit {
+                self.block_groups[i].clear_block_uninit();
+            }
+            self.group_changed(i)?;
+            self.check_group_accounting(i)?;
+
+            self.alloc_hints.advance(i, bit as u32);
+            let block = group_start + bit as u64;
+            self.alloc_hints.consume(block);
+            self.alloc_log.record(Allocation::Block(block));
+            debug!(
+                "Allocated block {} in block group {}, free blocks now: {}",
+                block, i, new_free_count
+            );
+            return Ok(block);
+        }
+
+        Err(Ext4Error::NoSpaceLeft)
+    }
+
+    /// Allocate the first block of the new directory `ino`
+    ///
+    /// With the `dir_prealloc` feature the block starts a run of free blocks
+    /// whose remainder is reserved for the directory, so it grows
+    /// contiguously.
+    #[cfg(not(feature = "read-only"))]
+    fn alloc_dir_block(&mut self, inode: &Inode) -> Ext4Result<u64> {
+        let ino = inode.ino;
+        let count = self.superblock.dir_prealloc_blocks();
+        if count > 1 {
+            if let Some(start) = self.find_free_run(count, self.alloc_groups(inode))? {
+                let end = start + count as u64;
+                debug!("Reserving blocks {}..{} for directory {}", start + 1, end, ino);
+                self.alloc_hints.set_goal(ino, start);
+                self.alloc_hints.reserve(ino, start + 1, count - 1);
+            }
+        }
+        self.alloc_data_block(inode)
+    }
+
+    /// Find `count` consecutive free blocks in the first `groups` block
+    /// groups that nobody has reserved
+    #[cfg(not(feature = "read-only"))]
+    fn find_free_run(&self, count: u32, groups: usize) -> Ext4Result<Option<u64>> {
+        let blocks_count = self.superblock.blocks_count();
+        for (i, bg) in self.block_groups.iter().enumerate().take(groups) {
+            if bg.free_blocks_count() < count {
+                continue;
+            }
+
+            let group_start = self.superblock.group_first_block(i as u32);
+            let limit = (blocks_count - group_start).min(self.superblock.blocks_per_group() as u64);
+            let Some(bit) = self.load_block_bitmap(i)?.find_free_run(count as usize) else {
+                continue;
+            };
+
+            let start = group_start + bit as u64;
+            let reserved = (start..start + count as u64)
+                .any(|b| self.alloc_hints.reserved_for_other(b, None));
+            if bit as u64 + count as u64 <= limit && !reserved {
+                return Ok(Some(start));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Block bitmap of `group`, from the bitmap cache if it is there
+    #[cfg(not(feature = "read-only"))]
+    fn load_block_bitmap(&self, group: usize) -> Ext4Result<Bitmap> {
+        self.with_bitmap(group, false, Bitmap::clone)
+    }
+
+    /// Run `f` on the inode bitmap of `group` if `inodes` is set, else on its
+    /// block bitmap, taken from the bitmap cache, where it is loaded first,
+    /// without copying it
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn with_bitmap<R>(
+        &self,
+        group: usize,
+        inodes: bool,
+        f: impl FnOnce(&Bitmap) -> R,
+    ) -> Ext4Result<R> {
+        let block = self.bitmap_block(group, inodes);
+        let mut bitmaps = self.bitmaps.borrow_mut();
+        if let Some(bitmap) = bitmaps.get(block) {
+            return Ok(f(bitmap));
+        }
+        drop(bitmaps);
+        let bitmap = self.bitmap_from_disk(group, inodes)?;
+        let mut bitmaps = self.bitmaps.borrow_mut();
+        if !bitmaps.enabled() {
+            return Ok(f(&bitmap));
+        }
+        bitmaps.insert(block, bitmap, false);
+        match bitmaps.get(block) {
+            Some(bitmap) => Ok(f(bitmap)),
+            // Evicted right away, with every other bitmap dirty
+            None => {
+                drop(bitmaps);
+                Ok(f(&self.bitmap_from_disk(group, inodes)?))
+            }
+        }
+    }
+
+    /// Change the inode bitmap of `group` if `inodes` is set, else its block
+    /// bitmap, in place with `change`
+    ///
+    /// If `change` returns `Some`, the bitmap is marked dirty in the bitmap
+    /// cache, to be written by [`Self::flush`], or written right away
+    /// without a cache.
+    #[cfg(not(feature = "read-only"))]
+    fn change_bitmap<R>(
+        &self,
+        group: usize,
+        inodes: bool,
+        change: impl FnOnce(&mut Bitmap) -> Ext4Result<Option<R>>,
+    ) -> Ext4Result<Option<R>> {
+        let block = self.bitmap_block(group, inodes);
+        if self.bitmaps.borrow().enabled() {
+            self.with_bitmap(group, inodes, |_| ())?;
+            let mut bitmaps = self.bitmaps.borrow_mut();
+            if let Some(bitmap) = bitmaps.get_mut(block) {
+                let result = change(bitmap)?;
+                if result.is_some() {
+                    bitmaps.mark_dirty(block);
+                }
+                return Ok(result);
+            }
+        }
+        let mut bitmap = self.bitmap_from_disk(group, inodes)?;
+        let result = change(&mut bitmap)?;
+        if result.is_some() {
+            self.write_block(block, bitmap.as_bytes())?;
+        }
+        Ok(result)
+    }
+
+    /// Block holding the inode bitmap of `group` if `inodes` is set, else
+    /// its block bitmap
+    #[cfg(not(feature = "read-only"))]
+    fn bitmap_block(&self, group: usize, inodes: bool) -> u64 {
+        match inodes {
+            true => self.block_groups[group].inode_bitmap(),
+            false => self.block_groups[group].block_bitmap(),
+        }
+    }
+
+    /// Read the inode bitmap of `group` if `inodes` is set, else its block
+    /// bitmap, from the device
+    ///
+    /// Bitmaps still flagged uninitialized are built instead: the block
+    /// bitmap holds the group's own metadata, and the inode bitmap of an
+    /// `INODE_UNINIT` group is clear up to the end of the group, whatever
+    /// is on disk.
+    #[cfg(not(feature = "read-only"))]
+    fn bitmap_from_disk(&self, group: usize, inodes: bool) -> Ext4Result<Bitmap> {
+        let mut buf = vec![0u8; self.superblock.block_size() as usize];
+        let bg = &self.block_groups[group];
+        if inodes && bg.inode_uninit() {
+            let mut bitmap = Bitmap::from_bytes(&buf);
+            let inodes = self.superblock.inodes_per_group() as usize;
+            bitmap.set_range(inodes, bitmap.size() - inodes)?;
+            return Ok(bitmap);
+        }
+        if !inodes && bg.block_uninit() {
+            self.init_block_bitmap(group as u32, &mut buf)?;
+        } else {
+            self.read_bitmap(group, inodes, &mut buf)?;
+        }
+        Ok(Bitmap::from_bytes(&buf))
+    }
+
+    /// Write the bitmap cached for `block` if it was changed
+    #[cfg(not(feature = "read-only"))]
+    fn write_bitmap(&self, block: u64) -> Ext4Result<()> {
+        let Some(bitmap) = self.bitmaps.borrow_mut().take_dirty(block) else {
+            return Ok(());
+        };
+        self.write_block(block, bitmap.as_bytes()).inspect_err(|_| {
+            self.bitmaps.borrow_mut().insert(block, bitmap.clone(), true);
+        })
+    }
+
+    /// Write the bitmaps of `group` changed since they were last written
+    #[cfg(not(feature = "read-only"))]
+    fn write_group_bitmaps(&self, group: usize) -> Ext4Result<()> {
+        let bg = &self.block_groups[group];
+        self.write_bitmap(bg.block_bitmap())?;
+        self.write_bitmap(bg.inode_bitmap())
+    }
+
+    /// Note that the descriptor of `group` changed along with its bitmaps
+    ///
+    /// Its bitmap checksums are updated, and the descriptor is written by
+    /// [`Self::flush`] with the others changed since, a table block at a
+    /// time. Without a bitmap cache it is written right away.
+    #[cfg(not(feature = "read-only"))]
+    fn group_changed(&mut self, group: usize) -> Ext4Result<()> {
+        if !self.bitmaps.borrow().enabled() {
+            return self.write_block_group(group as u32);
+        }
+        self.update_bitmap_csums(group)?;
+        self.bitmaps.borrow_mut().mark_group_dirty(group as u32);
+        Ok(())
+    }
+
+    /// Write the descriptors of the groups changed since they were last
+    /// written, reading and writing each descriptor table block once
+    #[cfg(not(feature = "read-only"))]
+    fn write_dirty_groups(&self) -> Ext4Result<()> {
+        let groups = self.bitmaps.borrow_mut().take_dirty_groups();
+        let descs_per_block = self.superblock.descs_per_block();
+        let desc_size = self.superblock.group_desc_size() as usize;
+        let mut buf = vec![0u8; self.superblock.block_size() as usize];
+        let mut pending = groups.iter().copied().peekable();
+        while let Some(&first) = pending.peek() {
+            let table = first / descs_per_block;
+            let block = self.superblock.group_desc_block(table);
+            let result = self.read_block(block, &mut buf).and_then(|()| {
+                while let Some(index) = pending.next_if(|&g| g / descs_per_block == table) {
+                    let offset = (index % descs_per_block) as usize * desc_size;
+                    let slot = &mut buf[offset..offset + desc_size];
+                    let mut bg = self.block_groups[index as usize].clone();
+                    bg.write_to(slot);
+                    bg.set_checksum(block_group::desc_checksum(&self.superblock, index, slot));
+                    bg.write_to(slot);
+                }
+                self.write_block(block, &buf)
+            });
+            if let Err(e) = result {
+                // Keep the descriptors of this block and the later ones dirty
+                let mut bitmaps = self.bitmaps.borrow_mut();
+                for group in groups.range(first..) {
+                    bitmaps.mark_group_dirty(*group);
+                }
+                return Err(e);
+            }
+            debug!("Wrote group descriptors of table block {}", block);
+        }
+        Ok(())
+    }
+
+    /// Store the checksums of the bitmaps of `group` in its descriptor, under
+    /// `metadata_csum`
+    ///
+    /// Each covers the bits of the group's clusters or inodes; bitmaps still
+    /// flagged uninitialized have none. 32-byte descriptors keep the low
+    /// halves only.
+    #[cfg(not(feature = "read-only"))]
+    fn update_bitmap_csums(&mut self, group: usize) -> Ext4Result<()> {
+        if !self.superblock.has_metadata_csum() {
+            return Ok(());
+        }
+        if !self.block_groups[group].block_uninit() {
+            let bits = self.superblock.clusters_per_group() as usize;
+            let csum =
+                self.with_bitmap(group, false, |b| self.bitmap_checksum(b.as_bytes(), bits))?;
+            self.block_groups[group].set_block_bitmap_csum(csum);
+        }
+        if !self.block_groups[group].inode_uninit() {
+            let bits = self.superblock.inodes_per_group() as usize;
+            let csum =
+                self.with_bitmap(group, true, |b| self.bitmap_checksum(b.as_bytes(), bits))?;
+            self.block_groups[group].set_inode_bitmap_csum(csum);
+        }
+        Ok(())
+    }
+
+    /// Build the block bitmap of a group flagged `BLOCK_UNINIT`
+    ///
+    /// Such a group only holds its own metadata: the superblock backup and
+    /// descriptor blocks at its start, plus its bitmaps and inode table when
+    /// they were not placed in another group. Bits past the end of the
+    /// filesystem are set as well.
+    #[cfg(not(feature = "read-only"))]
+    fn init_block_bitmap(&self, group: u32, buf: &mut [u8]) -> Ext4Result<()> {
+        let sb = &self.superblock;
+        let group_start = sb.group_first_block(group);
+        let group_len = (sb.blocks_count() - group_start).min(sb.blocks_per_group() as u64);
+
+        buf.fill(0);
+        let mut bitmap = Bitmap::from_bytes(buf);
+        bitmap.set_range(0, sb.group_overhead_blocks(group) as usize)?;
+        bitmap.set_range(group_len as usize, bitmap.size() - group_len as usize)?;
+
+        let bg = &self.block_groups[group as usize];
+        let itable_blocks = (sb.inodes_per_group() as u64 * sb.inode_size() as u64)
+            .div_ceil(sb.block_size() as u64);
+        let metadata = [
+            (bg.block_bitmap(), 1),
+            (bg.inode_bitmap(), 1),
+            (bg.inode_table(), itable_blocks),
+        ];
+        for (start, len) in metadata {
+            if start >= group_start && start + len <= group_start + group_len {
+                let bit = (start - group_start) as usize;
+                bitmap.set_range(bit, len as usize)?;
+            }
+        }
+
+        buf.copy_from_slice(bitmap.as_bytes());
+        debug!("Initialized block bitmap of uninitialized group {}", group);
+        Ok(())
+    }
+
+    /// Allocate a new inode
+    ///
+    /// Inodes below `s_first_ino`, 11 on revision 0 filesystems, are
+    /// reserved and never allocated. The inode gets a new generation number,
+    /// different from the one it had before, so that handles to a previous
+    /// user of the number go stale.
+    #[cfg(not(feature = "read-only"))]
+    pub fn alloc_inode(&mut self) -> Ext4Result<u32> {
+        self.alloc_inode_generation(false).map(|(ino, _)| ino)
+    }
+
+    /// Allocate an inode, returning its number and new generation
+    ///
+    /// Directories are also counted in the `used_dirs` of their group.
+    #[cfg(not(feature = "read-only"))]
+    fn alloc_inode_generation(&mut self, dir: bool) -> Ext4Result<(u32, u32)> {
+        self.check_writable()?;
+
+        // Simple inode allocation - find first free inode
+        let groups_count = self.block_groups.len();
+        for i in 0..groups_count {
+            // Check if this group has free inodes
+            if self.block_groups[i].free_inodes_count() > 0 {
+                // Reserved inodes are never handed out, even if their bits
+                // are clear, nor are bits past the group or the last inode
+                let first = i as u32 * self.superblock.inodes_per_group() + 1;
+                let from = self.superblock.first_inode().saturating_sub(first);
+                let to = (self.superblock.inodes_count() + 1 - first)
+                    .min(self.superblock.inodes_per_group());
+                // Mark inode as used in bitmap
+                let free = self.change_bitmap(i, true, |bitmap| {
+                    let free = bitmap.find_free_filtered(from as usize, to as usize, |_| false);
+                    if let Some(bit) = free {
+                        bitmap.set(bit)?;
+                    }
+                    Ok(free)
+                })?;
+                if let Some(bit) = free {
+                    let ino = first + bit as u32;
+                    
+                    // Update free inodes count in block group descriptor
+                    let new_free_count = self.block_groups[i].free_inodes_count() - 1;
+                    self.block_groups[i].set_free_inodes_count(new_free_count);
+                    if dir {
+                        let used_dirs = self.block_groups[i].used_dirs_count() + 1;
+                        self.block_groups[i].set_used_dirs_count(used_dirs);
+                    }
+                    self.init_itable_slot(i, bit as u32)?;
+                    
+                    // Write updated block group descriptor with the bitmap
+                    self.group_changed(i)?;
+                    self.check_group_accounting(i)?;
+                    
+                    debug!("Allocated inode {} in block group {}, free inodes now: {}", ino, i, new_free_count);
+                    self.alloc_log.record(match dir {
+                        true => Allocation::Directory(ino),
+                        false => Allocation::Inode(ino),
+                    });
+                    let generation = self.bump_generation(ino)?;
+                    return Ok((ino, generation));
+                }
+            }
+        }
+
+        Err(Ext4Error::NoSpaceLeft)
+    }
+
+    /// Make room in the inode table of `group` for its inode at `index`
+    ///
+    /// With group checksums, inodes past those in use (`itable_unused`) may
+    /// hold garbage until the table is zeroed, so the slot is zeroed before
+    /// it is counted as used. The changed flags and count only reach the
+    /// disk, with a new checksum, through the caller's
+    /// [`write_block_group`](Self::write_block_group).
+    #[cfg(not(feature = "read-only"))]
+    fn init_itable_slot(&mut self, group: usize, index: u32) -> Ext4Result<()> {
+        let bg = &mut self.block_groups[group];
+        bg.clear_inode_uninit();
+        if !self.superblock.has_group_csum() {
+            return Ok(());
+        }
+        let inodes_per_group = self.superblock.inodes_per_group();
+        let used = inodes_per_group.saturating_sub(bg.itable_unused());
+        if index < used {
+            return Ok(());
+        }
+        bg.set_itable_unused(inodes_per_group - index - 1);
+        if bg.itable_zeroed() {
+            return Ok(());
+        }
+
+        let ino = group as u32 * inodes_per_group + index + 1;
+        let (block, offset) = self.inode_location(ino)?;
+        let mut buf = vec![0u8; self.superblock.block_size() as usize];
+        self.read_block(block, &mut buf)?;
+        buf[offset..offset + self.superblock.inode_size() as usize].fill(0);
+        self.write_block(block, &buf)
+    }
+
+    /// Zero the inode tables of up to `max_groups` groups not zeroed yet
+    ///
+    /// Filesystems formatted with `lazy_itable_init` leave inode tables as
+    /// they were on the device; this does the work of Linux's `ext4lazyinit`
+    /// thread a few groups at a time, so it can run in the background. Only
+    /// the part of each table past the inodes in use is written, then the
+    /// group is flagged `ITABLE_ZEROED`. Returns the number of groups zeroed,
+    /// 0 once all are. Without group checksums, tables are always zeroed.
+    #[cfg(not(feature = "read-only"))]
+    pub fn zero_inode_tables(&mut self, max_groups: u32) -> Ext4Result<u32> {
+        self.check_writable()?;
+        if !self.superblock.has_group_csum() {
+            return Ok(0);
+        }
+
+        let block_size = self.superblock.block_size() as u64;
+        let inode_size = self.superblock.inode_size() as u64;
+        let inodes_per_group = self.superblock.inodes_per_group() as u64;
+        let itable_blocks = (inodes_per_group * inode_size).div_ceil(block_size);
+        let mut zeroed = 0;
+        for group in 0..self.block_groups.len() {
+            if zeroed == max_groups {
+                break;
+            }
+            let bg = &self.block_groups[group];
+            if bg.itable_zeroed() {
+                continue;
+            }
+
+            let used = match bg.inode_uninit() {
+                true => 0,
+                false => inodes_per_group.saturating_sub(bg.itable_unused() as u64),
+            };
+            let used_blocks = (used * inode_size).div_ceil(block_size);
+            self.zero_blocks(bg.inode_table() + used_blocks, itable_blocks - used_blocks)?;
+            debug!(
+                "Zeroed {} inode table blocks of group {}",
+                itable_blocks - used_blocks,
+                group
+            );
+
+            self.block_groups[group].set_itable_zeroed();
+            self.write_block_group(group as u32)?;
+            zeroed += 1;
+        }
+        Ok(zeroed)
+    }
+
+    /// Zero `count` blocks starting at `block`, a batch at a time
+    #[cfg(not(feature = "read-only"))]
+    fn zero_blocks(&self, block: u64, count: u64) -> Ext4Result<()> {
+        self.check_writable()?;
+        let block_size = self.superblock.block_size() as usize;
+        let zeros = vec![0u8; MAX_BATCH_BLOCKS * block_size];
+        let mut done = 0;
+        while done < count {
+            let batch = (count - done).min(MAX_BATCH_BLOCKS as u64);
+            let start = block + done;
+            let offset = self.block_offset(start)?;
+            let buf = &zeros[..batch as usize * block_size];
+            let result = device::write_bytes(&mut *self.device.borrow_mut(), offset, buf);
+            self.unflushed.borrow_mut().record(batch, self.now());
+            self.count_blocks(0, batch);
+            let mut caches = self.caches.borrow_mut();
+            for b in start..start + batch {
+                caches.blocks.remove(&b);
+            }
+            result.map_err(Ext4Error::from)?;
+            done += batch;
+        }
+        Ok(())
+    }
+
+    /// Run `op`, giving back the blocks and inodes it allocated if it fails
+    ///
+    /// Calls nested in `op` are part of it, and only the outermost call rolls
+    /// back. Inodes are released as on deletion. Allocations that can't be
+    /// undone, for example once the journal is aborted, are left to `fsck`.
+    #[cfg(not(feature = "read-only"))]
+    fn atomically<T>(&mut self, op: impl FnOnce(&mut Self) -> Ext4Result<T>) -> Ext4Result<T> {
+        self.alloc_log.begin();
+        let result = op(self);
+        let undo = self.alloc_log.end(result.is_err());
+        for allocation in undo.into_iter().rev() {
+            debug!("Rolling back {:?}", allocation);
+            if let Err(e) = self.undo_allocation(allocation) {
+                warn!("Failed to roll back {:?}: {:?}", allocation, e);
+            }
+        }
+        result
+    }
+
+    /// Create entry `name` in directory `parent` with `op`, as
+    /// [`atomically`](Self::atomically), and tell the watchers of `parent`
+    #[cfg(not(feature = "read-only"))]
+    fn create_atomically(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        op: impl FnOnce(&mut Self) -> Ext4Result<u32>,
+    ) -> Ext4Result<u32> {
+        let ino = self.atomically(op)?;
+        self.notify(parent, Change::Create { name, ino });
+        Ok(ino)
+    }
+
+    /// Return a block or inode allocated by a failed operation
+    #[cfg(not(feature = "read-only"))]
+    fn undo_allocation(&mut self, allocation: Allocation) -> Ext4Result<()> {
+        match allocation {
+            Allocation::Block(block) => self.free_block(block),
+            Allocation::Inode(ino) | Allocation::Directory(ino) => {
+                let mut inode = self.get_inode(ino)?;
+                self.alloc_hints.forget(ino);
+                inode.links_count = 0;
+                inode.dtime = self.now().to_raw().0;
+                self.write_inode(&inode)?;
+                self.free_inode(ino, matches!(allocation, Allocation::Directory(_)))
+            }
+        }
+    }
+
+    /// Give inode `ino` the next generation number, skipping its current one
+    #[cfg(not(feature = "read-only"))]
+    fn bump_generation(&mut self, ino: u32) -> Ext4Result<u32> {
+        let mut inode = self.get_inode(ino)?;
+        let mut generation = self.next_generation;
+        if generation == inode.generation {
+            generation = generation.wrapping_add(1);
+        }
+        self.next_generation = generation.wrapping_add(1);
+
+        inode.generation = generation;
+        self.write_inode(&inode)?;
+        Ok(generation)
+    }
+
+    /// Allocate an inode as described by `builder`, with its new generation
+    /// and all timestamps set to now
+    ///
+    /// The inode is not written.
+    #[cfg(not(feature = "read-only"))]
+    fn new_inode(&mut self, builder: &InodeBuilder) -> Ext4Result<Inode> {
+        let dir = builder.inode_type() == InodeType::Directory;
+        let (ino, generation) = self.alloc_inode_generation(dir)?;
+        let mut inode = builder.build();
+        inode.ino = ino;
+        inode.generation = generation;
+        inode.init_timestamps(self.now(), self.superblock.inode_size());
+        // As Linux does, only inodes that may hold data blocks get an extent
+        // tree; fast symlinks drop it again when storing their target
+        let holds_blocks = matches!(
+            inode.inode_type(),
+            InodeType::File | InodeType::Directory | InodeType::SymLink
+        );
+        if self.superblock.has_extents() && holds_blocks {
+            inode.flags |= InodeFlags::EXTENTS.bits();
+            extent::init_inline_root(&mut inode.block);
+        }
+        Ok(inode)
+    }
+
+    /// Get filesystem statistics
+    pub fn stats(&self) -> Ext4Result<FilesystemStats> {
+        Ok(FilesystemStats {
+            block_size: self.superblock.block_size(),
+            total_blocks: self.superblock.blocks_count(),
+            free_blocks: self.superblock.free_blocks_count(),
+            total_inodes: self.superblock.inodes_count() as u64,
+            free_inodes: self.superblock.free_inodes_count() as u64,
+        })
+    }
+
+    /// Number of block groups
+    pub fn groups_count(&self) -> u32 {
+        self.block_groups.len() as u32
+    }
+
+    /// Get usage statistics of block group `group`
+    pub fn group_stats(&self, group: u32) -> Ext4Result<GroupStats> {
+        let bg = self
+            .block_groups
+            .get(group as usize)
+            .ok_or(Ext4Error::InvalidArg)?;
+
+        let blocks_per_group = self.superblock.blocks_per_group() as u64;
+        let first_block =
+            self.superblock.first_data_block() as u64 + group as u64 * blocks_per_group;
+        let last_block = (first_block + blocks_per_group).min(self.superblock.blocks_count()) - 1;
+
+        Ok(GroupStats {
+            group,
+            first_block,
+            last_block,
+            free_blocks: bg.free_blocks_count(),
+            free_inodes: bg.free_inodes_count(),
+            used_dirs: bg.used_dirs_count(),
+            itable_unused: bg.itable_unused(),
+            flags: bg.flags(),
+            block_bitmap: bg.block_bitmap(),
+            inode_bitmap: bg.inode_bitmap(),
+            inode_table: bg.inode_table(),
+        })
+    }
+
+    /// Get a usage report covering the superblock and every block group
+    pub fn usage_report(&self) -> Ext4Result<UsageReport> {
+        let groups = (0..self.groups_count())
+            .map(|group| self.group_stats(group))
+            .collect::<Ext4Result<Vec<_>>>()?;
+        let sb = &self.superblock;
+
+        Ok(UsageReport {
+            uuid: *sb.uuid(),
+            block_size: sb.block_size(),
+            inode_size: sb.inode_size(),
+            first_data_block: sb.first_data_block(),
+            blocks_count: sb.blocks_count(),
+            reserved_blocks: sb.reserved_blocks_count(),
+            free_blocks: groups.iter().map(|g| g.free_blocks as u64).sum(),
+            inodes_count: sb.inodes_count(),
+            free_inodes: groups.iter().map(|g| g.free_inodes as u64).sum(),
+            used_dirs: groups.iter().map(|g| g.used_dirs as u64).sum(),
+            blocks_per_group: sb.blocks_per_group(),
+            inodes_per_group: sb.inodes_per_group(),
+            feature_compat: sb.feature_compat(),
+            feature_incompat: sb.feature_incompat(),
+            feature_ro_compat: sb.feature_ro_compat(),
+            groups,
+        })
+    }
+}
+
+impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<OverlayDevice<D>> {
+    /// Mount `device` with an in-memory copy-on-write overlay
+    ///
+    /// The filesystem is fully writable, but every modified block is kept in
+    /// memory and the underlying device is never written to.
+    pub fn new_overlay(device: D, mut options: MountOptions) -> Ext4Result<Self> {
+        options.read_only = false;
+        Self::new(OverlayDevice::new(device), options)
+    }
+
+    /// Number of device blocks modified since the overlay was mounted
+    pub fn overlay_blocks(&self) -> usize {
+        self.device.borrow().overlay_blocks()
+    }
+
+    /// Unmount, discarding all changes, and return the untouched device
+    pub fn discard_overlay(self) -> D {
+        self.device.into_inner().into_inner()
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<CopyOnWriteDevice<D>> {
+    /// Device blocks written since the device's last checkpoint
+    pub fn changed_blocks(&self) -> Vec<u64> {
+        self.device.borrow().changed_blocks()
+    }
+
+    /// Compare the device blocks written since the device's last checkpoint
+    /// with their original contents
+    pub fn diff(&self) -> Ext4Result<Vec<BlockDiff>> {
+        self.device
+            .borrow_mut()
+            .diff()
+            .map_err(Ext4Error::from)
+    }
+
+    /// Unmount, undo every write since the device's last checkpoint and
+    /// return the device
+    pub fn rollback(self) -> Ext4Result<CopyOnWriteDevice<D>> {
+        let mut device = self.device.into_inner();
+        device.rollback().map_err(Ext4Error::from)?;
+        Ok(device)
+    }
+}
+
+impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<RetryDevice<D>> {
+    /// Device requests retried so far
+    pub fn retry_stats(&self) -> RetryStats {
+        self.device.borrow().stats()
+    }
+}
+
+/// Filesystem statistics
+#[derive(Debug, Clone)]
+pub struct FilesystemStats {
+    pub block_size: u32,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+}
+
+/// Usage statistics of a single block group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupStats {
+    /// Group number
+    pub group: u32,
+    /// First block of the group
+    pub first_block: u64,
+    /// Last block of the group
+    pub last_block: u64,
+    /// Free blocks in the group
+    pub free_blocks: u32,
+    /// Free inodes in the group
+    pub free_inodes: u32,
+    /// Directories in the group
+    pub used_dirs: u32,
+    /// Inodes at the end of the inode table never used
+    pub itable_unused: u32,
+    /// Raw `bg_flags`
+    pub flags: u16,
+    /// Location of the block bitmap
+    pub block_bitmap: u64,
+    /// Location of the inode bitmap
+    pub inode_bitmap: u64,
+    /// First block of the inode table
+    pub inode_table: u64,
+}
+
+impl GroupStats {
+    /// Check if the inode bitmap and table are uninitialized
+    pub fn inode_uninit(&self) -> bool {
+        self.flags & block_group::EXT4_BG_INODE_UNINIT != 0
+    }
+
+    /// Check if the block bitmap is uninitialized
+    pub fn block_uninit(&self) -> bool {
+        self.flags & block_group::EXT4_BG_BLOCK_UNINIT != 0
+    }
+
+    /// Check if the inode table has been zeroed
+    pub fn itable_zeroed(&self) -> bool {
+        self.flags & block_group::EXT4_BG_INODE_ZEROED != 0
+    }
+}
+
+/// Filesystem-wide usage report, similar to `dumpe2fs -h`
+///
+/// Totals are summed from the group descriptors rather than taken from the
+/// superblock, whose counters are only updated lazily.
+#[derive(Debug, Clone)]
+pub struct UsageReport {
+    /// Volume UUID
+    pub uuid: [u8; 16],
+    /// Block size in bytes
+    pub block_size: u32,
+    /// Inode size in bytes
+    pub inode_size: u16,
+    /// First data block
+    pub first_data_block: u32,
+    /// Total blocks
+    pub blocks_count: u64,
+    /// Reserved blocks
+    pub reserved_blocks: u64,
+    /// Free blocks
+    pub free_blocks: u64,
+    /// Total inodes
+    pub inodes_count: u32,
+    /// Free inodes
+    pub free_inodes: u64,
+    /// Directories
+    pub used_dirs: u64,
+    /// Blocks per group
+    pub blocks_per_group: u32,
+    /// Inodes per group
+    pub inodes_per_group: u32,
+    /// Compatible feature flags
+    pub feature_compat: FeatureCompat,
+    /// Incompatible feature flags
+    pub feature_incompat: FeatureIncompat,
+    /// Read-only compatible feature flags
+    pub feature_ro_compat: FeatureRoCompat,
+    /// Per-group statistics
+    pub groups: Vec<GroupStats>,
+}
+
+/// Root inode number
+pub const EXT4_ROOT_INO: u32 = 2;
+
+/// Maximum number of inode table blocks fetched by one batched read
+const MAX_BATCH_BLOCKS: usize = 32;
+
+/// Allocate `len` zero bytes, failing with `NoMemory` rather than aborting
+/// when the allocator can't provide them
+pub(crate) fn try_zeroed(len: usize) -> Ext4Result<Vec<u8>> {
+    let mut buf = try_with_capacity(len)?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+/// Create an empty vector with room for `len` items, failing with
+/// `NoMemory` rather than aborting when the allocator can't provide it
+///
+/// Sizes read from the disk go through this, so that a corrupt one fails
+/// the operation instead of the whole system.
+pub(crate) fn try_with_capacity<T>(len: usize) -> Ext4Result<Vec<T>> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len).map_err(|_| Ext4Error::NoMemory)?;
+    Ok(vec)
+}
+
+/// Seconds after which `relatime` updates an access time even if it is
+/// after the modification and change times
+#[cfg(not(feature = "read-only"))]
+const RELATIME_INTERVAL: i64 = 24 * 60 * 60;
+
+/// Maximum link count of an inode
+///
+/// With the `dir_nlink` feature, a directory that would exceed it gets a link
+/// count of 1, meaning its subdirectories are no longer counted.
+pub const EXT4_LINK_MAX: u16 = 65000;
+
+/// Invalid inode number
+pub const EXT4_BAD_INO: u32 = 1;
+
+/// Inode reserving blocks for the group descriptor table to grow into
+pub const EXT4_RESIZE_INO: u32 = 7;
+
+/// Inode holding the journal
+pub const EXT4_JOURNAL_INO: u32 = 8;
+
+/// Longest path accepted by path lookups, including separators
+pub const EXT4_PATH_MAX: usize = 4096;
+
+/// Most components a path lookup will descend through
+pub const EXT4_MAX_PATH_DEPTH: usize = 1024;
+
+/// Ext4 filesystem operations
+impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
+    /// Find an inode by path
+    pub fn find_inode(&self, path: &str) -> Ext4Result<Inode> {
+        self.find_inode_bytes(path.as_bytes())
+    }
+
+    /// Find an inode by a path that may contain non-UTF-8 names
+    ///
+    /// Repeated slashes and "." components are ignored, and ".." follows the
+    /// parent entry of the directory it appears in. A path ending in "/",
+    /// "." or ".." must name a directory, otherwise this fails with
+    /// `NotADirectory`.
+    ///
+    /// Fails with `Loop` if the path is nested deeper than
+    /// [`EXT4_MAX_PATH_DEPTH`] or if a directory entry other than `..` leads
+    /// back to a directory already on the path, which only a corrupt image
+    /// can contain.
+    pub fn find_inode_bytes(&self, path: &[u8]) -> Ext4Result<Inode> {
+        self.resolve_path(self.root_inode()?, path, None)
+    }
+
+    /// Read the target of the symbolic link at `path`
+    ///
+    /// Links in the middle of the path are followed, the last one is read.
+    /// Fails with `InvalidArg` if `path` isn't a symbolic link, and with
+    /// `InvalidInput` if the target isn't UTF-8.
+    pub fn read_link(&self, path: &str) -> Ext4Result<String> {
+        let target = self.read_link_bytes(path.as_bytes())?;
+        String::from_utf8(target).map_err(|_| Ext4Error::InvalidInput)
+    }
+
+    /// Read the target of the symbolic link at a path that may contain
+    /// non-UTF-8 names
+    pub fn read_link_bytes(&self, path: &[u8]) -> Ext4Result<Vec<u8>> {
+        let inode = self.resolve_at(EXT4_ROOT_INO, path, ResolveFlags::NOFOLLOW)?;
+        if !inode.is_symlink() {
+            return Err(Ext4Error::InvalidArg);
+        }
+        symlink::read_target_bytes(self, &inode)
+    }
+
+    /// Get the metadata of inode `ino`
+    pub fn stat(&self, ino: u32) -> Ext4Result<Metadata> {
+        let inode = self.get_inode(ino)?;
+        Ok(Metadata::new(&inode, self.superblock.block_size()))
+    }
+
+    /// Get the metadata of the inode at `path`
+    pub fn stat_path(&self, path: &str) -> Ext4Result<Metadata> {
+        let inode = self.find_inode(path)?;
+        Ok(Metadata::new(&inode, self.superblock.block_size()))
+    }
+
+    /// Create a persistent handle for inode `ino`
+    pub fn encode_fh(&self, ino: u32) -> Ext4Result<FileHandle> {
+        let inode = self.get_inode(ino)?;
+        Ok(FileHandle {
+            ino,
+            generation: inode.generation,
+        })
+    }
+
+    /// Resolve a handle created by [`Self::encode_fh`]
+    ///
+    /// Fails with `StaleHandle` if the inode number is out of range or
+    /// reserved, if the inode has been deleted, or if it was reused since
+    /// the handle was created.
+    pub fn decode_fh(&self, handle: &FileHandle) -> Ext4Result<Inode> {
+        let ino = handle.ino;
+        if ino == 0 || ino > self.superblock.inodes_count() || self.is_reserved_inode(ino) {
+            return Err(Ext4Error::StaleHandle);
+        }
+
+        let inode = self.get_inode(ino)?;
+        if inode.links_count == 0 || inode.mode.bits() == 0 || inode.dtime != 0 {
+            debug!("Handle for inode {} refers to a deleted inode", ino);
+            return Err(Ext4Error::StaleHandle);
+        }
+        if inode.generation != handle.generation {
+            debug!(
+                "Handle for inode {} has generation {}, inode has {}",
+                ino, handle.generation, inode.generation
+            );
+            return Err(Ext4Error::StaleHandle);
+        }
+
+        Ok(inode)
+    }
+
+    /// Get the flags of inode `ino`
+    pub fn get_flags(&self, ino: u32) -> Ext4Result<InodeFlags> {
+        Ok(self.get_inode(ino)?.inode_flags())
+    }
+
+    /// Set the user-modifiable flags of inode `ino`
+    ///
+    /// Flags outside [`InodeFlags::USER_MODIFIABLE`] keep their current
+    /// value, like `chattr`. This is the only change allowed on an immutable
+    /// inode, so that the flag can be cleared again. As in Linux, `DAX` only
+    /// applies to regular files and directories.
+    #[cfg(not(feature = "read-only"))]
+    pub fn set_flags(&mut self, ino: u32, flags: InodeFlags) -> Ext4Result<()> {
+        self.check_writable()?;
+        let mut inode = self.get_inode(ino)?;
+        let keep = inode.inode_flags() - InodeFlags::USER_MODIFIABLE;
+        let new = keep | (flags & InodeFlags::USER_MODIFIABLE);
+        let changed = inode.inode_flags() ^ new;
+        if changed.contains(InodeFlags::DAX) && !inode.is_file() && !inode.is_dir() {
+            return Err(Ext4Error::InvalidInput);
+        }
+        debug!("Inode {} flags {:?} -> {:?}", ino, inode.inode_flags(), new);
+
+        inode.flags = new.bits();
+        inode.set_ctime(self.now());
+        self.write_inode(&inode)?;
+        self.notify(ino, Change::Attrib);
+        Ok(())
+    }
+
+    /// Look up a name in a directory, returning the inode number it refers to
+    pub fn lookup(&self, dir_ino: u32, name: &[u8]) -> Ext4Result<u32> {
+        let span = self.span_enter(TraceOp::Lookup, dir_ino);
+        let result = self.lookup_untraced(dir_ino, name);
+        self.span_exit(span, &result);
+        result
+    }
+
+    /// [`Self::lookup`] without its span
+    fn lookup_untraced(&self, dir_ino: u32, name: &[u8]) -> Ext4Result<u32> {
+        if let Some(ino) = self.cached_dentry(dir_ino, name) {
+            return Ok(ino);
+        }
+        let dir = self.get_inode(dir_ino)?;
+        if !dir.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+        let ino = match self.dx_lookup(&dir, name)? {
+            Some(ino) => ino,
+            None => self.find_in_directory(&dir, name)?,
+        };
+        self.cache_dentry(dir_ino, name, ino);
+        Ok(ino)
+    }
+
+    /// Look up `name` in directory `dir` in the directory entry cache
+    fn cached_dentry(&self, dir: u32, name: &[u8]) -> Option<u32> {
+        let key = (dir, name.to_vec());
+        self.caches.borrow_mut().dentries.get(&key).copied()
+    }
+
+    /// Remember that `name` in directory `dir` refers to inode `ino`
+    fn cache_dentry(&self, dir: u32, name: &[u8], ino: u32) {
+        self.caches
+            .borrow_mut()
+            .dentries
+            .insert((dir, name.to_vec()), ino, Caches::dentry_size(name));
+    }
+
+    /// Memory currently used by the caches
+    pub fn cache_usage(&self) -> CacheUsage {
+        self.caches.borrow().usage()
+    }
+
+    /// Release cached blocks, inodes and directory entries until the caches
+    /// use at most `target` bytes
+    ///
+    /// The caches may grow back to their budget afterwards.
+    pub fn shrink(&self, target: usize) {
+        let mut caches = self.caches.borrow_mut();
+        caches.shrink(target);
+        debug!("Shrank caches to {} bytes", caches.usage().total());
+    }
+
+    /// Compute the hashed directory index hash of `name`
+    ///
+    /// Uses the filesystem's default hash version and hash seed.
+    pub fn dir_hash(&self, name: &[u8]) -> Ext4Result<DxHash> {
+        let version = HashVersion::from_superblock(&self.superblock).ok_or_else(|| {
+            warn!(
+                "Unknown directory hash version {}",
+                self.superblock.def_hash_version()
+            );
+            Ext4Error::NotSupported
+        })?;
+        Ok(dx_hash(name, version, self.superblock.hash_seed()))
+    }
+
+    /// Walk the directory tree below `path` depth-first
+    ///
+    /// Symbolic links are reported but not followed and there is no depth
+    /// limit; use [`walk_with`](Self::walk_with) to change this.
+    pub fn walk(&self, path: &str) -> Ext4Result<Walk<'_, D>> {
+        Walk::new(self, path, WalkOptions::default())
+    }
+
+    /// Walk the directory tree below `path` with the given options
+    pub fn walk_with(&self, path: &str, options: WalkOptions) -> Ext4Result<Walk<'_, D>> {
+        Walk::new(self, path, options)
+    }
+
+    /// Count the entries of directory `ino` and report its size and index
+    ///
+    /// Blocks are scanned one at a time and entries counted without being
+    /// parsed. An indexed directory also reports its number of leaf blocks,
+    /// summed from the counts of its index nodes.
+    pub fn dir_stats(&self, ino: u32) -> Ext4Result<DirStats> {
+        let inode = self.get_inode(ino)?;
+        if !inode.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+
+        let block_size = self.superblock.block_size();
+        let mut entries = 0;
+        if let Some(data) = self.inline_dir_data(&inode)? {
+            entries = directory::count_entries(&data);
+        } else {
+            let mut block = vec![0u8; block_size as usize];
+            for i in 0..inode.block_count(block_size) {
+                let block_num = inode.get_block_number(i * block_size as u64, block_size, self)?;
+                if block_num == 0 {
+                    continue;
+                }
+                self.read_dir_data_block(&inode, block_num, &mut block)?;
+                entries += directory::count_entries(&block);
+            }
+        }
+
+        let indexed = self.is_indexed(&inode);
+        let index_leaves = match indexed {
+            true => self.dx_leaf_count(&inode)?,
+            false => None,
+        };
+        Ok(DirStats {
+            entries,
+            size: inode.size,
+            indexed,
+            index_leaves,
+        })
     }
 
     /// Read directory entries
+    ///
+    /// Entries naming a reserved inode other than the root are left out.
     pub fn read_dir(&self, ino: u32) -> Ext4Result<Vec<DirectoryEntry>> {
+        let mut entries = self.read_dir_entries(ino)?;
+        entries.retain(|e| {
+            let hidden = self.is_reserved_inode(e.ino);
+            if hidden {
+                warn!("Directory {} links reserved inode {} as {:?}", ino, e.ino, e.name);
+            }
+            !hidden
+        });
+        self.readahead_inodes(&entries);
+        Ok(entries)
+    }
+
+    /// Read every entry of directory `ino`, including those naming reserved
+    /// inodes
+    fn read_dir_entries(&self, ino: u32) -> Ext4Result<Vec<DirectoryEntry>> {
         let inode = self.get_inode(ino)?;
         if !inode.mode.contains(InodeMode::IFDIR) {
             return Err(Ext4Error::NotADirectory);
         }
+        if let Some(data) = self.inline_dir_data(&inode)? {
+            return Ok(Directory::from_bytes(&data)?.entries().to_vec());
+        }
 
         debug!(
             "Reading directory inode {}: size={}, blocks={}, mode={:?}",
@@ -529,7 +2571,6 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
         );
 
         let block_size = self.superblock.block_size();
-        let mut dir_data = Vec::new();
 
         // Special case for empty directories
         if inode.size == 0 || inode.blocks == 0 {
@@ -537,6 +2578,8 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
             return Ok(vec![]);
         }
 
+        let mut dir_data = self.try_dir_buffer(&inode)?;
+        let mut block_buf = try_zeroed(block_size as usize)?;
         for i in 0..inode.block_count(block_size) {
             let block_num = inode.get_block_number(i * block_size as u64, block_size, self)?;
             debug!("Directory block {}: block_num={}", i, block_num);
@@ -545,13 +2588,12 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
             }
 
             // Check if block number is valid
-            if block_num >= self.superblock.blocks_count() as u32 {
+            if block_num >= self.superblock.blocks_count() {
                 warn!("Invalid block number {} for directory inode {}, skipping", block_num, ino);
                 continue;
             }
 
-            let mut block_buf = vec![0u8; block_size as usize];
-            match self.read_block(block_num, &mut block_buf) {
+            match self.read_dir_data_block(&inode, block_num, &mut block_buf) {
                 Ok(_) => {
                     debug!(
                         "Read directory block {} ({} bytes), first 32 bytes: {:x?}",
@@ -561,6 +2603,7 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
                     );
                     dir_data.extend_from_slice(&block_buf);
                 }
+                Err(e @ (Ext4Error::BadChecksum | Ext4Error::InvalidState)) => return Err(e),
                 Err(e) => {
                     warn!("Failed to read directory block {} for inode {}: {:?}", block_num, ino, e);
                     continue;
@@ -583,7 +2626,7 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
                     rec_len: 12,
                     name_len: 1,
                     file_type: 2, // Directory
-                    name: String::from("."),
+                    name: FileName::from("."),
                 });
                 debug!("Added . entry to root directory");
             }
@@ -594,7 +2637,7 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
                     rec_len: 12,
                     name_len: 2,
                     file_type: 2, // Directory
-                    name: String::from(".."),
+                    name: FileName::from(".."),
                 });
                 debug!("Added .. entry to root directory");
             }
@@ -603,79 +2646,143 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
         Ok(dir.entries().to_vec())
     }
 
-    /// Create a new directory
-    pub fn create_dir(&mut self, parent: u32, name: &str, mode: InodeMode) -> Ext4Result<u32> {
-        if self.mount_options.read_only {
-            return Err(Ext4Error::ReadOnly);
+    /// Prefetch the inode table blocks holding the inodes of `entries`
+    ///
+    /// Looking up the entries usually follows reading a directory, so their
+    /// table blocks are read into the block cache now, with one request per
+    /// run of adjacent blocks. Failures are ignored: the inodes are read again
+    /// when needed.
+    fn readahead_inodes(&self, entries: &[DirectoryEntry]) {
+        let limit = self.mount_options.inode_readahead_blks as usize;
+        if limit == 0 || self.caches.borrow().blocks.capacity() == 0 {
+            return;
         }
 
-        // Check if directory already exists
-        let parent_inode = self.get_inode(parent)?;
-        if !parent_inode.mode.contains(InodeMode::IFDIR) {
-            return Err(Ext4Error::NotADirectory);
+        let mut blocks: Vec<u64> = entries
+            .iter()
+            .filter_map(|e| self.inode_location(e.ino).ok())
+            .map(|(block, _)| block)
+            .collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+        blocks.retain(|block| !self.caches.borrow().blocks.contains(block));
+        blocks.truncate(limit);
+
+        let block_size = self.superblock.block_size() as usize;
+        for run in blocks.chunk_by(|a, b| a + 1 == *b) {
+            for chunk in run.chunks(MAX_BATCH_BLOCKS) {
+                let Ok(mut buf) = try_zeroed(chunk.len() * block_size) else {
+                    return;
+                };
+                if self.read_blocks(chunk[0], &mut buf).is_err() {
+                    debug!("Inode table readahead at block {} failed", chunk[0]);
+                    continue;
+                }
+                let mut caches = self.caches.borrow_mut();
+                for (&block, data) in chunk.iter().zip(buf.chunks(block_size)) {
+                    caches.blocks.insert(block, data.to_vec(), block_size);
+                }
+            }
         }
+    }
+
+    /// Read directory entries together with the metadata of their inodes
+    ///
+    /// Inodes are fetched with [`get_inodes`](Self::get_inodes), so each inode
+    /// table block is read once per call no matter how many entries it serves.
+    pub fn read_dir_plus(&self, ino: u32) -> Ext4Result<Vec<DirEntryPlus>> {
+        let entries = self.read_dir(ino)?;
+        let inos: Vec<u32> = entries.iter().map(|e| e.ino).collect();
+        let inodes = self.get_inodes(&inos)?;
+        Ok(entries
+            .into_iter()
+            .zip(inodes)
+            .map(|(entry, inode)| DirEntryPlus::new(entry, &inode))
+            .collect())
+    }
+
+    /// Create a new directory
+    #[cfg(not(feature = "read-only"))]
+    pub fn create_dir(&mut self, parent: u32, name: &str, mode: InodeMode) -> Ext4Result<u32> {
+        self.create_dir_bytes(parent, name.as_bytes(), mode)
+    }
+
+    /// Create a new directory with a name that may not be valid UTF-8
+    #[cfg(not(feature = "read-only"))]
+    pub fn create_dir_bytes(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        mode: InodeMode,
+    ) -> Ext4Result<u32> {
+        let builder = InodeBuilder::dir().mode(mode.bits());
+        self.create_atomically(parent, name, |fs| fs.create_dir_inner(parent, name, &builder))
+    }
 
-        let dir_entries = self.read_dir(parent)?;
-        if dir_entries.iter().any(|e| e.name == name) {
-            return Err(Ext4Error::FileExists);
+    /// Create a directory, file, device node, FIFO or socket described by
+    /// `builder`
+    ///
+    /// Symbolic links need a target and are made with
+    /// [`symlink`](Self::symlink) instead: they fail with `InvalidArg`.
+    #[cfg(not(feature = "read-only"))]
+    pub fn mknod(&mut self, parent: u32, name: &[u8], builder: &InodeBuilder) -> Ext4Result<u32> {
+        match builder.inode_type() {
+            InodeType::Directory => self.create_atomically(parent, name, |fs| {
+                fs.create_dir_inner(parent, name, builder)
+            }),
+            InodeType::SymLink => Err(Ext4Error::InvalidArg),
+            _ => self.create_atomically(parent, name, |fs| {
+                fs.create_node_inner(parent, name, builder)
+            }),
         }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    fn create_dir_inner(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        builder: &InodeBuilder,
+    ) -> Ext4Result<u32> {
+        let parent_inode = self.check_new_entry(parent, name)?;
+        self.check_dir_link_max(&parent_inode)?;
 
         // Allocate new inode
-        let new_ino = self.alloc_inode()?;
-        let mut new_inode = Inode::new(new_ino);
-        new_inode.mode = mode | InodeMode::IFDIR; // Set as directory
-        new_inode.links_count = 2; // . and ..
+        let new_inode = self.new_inode(builder)?;
+        let new_ino = new_inode.ino;
 
         // Allocate block for directory
-        let block_num = self.alloc_block()?;
+        let block_num = self.alloc_dir_block(&new_inode)?;
 
         // Create directory entries (. and ..)
+        let dir_type = if self.superblock.has_filetype() { 2 } else { 0 };
         let mut dir = Directory::new();
         dir.add_entry(DirectoryEntry {
             ino: new_ino,
             rec_len: 12,
             name_len: 1,
-            file_type: 2, // Directory
-            name: String::from("."),
+            file_type: dir_type,
+            name: FileName::from("."),
         });
 
         dir.add_entry(DirectoryEntry {
             ino: parent,
             rec_len: 12,
             name_len: 2,
-            file_type: 2, // Directory
-            name: String::from(".."),
+            file_type: dir_type,
+            name: FileName::from(".."),
         });
 
         // Write directory data
-        let dir_data = dir.to_bytes()?;
-        let mut block_buf = vec![0u8; self.superblock.block_size() as usize];
-        block_buf[..dir_data.len()].copy_from_slice(&dir_data);
-        self.write_block(block_num, &block_buf)?;
+        let dir_data = self.dir_blocks(&new_inode, &dir)?;
+        self.write_block(block_num, &dir_data)?;
 
         // Update inode with proper extent structure
         let mut updated_inode = new_inode;
-        
-        // Check if filesystem uses extents
-        debug!("feature_incompat = 0x{:x}", self.superblock.feature_incompat());
-        if self.superblock.feature_incompat() & 0x0040 != 0 {
-            // EXT4_FEATURE_INCOMPAT_EXTENTS - use extent format
-            // Create an inline extent in the inode block array
-            // Format: [magic|entries|depth, block0, len0|start_hi0, start_lo0, ...]
-            updated_inode.block[0] = 0xF30A | ((1 as u32) << 16); // magic=0xF30A, entries=1, depth=0
-            updated_inode.block[1] = 0; // logical block 0
-            updated_inode.block[2] = (1 as u32) | ((block_num >> 16) & 0xFFFF) as u32; // len=1, start_hi=block_num>>16
-            updated_inode.block[3] = (block_num & 0xFFFF) as u32; // start_lo=block_num&0xFFFF
-            debug!("Created directory inode {} with extent format: block[0]=0x{:x}, block[1]=0x{:x}, block[2]=0x{:x}, block[3]=0x{:x}", 
-                   new_ino, updated_inode.block[0], updated_inode.block[1], updated_inode.block[2], updated_inode.block[3]);
-        } else {
-            // Traditional block mapping
-            updated_inode.block[0] = block_num;
-            debug!("Created directory inode {} with traditional format: block[0]=0x{:x}", new_ino, block_num);
-        }
-        
+        self.map_only_block(&mut updated_inode, block_num)?;
+
         updated_inode.size = dir_data.len() as u64;
-        updated_inode.blocks = 1;
+        updated_inode.charge_block(self.superblock.block_size());
 
         // Write inode
         self.write_inode(&updated_inode)?;
@@ -685,73 +2792,154 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
 
         // Update parent directory's links count
         let mut parent_inode_updated = parent_inode;
-        parent_inode_updated.links_count += 1;
+        self.inc_dir_links(&mut parent_inode_updated);
         self.write_inode(&parent_inode_updated)?;
 
         Ok(new_ino)
     }
 
+    /// Map `block` as the only block of the new inode `inode`
+    #[cfg(not(feature = "read-only"))]
+    fn map_only_block(&self, inode: &mut Inode, block: u64) -> Ext4Result<()> {
+        if inode.inode_flags().contains(InodeFlags::EXTENTS) {
+            extent::set_inline_block(&mut inode.block, 0, block)
+        } else {
+            inode.block[0] = inode.indirect_block_number(block)?;
+            Ok(())
+        }
+    }
+
     /// Create a new file
+    #[cfg(not(feature = "read-only"))]
     pub fn create_file(&mut self, parent: u32, name: &str, mode: InodeMode) -> Ext4Result<u32> {
-        if self.mount_options.read_only {
-            return Err(Ext4Error::ReadOnly);
-        }
+        self.create_file_bytes(parent, name.as_bytes(), mode)
+    }
 
-        // Check if file already exists
-        let parent_inode = self.get_inode(parent)?;
-        if !parent_inode.mode.contains(InodeMode::IFDIR) {
-            return Err(Ext4Error::NotADirectory);
-        }
+    /// Create a new file with a name that may not be valid UTF-8
+    #[cfg(not(feature = "read-only"))]
+    pub fn create_file_bytes(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        mode: InodeMode,
+    ) -> Ext4Result<u32> {
+        let builder = InodeBuilder::file().mode(mode.bits());
+        self.create_atomically(parent, name, |fs| fs.create_node_inner(parent, name, &builder))
+    }
 
-        let dir_entries = self.read_dir(parent)?;
-        if dir_entries.iter().any(|e| e.name == name) {
-            return Err(Ext4Error::FileExists);
-        }
+    /// Create an inode without blocks, such as an empty file
+    #[cfg(not(feature = "read-only"))]
+    fn create_node_inner(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        builder: &InodeBuilder,
+    ) -> Ext4Result<u32> {
+        self.check_new_entry(parent, name)?;
 
         // Allocate new inode
-        let new_ino = self.alloc_inode()?;
-        let mut new_inode = Inode::new(new_ino);
-        new_inode.mode = mode | InodeMode::IFREG; // Set as regular file
-        new_inode.links_count = 1; // One link from parent directory
+        let new_inode = self.new_inode(builder)?;
+        let new_ino = new_inode.ino;
 
         // Write inode (no blocks allocated initially for empty file)
         self.write_inode(&new_inode)?;
 
         // Add entry to parent directory
-        self.add_dir_entry(parent, new_ino, name, InodeType::File)?;
+        self.add_dir_entry(parent, new_ino, name, new_inode.inode_type())?;
 
         Ok(new_ino)
     }
 
+    /// Check that `name` can be created in directory `parent`, returning
+    /// the directory
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn check_new_entry(&self, parent: u32, name: &[u8]) -> Ext4Result<Inode> {
+        validate_name(name)?;
+        self.check_writable()?;
+
+        // Check if the name already exists
+        let parent_inode = self.get_inode(parent)?;
+        parent_inode.check_modify()?;
+        if !parent_inode.mode.contains(InodeMode::IFDIR) {
+            return Err(Ext4Error::NotADirectory);
+        }
+
+        match self.lookup_untraced(parent, name) {
+            Ok(_) => Err(Ext4Error::FileExists),
+            Err(Ext4Error::InodeNotFound) => Ok(parent_inode),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Add an entry to a directory
+    #[cfg(not(feature = "read-only"))]
     fn add_dir_entry(
         &mut self,
         dir_ino: u32,
         ino: u32,
-        name: &str,
+        name: &[u8],
         file_type: InodeType,
     ) -> Ext4Result<()> {
-        let dir_inode = self.get_inode(dir_ino)?;
-        let block_size = self.superblock.block_size();
+        let mut dir_inode = self.get_inode(dir_ino)?;
 
-        // Read directory data
-        let mut dir_data = Vec::new();
-        for i in 0..dir_inode.block_count(block_size) {
-            let block_num = dir_inode.get_block_number(i * block_size as u64, block_size, self)?;
-            if block_num == 0 {
-                continue;
-            }
+        // Calculate proper record length (aligned to 4 bytes)
+        let name_len = name.len();
+        let rec_len = ((8 + name_len + 3) & !3) as u16;
+        let entry = DirectoryEntry {
+            ino,
+            rec_len,
+            name_len: name_len as u8,
+            file_type: self.dir_file_type(file_type),
+            name: FileName::from(name),
+        };
+        if self.is_indexed(&dir_inode) && self.dx_add_entry(&mut dir_inode, entry.clone())? {
+            return Ok(());
+        }
 
-            let mut block_buf = vec![0u8; block_size as usize];
-            self.read_block(block_num, &mut block_buf)?;
-            dir_data.extend_from_slice(&block_buf);
+        let mut dir = self.read_directory(&dir_inode)?;
+        dir.add_entry(entry);
+        self.write_directory(&mut dir_inode, &dir)
+    }
+
+    /// Fail if directory `dir` can't take another subdirectory
+    ///
+    /// Only filesystems without the `dir_nlink` feature have a limit.
+    #[cfg(not(feature = "read-only"))]
+    fn check_dir_link_max(&self, dir: &Inode) -> Ext4Result<()> {
+        if !self.superblock.has_dir_nlink() && dir.links_count >= EXT4_LINK_MAX {
+            return Err(Ext4Error::TooManyLinks);
+        }
+        Ok(())
+    }
+
+    /// Count a new subdirectory in the link count of directory `dir`
+    ///
+    /// Past [`EXT4_LINK_MAX`] the count is set to 1 and no longer maintained.
+    #[cfg(not(feature = "read-only"))]
+    fn inc_dir_links(&self, dir: &mut Inode) {
+        if dir.links_count == 1 {
+            return;
+        }
+        dir.links_count += 1;
+        if dir.links_count > EXT4_LINK_MAX && self.superblock.has_dir_nlink() {
+            debug!("Directory {} exceeds the link limit, no longer counting", dir.ino);
+            dir.links_count = 1;
         }
+    }
 
-        // Parse directory
-        let mut dir = Directory::from_bytes(&dir_data)?;
+    /// Remove a subdirectory from the link count of directory `dir`
+    #[cfg(not(feature = "read-only"))]
+    fn dec_dir_links(&self, dir: &mut Inode) {
+        if dir.links_count > 2 {
+            dir.links_count -= 1;
+        }
+    }
 
-        // Add new entry
-        let file_type_num = match file_type {
+    /// Directory entry file type of `inode_type`, 0 without the filetype
+    /// feature
+    fn dir_file_type(&self, inode_type: InodeType) -> u8 {
+        match inode_type {
+            _ if !self.superblock.has_filetype() => 0,
             InodeType::File => 1,
             InodeType::Directory => 2,
             InodeType::CharDevice => 3,
@@ -759,86 +2947,369 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
             InodeType::Fifo => 5,
             InodeType::Socket => 6,
             InodeType::SymLink => 7,
-        };
+        }
+    }
 
-        // Calculate proper record length (aligned to 4 bytes)
-        let name_len = name.len();
-        let rec_len = ((8 + name_len + 3) & !3) as u16;
+    /// Read and parse all entries of the directory `dir_inode`
+    #[cfg(not(feature = "read-only"))]
+    fn read_directory(&self, dir_inode: &Inode) -> Ext4Result<Directory> {
+        if let Some(data) = self.inline_dir_data(dir_inode)? {
+            return Directory::from_bytes(&data);
+        }
+        let block_size = self.superblock.block_size();
+        let mut dir_data = self.try_dir_buffer(dir_inode)?;
+        let mut block_buf = try_zeroed(block_size as usize)?;
+        for i in 0..dir_inode.block_count(block_size) {
+            let block_num = dir_inode.get_block_number(i * block_size as u64, block_size, self)?;
+            if block_num == 0 {
+                continue;
+            }
 
-        dir.add_entry(DirectoryEntry {
-            ino,
-            rec_len,
-            name_len: name_len as u8,
-            file_type: file_type_num,
-            name: String::from(name),
-        });
+            self.read_dir_data_block(dir_inode, block_num, &mut block_buf)?;
+            dir_data.extend_from_slice(&block_buf);
+        }
 
-        // Write back directory data
-        let new_dir_data = dir.to_bytes()?;
-        let required_blocks = (new_dir_data.len() + block_size as usize - 1) / block_size as usize;
-        let current_blocks = dir_inode.block_count(block_size) as usize;
+        Directory::from_bytes(&dir_data)
+    }
 
-        // Clone the inode to update it
-        let mut updated_inode = dir_inode.clone();
+    /// Allocate room for all the blocks of directory `dir_inode`
+    fn try_dir_buffer(&self, dir_inode: &Inode) -> Ext4Result<Vec<u8>> {
+        let block_size = self.superblock.block_size();
+        let len = dir_inode.block_count(block_size) * block_size as u64;
+        try_with_capacity(usize::try_from(len).map_err(|_| Ext4Error::NoMemory)?)
+    }
 
-        // Allocate more blocks if needed
-        if required_blocks > current_blocks {
-            for i in current_blocks..required_blocks {
-                let new_block = self.alloc_block()?;
-                updated_inode.set_block(i as u64, new_block, block_size, self)?;
+    /// Find `name` in the blocks of directory `dir_inode`, returning its
+    /// inode number
+    ///
+    /// Blocks are searched one at a time with [`find_entry_in_block`], so
+    /// nothing is allocated per entry.
+    fn find_in_directory(&self, dir_inode: &Inode, name: &[u8]) -> Ext4Result<u32> {
+        if let Some(data) = self.inline_dir_data(dir_inode)? {
+            return find_entry_in_block(&data, name).ok_or(Ext4Error::InodeNotFound);
+        }
+        let block_size = self.superblock.block_size();
+        let mut block = vec![0u8; block_size as usize];
+        for i in 0..dir_inode.block_count(block_size) {
+            let block_num = dir_inode.get_block_number(i * block_size as u64, block_size, self)?;
+            if block_num == 0 {
+                continue;
             }
+            self.read_dir_data_block(dir_inode, block_num, &mut block)?;
+            if let Some(ino) = find_entry_in_block(&block, name) {
+                return Ok(ino);
+            }
+        }
+        // The root directory is its own parent even without the entries
+        if dir_inode.ino == EXT4_ROOT_INO && (name == b"." || name == b"..") {
+            return Ok(EXT4_ROOT_INO);
         }
+        Err(Ext4Error::InodeNotFound)
+    }
 
-        // Write directory data to blocks
-        for (i, chunk) in new_dir_data.chunks(block_size as usize).enumerate() {
-            let block_num = updated_inode.get_block_number(
-                (i as u64) * (block_size as u64),
-                block_size,
-                self,
-            )?;
+    /// Write `dir` back as the contents of the directory `dir_inode`
+    ///
+    /// The directory grows as needed but never shrinks: blocks left without
+    /// entries hold a single unused record. An indexed directory gets its
+    /// hashed index rebuilt, and is only written as a linear directory
+    /// without it if the filesystem's default hash is unknown. An inline
+    /// directory stays inline while its entries fit.
+    #[cfg(not(feature = "read-only"))]
+    fn write_directory(&mut self, dir_inode: &mut Inode, dir: &Directory) -> Ext4Result<()> {
+        if dir_inode.has_inline_data() {
+            return self.write_inline_directory(dir_inode, dir);
+        }
+        let block_size = self.superblock.block_size();
+        let current_blocks = dir_inode.block_count(block_size) as usize;
+        let indexed = match self.is_indexed(dir_inode) {
+            true => self.dx_blocks(dir_inode, dir, current_blocks)?,
+            false => None,
+        };
+        let rebuilt_index = indexed.is_some();
+        let mut data = match indexed {
+            Some(data) => data,
+            None => self.dir_blocks(dir_inode, dir)?,
+        };
+        if !self.superblock.has_large_dir() && data.len() as u64 > u32::MAX as u64 {
+            warn!("Directory {} would outgrow 4 GiB without large_dir", dir_inode.ino);
+            return Err(Ext4Error::NoSpaceLeft);
+        }
+        let required_blocks = data.len() / block_size as usize;
 
+        // Allocate more blocks if needed, all of them before mapping any so
+        // that running out of space leaves the directory untouched
+        let mut new_blocks = Vec::new();
+        for _ in current_blocks..required_blocks {
+            new_blocks.push(self.alloc_data_block(dir_inode)?);
+        }
+        for (i, new_block) in (current_blocks..).zip(new_blocks) {
+            dir_inode.charge_block(block_size);
+            dir_inode.set_block(i as u64, new_block, block_size, self)?;
+        }
+
+        for _ in required_blocks..current_blocks {
+            data.extend_from_slice(&self.dir_blocks(dir_inode, &Directory::new())?);
+        }
+
+        // Write directory data to blocks
+        for (i, chunk) in data.chunks(block_size as usize).enumerate() {
+            let block_num =
+                dir_inode.get_block_number(i as u64 * block_size as u64, block_size, self)?;
             if block_num == 0 {
                 return Err(Ext4Error::BlockNotFound);
             }
+            self.write_block(block_num, chunk)?;
+        }
 
-            let mut block_buf = vec![0u8; block_size as usize];
-            block_buf[..chunk.len()].copy_from_slice(chunk);
-            self.write_block(block_num, &block_buf)?;
+        self.caches.borrow_mut().invalidate_dir(dir_inode.ino);
+        if !rebuilt_index && dir_inode.inode_flags().contains(InodeFlags::INDEX) {
+            debug!("Dropping hashed index of directory {}", dir_inode.ino);
+            dir_inode.flags &= !InodeFlags::INDEX.bits();
         }
+        dir_inode.size = data.len() as u64;
+        self.write_inode(dir_inode)
+    }
 
-        // Update directory inode size and write it back
-        updated_inode.size = new_dir_data.len() as u64;
-        updated_inode.blocks =
-            ((new_dir_data.len() + block_size as usize - 1) / block_size as usize) as u64;
-        self.write_inode(&updated_inode)?;
+    /// Lay out the entries of `dir` in the blocks of directory `dir_inode`
+    ///
+    /// Under `metadata_csum` each block ends with a dirent tail holding its
+    /// checksum. Without entries, the result is a single block holding one
+    /// unused record.
+    #[cfg(not(feature = "read-only"))]
+    fn dir_blocks(&self, dir_inode: &Inode, dir: &Directory) -> Ext4Result<Vec<u8>> {
+        let block_size = self.superblock.block_size() as usize;
+        let tail = match self.superblock.has_metadata_csum() {
+            true => DIRENT_TAIL_SIZE,
+            false => 0,
+        };
+        let space = block_size - tail;
+        let mut entries = dir.to_blocks(space)?;
+        if entries.is_empty() {
+            entries.resize(space, 0);
+            entries[4..6].copy_from_slice(&(space as u16).to_le_bytes());
+        }
+        if tail == 0 {
+            return Ok(entries);
+        }
 
-        Ok(())
+        let seed = self.inode_csum_seed(dir_inode);
+        let mut data = Vec::new();
+        for chunk in entries.chunks(space) {
+            data.extend_from_slice(chunk);
+            data.resize(data.len() + tail, 0);
+            let start = data.len() - block_size;
+            set_dirent_tail(&mut data[start..], seed);
+        }
+        Ok(data)
     }
 
-    /// Write an inode to disk
-    fn write_inode(&self, inode: &Inode) -> Ext4Result<()> {
-        let block_group = (inode.ino - 1) / self.superblock.inodes_per_group();
-        let index = (inode.ino - 1) % self.superblock.inodes_per_group();
+    /// Return `block` to the block bitmap of its group
+    #[cfg(not(feature = "read-only"))]
+    fn free_block(&mut self, block: u64) -> Ext4Result<()> {
+        self.check_writable()?;
+        let first_data_block = self.superblock.first_data_block() as u64;
+        if block < first_data_block || block >= self.superblock.blocks_count() {
+            return Err(Ext4Error::InvalidArg);
+        }
 
-        if block_group as usize >= self.block_groups.len() {
-            return Err(Ext4Error::InodeNotFound);
+        let rel = block - first_data_block;
+        let blocks_per_group = self.superblock.blocks_per_group() as u64;
+        let group = (rel / blocks_per_group) as usize;
+        let bit = (rel % blocks_per_group) as usize;
+        let freed = self.change_bitmap(group, false, |bitmap| {
+            if !bitmap.is_set(bit) {
+                return Ok(None);
+            }
+            bitmap.clear(bit).map(Some)
+        })?;
+        if freed.is_none() {
+            warn!("Freeing block {} which is already free", block);
+            return Ok(());
         }
 
-        let bg_desc = &self.block_groups[block_group as usize];
-        let inode_table_block = bg_desc.inode_table();
-        let inode_size = self.superblock.inode_size();
-        let inodes_per_block = self.superblock.block_size() / inode_size as u32;
-        let block_offset = index / inodes_per_block;
-        let inode_offset = (index % inodes_per_block) * inode_size as u32;
+        let new_free_count = self.block_groups[group].free_blocks_count() + 1;
+        self.block_groups[group].set_free_blocks_count(new_free_count);
+        self.group_changed(group)?;
+        self.check_group_accounting(group)
+    }
+
+    /// Return inode `ino` to the inode bitmap of its group, and take it out
+    /// of the `used_dirs` of the group if it is a directory
+    #[cfg(not(feature = "read-only"))]
+    fn free_inode(&mut self, ino: u32, dir: bool) -> Ext4Result<()> {
+        self.check_writable()?;
+        if ino == 0 || ino > self.superblock.inodes_count() {
+            return Err(Ext4Error::InvalidArg);
+        }
+
+        let group = ((ino - 1) / self.superblock.inodes_per_group()) as usize;
+        let bit = ((ino - 1) % self.superblock.inodes_per_group()) as usize;
+        let freed = self.change_bitmap(group, true, |bitmap| {
+            if !bitmap.is_set(bit) {
+                return Ok(None);
+            }
+            bitmap.clear(bit).map(Some)
+        })?;
+        if freed.is_none() {
+            warn!("Freeing inode {} which is already free", ino);
+            return Ok(());
+        }
 
+        let new_free_count = self.block_groups[group].free_inodes_count() + 1;
+        self.block_groups[group].set_free_inodes_count(new_free_count);
+        if dir {
+            let used_dirs = self.block_groups[group].used_dirs_count().saturating_sub(1);
+            self.block_groups[group].set_used_dirs_count(used_dirs);
+        }
+        self.group_changed(group)?;
+        self.check_group_accounting(group)
+    }
+
+    /// Free `inode` and its data blocks after its last link is gone
+    ///
+    /// The inode stays in the orphan file until it is freed, so that a crash
+    /// halfway through is cleaned up at the next mount. If it is still open,
+    /// it is only written without links and freed when its last file is
+    /// closed, so that readers keep its blocks.
+    #[cfg(not(feature = "read-only"))]
+    fn release_inode(&mut self, inode: &mut Inode) -> Ext4Result<()> {
+        self.release_closed()?;
+        self.add_orphan(inode.ino)?;
+        if self.is_open(inode.ino) {
+            debug!("Inode {} is still open, releasing it on its last close", inode.ino);
+            inode.links_count = 0;
+            self.unlinked_open.insert(inode.ino);
+            return self.write_inode(inode);
+        }
+        self.free_unlinked_inode(inode)?;
+        self.remove_orphan(inode.ino)
+    }
+
+    /// Free unlinked `inode` and its data blocks
+    ///
+    /// The inode is marked deleted first, so that if this is cut short, it
+    /// is still found unlinked when cleaning up orphans. The blocks holding
+    /// its block map, indirect blocks or extent tree nodes, are freed after
+    /// its data blocks.
+    #[cfg(not(feature = "read-only"))]
+    fn free_unlinked_inode(&mut self, inode: &mut Inode) -> Ext4Result<()> {
+        debug!("Releasing inode {}", inode.ino);
+        inode.links_count = 0;
+        inode.dtime = self.now().to_raw().0;
+        self.write_inode(inode)?;
+
+        let block_size = self.superblock.block_size();
+        let cluster_size = self.superblock.cluster_size();
+        // Fast symlinks and inline data keep no block numbers in i_block
+        if inode.blocks != 0
+            && !inode.is_fast_symlink(block_size, cluster_size)
+            && !inode.has_inline_data()
+        {
+            let map_blocks = match inode.inode_flags().contains(InodeFlags::EXTENTS) {
+                true => extent::extent_node_blocks(self, inode)?,
+                false => inode.indirect_blocks(self)?,
+            };
+            for i in 0..inode.block_count(block_size) {
+                let block = inode.get_block_number(i * block_size as u64, block_size, self)?;
+                if block != 0 {
+                    self.free_block(block)?;
+                }
+            }
+            for block in map_blocks {
+                self.free_block(block)?;
+            }
+        }
+        self.alloc_hints.forget(inode.ino);
+        self.forget_locks(inode.ino);
+        self.forget_watches(inode.ino);
+
+        self.caches.borrow_mut().invalidate_dir(inode.ino);
+        self.free_inode(inode.ino, inode.is_dir())
+    }
+
+    /// Write an inode, passing its inode table block through a journal
+    /// transaction first if there is one
+    ///
+    /// Once the transaction is committed, a crash leaves either the old
+    /// inode or, after the journal is replayed, the new one. The log is
+    /// emptied once the inode is written in place; failing to do so aborts
+    /// the journal, as the log may still hold the transaction. Journals
+    /// whose log format isn't written here get a plain
+    /// [`write_inode`](Self::write_inode).
+    #[cfg(not(feature = "read-only"))]
+    fn write_inode_journaled(&mut self, inode: &Inode) -> Ext4Result<()> {
+        let Some(mut journal) = self.journal.take() else {
+            return self.write_inode(inode);
+        };
+        if !journal.is_loggable() {
+            self.journal = Some(journal);
+            return self.write_inode(inode);
+        }
+        let result = self.log_inode(&mut journal, inode);
+        self.journal = Some(journal);
+        result?;
+
+        let result = self
+            .write_inode(inode)
+            .and_then(|()| self.journal.as_ref().unwrap().checkpoint(self));
+        if let Err(e) = result {
+            warn!("Failed to checkpoint inode {}: {:?}", inode.ino, e);
+            self.abort_journal(-journal::EIO)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Commit a transaction holding the inode table block of `inode`, with
+    /// `inode` written into it
+    ///
+    /// See [`Journal::commit_transaction`] for how it reaches the log.
+    #[cfg(not(feature = "read-only"))]
+    fn log_inode(&mut self, journal: &mut Journal, inode: &Inode) -> Ext4Result<()> {
+        self.check_writable()?;
+        let (block, inode_offset) = self.inode_location(inode.ino)?;
+        let inode_size = self.superblock.inode_size() as usize;
         let mut buf = vec![0u8; self.superblock.block_size() as usize];
-        self.read_block(inode_table_block + block_offset, &mut buf)?;
+        self.read_block(block, &mut buf)?;
+        self.encode_inode(inode, &mut buf[inode_offset..inode_offset + inode_size]);
+
+        journal.begin_transaction()?;
+        if let Err(e) = journal.add_block(block, buf, BlockType::Data) {
+            journal.abort_transaction()?;
+            return Err(e);
+        }
+        let span = self.span_enter(TraceOp::Commit, inode.ino);
+        let result = journal.commit_transaction(self);
+        self.span_exit(span, &result);
+        result
+    }
+
+    /// Write an inode to disk
+    #[cfg(not(feature = "read-only"))]
+    fn write_inode(&self, inode: &Inode) -> Ext4Result<()> {
+        let (block, inode_offset) = self.inode_location(inode.ino)?;
+        let inode_size = self.superblock.inode_size() as usize;
 
-        let inode_data = inode.to_bytes();
-        buf[inode_offset as usize..(inode_offset + inode_size as u32) as usize]
-            .copy_from_slice(&inode_data);
+        let mut buf = self.take_block_buf();
+        self.read_block(block, &mut buf)?;
 
-        self.write_block(inode_table_block + block_offset, &buf)?;
+        self.encode_inode(inode, &mut buf[inode_offset..inode_offset + inode_size]);
+
+        self.write_block(block, &buf)?;
+        // Cache the inode as it reads back, which differs for owners the
+        // ID map squashes and for unlinked open files
+        let cached = if self.mount_options.id_map.is_some()
+            || self.unlinked_open.contains(&inode.ino)
+        {
+            self.parse_inode(&buf[inode_offset..inode_offset + inode_size], inode.ino)?
+        } else {
+            inode.clone()
+        };
+        self.put_block_buf(buf);
+        self.caches
+            .borrow_mut()
+            .inodes
+            .insert(inode.ino, cached, Caches::inode_size());
         Ok(())
     }
 }
@@ -846,10 +3317,8 @@ impl<D: axdriver_block::BlockDriverOps> Ext4FileSystem<D> {
 impl Inode {
     /// Create a new directory inode
     pub fn new_directory(ino: u32, mode: InodeMode) -> Self {
-        let mut inode = Self::new(ino);
-        inode.mode = mode;
-        inode.mode.insert(InodeMode::IFDIR);
-        inode.links_count = 2; // . and ..
+        let mut inode = InodeBuilder::dir().mode(mode.bits()).build();
+        inode.ino = ino;
         inode
     }
 }
@@ -0,0 +1,259 @@
+//! Advisory file locks
+//!
+//! Byte-range locks as `fcntl(F_SETLK)` places them, held in memory only.
+//! Each lock belongs to an owner chosen by the caller, such as a process
+//! for POSIX locks or an open file description for `flock`, which locks
+//! the whole file. Locks of one owner never conflict with each other: a new
+//! lock replaces whatever the owner held in its range, splitting or merging
+//! neighbouring locks as needed. A lock conflicts with a lock of another
+//! owner over an overlapping range when either of them is exclusive.
+//!
+//! Nothing here blocks. An owner that wants to wait for a conflicting lock
+//! is recorded as waiting on its holder, and is refused with `Deadlock` if
+//! that holder is itself waiting, directly or not, on the owner.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{Ext4Error, Ext4FileSystem, Ext4Result};
+
+/// Kind of an advisory lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Read lock, shared with other shared locks
+    Shared,
+    /// Write lock, conflicting with any lock of another owner
+    Exclusive,
+}
+
+/// Advisory lock on a range of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileLock {
+    /// Owner of the lock
+    pub owner: u64,
+    /// Kind of the lock
+    pub kind: LockKind,
+    /// First byte locked
+    pub start: u64,
+    /// Number of bytes locked; 0 locks to the end of the file, however far
+    /// it grows
+    pub len: u64,
+}
+
+/// Lock held on bytes `start..end`, `end` being `u64::MAX` for locks to the
+/// end of the file
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    owner: u64,
+    kind: LockKind,
+    start: u64,
+    end: u64,
+}
+
+impl Held {
+    fn new(lock: &FileLock) -> Ext4Result<Self> {
+        let end = match lock.len {
+            0 => u64::MAX,
+            len => lock.start.checked_add(len).ok_or(Ext4Error::InvalidArg)?,
+        };
+        Ok(Self {
+            owner: lock.owner,
+            kind: lock.kind,
+            start: lock.start,
+            end,
+        })
+    }
+
+    fn to_lock(self) -> FileLock {
+        let len = match self.end {
+            u64::MAX => 0,
+            end => end - self.start,
+        };
+        FileLock {
+            owner: self.owner,
+            kind: self.kind,
+            start: self.start,
+            len,
+        }
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+
+    fn conflicts(&self, other: &Held) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.kind == LockKind::Exclusive || other.kind == LockKind::Exclusive)
+    }
+}
+
+/// Locks held on open inodes, and the owners waiting for them
+#[derive(Debug, Default)]
+pub(crate) struct LockTable {
+    /// Locks held, by inode
+    held: BTreeMap<u32, Vec<Held>>,
+    /// Owner each waiting owner waits on
+    waits: BTreeMap<u64, u64>,
+}
+
+impl LockTable {
+    /// First lock on `ino` that conflicts with `lock`
+    fn conflict(&self, ino: u32, lock: &Held) -> Option<Held> {
+        self.held.get(&ino)?.iter().find(|held| held.conflicts(lock)).copied()
+    }
+
+    /// Drop what `owner` holds on bytes `start..end` of `ino`, keeping the
+    /// parts of its locks outside them
+    fn carve(&mut self, ino: u32, owner: u64, start: u64, end: u64) {
+        let Some(locks) = self.held.get_mut(&ino) else {
+            return;
+        };
+        let mut kept = Vec::with_capacity(locks.len() + 1);
+        for held in locks.drain(..) {
+            if held.owner != owner || !held.overlaps(start, end) {
+                kept.push(held);
+                continue;
+            }
+            if held.start < start {
+                kept.push(Held { end: start, ..held });
+            }
+            if end < held.end {
+                kept.push(Held { start: end, ..held });
+            }
+        }
+        *locks = kept;
+        if locks.is_empty() {
+            self.held.remove(&ino);
+        }
+    }
+
+    /// Give `lock` on `ino` to its owner, merging it with the owner's
+    /// adjacent locks of the same kind
+    fn insert(&mut self, ino: u32, mut lock: Held) {
+        self.carve(ino, lock.owner, lock.start, lock.end);
+        let locks = self.held.entry(ino).or_default();
+        locks.retain(|held| {
+            let adjacent = held.end == lock.start || lock.end == held.start;
+            if held.owner != lock.owner || held.kind != lock.kind || !adjacent {
+                return true;
+            }
+            lock.start = lock.start.min(held.start);
+            lock.end = lock.end.max(held.end);
+            false
+        });
+        locks.push(lock);
+    }
+
+    /// Check whether `owner` waiting on `holder` closes a cycle of waiting
+    /// owners
+    fn would_deadlock(&self, owner: u64, holder: u64) -> bool {
+        let mut current = holder;
+        // Each owner waits on at most one other, so a chain longer than the
+        // number of waiters must loop without passing through `owner`
+        for _ in 0..=self.waits.len() {
+            if current == owner {
+                return true;
+            }
+            match self.waits.get(&current) {
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Place `lock` on inode `ino`, as `fcntl(F_SETLK)`
+    ///
+    /// Fails with `WouldBlock`, changing nothing, if another owner holds a
+    /// conflicting lock.
+    pub fn set_lock(&self, ino: u32, lock: FileLock) -> Ext4Result<()> {
+        let held = Held::new(&lock)?;
+        let mut locks = self.locks.borrow_mut();
+        if let Some(other) = locks.conflict(ino, &held) {
+            trace!("Lock of {} on inode {} conflicts with {}", lock.owner, ino, other.owner);
+            return Err(Ext4Error::WouldBlock);
+        }
+        locks.waits.remove(&lock.owner);
+        locks.insert(ino, held);
+        Ok(())
+    }
+
+    /// Place `lock` on inode `ino`, or record its owner as waiting for it,
+    /// as `fcntl(F_SETLKW)`
+    ///
+    /// Returns `false` if another owner holds a conflicting lock; the caller
+    /// then sleeps and tries again once a lock on `ino` is released, or
+    /// calls [`cancel_wait`](Self::cancel_wait) if it gives up. Fails with
+    /// `Deadlock` instead if that owner waits, directly or through others,
+    /// for a lock of the owner of `lock`.
+    pub fn wait_lock(&self, ino: u32, lock: FileLock) -> Ext4Result<bool> {
+        let held = Held::new(&lock)?;
+        let mut locks = self.locks.borrow_mut();
+        if let Some(other) = locks.conflict(ino, &held) {
+            if locks.would_deadlock(lock.owner, other.owner) {
+                debug!("Lock of {} on inode {} would deadlock", lock.owner, ino);
+                locks.waits.remove(&lock.owner);
+                return Err(Ext4Error::Deadlock);
+            }
+            locks.waits.insert(lock.owner, other.owner);
+            return Ok(false);
+        }
+        locks.waits.remove(&lock.owner);
+        locks.insert(ino, held);
+        Ok(true)
+    }
+
+    /// Stop counting `owner` as waiting for a lock
+    pub fn cancel_wait(&self, owner: u64) {
+        self.locks.borrow_mut().waits.remove(&owner);
+    }
+
+    /// First lock on inode `ino` that would keep `lock` from being placed,
+    /// as `fcntl(F_GETLK)`
+    pub fn test_lock(&self, ino: u32, lock: FileLock) -> Ext4Result<Option<FileLock>> {
+        let held = Held::new(&lock)?;
+        Ok(self.locks.borrow().conflict(ino, &held).map(Held::to_lock))
+    }
+
+    /// Release what `owner` holds on `len` bytes of inode `ino` from
+    /// `start`, 0 meaning to the end of the file, as `F_UNLCK`
+    pub fn unlock(&self, ino: u32, owner: u64, start: u64, len: u64) -> Ext4Result<()> {
+        let range = Held::new(&FileLock {
+            owner,
+            kind: LockKind::Shared,
+            start,
+            len,
+        })?;
+        self.locks.borrow_mut().carve(ino, owner, range.start, range.end);
+        Ok(())
+    }
+
+    /// Release every lock `owner` holds on inode `ino`, as closing a file
+    /// does, and stop counting it as waiting for one
+    pub fn release_locks(&self, ino: u32, owner: u64) {
+        let mut locks = self.locks.borrow_mut();
+        locks.waits.remove(&owner);
+        locks.carve(ino, owner, 0, u64::MAX);
+    }
+
+    /// Locks held on inode `ino`, in no particular order
+    pub fn locks(&self, ino: u32) -> Vec<FileLock> {
+        self.locks
+            .borrow()
+            .held
+            .get(&ino)
+            .map_or_else(Vec::new, |locks| locks.iter().map(|held| held.to_lock()).collect())
+    }
+
+    /// Drop the locks left on inode `ino` once it is freed, so that they
+    /// don't carry over to the next inode of that number
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn forget_locks(&mut self, ino: u32) {
+        self.locks.get_mut().held.remove(&ino);
+    }
+}
@@ -0,0 +1,167 @@
+//! File metadata as reported by `stat` and `statx`
+
+use bitflags::bitflags;
+
+use crate::{Inode, InodeFlags, InodeMode, InodeTimes, InodeType, Timestamp};
+
+bitflags! {
+    /// Fields of [`Metadata`] holding real values, as the `STATX_*` mask
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StatxMask: u32 {
+        /// File type
+        const TYPE = 0x0001;
+        /// Permission bits
+        const MODE = 0x0002;
+        /// Link count
+        const NLINK = 0x0004;
+        /// Owner
+        const UID = 0x0008;
+        /// Group
+        const GID = 0x0010;
+        /// Access time
+        const ATIME = 0x0020;
+        /// Modification time
+        const MTIME = 0x0040;
+        /// Status change time
+        const CTIME = 0x0080;
+        /// Inode number
+        const INO = 0x0100;
+        /// Size
+        const SIZE = 0x0200;
+        /// Allocated blocks
+        const BLOCKS = 0x0400;
+        /// Birth time
+        const BTIME = 0x0800;
+    }
+}
+
+impl StatxMask {
+    /// Fields of a plain `stat`, valid on every inode
+    pub const BASIC_STATS: Self = Self::from_bits_truncate(0x07ff);
+}
+
+bitflags! {
+    /// File attributes reported by `statx`, as the `STATX_ATTR_*` values
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StatxAttributes: u64 {
+        /// Compressed by the filesystem
+        const COMPRESSED = 0x0000_0004;
+        /// Cannot be modified, deleted or renamed
+        const IMMUTABLE = 0x0000_0010;
+        /// Writes may only append
+        const APPEND = 0x0000_0020;
+        /// Skipped by backups
+        const NODUMP = 0x0000_0040;
+        /// Contents are encrypted
+        const ENCRYPTED = 0x0000_0800;
+        /// Contents are protected by fs-verity
+        const VERITY = 0x0010_0000;
+    }
+}
+
+impl StatxAttributes {
+    /// Attributes ext4 can report, the `stx_attributes_mask` of every inode
+    pub const SUPPORTED: Self = Self::all();
+
+    /// Attributes set by the flags of `inode`
+    fn from_inode(inode: &Inode) -> Self {
+        let flags = inode.inode_flags();
+        [
+            (InodeFlags::COMPR, Self::COMPRESSED),
+            (InodeFlags::IMMUTABLE, Self::IMMUTABLE),
+            (InodeFlags::APPEND, Self::APPEND),
+            (InodeFlags::NODUMP, Self::NODUMP),
+            (InodeFlags::ENCRYPT, Self::ENCRYPTED),
+            (InodeFlags::VERITY, Self::VERITY),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .fold(Self::empty(), |attrs, (_, attr)| attrs | attr)
+    }
+}
+
+/// Metadata of an inode, decoded from its on-disk fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Inode number
+    pub ino: u32,
+    /// File type
+    pub inode_type: InodeType,
+    /// File type and permission bits
+    pub mode: InodeMode,
+    /// Number of hard links
+    pub nlink: u32,
+    /// User ID
+    pub uid: u32,
+    /// Group ID
+    pub gid: u32,
+    /// File size in bytes
+    pub size: u64,
+    /// Allocated space in 512-byte units
+    pub blocks: u64,
+    /// Filesystem block size, the preferred I/O size
+    pub block_size: u32,
+    /// Timestamps with nanoseconds
+    pub times: InodeTimes,
+    /// Generation number, changed when the inode is reused
+    pub generation: u32,
+    /// Inode flags (`chattr` attributes)
+    pub flags: u32,
+    /// Fields holding real values; birth time needs large inodes
+    pub mask: StatxMask,
+    /// `statx` attributes of the inode
+    pub attributes: StatxAttributes,
+    /// Attributes the filesystem can report, set or not
+    pub attributes_mask: StatxAttributes,
+}
+
+impl Metadata {
+    /// Decode the metadata of `inode` on a filesystem with `block_size`
+    pub fn new(inode: &Inode, block_size: u32) -> Self {
+        Self {
+            ino: inode.ino,
+            inode_type: inode.inode_type(),
+            mode: inode.mode,
+            nlink: inode.links_count as u32,
+            uid: inode.full_uid(),
+            gid: inode.full_gid(),
+            size: inode.size,
+            blocks: inode.sectors(block_size),
+            block_size,
+            times: inode.times(),
+            generation: inode.generation,
+            flags: inode.flags,
+            mask: match inode.crtime() {
+                Some(_) => StatxMask::BASIC_STATS | StatxMask::BTIME,
+                None => StatxMask::BASIC_STATS,
+            },
+            attributes: StatxAttributes::from_inode(inode),
+            attributes_mask: StatxAttributes::SUPPORTED,
+        }
+    }
+
+    /// Creation time, if the inode is large enough to record it
+    pub fn birth_time(&self) -> Option<Timestamp> {
+        self.times.crtime
+    }
+
+    /// Check if this is a directory
+    pub fn is_dir(&self) -> bool {
+        self.inode_type == InodeType::Directory
+    }
+
+    /// Check if this is a regular file
+    pub fn is_file(&self) -> bool {
+        self.inode_type == InodeType::File
+    }
+
+    /// Check if this is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.inode_type == InodeType::SymLink
+    }
+
+    /// Permission bits, without the file type
+    pub fn permissions(&self) -> u16 {
+        (self.mode & !InodeMode::IFMT).bits()
+    }
+}
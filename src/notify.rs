@@ -0,0 +1,181 @@
+//! Change notification
+//!
+//! A [`Watcher`] registered with [`Ext4FileSystem::watch`] is told about
+//! changes to the inode it watches once they are made, which is enough to
+//! build an inotify-like service without polling. Directories report the
+//! names created, removed and renamed in them; any inode reports writes to
+//! its data and changes to its attributes. An entry replaced by a rename is
+//! reported as unlinked from its directory before the rename itself.
+//!
+//! Watches of an inode end when it is freed, so that they don't carry over
+//! to the next inode of that number; each is told so with a last
+//! [`Change::Removed`].
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use bitflags::bitflags;
+
+use crate::Ext4FileSystem;
+
+bitflags! {
+    /// Kinds of changes a watch reports
+    #[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+    pub struct WatchMask: u32 {
+        /// Entries created in a directory
+        const CREATE = 1 << 0;
+        /// Entries removed from a directory
+        const UNLINK = 1 << 1;
+        /// Entries renamed from or to a directory
+        const RENAME = 1 << 2;
+        /// Data written or truncated
+        const WRITE = 1 << 3;
+        /// Flags or other attributes changed
+        const ATTRIB = 1 << 4;
+    }
+}
+
+/// Change to a watched inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    /// Entry `name` linking inode `ino` was created in the directory
+    Create { name: &'a [u8], ino: u32 },
+    /// Entry `name` linking inode `ino` was removed from the directory
+    Unlink { name: &'a [u8], ino: u32 },
+    /// Entry `old_name` in `old_dir`, linking inode `ino`, was renamed to
+    /// `new_name` in `new_dir`; reported once to each directory
+    Rename {
+        old_dir: u32,
+        old_name: &'a [u8],
+        new_dir: u32,
+        new_name: &'a [u8],
+        ino: u32,
+    },
+    /// Data of the inode was written or truncated
+    Write,
+    /// Attributes of the inode changed
+    Attrib,
+    /// The inode was freed and the watch removed; reported whatever the
+    /// mask of the watch, and last
+    Removed,
+}
+
+impl Change<'_> {
+    /// Kind of the change, as selected in a [`WatchMask`]
+    pub fn mask(&self) -> WatchMask {
+        match self {
+            Change::Create { .. } => WatchMask::CREATE,
+            Change::Unlink { .. } => WatchMask::UNLINK,
+            Change::Rename { .. } => WatchMask::RENAME,
+            Change::Write => WatchMask::WRITE,
+            Change::Attrib => WatchMask::ATTRIB,
+            Change::Removed => WatchMask::empty(),
+        }
+    }
+}
+
+/// Identifier of a watch, as returned by [`Ext4FileSystem::watch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WatchId(u64);
+
+/// Receiver of changes
+///
+/// Called synchronously from the operation making the change, so it should
+/// be cheap, typically queueing the change for a waiting task. It may add
+/// and remove watches.
+pub trait Watcher: Send + Sync + core::fmt::Debug {
+    /// Inode `ino`, watched by `watch`, saw `change`
+    fn changed(&self, watch: WatchId, ino: u32, change: &Change<'_>);
+}
+
+/// Watch on one inode
+#[derive(Debug)]
+struct Watch {
+    id: WatchId,
+    #[cfg(not(feature = "read-only"))]
+    mask: WatchMask,
+    #[cfg(not(feature = "read-only"))]
+    watcher: Arc<dyn Watcher>,
+}
+
+/// Watches registered with [`Ext4FileSystem::watch`]
+#[derive(Debug, Default)]
+pub(crate) struct Watches {
+    /// Watches by inode
+    by_inode: BTreeMap<u32, Vec<Watch>>,
+    /// Identifier of the next watch
+    next_id: u64,
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Report changes of the kinds in `mask` to inode `ino` to `watcher`
+    ///
+    /// An inode can have any number of watches, even from the same watcher.
+    pub fn watch(&self, ino: u32, mask: WatchMask, watcher: Arc<dyn Watcher>) -> WatchId {
+        // Nothing changes without the write paths, so watchers are never
+        // called
+        #[cfg(feature = "read-only")]
+        let _ = (mask, watcher);
+        let mut watches = self.watches.borrow_mut();
+        let id = WatchId(watches.next_id);
+        watches.next_id += 1;
+        watches.by_inode.entry(ino).or_default().push(Watch {
+            id,
+            #[cfg(not(feature = "read-only"))]
+            mask,
+            #[cfg(not(feature = "read-only"))]
+            watcher,
+        });
+        id
+    }
+
+    /// Remove watch `id`, returning whether it was still there
+    pub fn unwatch(&self, id: WatchId) -> bool {
+        let mut watches = self.watches.borrow_mut();
+        let Some((&ino, list)) = watches
+            .by_inode
+            .iter_mut()
+            .find(|(_, list)| list.iter().any(|watch| watch.id == id))
+        else {
+            return false;
+        };
+        list.retain(|watch| watch.id != id);
+        if list.is_empty() {
+            watches.by_inode.remove(&ino);
+        }
+        true
+    }
+
+    /// Tell the watchers of inode `ino` about `change`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn notify(&self, ino: u32, change: Change<'_>) {
+        // Watchers are called without the table borrowed, so that they can
+        // add and remove watches
+        let targets: Vec<(WatchId, Arc<dyn Watcher>)> = {
+            let watches = self.watches.borrow();
+            let Some(list) = watches.by_inode.get(&ino) else {
+                return;
+            };
+            list.iter()
+                .filter(|watch| watch.mask.contains(change.mask()))
+                .map(|watch| (watch.id, watch.watcher.clone()))
+                .collect()
+        };
+        for (id, watcher) in targets {
+            watcher.changed(id, ino, &change);
+        }
+    }
+
+    /// Drop the watches of inode `ino` once it is freed, telling each of
+    /// them
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn forget_watches(&mut self, ino: u32) {
+        let Some(list) = self.watches.get_mut().by_inode.remove(&ino) else {
+            return;
+        };
+        for watch in list {
+            watch.watcher.changed(watch.id, ino, &Change::Removed);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Orphan inodes
+//!
+//! An inode whose last link is gone, or whose blocks are being truncated, is
+//! an orphan until its blocks are freed: if the system crashes in between,
+//! the next mount finishes the job. Older filesystems chain orphans from
+//! `s_last_orphan` through their `i_dtime`. With the `orphan_file` feature,
+//! they are listed instead in the blocks of a dedicated file, each block an
+//! array of inode numbers followed by a magic number and, under
+//! `metadata_csum`, a checksum. The `orphan_present` feature tells that the
+//! file may hold entries.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+#[cfg(not(feature = "read-only"))]
+use crate::FeatureRoCompat;
+use crate::{crc32c, Ext4Error, Ext4FileSystem, Ext4Result, FeatureCompat, Inode};
+
+/// Magic number in the tail of every orphan file block
+const ORPHAN_BLOCK_MAGIC: u32 = 0x0B10_CA04;
+
+/// Size of the tail of an orphan file block
+const ORPHAN_TAIL_SIZE: usize = 8;
+
+/// Number of inode numbers in an orphan file block of `block_size` bytes
+fn entries_per_block(block_size: usize) -> usize {
+    (block_size - ORPHAN_TAIL_SIZE) / 4
+}
+
+/// Inode number in slot `i` of orphan file block `block`
+fn entry(block: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+/// Compute the checksum of orphan file block `block`, stored at `block_num`
+fn orphan_block_csum(seed: u32, block_num: u64, block: &[u8]) -> u32 {
+    let csum = crc32c(seed, &block_num.to_le_bytes());
+    crc32c(csum, &block[..entries_per_block(block.len()) * 4])
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// List the orphan inodes, waiting to be released or truncated
+    ///
+    /// Inodes on the `s_last_orphan` list come first, then those of the
+    /// orphan file. Both are emptied at mount unless it is read-only, so the
+    /// list is only non-empty after a crash.
+    pub fn orphans(&self) -> Ext4Result<Vec<u32>> {
+        let mut orphans = Vec::new();
+        let mut ino = self.superblock.last_orphan();
+        while ino != 0 {
+            if orphans.len() >= self.superblock.inodes_count() as usize {
+                warn!("Orphan inode list loops back to inode {}", ino);
+                return Err(Ext4Error::Loop);
+            }
+            orphans.push(ino);
+            ino = self.get_inode(ino)?.dtime;
+        }
+
+        if let Some(file) = self.orphan_file()? {
+            let block_size = self.superblock.block_size() as usize;
+            for index in 0..file.block_count(block_size as u32) {
+                let (_, block) = self.read_orphan_block(&file, index)?;
+                orphans.extend(
+                    (0..entries_per_block(block_size))
+                        .map(|i| entry(&block, i))
+                        .filter(|&ino| ino != 0),
+                );
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Record `ino` in the orphan file before freeing its blocks
+    ///
+    /// Without the `orphan_file` feature, or if the file is full, the inode
+    /// isn't recorded and a crash may leak its blocks.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn add_orphan(&mut self, ino: u32) -> Ext4Result<()> {
+        let Some(file) = self.orphan_file()? else {
+            return Ok(());
+        };
+        let block_size = self.superblock.block_size() as usize;
+        for index in 0..file.block_count(block_size as u32) {
+            let (block_num, mut block) = self.read_orphan_block(&file, index)?;
+            let Some(slot) = (0..entries_per_block(block_size)).find(|&i| entry(&block, i) == 0)
+            else {
+                continue;
+            };
+            block[slot * 4..slot * 4 + 4].copy_from_slice(&ino.to_le_bytes());
+            self.write_orphan_block(&file, block_num, &mut block)?;
+            debug!("Added orphan inode {} in orphan file block {}", ino, index);
+            return self.set_orphan_state(true, self.superblock.last_orphan());
+        }
+        warn!("Orphan file is full, not recording inode {}", ino);
+        Ok(())
+    }
+
+    /// Remove `ino` from the orphan file once its blocks are freed
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn remove_orphan(&mut self, ino: u32) -> Ext4Result<()> {
+        let Some(file) = self.orphan_file()? else {
+            return Ok(());
+        };
+        let block_size = self.superblock.block_size() as usize;
+        let mut found = false;
+        let mut remaining = false;
+        for index in 0..file.block_count(block_size as u32) {
+            let (block_num, mut block) = self.read_orphan_block(&file, index)?;
+            let slots = entries_per_block(block_size);
+            if !found {
+                if let Some(slot) = (0..slots).find(|&i| entry(&block, i) == ino) {
+                    block[slot * 4..slot * 4 + 4].fill(0);
+                    self.write_orphan_block(&file, block_num, &mut block)?;
+                    found = true;
+                }
+            }
+            remaining |= (0..slots).any(|i| entry(&block, i) != 0);
+        }
+        if !found {
+            debug!("Inode {} is not in the orphan file", ino);
+        }
+        if remaining {
+            return Ok(());
+        }
+        self.set_orphan_state(false, self.superblock.last_orphan())
+    }
+
+    /// Finish releasing the orphans left by a crash
+    ///
+    /// Unlinked orphans are released. Linked ones were being truncated, and
+    /// truncation unmaps the blocks before writing the new size, so at worst
+    /// those blocks leak. Failures are logged and leave the rest to `fsck`.
+    pub(crate) fn process_orphans(&mut self) {
+        if self.check_writable().is_err() {
+            return;
+        }
+        #[cfg(not(feature = "read-only"))]
+        if let Err(e) = self.release_orphans() {
+            warn!("Failed to process orphan inodes: {:?}", e);
+        }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    fn release_orphans(&mut self) -> Ext4Result<()> {
+        let present = self
+            .superblock
+            .feature_ro_compat()
+            .contains(FeatureRoCompat::ORPHAN_PRESENT);
+        if self.superblock.last_orphan() == 0 && !present {
+            return Ok(());
+        }
+
+        for ino in self.orphans()? {
+            let mut inode = self.get_inode(ino)?;
+            if inode.links_count == 0 {
+                debug!("Releasing orphan inode {}", ino);
+                self.free_unlinked_inode(&mut inode)?;
+            } else if inode.dtime != 0 {
+                // Only used as the link of the orphan list
+                inode.dtime = 0;
+                self.write_inode(&inode)?;
+            }
+        }
+
+        if let Some(file) = self.orphan_file()? {
+            let block_size = self.superblock.block_size();
+            for index in 0..file.block_count(block_size) {
+                let (block_num, mut block) = self.read_orphan_block(&file, index)?;
+                let entries = entries_per_block(block.len()) * 4;
+                if block[..entries].iter().any(|&b| b != 0) {
+                    block[..entries].fill(0);
+                    self.write_orphan_block(&file, block_num, &mut block)?;
+                }
+            }
+        }
+        self.set_orphan_state(false, 0)
+    }
+
+    /// The orphan file, if the filesystem has one
+    fn orphan_file(&self) -> Ext4Result<Option<Inode>> {
+        let ino = self.superblock.orphan_file_inum();
+        if !self
+            .superblock
+            .feature_compat()
+            .contains(FeatureCompat::ORPHAN_FILE)
+            || ino == 0
+        {
+            return Ok(None);
+        }
+        self.get_inode(ino).map(Some)
+    }
+
+    /// Read block `index` of orphan file `file`, returning it with its
+    /// block number
+    fn read_orphan_block(&self, file: &Inode, index: u64) -> Ext4Result<(u64, Vec<u8>)> {
+        let block_size = self.superblock.block_size();
+        let block_num = file.get_block_number(index * block_size as u64, block_size, self)?;
+        if block_num == 0 {
+            warn!("Orphan file has a hole at block {}", index);
+            return Err(Ext4Error::InvalidInput);
+        }
+        let mut block = vec![0u8; block_size as usize];
+        self.read_block(block_num, &mut block)?;
+
+        let tail = block.len() - ORPHAN_TAIL_SIZE;
+        let magic = u32::from_le_bytes(block[tail..tail + 4].try_into().unwrap());
+        if magic != ORPHAN_BLOCK_MAGIC {
+            warn!("Orphan file block {} has bad magic {:#x}", index, magic);
+            return Err(Ext4Error::InvalidInput);
+        }
+        if self.superblock.has_metadata_csum() {
+            let stored = u32::from_le_bytes(block[tail + 4..].try_into().unwrap());
+            if stored != orphan_block_csum(self.inode_csum_seed(file), block_num, &block) {
+                warn!("Orphan file block {} failed verification", index);
+                return Err(Ext4Error::BadChecksum);
+            }
+        }
+        Ok((block_num, block))
+    }
+
+    /// Write orphan file block `block` back to `block_num`
+    #[cfg(not(feature = "read-only"))]
+    fn write_orphan_block(
+        &mut self,
+        file: &Inode,
+        block_num: u64,
+        block: &mut [u8],
+    ) -> Ext4Result<()> {
+        if self.superblock.has_metadata_csum() {
+            let csum = orphan_block_csum(self.inode_csum_seed(file), block_num, block);
+            let tail = block.len() - ORPHAN_TAIL_SIZE;
+            block[tail + 4..].copy_from_slice(&csum.to_le_bytes());
+        }
+        self.write_block(block_num, block)
+    }
+
+    /// Store the `orphan_present` feature and the head of the orphan list
+    /// in the superblock
+    #[cfg(not(feature = "read-only"))]
+    fn set_orphan_state(&mut self, present: bool, last_orphan: u32) -> Ext4Result<()> {
+        let current = self
+            .superblock
+            .feature_ro_compat()
+            .contains(FeatureRoCompat::ORPHAN_PRESENT);
+        if current == present && self.superblock.last_orphan() == last_orphan {
+            return Ok(());
+        }
+
+        let mut superblock = self.superblock.clone();
+        superblock.set_orphan_present(present);
+        superblock.set_last_orphan(last_orphan);
+        superblock.write_orphan_state(&mut *self.device.borrow_mut())?;
+        let sb_block = self.superblock.superblock_block();
+        self.caches.borrow_mut().blocks.remove(&sb_block);
+        self.superblock = superblock;
+        Ok(())
+    }
+}
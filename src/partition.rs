@@ -0,0 +1,160 @@
+//! MBR and GPT partition table parsing
+//!
+//! Only what is needed to locate a filesystem inside a partitioned disk:
+//! primary MBR entries and GPT entries. Extended MBR partitions are reported
+//! but not followed, and GPT checksums are not verified.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{Ext4Error, Ext4Result};
+
+/// MBR partition type of a protective MBR in front of a GPT
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+/// MBR partition type used by Linux filesystems
+const MBR_TYPE_LINUX: u8 = 0x83;
+/// GPT partition type GUID of Linux filesystem data, in on-disk byte order
+const GPT_TYPE_LINUX: [u8; 16] = [
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+];
+/// Upper bound on the GPT entry array size, to cope with corrupt headers
+const GPT_MAX_ENTRY_BYTES: usize = 1 << 20;
+
+/// Type of a partition table entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// MBR entry with its one-byte system ID
+    Mbr(u8),
+    /// GPT entry with its partition type GUID in on-disk byte order
+    Gpt([u8; 16]),
+}
+
+/// Partition table entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Partition number, starting at 1
+    pub number: u32,
+    /// First device block of the partition
+    pub start: u64,
+    /// Length in device blocks
+    pub num_blocks: u64,
+    /// Partition type
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    /// Check if the partition type marks a Linux filesystem
+    pub fn is_linux(&self) -> bool {
+        match self.kind {
+            PartitionKind::Mbr(id) => id == MBR_TYPE_LINUX,
+            PartitionKind::Gpt(guid) => guid == GPT_TYPE_LINUX,
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Read `count` device blocks starting at `block`
+fn read_blocks<D: BlockDriverOps>(device: &mut D, block: u64, count: usize) -> Ext4Result<Vec<u8>> {
+    let mut buf = vec![0u8; count * device.block_size()];
+    device
+        .read_block(block, &mut buf)
+        .map_err(Ext4Error::from)?;
+    Ok(buf)
+}
+
+/// Read the partition table of `device`
+///
+/// Partition offsets are expressed in device blocks, i.e. the device block
+/// size is taken as the logical block size of the disk. Returns an empty list
+/// if the device has no MBR signature.
+pub fn read_partitions<D: BlockDriverOps>(device: &mut D) -> Ext4Result<Vec<Partition>> {
+    let block_size = device.block_size();
+    if block_size < 512 {
+        return Err(Ext4Error::NotSupported);
+    }
+
+    let mbr = read_blocks(device, 0, 1)?;
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        debug!("No MBR signature found");
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        let id = entry[4];
+        let start = read_u32(entry, 8) as u64;
+        let num_blocks = read_u32(entry, 12) as u64;
+        if id == 0 || num_blocks == 0 {
+            continue;
+        }
+        if id == MBR_TYPE_GPT_PROTECTIVE {
+            return read_gpt(device);
+        }
+
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start,
+            num_blocks,
+            kind: PartitionKind::Mbr(id),
+        });
+    }
+
+    Ok(partitions)
+}
+
+/// Read the GPT whose header is at block 1
+fn read_gpt<D: BlockDriverOps>(device: &mut D) -> Ext4Result<Vec<Partition>> {
+    let block_size = device.block_size();
+    let header = read_blocks(device, 1, 1)?;
+    if &header[..8] != b"EFI PART" {
+        warn!("Protective MBR present but no GPT header found");
+        return Err(Ext4Error::InvalidState);
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let num_entries = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let total = num_entries.saturating_mul(entry_size);
+    if entry_size < 128 || total > GPT_MAX_ENTRY_BYTES {
+        warn!(
+            "Invalid GPT entry array: {} entries of {} bytes",
+            num_entries, entry_size
+        );
+        return Err(Ext4Error::InvalidState);
+    }
+
+    let entries = read_blocks(device, entries_lba, total.div_ceil(block_size))?;
+    let mut partitions = Vec::new();
+    for i in 0..num_entries {
+        let entry = &entries[i * entry_size..(i + 1) * entry_size];
+        let type_guid: [u8; 16] = entry[..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+
+        let first = read_u64(entry, 32);
+        let last = read_u64(entry, 40);
+        if last < first {
+            warn!("GPT entry {} ends before it starts", i + 1);
+            continue;
+        }
+
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start: first,
+            num_blocks: last - first + 1,
+            kind: PartitionKind::Gpt(type_guid),
+        });
+    }
+
+    Ok(partitions)
+}
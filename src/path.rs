@@ -0,0 +1,243 @@
+//! Path lookup
+//!
+//! Paths are split first: empty components and "." are dropped, but ".." is
+//! kept, since it has to be resolved through the directory's real parent
+//! entry, and only after checking that the path up to it names a directory.
+//! [`Ext4FileSystem::resolve_at`] also follows symbolic links, by splicing
+//! their targets into the rest of the path.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use bitflags::bitflags;
+use log::*;
+
+use crate::{
+    symlink, Ext4Error, Ext4FileSystem, Ext4Result, FileName, Inode, EXT4_MAX_PATH_DEPTH,
+    EXT4_PATH_MAX, EXT4_ROOT_INO,
+};
+
+/// Most symbolic links followed in one lookup, as Linux's `MAXSYMLINKS`
+const MAX_SYMLINKS: usize = 40;
+
+bitflags! {
+    /// Flags of [`Ext4FileSystem::resolve_at`], after the `AT_*` flags and
+    /// the `RESOLVE_*` flags of `openat2`
+    #[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+    pub struct ResolveFlags: u32 {
+        /// Return a symbolic link in the last component instead of its
+        /// target, as `AT_SYMLINK_NOFOLLOW`
+        const NOFOLLOW = 1 << 0;
+        /// Resolve an empty path to the starting inode, as `AT_EMPTY_PATH`
+        const EMPTY_PATH = 1 << 1;
+        /// Fail with [`Ext4Error::Loop`] on any symbolic link that would be
+        /// followed, as `RESOLVE_NO_SYMLINKS`
+        const NO_SYMLINKS = 1 << 2;
+        /// Fail with [`Ext4Error::CrossDevice`] on absolute paths and on
+        /// ".." leading out of the starting directory, as `RESOLVE_BENEATH`
+        const BENEATH = 1 << 3;
+    }
+}
+
+/// A component of a normalized path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Component<'a> {
+    /// ".."
+    Parent,
+    /// Any other name
+    Name(&'a [u8]),
+}
+
+/// A path split into components
+#[derive(Debug)]
+struct SplitPath<'a> {
+    /// Components left after dropping empty ones and "."
+    components: Vec<Component<'a>>,
+    /// The path ends in "/", "." or "..", so it must name a directory
+    dir_only: bool,
+}
+
+impl Component<'_> {
+    /// Name to look up in the directory
+    fn name(&self) -> &[u8] {
+        match self {
+            Component::Parent => b"..",
+            Component::Name(name) => name,
+        }
+    }
+}
+
+/// Split `path` into the components to look up
+///
+/// Fails with `InvalidPath` if the path is longer than [`EXT4_PATH_MAX`] and
+/// with `Loop` if it has more than [`EXT4_MAX_PATH_DEPTH`] components,
+/// counting "." ones.
+fn split(path: &[u8]) -> Ext4Result<SplitPath<'_>> {
+    if path.len() > EXT4_PATH_MAX {
+        return Err(Ext4Error::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    let mut depth = 0;
+    let mut last = None;
+    for name in path.split(|&b| b == b'/').filter(|s| !s.is_empty()) {
+        depth += 1;
+        last = Some(name);
+        match name {
+            b"." => {}
+            b".." => components.push(Component::Parent),
+            _ => components.push(Component::Name(name)),
+        }
+    }
+    if depth > EXT4_MAX_PATH_DEPTH {
+        warn!("Path has {} components, more than the limit", depth);
+        return Err(Ext4Error::Loop);
+    }
+
+    let dir_only = path.ends_with(b"/") || matches!(last, Some(b"." | b".."));
+    Ok(SplitPath {
+        components,
+        dir_only,
+    })
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Look up `path` relative to directory `dir_ino`, as the `*at()` calls do
+    ///
+    /// Absolute paths start at the root whatever `dir_ino` is. Unlike
+    /// [`Self::find_inode`], symbolic links are followed: always in the
+    /// middle of the path, and in the last component unless
+    /// [`ResolveFlags::NOFOLLOW`] is given. More than 40 links in one lookup
+    /// fail with `Loop`. An empty path fails with `InodeNotFound` unless
+    /// [`ResolveFlags::EMPTY_PATH`] is given.
+    pub fn resolve_at(&self, dir_ino: u32, path: &[u8], flags: ResolveFlags) -> Ext4Result<Inode> {
+        let mut start = self.get_inode(dir_ino)?;
+        if path.is_empty() {
+            return match flags.contains(ResolveFlags::EMPTY_PATH) {
+                true => Ok(start),
+                false => Err(Ext4Error::InodeNotFound),
+            };
+        }
+
+        if path.starts_with(b"/") {
+            if flags.contains(ResolveFlags::BENEATH) {
+                return Err(Ext4Error::CrossDevice);
+            }
+            start = self.root_inode()?;
+        } else if !start.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+        self.resolve_path(start, path, Some(flags))
+    }
+
+    /// Look up `path` starting at directory `start`
+    ///
+    /// Symbolic links are only followed with `flags`; without, they are
+    /// returned as the last component and are not directories elsewhere.
+    pub(crate) fn resolve_path(
+        &self,
+        start: Inode,
+        path: &[u8],
+        flags: Option<ResolveFlags>,
+    ) -> Ext4Result<Inode> {
+        let beneath = flags.is_some_and(|f| f.contains(ResolveFlags::BENEATH));
+        let mut current = start;
+        // Directories from the topmost one reached down to the current one
+        let mut ancestors = vec![current.ino];
+        let mut path = path.to_vec();
+        let mut links = 0;
+
+        'restart: loop {
+            let split = split(&path)?;
+            for (i, component) in split.components.iter().enumerate() {
+                if !current.is_dir() {
+                    return Err(Ext4Error::NotADirectory);
+                }
+
+                let ino = self.lookup_in(&current, component.name())?;
+                match component {
+                    Component::Parent if ancestors.len() > 1 => {
+                        ancestors.pop();
+                    }
+                    Component::Parent if beneath && ino != current.ino => {
+                        return Err(Ext4Error::CrossDevice);
+                    }
+                    Component::Parent => ancestors[0] = ino,
+                    Component::Name(name) => {
+                        if ancestors.contains(&ino) {
+                            warn!(
+                                "Directory entry {:?} in inode {} loops back to inode {}",
+                                FileName::from(*name),
+                                current.ino,
+                                ino
+                            );
+                            let e = Ext4Error::Loop;
+                            self.record_error("resolve_path", line!(), current.ino, 0, &e);
+                            return Err(e);
+                        }
+                        ancestors.push(ino);
+                    }
+                }
+
+                let inode = self.get_inode(ino)?;
+                let last = i + 1 == split.components.len();
+                let follow = match flags {
+                    Some(flags) if inode.is_symlink() => {
+                        !last || split.dir_only || !flags.contains(ResolveFlags::NOFOLLOW)
+                    }
+                    _ => false,
+                };
+                if !follow {
+                    current = inode;
+                    continue;
+                }
+
+                // Splice the link target into the rest of the path
+                let flags = flags.unwrap_or_default();
+                links += 1;
+                if flags.contains(ResolveFlags::NO_SYMLINKS) || links > MAX_SYMLINKS {
+                    return Err(Ext4Error::Loop);
+                }
+                let mut next = symlink::read_target_bytes(self, &inode)?;
+                if next.is_empty() {
+                    return Err(Ext4Error::InodeNotFound);
+                }
+                for rest in &split.components[i + 1..] {
+                    next.push(b'/');
+                    next.extend_from_slice(rest.name());
+                }
+                if split.dir_only {
+                    next.push(b'/');
+                }
+
+                ancestors.pop();
+                if next.starts_with(b"/") {
+                    if beneath {
+                        return Err(Ext4Error::CrossDevice);
+                    }
+                    current = self.root_inode()?;
+                    ancestors = vec![EXT4_ROOT_INO];
+                }
+                path = next;
+                continue 'restart;
+            }
+
+            if split.dir_only && !current.is_dir() {
+                return Err(Ext4Error::NotADirectory);
+            }
+            return Ok(current);
+        }
+    }
+
+    /// Look up `name` in directory `dir`, using the directory entry cache
+    fn lookup_in(&self, dir: &Inode, name: &[u8]) -> Ext4Result<u32> {
+        if let Some(ino) = self.cached_dentry(dir.ino, name) {
+            return Ok(ino);
+        }
+        let ino = match self.dx_lookup(dir, name)? {
+            Some(ino) => ino,
+            None => self.find_in_directory(dir, name)?,
+        };
+        self.cache_dentry(dir.ino, name, ino);
+        Ok(ino)
+    }
+}
@@ -0,0 +1,339 @@
+//! Renaming directory entries
+//!
+//! Besides a plain POSIX rename, [`RenameFlags`] selects the `renameat2`
+//! modes: refusing to replace an existing target, or swapping two entries.
+//!
+//! A directory moved to another parent carries a ".." entry naming its old
+//! one. The entry is rewritten to name the new parent: in the index root
+//! for an indexed directory, so that the index survives, and in the first
+//! block otherwise. Each ".." is a link to the parent it names, so the old
+//! parent loses a link and the new one gains one; a directory replaced by
+//! the move takes its link to the new parent with it. A ".." that doesn't
+//! name the directory the entry is in fails the rename as corruption
+//! before anything is changed.
+
+use axdriver_block::BlockDriverOps;
+use bitflags::bitflags;
+use log::*;
+
+use crate::htree::{self, DxKind};
+use crate::{
+    validate_name, Change, Directory, DirectoryEntry, Ext4Error, Ext4FileSystem, Ext4Result,
+    FileName, Inode, EXT4_MAX_PATH_DEPTH, EXT4_ROOT_INO,
+};
+
+bitflags! {
+    /// Flags of [`Ext4FileSystem::rename`], with the values used by `renameat2`
+    #[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+    pub struct RenameFlags: u32 {
+        /// Fail with [`Ext4Error::FileExists`] if the target exists
+        const NOREPLACE = 1 << 0;
+        /// Swap the source and target entries, which must both exist
+        const EXCHANGE = 1 << 1;
+    }
+}
+
+/// Check if `name` is "." or ".."
+fn is_dot(name: &[u8]) -> bool {
+    name == b"." || name == b".."
+}
+
+/// Point the entry `name` of `dir` at the inode of `from`
+fn set_entry(dir: &mut Directory, name: &[u8], from: &DirectoryEntry) {
+    if let Some(entry) = dir.find_entry_mut(name) {
+        entry.ino = from.ino;
+        entry.file_type = from.file_type;
+    }
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Rename `old_name` in directory `old_dir` to `new_name` in `new_dir`
+    ///
+    /// Without flags an existing target is replaced as by POSIX `rename`: a
+    /// directory only replaces an empty directory and a non-directory only a
+    /// non-directory. Immutable or append-only inodes can't be moved or
+    /// replaced, and entries can't be removed from such directories.
+    pub fn rename(
+        &mut self,
+        old_dir: u32,
+        old_name: &[u8],
+        new_dir: u32,
+        new_name: &[u8],
+        flags: RenameFlags,
+    ) -> Ext4Result<()> {
+        self.check_writable()?;
+        validate_name(new_name)?;
+        if flags.contains(RenameFlags::NOREPLACE | RenameFlags::EXCHANGE)
+            || is_dot(old_name)
+            || is_dot(new_name)
+        {
+            return Err(Ext4Error::InvalidArg);
+        }
+
+        let mut old_parent = self.get_inode(old_dir)?;
+        let mut new_parent = self.get_inode(new_dir)?;
+        if !old_parent.is_dir() || !new_parent.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+        old_parent.check_remove()?;
+        new_parent.check_modify()?;
+
+        let same_dir = old_dir == new_dir;
+        let mut old_entries = self.read_directory(&old_parent)?;
+        let mut new_entries = match same_dir {
+            true => None,
+            false => Some(self.read_directory(&new_parent)?),
+        };
+
+        let source = old_entries
+            .find_entry(old_name)
+            .cloned()
+            .ok_or(Ext4Error::InodeNotFound)?;
+        let target = new_entries
+            .as_ref()
+            .unwrap_or(&old_entries)
+            .find_entry(new_name)
+            .cloned();
+        let source_inode = self.get_inode(source.ino)?;
+        source_inode.check_remove()?;
+
+        match &target {
+            Some(_) if flags.contains(RenameFlags::NOREPLACE) => {
+                return Err(Ext4Error::FileExists);
+            }
+            None if flags.contains(RenameFlags::EXCHANGE) => {
+                return Err(Ext4Error::InodeNotFound);
+            }
+            // Both names already refer to the same inode
+            Some(target) if target.ino == source.ino => return Ok(()),
+            _ => {}
+        }
+        if source_inode.is_dir() && !same_dir {
+            self.check_not_ancestor(source.ino, new_dir)?;
+            self.check_parent(source.ino, old_dir)?;
+            if target.is_none() {
+                self.check_dir_link_max(&new_parent)?;
+            }
+        }
+
+        // Changes of the parents' link counts from moved ".." entries
+        let mut old_links = 0;
+        let mut new_links = 0;
+        let mut replaced = None;
+        let mut exchanged = None;
+        match target {
+            Some(target) if flags.contains(RenameFlags::EXCHANGE) => {
+                let target_inode = self.get_inode(target.ino)?;
+                target_inode.check_remove()?;
+                if target_inode.is_dir() && !same_dir {
+                    self.check_not_ancestor(target.ino, old_dir)?;
+                    self.check_parent(target.ino, new_dir)?;
+                    self.set_parent(target.ino, old_dir)?;
+                    old_links += 1;
+                    new_links -= 1;
+                }
+                set_entry(&mut old_entries, old_name, &target);
+                set_entry(new_entries.as_mut().unwrap_or(&mut old_entries), new_name, &source);
+                self.touch_ctime(target.ino)?;
+                exchanged = Some(target.ino);
+            }
+            Some(target) => {
+                let target_inode = self.get_inode(target.ino)?;
+                target_inode.check_remove()?;
+                match (source_inode.is_dir(), target_inode.is_dir()) {
+                    (true, false) => return Err(Ext4Error::NotADirectory),
+                    (false, true) => return Err(Ext4Error::IsADirectory),
+                    (true, true) if !self.is_empty_dir(&target_inode)? => {
+                        return Err(Ext4Error::DirNotEmpty);
+                    }
+                    _ => {}
+                }
+                if target_inode.is_dir() {
+                    new_links -= 1;
+                }
+                old_entries.remove_entry(old_name);
+                set_entry(new_entries.as_mut().unwrap_or(&mut old_entries), new_name, &source);
+                replaced = Some(target_inode);
+            }
+            None => {
+                old_entries.remove_entry(old_name);
+                new_entries.as_mut().unwrap_or(&mut old_entries).add_entry(DirectoryEntry {
+                    rec_len: ((8 + new_name.len() + 3) & !3) as u16,
+                    name_len: new_name.len() as u8,
+                    name: FileName::from(new_name),
+                    ..source.clone()
+                });
+            }
+        }
+
+        if source_inode.is_dir() && !same_dir {
+            self.set_parent(source.ino, new_dir)?;
+            old_links -= 1;
+            new_links += 1;
+        }
+        self.touch_ctime(source.ino)?;
+
+        let now = self.now();
+        match new_entries {
+            Some(new_entries) => {
+                self.adjust_dir_links(&mut new_parent, new_links);
+                new_parent.set_mtime(now);
+                new_parent.set_ctime(now);
+                self.write_directory(&mut new_parent, &new_entries)?;
+            }
+            None => old_links += new_links,
+        }
+        self.adjust_dir_links(&mut old_parent, old_links);
+        old_parent.set_mtime(now);
+        old_parent.set_ctime(now);
+        self.write_directory(&mut old_parent, &old_entries)?;
+
+        if let Some(mut inode) = replaced {
+            self.notify(new_dir, Change::Unlink { name: new_name, ino: inode.ino });
+            inode.links_count = match inode.is_dir() {
+                true => 0,
+                false => inode.links_count.saturating_sub(1),
+            };
+            if inode.links_count == 0 {
+                self.release_inode(&mut inode)?;
+            } else {
+                inode.set_ctime(now);
+                self.write_inode(&inode)?;
+                self.notify(inode.ino, Change::Attrib);
+            }
+        }
+
+        self.notify_rename(old_dir, old_name, new_dir, new_name, source.ino);
+        if let Some(target) = exchanged {
+            self.notify_rename(new_dir, new_name, old_dir, old_name, target);
+        }
+
+        debug!(
+            "Renamed {:?} in {} to {:?} in {} ({:?})",
+            FileName::from(old_name),
+            old_dir,
+            FileName::from(new_name),
+            new_dir,
+            flags
+        );
+        Ok(())
+    }
+
+    /// Tell the watchers of both directories that `old_name` in `old_dir`,
+    /// linking inode `ino`, is now `new_name` in `new_dir`
+    fn notify_rename(
+        &self,
+        old_dir: u32,
+        old_name: &[u8],
+        new_dir: u32,
+        new_name: &[u8],
+        ino: u32,
+    ) {
+        let change = Change::Rename {
+            old_dir,
+            old_name,
+            new_dir,
+            new_name,
+            ino,
+        };
+        self.notify(old_dir, change);
+        if new_dir != old_dir {
+            self.notify(new_dir, change);
+        }
+    }
+
+    /// Fail if directory `dir` is `start` or one of its ancestors
+    fn check_not_ancestor(&self, dir: u32, start: u32) -> Ext4Result<()> {
+        let mut current = start;
+        for _ in 0..EXT4_MAX_PATH_DEPTH {
+            if current == dir {
+                return Err(Ext4Error::InvalidArg);
+            }
+            if current == EXT4_ROOT_INO {
+                return Ok(());
+            }
+            current = self.lookup(current, b"..")?;
+        }
+        Err(Ext4Error::Loop)
+    }
+
+    /// Fail if the ".." entry of directory `dir` doesn't name `parent`, the
+    /// directory it is listed in
+    fn check_parent(&self, dir: u32, parent: u32) -> Ext4Result<()> {
+        let dotdot = self.lookup(dir, b"..")?;
+        if dotdot != parent {
+            warn!(
+                "Directory {} is in directory {} but its \"..\" names {}",
+                dir, parent, dotdot
+            );
+            let e = Ext4Error::InvalidState;
+            self.record_error("rename", line!(), dir, 0, &e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Apply a change of `delta` subdirectories to the link count of `dir`
+    fn adjust_dir_links(&self, dir: &mut Inode, delta: i32) {
+        for _ in 0..delta {
+            self.inc_dir_links(dir);
+        }
+        for _ in delta..0 {
+            self.dec_dir_links(dir);
+        }
+    }
+
+    /// Check if a directory holds nothing but "." and ".."
+    fn is_empty_dir(&self, inode: &Inode) -> Ext4Result<bool> {
+        let entries = self.read_directory(inode)?;
+        Ok(entries.entries().iter().all(|e| is_dot(e.name.as_bytes())))
+    }
+
+    /// Point the ".." entry of directory `dir` at `parent`
+    fn set_parent(&mut self, dir: u32, parent: u32) -> Ext4Result<()> {
+        let mut inode = self.get_inode(dir)?;
+        if self.is_indexed(&inode) {
+            return self.set_indexed_parent(&inode, parent);
+        }
+        let mut entries = self.read_directory(&inode)?;
+        let Some(dotdot) = entries.find_entry_mut("..") else {
+            let e = Ext4Error::InvalidState;
+            self.record_error("set_parent", line!(), dir, 0, &e);
+            return Err(e);
+        };
+        dotdot.ino = parent;
+        self.write_directory(&mut inode, &entries)
+    }
+
+    /// Point the ".." entry of indexed directory `dir` at `parent`
+    ///
+    /// ".." lives in the index root, which is updated in place with a new
+    /// checksum so the index survives.
+    fn set_indexed_parent(&mut self, dir: &Inode, parent: u32) -> Ext4Result<()> {
+        let block_size = self.superblock().block_size();
+        let block_num = dir.get_block_number(0, block_size, self)?;
+        let mut block = self.read_dir_block(dir, 0)?;
+        self.check_dx_node(dir, &block, DxKind::Root)?;
+        let dot_rec_len = u16::from_le_bytes([block[4], block[5]]);
+        if dot_rec_len != 12 || block[12 + 6] != 2 || &block[12 + 8..12 + 10] != b".." {
+            let e = Ext4Error::InvalidState;
+            self.record_error("set_parent", line!(), dir.ino, block_num, &e);
+            return Err(e);
+        }
+
+        block[12..16].copy_from_slice(&parent.to_le_bytes());
+        if self.superblock().has_metadata_csum() {
+            htree::set_dx_csum(self.inode_csum_seed(dir), &mut block, DxKind::Root)?;
+        }
+        self.write_block(block_num, &block)?;
+        self.caches.borrow_mut().invalidate_dir(dir.ino);
+        Ok(())
+    }
+
+    /// Update the change time of inode `ino`
+    fn touch_ctime(&mut self, ino: u32) -> Ext4Result<()> {
+        let mut inode = self.get_inode(ino)?;
+        inode.set_ctime(self.now());
+        self.write_inode(&inode)
+    }
+}
@@ -0,0 +1,126 @@
+//! Resize inode
+//!
+//! With the `resize_inode` feature, inode 7 owns the reserved group
+//! descriptor blocks that follow the descriptor table in every group holding
+//! a superblock backup, so that online resizing can grow the table in place.
+//! Its double-indirect block lists the primary reserved blocks, and each of
+//! those, read as an indirect block, lists the block's backups in order.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{Ext4Error, Ext4FileSystem, Ext4Result, FeatureCompat, Inode, EXT4_RESIZE_INO};
+
+/// Slot of the double-indirect block in `i_block`
+const DIND_BLOCK: usize = 13;
+
+/// A reserved group descriptor block and its backups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservedGdtBlock {
+    /// Block in the primary descriptor table area
+    pub primary: u64,
+    /// Copies in the groups holding superblock backups, in group order
+    pub backups: Vec<u64>,
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Check if `ino` is one of the reserved inodes below `s_first_ino`,
+    /// other than the root directory
+    pub fn is_reserved_inode(&self, ino: u32) -> bool {
+        ino != crate::EXT4_ROOT_INO && ino < self.superblock.first_inode()
+    }
+
+    /// Get the resize inode
+    ///
+    /// Fails with `NotSupported` without the `resize_inode` feature.
+    pub fn resize_inode(&self) -> Ext4Result<Inode> {
+        if !self.superblock.feature_compat().contains(FeatureCompat::RESIZE_INODE) {
+            return Err(Ext4Error::NotSupported);
+        }
+        self.get_inode(EXT4_RESIZE_INO)
+    }
+
+    /// List the reserved group descriptor blocks mapped by the resize inode
+    ///
+    /// Fails with `InvalidState` unless the inode maps exactly the
+    /// `s_reserved_gdt_blocks` blocks following the descriptor table, each
+    /// with a backup in every group holding a superblock backup.
+    pub fn reserved_gdt_blocks(&self) -> Ext4Result<Vec<ReservedGdtBlock>> {
+        let inode = self.resize_inode()?;
+        let sb = &self.superblock;
+        let reserved = sb.reserved_gdt_blocks() as u32;
+        let dind = inode.block[DIND_BLOCK] as u64;
+        if dind == 0 {
+            if reserved == 0 {
+                return Ok(Vec::new());
+            }
+            warn!("Resize inode has no double-indirect block");
+            return Err(Ext4Error::InvalidState);
+        }
+
+        let block_size = sb.block_size() as usize;
+        let per_block = (block_size / 4) as u32;
+        let mut buf = vec![0u8; block_size];
+        self.read_block(dind, &mut buf)?;
+        let dind_map = block_numbers(&buf);
+        let mapped = dind_map.iter().filter(|&&block| block != 0).count();
+        if mapped != reserved as usize {
+            warn!(
+                "Resize inode maps {} reserved descriptor blocks, superblock has {}",
+                mapped, reserved
+            );
+            return Err(Ext4Error::InvalidState);
+        }
+
+        let backup_groups: Vec<u32> = (1..sb.groups_count())
+            .filter(|&group| sb.group_has_super(group))
+            .collect();
+        let mut blocks = crate::try_with_capacity(reserved as usize)?;
+        for index in 0..reserved {
+            let offset = sb.desc_blocks() + index;
+            let primary = (sb.first_data_block() + 1 + offset) as u64;
+            if dind_map[(offset % per_block) as usize] != primary {
+                warn!("Resize inode doesn't map reserved descriptor block {}", primary);
+                return Err(Ext4Error::InvalidState);
+            }
+
+            // The backups sit at the same place in each backup group
+            self.read_block(primary, &mut buf)?;
+            let backups: Vec<u64> = backup_groups
+                .iter()
+                .map(|&group| primary + group as u64 * sb.blocks_per_group() as u64)
+                .collect();
+            if block_numbers(&buf)[..backups.len()] != backups[..] {
+                warn!("Resize inode maps wrong backups of reserved descriptor block {}", primary);
+                return Err(Ext4Error::InvalidState);
+            }
+            blocks.push(ReservedGdtBlock { primary, backups });
+        }
+        Ok(blocks)
+    }
+
+    /// Check the resize inode against the superblock at mount
+    ///
+    /// A mismatch is logged and recorded in the error log. The filesystem
+    /// still mounts, but [`reserved_gdt_blocks`](Self::reserved_gdt_blocks)
+    /// keeps failing, so the reserved blocks can't be claimed for growing
+    /// the descriptor table.
+    pub(crate) fn check_resize_inode(&self) {
+        if !self.superblock.feature_compat().contains(FeatureCompat::RESIZE_INODE) {
+            return;
+        }
+        if let Err(e) = self.reserved_gdt_blocks() {
+            error!("Resize inode doesn't match the superblock: {:?}", e);
+            self.record_error("check_resize_inode", line!(), EXT4_RESIZE_INO, 0, &e);
+        }
+    }
+}
+
+/// Read `block` as an array of block numbers
+fn block_numbers(block: &[u8]) -> Vec<u64> {
+    block
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64)
+        .collect()
+}
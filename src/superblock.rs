@@ -1,8 +1,405 @@
+use alloc::string::String;
 use alloc::vec::Vec;
+#[cfg(not(feature = "read-only"))]
 use axdriver_block::BlockDriverOps;
+use bitflags::bitflags;
+use core::fmt;
 use log::*;
 
-use crate::{Ext4Error, Ext4Result};
+use crate::{Ext4Error, Ext4Result, Uuid};
+
+/// Byte offset of the primary superblock
+const SUPERBLOCK_OFFSET: u32 = 1024;
+/// Filesystem state: errors detected
+const EXT4_ERROR_FS: u16 = 0x0002;
+/// Original revision with fixed inode size and first inode
+const EXT4_GOOD_OLD_REV: u32 = 0;
+/// First non-reserved inode on revision 0 filesystems
+const EXT4_GOOD_OLD_FIRST_INO: u32 = 11;
+/// Inode size on revision 0 filesystems
+const EXT4_GOOD_OLD_INODE_SIZE: u16 = 128;
+/// Size of a block group descriptor without the 64bit feature
+const EXT4_MIN_DESC_SIZE: u16 = 32;
+/// Minimum size of a block group descriptor with the 64bit feature
+const EXT4_MIN_DESC_SIZE_64BIT: u16 = 64;
+/// Maximum size of a block group descriptor
+const EXT4_MAX_DESC_SIZE: u16 = 1024;
+/// Offset of the inode count in the superblock
+#[cfg(not(feature = "read-only"))]
+const INODES_COUNT_OFFSET: usize = 0x00;
+/// Offset of the low half of the block count in the superblock
+#[cfg(not(feature = "read-only"))]
+const BLOCKS_COUNT_OFFSET: usize = 0x04;
+/// Offset of the low half of the reserved block count in the superblock
+#[cfg(not(feature = "read-only"))]
+const RESERVED_COUNT_OFFSET: usize = 0x08;
+/// Offset of the magic number in the superblock
+#[cfg(not(feature = "read-only"))]
+const MAGIC_OFFSET: usize = 0x38;
+/// Offset of the filesystem state in the superblock
+#[cfg(not(feature = "read-only"))]
+const STATE_OFFSET: usize = 0x3A;
+/// Offset of the compatible features in the superblock
+#[cfg(not(feature = "read-only"))]
+const COMPAT_OFFSET: usize = 0x5C;
+/// Offset of the incompatible features in the superblock
+#[cfg(not(feature = "read-only"))]
+const INCOMPAT_OFFSET: usize = 0x60;
+/// Offset of the read-only compatible features in the superblock
+#[cfg(not(feature = "read-only"))]
+const RO_COMPAT_OFFSET: usize = 0x64;
+/// Offset of the reserved group descriptor block count in the superblock
+#[cfg(not(feature = "read-only"))]
+const RESERVED_GDT_OFFSET: usize = 0xCE;
+/// Offset of the head of the orphan inode list in the superblock
+#[cfg(not(feature = "read-only"))]
+const LAST_ORPHAN_OFFSET: usize = 0xE8;
+/// Offset of the filesystem UUID in the superblock
+#[cfg(not(feature = "read-only"))]
+const UUID_OFFSET: usize = 0x68;
+/// Offset of the volume label in the superblock
+#[cfg(not(feature = "read-only"))]
+const LABEL_OFFSET: usize = 0x78;
+/// Offset of the high half of the block count in the superblock
+#[cfg(not(feature = "read-only"))]
+const BLOCKS_COUNT_HI_OFFSET: usize = 0x150;
+/// Offset of the high half of the reserved block count in the superblock
+#[cfg(not(feature = "read-only"))]
+const RESERVED_COUNT_HI_OFFSET: usize = 0x154;
+/// Offset of the error counter in the superblock
+const ERROR_COUNT_OFFSET: usize = 0x194;
+/// Offset of the superblock checksum
+const CHECKSUM_OFFSET: usize = 0x3FC;
+/// Length of the function names recorded for errors
+const ERROR_FUNC_LEN: usize = 32;
+/// `s_*_error_errcode` value for I/O errors
+const EXT4_ERR_EIO: u8 = 2;
+/// `s_*_error_errcode` value for failed allocations
+const EXT4_ERR_ENOMEM: u8 = 3;
+/// `s_*_error_errcode` value for corrupted metadata
+const EXT4_ERR_EFSCORRUPTED: u8 = 5;
+
+/// Offsets of the fields describing one recorded error
+struct ErrorFields {
+    time: usize,
+    time_hi: usize,
+    ino: usize,
+    block: usize,
+    func: usize,
+    line: usize,
+    errcode: usize,
+}
+
+/// Fields of `s_first_error_*`
+const FIRST_ERROR: ErrorFields = ErrorFields {
+    time: 0x198,
+    time_hi: 0x278,
+    ino: 0x19C,
+    block: 0x1A0,
+    func: 0x1A8,
+    line: 0x1C8,
+    errcode: 0x27A,
+};
+
+/// Fields of `s_last_error_*`
+const LAST_ERROR: ErrorFields = ErrorFields {
+    time: 0x1CC,
+    time_hi: 0x279,
+    ino: 0x1D0,
+    block: 0x1D8,
+    func: 0x1E0,
+    line: 0x1D4,
+    errcode: 0x27B,
+};
+
+/// Inconsistent superblock field found by [`SuperBlock::validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperBlockError {
+    /// Unsupported block size
+    BlockSize(u32),
+    /// Zero blocks per group, or without bigalloc a number other than the
+    /// clusters per group
+    BlocksPerGroup(u32),
+    /// Zero, or more clusters per group than a bitmap block can track
+    ClustersPerGroup(u32),
+    /// Zero, more inodes per group than a bitmap block can track, or an
+    /// inode table larger than its group
+    InodesPerGroup(u32),
+    /// Inode size below 128 bytes, not a power of two, or above the block size
+    InodeSize(u16),
+    /// First data block does not match the block size
+    FirstDataBlock(u32),
+    /// No blocks after the first data block
+    BlocksCount(u64),
+    /// More inodes than the groups hold
+    InodesCount(u32),
+    /// Invalid group descriptor size on a 64bit filesystem
+    DescSize(u16),
+    /// First non-reserved inode inside the range reserved on revision 0
+    /// filesystems, or past the last inode
+    FirstInode(u32),
+}
+
+impl fmt::Display for SuperBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlockSize(v) => write!(f, "invalid block size {}", v),
+            Self::BlocksPerGroup(v) => write!(f, "invalid blocks per group {}", v),
+            Self::ClustersPerGroup(v) => write!(f, "invalid clusters per group {}", v),
+            Self::InodesPerGroup(v) => write!(f, "invalid inodes per group {}", v),
+            Self::InodeSize(v) => write!(f, "invalid inode size {}", v),
+            Self::FirstDataBlock(v) => write!(f, "invalid first data block {}", v),
+            Self::BlocksCount(v) => write!(f, "invalid blocks count {}", v),
+            Self::InodesCount(v) => write!(f, "invalid inodes count {}", v),
+            Self::DescSize(v) => write!(f, "invalid group descriptor size {}", v),
+            Self::FirstInode(v) => write!(f, "invalid first inode {}", v),
+        }
+    }
+}
+
+/// An error recorded in the superblock
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ErrorRecord {
+    /// Seconds since the Unix epoch
+    pub time: i64,
+    /// Inode involved, or 0
+    pub ino: u32,
+    /// Block involved, or 0
+    pub block: u64,
+    /// Function that detected the error
+    pub func: String,
+    /// Source line that detected the error
+    pub line: u32,
+    /// Kind of error, as one of the kernel's `EXT4_ERR_*` codes
+    pub errcode: u8,
+}
+
+impl ErrorRecord {
+    /// Describe `error`, detected at `line` of `func`
+    pub(crate) fn new(
+        time: i64,
+        func: &str,
+        line: u32,
+        ino: u32,
+        block: u64,
+        error: &Ext4Error,
+    ) -> Self {
+        let errcode = match error {
+            Ext4Error::IoError | Ext4Error::Device(_) => EXT4_ERR_EIO,
+            Ext4Error::NoMemory => EXT4_ERR_ENOMEM,
+            _ => EXT4_ERR_EFSCORRUPTED,
+        };
+        Self {
+            time,
+            ino,
+            block,
+            func: func.into(),
+            line,
+            errcode,
+        }
+    }
+
+    fn from_bytes(data: &[u8], fields: &ErrorFields) -> Option<Self> {
+        let time = read_le32(data, fields.time) as i64 | ((data[fields.time_hi] as i64) << 32);
+        let func = &data[fields.func..fields.func + ERROR_FUNC_LEN];
+        let line = read_le32(data, fields.line);
+        // Errors recorded without a clock have no time, but always a
+        // function; e2fsck clears all the fields together
+        if time == 0 && func[0] == 0 && line == 0 {
+            return None;
+        }
+        let func_len = func.iter().position(|&b| b == 0).unwrap_or(ERROR_FUNC_LEN);
+        Some(Self {
+            time,
+            ino: read_le32(data, fields.ino),
+            block: read_le32(data, fields.block) as u64
+                | (read_le32(data, fields.block + 4) as u64) << 32,
+            func: String::from_utf8_lossy(&func[..func_len]).into(),
+            line,
+            errcode: data[fields.errcode],
+        })
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    fn write_to(&self, data: &mut [u8], fields: &ErrorFields) {
+        data[fields.time..fields.time + 4].copy_from_slice(&(self.time as u32).to_le_bytes());
+        data[fields.time_hi] = (self.time >> 32) as u8;
+        data[fields.ino..fields.ino + 4].copy_from_slice(&self.ino.to_le_bytes());
+        data[fields.block..fields.block + 8].copy_from_slice(&self.block.to_le_bytes());
+        // NUL padded, and not necessarily NUL terminated
+        let func = &mut data[fields.func..fields.func + ERROR_FUNC_LEN];
+        let len = self.func.len().min(ERROR_FUNC_LEN);
+        func.fill(0);
+        func[..len].copy_from_slice(&self.func.as_bytes()[..len]);
+        data[fields.line..fields.line + 4].copy_from_slice(&self.line.to_le_bytes());
+        data[fields.errcode] = self.errcode;
+    }
+}
+
+/// Error accounting of the superblock, as shown by `dumpe2fs`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ErrorLog {
+    /// Number of errors recorded
+    pub count: u32,
+    /// First error recorded since the log was last cleared by `e2fsck`
+    pub first: Option<ErrorRecord>,
+    /// Most recent error
+    pub last: Option<ErrorRecord>,
+}
+
+impl ErrorLog {
+    /// Parse the error fields of a raw superblock
+    fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            count: read_le32(data, ERROR_COUNT_OFFSET),
+            first: ErrorRecord::from_bytes(data, &FIRST_ERROR),
+            last: ErrorRecord::from_bytes(data, &LAST_ERROR),
+        }
+    }
+
+    /// Add `record` to the log
+    ///
+    /// Like the kernel, the first error is kept until `e2fsck` clears it.
+    pub(crate) fn record(&mut self, record: ErrorRecord) {
+        self.count = self.count.saturating_add(1);
+        if self.first.is_none() {
+            self.first = Some(record.clone());
+        }
+        self.last = Some(record);
+    }
+
+    /// Store the log in the raw superblock `data`, marking it as having errors
+    #[cfg(not(feature = "read-only"))]
+    fn write_to(&self, data: &mut [u8]) {
+        let state = u16::from_le_bytes([data[STATE_OFFSET], data[STATE_OFFSET + 1]]);
+        let state = state | EXT4_ERROR_FS;
+        data[STATE_OFFSET..STATE_OFFSET + 2].copy_from_slice(&state.to_le_bytes());
+        data[ERROR_COUNT_OFFSET..ERROR_COUNT_OFFSET + 4].copy_from_slice(&self.count.to_le_bytes());
+        if let Some(first) = &self.first {
+            first.write_to(data, &FIRST_ERROR);
+        }
+        if let Some(last) = &self.last {
+            last.write_to(data, &LAST_ERROR);
+        }
+    }
+}
+
+/// Read a little-endian u32 at `offset` of `data`
+fn read_le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Recompute the checksum of the raw superblock `data`
+///
+/// Only meaningful with the `metadata_csum` feature.
+#[cfg(not(feature = "read-only"))]
+fn update_checksum(data: &mut [u8]) {
+    let csum = crate::crc32c(!0, &data[..CHECKSUM_OFFSET]);
+    data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&csum.to_le_bytes());
+}
+
+bitflags! {
+    /// Miscellaneous flags (`s_flags`)
+    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+    pub struct SuperBlockFlags: u32 {
+        /// Directory hashes treat name bytes as signed `char`
+        const SIGNED_HASH = 0x0001;
+        /// Directory hashes treat name bytes as unsigned `char`
+        const UNSIGNED_HASH = 0x0002;
+        /// The filesystem is meant for testing development code
+        const TEST_FS = 0x0004;
+    }
+}
+
+bitflags! {
+    /// Compatible features (`s_feature_compat`)
+    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+    pub struct FeatureCompat: u32 {
+        const DIR_PREALLOC = 0x0001;
+        const IMAGIC_INODES = 0x0002;
+        const HAS_JOURNAL = 0x0004;
+        const EXT_ATTR = 0x0008;
+        const RESIZE_INODE = 0x0010;
+        const DIR_INDEX = 0x0020;
+        const LAZY_BG = 0x0040;
+        const EXCLUDE_INODE = 0x0080;
+        const EXCLUDE_BITMAP = 0x0100;
+        const SPARSE_SUPER2 = 0x0200;
+        const FAST_COMMIT = 0x0400;
+        const STABLE_INODES = 0x0800;
+        const ORPHAN_FILE = 0x1000;
+    }
+}
+
+bitflags! {
+    /// Incompatible features (`s_feature_incompat`)
+    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+    pub struct FeatureIncompat: u32 {
+        const COMPRESSION = 0x0001;
+        const FILETYPE = 0x0002;
+        const RECOVER = 0x0004;
+        const JOURNAL_DEV = 0x0008;
+        const META_BG = 0x0010;
+        const EXTENTS = 0x0040;
+        const BIT64 = 0x0080;
+        const MMP = 0x0100;
+        const FLEX_BG = 0x0200;
+        const EA_INODE = 0x0400;
+        const DIRDATA = 0x1000;
+        const CSUM_SEED = 0x2000;
+        const LARGEDIR = 0x4000;
+        const INLINE_DATA = 0x8000;
+        const ENCRYPT = 0x10000;
+        const CASEFOLD = 0x20000;
+    }
+}
+
+bitflags! {
+    /// Read-only compatible features (`s_feature_ro_compat`)
+    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+    pub struct FeatureRoCompat: u32 {
+        const SPARSE_SUPER = 0x0001;
+        const LARGE_FILE = 0x0002;
+        const BTREE_DIR = 0x0004;
+        const HUGE_FILE = 0x0008;
+        const GDT_CSUM = 0x0010;
+        const DIR_NLINK = 0x0020;
+        const EXTRA_ISIZE = 0x0040;
+        const HAS_SNAPSHOT = 0x0080;
+        const QUOTA = 0x0100;
+        const BIGALLOC = 0x0200;
+        const METADATA_CSUM = 0x0400;
+        const REPLICA = 0x0800;
+        const READONLY = 0x1000;
+        const PROJECT = 0x2000;
+        const SHARED_BLOCKS = 0x4000;
+        const VERITY = 0x8000;
+        const ORPHAN_PRESENT = 0x10000;
+    }
+}
+
+impl FeatureIncompat {
+    /// Features whose on-disk structures writes would not keep up to date,
+    /// and `RECOVER`: the journal holds transactions that were never
+    /// replayed, and writing around them would be undone by the replay
+    pub const WRITE_UNSUPPORTED: Self = Self::COMPRESSION
+        .union(Self::RECOVER)
+        .union(Self::JOURNAL_DEV)
+        .union(Self::MMP)
+        .union(Self::DIRDATA)
+        .union(Self::ENCRYPT)
+        .union(Self::CASEFOLD);
+}
+
+impl FeatureRoCompat {
+    /// Features whose on-disk structures writes would not keep up to date
+    pub const WRITE_UNSUPPORTED: Self = Self::HAS_SNAPSHOT
+        .union(Self::QUOTA)
+        .union(Self::BIGALLOC)
+        .union(Self::REPLICA)
+        .union(Self::READONLY)
+        .union(Self::SHARED_BLOCKS);
+}
 
 /// Ext4 superblock structure
 #[derive(Debug, Clone)]
@@ -64,11 +461,11 @@ pub struct SuperBlock {
     /// Block group number of this superblock
     block_group_nr: u16,
     /// Feature compatibility flags
-    feature_compat: u32,
+    feature_compat: FeatureCompat,
     /// Feature incompatibility flags
-    feature_incompat: u32,
+    feature_incompat: FeatureIncompat,
     /// Feature read-only compatibility flags
-    feature_ro_compat: u32,
+    feature_ro_compat: FeatureRoCompat,
     /// Filesystem UUID
     uuid: [u8; 16],
     /// Volume name
@@ -121,8 +518,8 @@ pub struct SuperBlock {
     flags: u32,
     /// RAID stride
     raid_stride: u16,
-    /// Multi-mount protection interval
-    mmp_interval: u8,
+    /// Multi-mount protection update interval in seconds
+    mmp_interval: u16,
     /// Multi-mount protection block number
     mmp_block: u64,
     /// RAID stripe width
@@ -133,16 +530,22 @@ pub struct SuperBlock {
     padding: u8,
     /// Checksum seed
     checksum_seed: u32,
-    /// Writable snapshots
-    wtime_hi: u16,
-    /// Mtime high bits
-    mtime_hi: u16,
+    /// Write time high bits
+    wtime_hi: u8,
+    /// Mount time high bits
+    mtime_hi: u8,
     /// Mkfs time high bits
-    mkfs_time_hi: u16,
-    /// Mtime high bits
-    awtime_hi: u16,
+    mkfs_time_hi: u8,
+    /// Last check time high bits
+    lastcheck_hi: u8,
     /// Checksum of the superblock
     checksum: u32,
+    /// Groups holding superblock backups with `sparse_super2`
+    backup_bgs: [u32; 2],
+    /// Orphan file inode number
+    orphan_file_inum: u32,
+    /// Errors recorded at mount time
+    error_log: ErrorLog,
 }
 
 impl SuperBlock {
@@ -151,38 +554,180 @@ impl SuperBlock {
     where
         D: axdriver_block::BlockDriverOps,
     {
-        // The ext4 superblock is always at offset 1024 from the start of the filesystem
-        // We need to read blocks that contain this offset
-        let block_size = device.block_size();
-        let start_block = 1024 / block_size;
-        let offset_in_block = 1024 % block_size;
-
-        let mut buf = vec![0u8; 1024]; // Read 1024 bytes for superblock
-        let mut temp_buf = vec![0u8; block_size];
-
-        // Read the block that contains the superblock
-        device
-            .read_block(start_block as u64, &mut temp_buf)
-            .map_err(|_| Ext4Error::IoError)?;
-
-        // Copy superblock data from the block
-        let remaining = block_size - offset_in_block;
-        let to_copy = core::cmp::min(1024, remaining);
-        buf[..to_copy].copy_from_slice(&temp_buf[offset_in_block..offset_in_block + to_copy]);
-
-        // If we need more data, read the next block
-        if to_copy < 1024 {
-            device
-                .read_block((start_block + 1) as u64, &mut temp_buf)
-                .map_err(|_| Ext4Error::IoError)?;
-            let remaining_to_copy = 1024 - to_copy;
-            buf[to_copy..].copy_from_slice(&temp_buf[..remaining_to_copy]);
-        }
+        // The ext4 superblock is always at offset 1024 from the start of the
+        // filesystem, whatever the device's sector size
+        let mut buf = vec![0u8; 1024];
+        crate::device::read_bytes(device, SUPERBLOCK_OFFSET as u64, &mut buf)
+            .map_err(Ext4Error::from)?;
 
         // Parse the superblock
         Self::from_bytes(&buf)
     }
 
+    /// Store `log` in the primary superblock on `device`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_error_log<D>(&self, device: &mut D, log: &ErrorLog) -> Ext4Result<()>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        self.edit_on_device(device, SUPERBLOCK_OFFSET as u64, |data| log.write_to(data))
+    }
+
+    /// Store the state flags in the primary superblock on `device`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_state<D>(&self, device: &mut D) -> Ext4Result<()>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        self.edit_on_device(device, SUPERBLOCK_OFFSET as u64, |data| {
+            data[STATE_OFFSET..STATE_OFFSET + 2].copy_from_slice(&self.state.to_le_bytes());
+        })
+    }
+
+    /// Store the orphan list head and the `orphan_present` feature in the
+    /// primary superblock on `device`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_orphan_state<D>(&self, device: &mut D) -> Ext4Result<()>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        self.edit_on_device(device, SUPERBLOCK_OFFSET as u64, |data| {
+            data[RO_COMPAT_OFFSET..RO_COMPAT_OFFSET + 4]
+                .copy_from_slice(&self.feature_ro_compat.bits().to_le_bytes());
+            data[LAST_ORPHAN_OFFSET..LAST_ORPHAN_OFFSET + 4]
+                .copy_from_slice(&self.last_orphan.to_le_bytes());
+        })
+    }
+
+    /// Set or clear the `needs_recovery` feature in the primary superblock
+    /// on `device`, leaving the features of `self` as they are
+    ///
+    /// The feature tells the next mount that the journal holds a committed
+    /// transaction; this mount is the one writing it, so it keeps writing.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_needs_recovery<D>(&self, device: &mut D, recover: bool) -> Ext4Result<()>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        self.edit_on_device(device, SUPERBLOCK_OFFSET as u64, |data| {
+            let mut incompat = read_le32(data, INCOMPAT_OFFSET);
+            incompat &= !FeatureIncompat::RECOVER.bits();
+            if recover {
+                incompat |= FeatureIncompat::RECOVER.bits();
+            }
+            data[INCOMPAT_OFFSET..INCOMPAT_OFFSET + 4].copy_from_slice(&incompat.to_le_bytes());
+        })
+    }
+
+    /// Store the geometry, features, UUID and label in the superblock copy
+    /// at byte `offset` of `device`
+    ///
+    /// These are the fields `tune2fs` and `resize2fs` keep in step across
+    /// copies. The `orphan_present` feature of a copy is left as it is, as
+    /// the orphan list only lives in the primary superblock.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_shared_fields<D>(&self, device: &mut D, offset: u64) -> Ext4Result<()>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        let put_u32 = |data: &mut [u8], at: usize, value: u32| {
+            data[at..at + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        self.edit_on_device(device, offset, |data| {
+            put_u32(data, INODES_COUNT_OFFSET, self.inodes_count);
+            put_u32(data, BLOCKS_COUNT_OFFSET, self.blocks_count as u32);
+            put_u32(data, RESERVED_COUNT_OFFSET, self.reserved_blocks_count as u32);
+            put_u32(data, COMPAT_OFFSET, self.feature_compat.bits());
+            put_u32(data, INCOMPAT_OFFSET, self.feature_incompat.bits());
+            let orphan = FeatureRoCompat::ORPHAN_PRESENT.bits();
+            let ro_compat = (self.feature_ro_compat.bits() & !orphan)
+                | (read_le32(data, RO_COMPAT_OFFSET) & orphan);
+            put_u32(data, RO_COMPAT_OFFSET, ro_compat);
+            data[UUID_OFFSET..UUID_OFFSET + 16].copy_from_slice(&self.uuid);
+            data[LABEL_OFFSET..LABEL_OFFSET + 16].copy_from_slice(&self.volume_name);
+            data[RESERVED_GDT_OFFSET..RESERVED_GDT_OFFSET + 2]
+                .copy_from_slice(&self.reserved_gdt_blocks.to_le_bytes());
+            if self.has_64bit() {
+                put_u32(data, BLOCKS_COUNT_HI_OFFSET, (self.blocks_count >> 32) as u32);
+                put_u32(data, RESERVED_COUNT_HI_OFFSET, (self.reserved_blocks_count >> 32) as u32);
+            }
+        })
+    }
+
+    /// Apply `edit` to the superblock copy at byte `offset` of `device`
+    ///
+    /// The checksum is updated to match if the filesystem has one. A copy
+    /// without the ext4 magic is left alone.
+    #[cfg(not(feature = "read-only"))]
+    fn edit_on_device<D>(
+        &self,
+        device: &mut D,
+        offset: u64,
+        edit: impl FnOnce(&mut [u8]),
+    ) -> Ext4Result<()>
+    where
+        D: axdriver_block::BlockDriverOps,
+    {
+        let mut buf = vec![0u8; 1024];
+        crate::device::read_bytes(device, offset, &mut buf).map_err(Ext4Error::from)?;
+        if buf[MAGIC_OFFSET..MAGIC_OFFSET + 2] != 0xEF53u16.to_le_bytes() {
+            warn!("No superblock at byte {}", offset);
+            return Err(Ext4Error::InvalidMagic);
+        }
+
+        edit(&mut buf);
+        if self.has_metadata_csum() {
+            update_checksum(&mut buf);
+        }
+        crate::device::write_bytes(device, offset, &buf).map_err(Ext4Error::from)
+    }
+
+    /// Byte offsets of the primary superblock and its backups
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn copy_offsets(&self) -> Vec<u64> {
+        (0..self.groups_count())
+            .filter(|&group| self.group_has_super(group))
+            .map(|group| match group {
+                0 => SUPERBLOCK_OFFSET as u64,
+                _ => self.group_first_block(group) * self.block_size as u64,
+            })
+            .collect()
+    }
+
+    /// Primary group descriptor blocks paired with the blocks holding
+    /// their backups
+    ///
+    /// Every group with a superblock backup holds a copy of the contiguous
+    /// descriptor table right after it; with `meta_bg` each later meta
+    /// group's block is also copied to its second and last groups.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn desc_backup_blocks(&self) -> Vec<(u64, u64)> {
+        let per_block = self.descs_per_block();
+        let mut pairs = Vec::new();
+        for group in 1..self.groups_count() {
+            let meta_group = group / per_block;
+            let count = self.group_desc_blocks(group);
+            if self.has_meta_bg() && meta_group >= self.first_meta_bg {
+                if count != 0 && group != meta_group * per_block {
+                    let block = self.group_first_block(group) + self.group_has_super(group) as u64;
+                    pairs.push((self.group_desc_block(meta_group), block));
+                }
+                continue;
+            }
+            let start = self.group_first_block(group) + 1;
+            pairs.extend(
+                (0..count).map(|index| (self.group_desc_block(index), start + index as u64)),
+            );
+        }
+        pairs
+    }
+
+    /// Block holding the primary superblock
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn superblock_block(&self) -> u64 {
+        (SUPERBLOCK_OFFSET / self.block_size) as u64
+    }
+
     /// Parse superblock from bytes
     pub fn from_bytes(data: &[u8]) -> Ext4Result<Self> {
         if data.len() < 1024 {
@@ -231,12 +776,16 @@ impl SuperBlock {
         let rev_level = read_u32(76);
         let default_reserved_uid = read_u16(80);
         let default_reserved_gid = read_u16(82);
-        let first_inode = read_u32(84);
-        let inode_size = read_u16(88);
+        // Revision 0 filesystems have fixed values for these fields
+        let (first_inode, inode_size) = if rev_level == EXT4_GOOD_OLD_REV {
+            (EXT4_GOOD_OLD_FIRST_INO, EXT4_GOOD_OLD_INODE_SIZE)
+        } else {
+            (read_u32(84), read_u16(88))
+        };
         let block_group_nr = read_u16(90);
-        let feature_compat = read_u32(92);
-        let feature_incompat = read_u32(96);
-        let feature_ro_compat = read_u32(100);
+        let feature_compat = FeatureCompat::from_bits_retain(read_u32(92));
+        let feature_incompat = FeatureIncompat::from_bits_retain(read_u32(96));
+        let feature_ro_compat = FeatureRoCompat::from_bits_retain(read_u32(100));
 
         let uuid = {
             let bytes = read_bytes(104, 16);
@@ -299,17 +848,20 @@ impl SuperBlock {
         let want_extra_isize = read_u16(350);
         let flags = read_u32(352);
         let raid_stride = read_u16(356);
-        let mmp_interval = read_u8(358);
-        let mmp_block_lo = read_u32(359);
-        let raid_stripe_width = read_u32(363);
-        let checksum_type = read_u8(367);
-        let padding = read_u8(368);
-        let checksum_seed = read_u32(369);
-        let wtime_hi = read_u16(373);
-        let mtime_hi = read_u16(375);
-        let mkfs_time_hi = read_u16(377);
-        let awtime_hi = read_u16(379);
-        let checksum = read_u32(381);
+        let mmp_interval = read_u16(358);
+        let mmp_block = read_u32(360) as u64 | (read_u32(364) as u64) << 32;
+        let raid_stripe_width = read_u32(368);
+        let checksum_type = read_u8(373);
+        let padding = read_u8(375);
+        let checksum_seed = read_u32(624);
+        let wtime_hi = read_u8(628);
+        let mtime_hi = read_u8(629);
+        let mkfs_time_hi = read_u8(630);
+        let lastcheck_hi = read_u8(631);
+        let checksum = read_u32(CHECKSUM_OFFSET);
+        let backup_bgs = [read_u32(588), read_u32(592)];
+        let orphan_file_inum = read_u32(640);
+        let error_log = ErrorLog::from_bytes(data);
 
         // Combine high and low parts for 64-bit values
         let blocks_count = ((blocks_count_hi as u64) << 32) | (blocks_count_lo as u64);
@@ -317,7 +869,6 @@ impl SuperBlock {
             ((reserved_blocks_count_hi as u64) << 32) | (reserved_blocks_count_lo as u64);
         let free_blocks_count =
             ((free_blocks_count_hi as u64) << 32) | (free_blocks_count_lo as u64);
-        let mmp_block = ((mmp_block_lo as u64) << 32) | (mmp_block_lo as u64);
 
         // Calculate block size
         let block_size = 1024 << log_block_size;
@@ -399,12 +950,18 @@ impl SuperBlock {
             wtime_hi,
             mtime_hi,
             mkfs_time_hi,
-            awtime_hi,
+            lastcheck_hi,
             checksum,
+            backup_bgs,
+            orphan_file_inum,
+            error_log,
         })
     }
 
     /// Validate the superblock
+    ///
+    /// Checks that the geometry is self-consistent before anything else is
+    /// read from the device.
     pub fn validate(&self) -> Ext4Result<()> {
         if self.magic != 0xEF53 {
             error!("Invalid ext4 magic number: 0x{:04X}", self.magic);
@@ -414,10 +971,106 @@ impl SuperBlock {
         if self.state != 1 {
             warn!("Filesystem state is not clean: {}", self.state);
         }
+        self.check_flags();
+
+        self.check_geometry().map_err(|e| {
+            error!("Corrupt superblock: {}", e);
+            Ext4Error::CorruptSuperblock(e)
+        })
+    }
+
+    /// Warn about `s_flags` that leave the directory hash ambiguous or mark
+    /// a test filesystem
+    ///
+    /// Without either hash flag, Linux hashes as the `char` of the machine
+    /// that mounts the filesystem; names are hashed as signed here, like
+    /// x86 does. With both, the unsigned flag wins, as in Linux.
+    fn check_flags(&self) {
+        let flags = self.super_flags();
+        if self.has_dir_index() {
+            let hash_flags = SuperBlockFlags::SIGNED_HASH | SuperBlockFlags::UNSIGNED_HASH;
+            if !flags.intersects(hash_flags) {
+                warn!("Neither signed nor unsigned directory hash flag set, assuming signed");
+            } else if flags.contains(hash_flags) {
+                warn!("Both signed and unsigned directory hash flags set, using unsigned");
+            }
+        }
+        if flags.contains(SuperBlockFlags::TEST_FS) {
+            warn!("Filesystem is flagged for testing development code");
+        }
+    }
+
+    fn check_geometry(&self) -> Result<(), SuperBlockError> {
+        use SuperBlockError::*;
 
         if self.block_size != 1024 && self.block_size != 2048 && self.block_size != 4096 {
-            error!("Invalid block size: {}", self.block_size);
-            return Err(Ext4Error::InvalidState);
+            return Err(BlockSize(self.block_size));
+        }
+
+        // Each group's block and inode bitmaps fit in one block; with
+        // bigalloc the block bitmap tracks clusters
+        let bits_per_block = self.block_size * 8;
+        // Without bigalloc, clusters are blocks
+        let bigalloc = self.feature_ro_compat.contains(FeatureRoCompat::BIGALLOC);
+        if self.blocks_per_group == 0
+            || (!bigalloc && self.blocks_per_group != self.clusters_per_group)
+        {
+            return Err(BlocksPerGroup(self.blocks_per_group));
+        }
+        if self.clusters_per_group == 0 || self.clusters_per_group > bits_per_block {
+            return Err(ClustersPerGroup(self.clusters_per_group));
+        }
+        if self.inodes_per_group == 0 || self.inodes_per_group > bits_per_block {
+            return Err(InodesPerGroup(self.inodes_per_group));
+        }
+
+        let inode_size = self.inode_size as u32;
+        if inode_size < EXT4_GOOD_OLD_INODE_SIZE as u32
+            || !inode_size.is_power_of_two()
+            || inode_size > self.block_size
+        {
+            return Err(InodeSize(self.inode_size));
+        }
+
+        // The inode table must fit in its group along with the bitmaps
+        let itable_blocks = (self.inodes_per_group as u64 * inode_size as u64)
+            .div_ceil(self.block_size as u64);
+        if itable_blocks + 2 > self.blocks_per_group as u64 {
+            return Err(InodesPerGroup(self.inodes_per_group));
+        }
+
+        // Block 0 holds the superblock unless blocks are 1 KiB, where the
+        // superblock is block 1 and is the first data block. Bigalloc
+        // filesystems start at block 0 whatever the block size.
+        let expected = if self.block_size == 1024
+            && !self.feature_ro_compat.contains(FeatureRoCompat::BIGALLOC)
+        {
+            1
+        } else {
+            0
+        };
+        if self.first_data_block != expected {
+            return Err(FirstDataBlock(self.first_data_block));
+        }
+        if self.blocks_count <= self.first_data_block as u64 {
+            return Err(BlocksCount(self.blocks_count));
+        }
+
+        if self.inodes_count as u64 > self.inodes_per_group as u64 * self.groups_count() as u64 {
+            return Err(InodesCount(self.inodes_count));
+        }
+
+        // Revision 1 may reserve more inodes than revision 0, never fewer
+        if self.first_inode < EXT4_GOOD_OLD_FIRST_INO || self.first_inode > self.inodes_count {
+            return Err(FirstInode(self.first_inode));
+        }
+
+        if self.has_64bit()
+            && (self.desc_size < EXT4_MIN_DESC_SIZE_64BIT
+                || self.desc_size > EXT4_MAX_DESC_SIZE
+                || !self.desc_size.is_power_of_two())
+        {
+            return Err(DescSize(self.desc_size));
         }
 
         Ok(())
@@ -475,6 +1128,26 @@ impl SuperBlock {
     pub fn state(&self) -> u16 {
         self.state
     }
+
+    /// Check if the filesystem is marked as having errors
+    pub fn has_errors(&self) -> bool {
+        self.state & EXT4_ERROR_FS != 0
+    }
+
+    /// Mark the filesystem as having errors, in memory only; see
+    /// [`write_state`](Self::write_state)
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn mark_errors(&mut self) {
+        self.state |= EXT4_ERROR_FS;
+    }
+
+    /// Errors recorded in the superblock when it was read
+    ///
+    /// [`Ext4FileSystem::error_log`](crate::Ext4FileSystem::error_log) also
+    /// includes the errors detected since.
+    pub fn error_log(&self) -> &ErrorLog {
+        &self.error_log
+    }
     pub fn errors(&self) -> u16 {
         self.errors
     }
@@ -508,13 +1181,13 @@ impl SuperBlock {
     pub fn block_group_nr(&self) -> u16 {
         self.block_group_nr
     }
-    pub fn feature_compat(&self) -> u32 {
+    pub fn feature_compat(&self) -> FeatureCompat {
         self.feature_compat
     }
-    pub fn feature_incompat(&self) -> u32 {
+    pub fn feature_incompat(&self) -> FeatureIncompat {
         self.feature_incompat
     }
-    pub fn feature_ro_compat(&self) -> u32 {
+    pub fn feature_ro_compat(&self) -> FeatureRoCompat {
         self.feature_ro_compat
     }
     pub fn uuid(&self) -> &[u8; 16] {
@@ -523,6 +1196,34 @@ impl SuperBlock {
     pub fn volume_name(&self) -> &[u8; 16] {
         &self.volume_name
     }
+
+    /// Filesystem UUID
+    pub fn volume_uuid(&self) -> Uuid {
+        Uuid(self.uuid)
+    }
+
+    /// Volume label, up to the first NUL byte
+    ///
+    /// A label that isn't valid UTF-8 is cut short before the first invalid
+    /// sequence.
+    pub fn label(&self) -> &str {
+        let len = self.volume_name.iter().position(|&b| b == 0).unwrap_or(16);
+        let name = &self.volume_name[..len];
+        match core::str::from_utf8(name) {
+            Ok(label) => label,
+            Err(e) => core::str::from_utf8(&name[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_volume_name(&mut self, name: [u8; 16]) {
+        self.volume_name = name;
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_uuid(&mut self, uuid: Uuid) {
+        self.uuid = uuid.0;
+    }
     pub fn last_mounted(&self) -> &[u8; 64] {
         &self.last_mounted
     }
@@ -550,6 +1251,26 @@ impl SuperBlock {
     pub fn last_orphan(&self) -> u32 {
         self.last_orphan
     }
+    pub fn orphan_file_inum(&self) -> u32 {
+        self.orphan_file_inum
+    }
+
+    /// Set or clear the `orphan_present` feature
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_orphan_present(&mut self, present: bool) {
+        self.feature_ro_compat.set(FeatureRoCompat::ORPHAN_PRESENT, present);
+    }
+
+    /// Set the `large_file` feature
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_large_file(&mut self) {
+        self.feature_ro_compat.insert(FeatureRoCompat::LARGE_FILE);
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_last_orphan(&mut self, ino: u32) {
+        self.last_orphan = ino;
+    }
     pub fn hash_seed(&self) -> &[u32; 4] {
         &self.hash_seed
     }
@@ -562,6 +1283,223 @@ impl SuperBlock {
     pub fn desc_size(&self) -> u16 {
         self.desc_size
     }
+
+    /// Features of the filesystem that keep it from being written, empty
+    /// if it may be
+    pub fn write_blockers(&self) -> (FeatureIncompat, FeatureRoCompat) {
+        (
+            self.feature_incompat & FeatureIncompat::WRITE_UNSUPPORTED,
+            self.feature_ro_compat & FeatureRoCompat::WRITE_UNSUPPORTED,
+        )
+    }
+
+    /// Check if files may use extent trees
+    pub fn has_extents(&self) -> bool {
+        self.feature_incompat.contains(FeatureIncompat::EXTENTS)
+    }
+
+    /// Check if block numbers are 64-bit
+    pub fn has_64bit(&self) -> bool {
+        self.feature_incompat.contains(FeatureIncompat::BIT64)
+    }
+
+    /// Check if directory entries record the file type
+    pub fn has_filetype(&self) -> bool {
+        self.feature_incompat.contains(FeatureIncompat::FILETYPE)
+    }
+
+    /// Check if block group metadata is grouped into flexible groups
+    pub fn has_flex_bg(&self) -> bool {
+        self.feature_incompat.contains(FeatureIncompat::FLEX_BG)
+    }
+
+    /// Number of blocks to preallocate for a new directory
+    ///
+    /// `s_prealloc_dir_blocks` only applies with the `dir_prealloc` feature;
+    /// otherwise directories start with a single block.
+    pub fn dir_prealloc_blocks(&self) -> u32 {
+        if self.feature_compat.contains(FeatureCompat::DIR_PREALLOC) {
+            (self.prealloc_dir_blocks as u32).max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Check if directories may have more than [`EXT4_LINK_MAX`] subdirectories
+    ///
+    /// [`EXT4_LINK_MAX`]: crate::EXT4_LINK_MAX
+    pub fn has_dir_nlink(&self) -> bool {
+        self.feature_ro_compat.contains(FeatureRoCompat::DIR_NLINK)
+    }
+
+    /// Check if the filesystem has a journal
+    pub fn has_journal(&self) -> bool {
+        self.feature_compat.contains(FeatureCompat::HAS_JOURNAL)
+    }
+
+    /// Check if directories may use hashed indexes
+    pub fn has_dir_index(&self) -> bool {
+        self.feature_compat.contains(FeatureCompat::DIR_INDEX)
+    }
+
+    /// Check if metadata is protected by crc32c checksums
+    pub fn has_metadata_csum(&self) -> bool {
+        self.feature_ro_compat.contains(FeatureRoCompat::METADATA_CSUM)
+    }
+
+    /// Check if directories may have a three-level hashed index and grow
+    /// past 4 GiB
+    pub fn has_large_dir(&self) -> bool {
+        self.feature_incompat.contains(FeatureIncompat::LARGEDIR)
+    }
+
+    /// Check if files may be protected by fs-verity
+    pub fn has_verity(&self) -> bool {
+        self.feature_ro_compat.contains(FeatureRoCompat::VERITY)
+    }
+
+    /// Check if group descriptors carry checksums, either crc16
+    /// (`gdt_csum`) or crc32c (`metadata_csum`)
+    pub fn has_group_csum(&self) -> bool {
+        self.feature_ro_compat
+            .intersects(FeatureRoCompat::GDT_CSUM | FeatureRoCompat::METADATA_CSUM)
+    }
+
+    /// Size of an on-disk block group descriptor
+    ///
+    /// `s_desc_size` is only meaningful with the 64bit feature; otherwise
+    /// descriptors are always 32 bytes.
+    pub fn group_desc_size(&self) -> u32 {
+        if self.has_64bit() {
+            self.desc_size.max(EXT4_MIN_DESC_SIZE_64BIT) as u32
+        } else {
+            EXT4_MIN_DESC_SIZE as u32
+        }
+    }
+
+    /// Check if superblock backups are kept only in some groups
+    pub fn has_sparse_super(&self) -> bool {
+        self.feature_ro_compat.contains(FeatureRoCompat::SPARSE_SUPER)
+    }
+
+    /// Check if group descriptors are stored in meta block groups
+    pub fn has_meta_bg(&self) -> bool {
+        self.feature_incompat.contains(FeatureIncompat::META_BG)
+    }
+
+    /// Number of block groups
+    pub fn groups_count(&self) -> u32 {
+        let data_blocks = self.blocks_count.saturating_sub(self.first_data_block as u64);
+        data_blocks.div_ceil(self.blocks_per_group as u64) as u32
+    }
+
+    /// Number of group descriptors in one block
+    pub fn descs_per_block(&self) -> u32 {
+        self.block_size / self.group_desc_size()
+    }
+
+    /// First block of block group `group`
+    pub fn group_first_block(&self, group: u32) -> u64 {
+        self.first_data_block as u64 + group as u64 * self.blocks_per_group as u64
+    }
+
+    /// Check if block group `group` holds a superblock backup
+    ///
+    /// Group 0 always holds the primary superblock. With `sparse_super2` only
+    /// the groups named in `s_backup_bgs` hold backups; with `sparse_super`
+    /// only group 1 and powers of 3, 5 and 7 do.
+    pub fn group_has_super(&self, group: u32) -> bool {
+        if group == 0 {
+            return true;
+        }
+        if self.feature_compat.contains(FeatureCompat::SPARSE_SUPER2) {
+            return self.backup_bgs.contains(&group);
+        }
+        if group == 1 || !self.has_sparse_super() {
+            return true;
+        }
+        if group & 1 == 0 {
+            return false;
+        }
+        [3u32, 5, 7].iter().any(|&base| {
+            let mut n = group;
+            while n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        })
+    }
+
+    /// Filesystem block holding group descriptor block `index`
+    ///
+    /// Without `meta_bg` the descriptor table follows the primary superblock.
+    /// With `meta_bg`, descriptor blocks from `s_first_meta_bg` on are spread
+    /// out: block `index` describes meta group `index` and sits at the start
+    /// of its first group, after the superblock backup if there is one.
+    pub fn group_desc_block(&self, index: u32) -> u64 {
+        // Block holding the primary superblock: 1 with 1 KiB blocks, else 0
+        let sb_block = (SUPERBLOCK_OFFSET / self.block_size) as u64;
+        if !self.has_meta_bg() || index < self.first_meta_bg {
+            return sb_block + 1 + index as u64;
+        }
+
+        let group = index * self.descs_per_block();
+        let mut block = self.group_first_block(group);
+        if self.group_has_super(group) {
+            block += 1;
+        }
+        // With 1 KiB blocks and bigalloc, group 0 starts at block 0 but the
+        // superblock still occupies block 1
+        if group == 0 && self.first_data_block == 0 && sb_block == 1 {
+            block += 1;
+        }
+        block
+    }
+
+    /// Number of group descriptor blocks, not counting reserved ones
+    pub fn desc_blocks(&self) -> u32 {
+        self.groups_count().div_ceil(self.descs_per_block())
+    }
+
+    /// Number of group descriptor blocks stored at the start of `group`
+    ///
+    /// Groups holding a superblock backup also hold a copy of the contiguous
+    /// descriptor table, which with `meta_bg` only covers the first
+    /// `s_first_meta_bg` descriptor blocks. Each later meta group keeps its
+    /// single descriptor block in its first group, with backups in its second
+    /// and last groups.
+    pub fn group_desc_blocks(&self, group: u32) -> u32 {
+        let per_block = self.descs_per_block();
+        let meta_group = group / per_block;
+        if self.has_meta_bg() && meta_group >= self.first_meta_bg {
+            let first = meta_group * per_block;
+            let last = first + per_block - 1;
+            return (group == first || group == first + 1 || group == last) as u32;
+        }
+
+        if !self.group_has_super(group) {
+            0
+        } else if self.has_meta_bg() {
+            self.first_meta_bg
+        } else {
+            self.desc_blocks()
+        }
+    }
+
+    /// Number of blocks at the start of `group` taken by the superblock
+    /// backup and group descriptors, including reserved descriptor blocks
+    pub fn group_overhead_blocks(&self, group: u32) -> u32 {
+        let has_super = self.group_has_super(group) as u32;
+        let reserved = if has_super != 0
+            && (!self.has_meta_bg() || group / self.descs_per_block() < self.first_meta_bg)
+        {
+            self.reserved_gdt_blocks as u32
+        } else {
+            0
+        };
+        has_super + self.group_desc_blocks(group) + reserved
+    }
+
     pub fn default_mount_opts(&self) -> u32 {
         self.default_mount_opts
     }
@@ -592,10 +1530,18 @@ impl SuperBlock {
     pub fn flags(&self) -> u32 {
         self.flags
     }
+    /// `s_flags`, including any bits unknown here
+    pub fn super_flags(&self) -> SuperBlockFlags {
+        SuperBlockFlags::from_bits_retain(self.flags)
+    }
+    /// Check if the filesystem is flagged for testing development code
+    pub fn is_test_fs(&self) -> bool {
+        self.super_flags().contains(SuperBlockFlags::TEST_FS)
+    }
     pub fn raid_stride(&self) -> u16 {
         self.raid_stride
     }
-    pub fn mmp_interval(&self) -> u8 {
+    pub fn mmp_interval(&self) -> u16 {
         self.mmp_interval
     }
     pub fn mmp_block(&self) -> u64 {
@@ -613,17 +1559,30 @@ impl SuperBlock {
     pub fn checksum_seed(&self) -> u32 {
         self.checksum_seed
     }
-    pub fn wtime_hi(&self) -> u16 {
+
+    /// Seed of the metadata checksums
+    ///
+    /// With the `csum_seed` feature, it is stored in `s_checksum_seed` so the
+    /// UUID can change without rewriting every checksum. Otherwise it is
+    /// derived from the UUID.
+    pub fn csum_seed(&self) -> u32 {
+        if self.feature_incompat.contains(FeatureIncompat::CSUM_SEED) {
+            self.checksum_seed
+        } else {
+            crate::crc32c(!0, &self.uuid)
+        }
+    }
+    pub fn wtime_hi(&self) -> u8 {
         self.wtime_hi
     }
-    pub fn mtime_hi(&self) -> u16 {
+    pub fn mtime_hi(&self) -> u8 {
         self.mtime_hi
     }
-    pub fn mkfs_time_hi(&self) -> u16 {
+    pub fn mkfs_time_hi(&self) -> u8 {
         self.mkfs_time_hi
     }
-    pub fn awtime_hi(&self) -> u16 {
-        self.awtime_hi
+    pub fn lastcheck_hi(&self) -> u8 {
+        self.lastcheck_hi
     }
     pub fn checksum(&self) -> u32 {
         self.checksum
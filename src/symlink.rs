@@ -3,83 +3,38 @@ use alloc::vec::Vec;
 use axdriver_block::BlockDriverOps;
 use log::*;
 
-use crate::{Ext4Error, Ext4Result, Inode};
+#[cfg(not(feature = "read-only"))]
+use crate::{InodeBuilder, InodeType};
+use crate::{Ext4Error, Ext4FileSystem, Ext4Result, Inode, InodeFlags};
+
+/// Room for the target of a fast symlink in `i_block`
+#[cfg(not(feature = "read-only"))]
+const FAST_SYMLINK_MAX: usize = 60;
 
 /// Symbolic link operations
 pub struct SymLink {
     inode: Inode,
-    target: Option<String>,
 }
 
 impl SymLink {
     /// Create a new symbolic link
     pub fn new(inode: Inode) -> Self {
-        Self {
-            inode,
-            target: None,
-        }
+        Self { inode }
     }
 
     /// Get the target path
-    pub fn target<D>(&self, fs: &mut crate::Ext4FileSystem<D>) -> Ext4Result<String>
+    pub fn target<D>(&self, fs: &crate::Ext4FileSystem<D>) -> Ext4Result<String>
     where
         D: BlockDriverOps,
     {
-        if let Some(target) = &self.target {
-            return Ok(target.clone());
-        }
-
-        // Read the target from the inode
-        if self.inode.size < 60 {
-            // Short symlink is stored in the inode block pointers
-            let mut target_bytes = Vec::new();
-            for &block in &self.inode.block {
-                if block == 0 {
-                    break;
-                }
-                target_bytes.push((block & 0xFF) as u8);
-                target_bytes.push(((block >> 8) & 0xFF) as u8);
-                target_bytes.push(((block >> 16) & 0xFF) as u8);
-                target_bytes.push(((block >> 24) & 0xFF) as u8);
-            }
-
-            // Trim to the actual size
-            target_bytes.truncate(self.inode.size as usize);
-
-            String::from_utf8(target_bytes).map_err(|_| Ext4Error::InvalidInput)
-        } else {
-            // Long symlink is stored in blocks
-            let block_size = fs.superblock().block_size();
-            let mut target_bytes = Vec::new();
-
-            for i in 0..self.inode.block_count(block_size) {
-                let block_num =
-                    self.inode
-                        .get_block_number(i * block_size as u64, block_size, fs)?;
-                if block_num == 0 {
-                    break;
-                }
-
-                let mut block_buf = vec![0u8; block_size as usize];
-                fs.read_block(block_num, &mut block_buf)?;
-
-                let remaining = self.inode.size - target_bytes.len() as u64;
-                let to_read = (remaining as usize).min(block_size as usize);
-                target_bytes.extend_from_slice(&block_buf[..to_read]);
-            }
-
-            String::from_utf8(target_bytes).map_err(|_| Ext4Error::InvalidInput)
-        }
-    }
-
-    /// Set the target path
-    pub fn set_target(&mut self, target: String) {
-        self.target = Some(target);
+        read_target(fs, &self.inode)
     }
 
-    /// Create a symbolic link
+    /// Create a symbolic link named `name` in directory `parent_ino`,
+    /// pointing at `target`, as [`Ext4FileSystem::symlink`]
+    #[cfg(not(feature = "read-only"))]
     pub fn create<D>(
-        fs: &mut crate::Ext4FileSystem<D>,
+        fs: &mut Ext4FileSystem<D>,
         parent_ino: u32,
         name: &str,
         target: &str,
@@ -87,30 +42,115 @@ impl SymLink {
     where
         D: BlockDriverOps,
     {
-        // Allocate a new inode
-        let ino = fs.alloc_inode()?;
-        let mut inode = fs.get_inode(ino)?;
+        fs.symlink(parent_ino, name.as_bytes(), target.as_bytes(), &InodeBuilder::symlink())
+    }
+}
 
-        // Set up the inode as a symlink
-        let mode = crate::inode::InodeMode::IFLNK
-            | crate::inode::InodeMode::IRUSR
-            | crate::inode::InodeMode::IWUSR
-            | crate::inode::InodeMode::IXUSR
-            | crate::inode::InodeMode::IRGRP
-            | crate::inode::InodeMode::IXGRP
-            | crate::inode::InodeMode::IROTH
-            | crate::inode::InodeMode::IXOTH;
-        let target_bytes = target.as_bytes();
-        let size = target_bytes.len() as u64;
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Create a symbolic link named `name` in directory `parent`, pointing
+    /// at `target`, with the owner and permissions of `builder`
+    ///
+    /// Targets shorter than 60 bytes are stored in the inode itself, longer
+    /// ones in a block. Fails with `InvalidArg` if `builder` doesn't
+    /// describe a symbolic link, or the target is empty or doesn't fit in a
+    /// block.
+    #[cfg(not(feature = "read-only"))]
+    pub fn symlink(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        target: &[u8],
+        builder: &InodeBuilder,
+    ) -> Ext4Result<u32> {
+        let block_size = self.superblock.block_size();
+        if builder.inode_type() != InodeType::SymLink
+            || target.is_empty()
+            || target.len() >= block_size as usize
+        {
+            return Err(Ext4Error::InvalidArg);
+        }
+        self.create_atomically(parent, name, |fs| {
+            fs.create_symlink_inner(parent, name, target, builder)
+        })
+    }
 
-        // For now, just return the allocated inode number
-        // In a full implementation, we would:
-        // 1. Set up the inode with the correct mode and size
-        // 2. Store the target path either in the inode or in blocks
-        // 3. Write the inode back to disk
-        // 4. Add a directory entry to the parent directory
+    #[cfg(not(feature = "read-only"))]
+    fn create_symlink_inner(
+        &mut self,
+        parent: u32,
+        name: &[u8],
+        target: &[u8],
+        builder: &InodeBuilder,
+    ) -> Ext4Result<u32> {
+        self.check_new_entry(parent, name)?;
+        let block_size = self.superblock.block_size();
+        let mut inode = self.new_inode(builder)?;
+        if target.len() < FAST_SYMLINK_MAX {
+            inode.flags &= !InodeFlags::EXTENTS.bits();
+            let mut area = [0u8; FAST_SYMLINK_MAX];
+            area[..target.len()].copy_from_slice(target);
+            for (slot, bytes) in inode.block.iter_mut().zip(area.chunks_exact(4)) {
+                *slot = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        } else {
+            let block = self.alloc_data_block(&inode)?;
+            let mut buf = vec![0u8; block_size as usize];
+            buf[..target.len()].copy_from_slice(target);
+            self.write_block(block, &buf)?;
+            self.map_only_block(&mut inode, block)?;
+            inode.charge_block(block_size);
+        }
+        inode.size = target.len() as u64;
+        self.write_inode(&inode)?;
+        self.add_dir_entry(parent, inode.ino, name, InodeType::SymLink)?;
+        debug!("Created symlink {} in directory {}", inode.ino, parent);
+        Ok(inode.ino)
+    }
+}
+
+/// Read the target of a symbolic link inode
+pub(crate) fn read_target<D>(fs: &crate::Ext4FileSystem<D>, inode: &Inode) -> Ext4Result<String>
+where
+    D: BlockDriverOps,
+{
+    String::from_utf8(read_target_bytes(fs, inode)?).map_err(|_| Ext4Error::InvalidInput)
+}
 
-        warn!("symlink creation not yet fully implemented for pure Rust ext4");
-        Ok(ino)
+/// Read the target of a symbolic link inode, which need not be UTF-8
+pub(crate) fn read_target_bytes<D>(
+    fs: &crate::Ext4FileSystem<D>,
+    inode: &Inode,
+) -> Ext4Result<Vec<u8>>
+where
+    D: BlockDriverOps,
+{
+    let sb = fs.superblock();
+    if inode.is_fast_symlink(sb.block_size(), sb.cluster_size()) {
+        // Short symlink is stored in the inode block pointers
+        let mut target_bytes: Vec<u8> = inode.block.iter().flat_map(|b| b.to_le_bytes()).collect();
+        target_bytes.truncate(inode.size as usize);
+        Ok(target_bytes)
+    } else if inode.inode_flags().contains(InodeFlags::INLINE_DATA) {
+        warn!("Inline data symlink {} not supported", inode.ino);
+        Err(Ext4Error::NotSupported)
+    } else {
+        // Long symlink is stored in blocks
+        let block_size = fs.superblock().block_size();
+        let mut target_bytes = Vec::new();
+
+        for i in 0..inode.block_count(block_size) {
+            let block_num = inode.get_block_number(i * block_size as u64, block_size, fs)?;
+            if block_num == 0 {
+                break;
+            }
+
+            let mut block_buf = vec![0u8; block_size as usize];
+            fs.read_block(block_num, &mut block_buf)?;
+
+            let remaining = inode.size - target_bytes.len() as u64;
+            let to_read = (remaining as usize).min(block_size as usize);
+            target_bytes.extend_from_slice(&block_buf[..to_read]);
+        }
+        Ok(target_bytes)
     }
 }
@@ -0,0 +1,133 @@
+//! Operation tracing
+//!
+//! With the `tracing` feature, a [`Tracer`] set in
+//! [`MountOptions::tracer`] is told when mount, lookup, file read and write
+//! and journal commit operations start and end, with the number of blocks
+//! each moved to and from the device. Without the feature spans compile to
+//! nothing.
+//!
+//! [`MountOptions::tracer`]: crate::MountOptions::tracer
+
+use axdriver_block::BlockDriverOps;
+
+use crate::{Ext4FileSystem, Ext4Result, MountOptions};
+
+/// Operation covered by a span
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    /// Mounting the filesystem
+    Mount,
+    /// Looking up a name in a directory
+    Lookup,
+    /// Reading from a file
+    Read,
+    /// Writing to a file
+    Write,
+    /// Committing a journal transaction
+    Commit,
+}
+
+/// Device blocks read and written during a span
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockCounts {
+    /// Blocks read from the device; cache hits aren't counted
+    pub read: u64,
+    /// Blocks written to the device
+    pub written: u64,
+}
+
+/// Receiver of spans
+///
+/// Called synchronously from the operation being traced, so it should be
+/// cheap, typically just taking a timestamp.
+#[cfg(feature = "tracing")]
+pub trait Tracer: Sync + core::fmt::Debug {
+    /// Operation `op` on inode `ino` (0 for none) starts
+    fn enter(&self, op: TraceOp, ino: u32);
+    /// Operation `op` on inode `ino` ends, having moved `blocks`
+    fn exit(&self, op: TraceOp, ino: u32, blocks: BlockCounts, ok: bool);
+}
+
+/// A started span, ended with [`Span::finish`]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    tracer: Option<&'static dyn Tracer>,
+    #[cfg(feature = "tracing")]
+    op: TraceOp,
+    #[cfg(feature = "tracing")]
+    ino: u32,
+    #[cfg(feature = "tracing")]
+    start: BlockCounts,
+}
+
+impl Span {
+    /// Start a span for `op` on inode `ino` with the tracer of `options`,
+    /// `counts` being the blocks moved so far
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn start(
+        options: &MountOptions,
+        op: TraceOp,
+        ino: u32,
+        counts: BlockCounts,
+    ) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            if let Some(tracer) = options.tracer {
+                tracer.enter(op, ino);
+            }
+            Self {
+                tracer: options.tracer,
+                op,
+                ino,
+                start: counts,
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        Self {}
+    }
+
+    /// End the span, `counts` being the blocks moved so far
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn finish(self, counts: BlockCounts, ok: bool) {
+        #[cfg(feature = "tracing")]
+        if let Some(tracer) = self.tracer {
+            let blocks = BlockCounts {
+                read: counts.read - self.start.read,
+                written: counts.written - self.start.written,
+            };
+            tracer.exit(self.op, self.ino, blocks, ok);
+        }
+    }
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Blocks moved to and from the device since mount, as far as counted
+    pub(crate) fn block_counts(&self) -> BlockCounts {
+        #[cfg(feature = "tracing")]
+        return self.block_counts.get();
+        #[cfg(not(feature = "tracing"))]
+        BlockCounts::default()
+    }
+
+    /// Start a span for `op` on inode `ino`
+    pub(crate) fn span_enter(&self, op: TraceOp, ino: u32) -> Span {
+        Span::start(&self.mount_options, op, ino, self.block_counts())
+    }
+
+    /// End `span` with the outcome of its operation
+    pub(crate) fn span_exit<T>(&self, span: Span, result: &Ext4Result<T>) {
+        span.finish(self.block_counts(), result.is_ok());
+    }
+
+    /// Count device blocks read and written for spans
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn count_blocks(&self, read: u64, written: u64) {
+        #[cfg(feature = "tracing")]
+        {
+            let mut counts = self.block_counts.get();
+            counts.read += read;
+            counts.written += written;
+            self.block_counts.set(counts);
+        }
+    }
+}
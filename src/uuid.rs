@@ -0,0 +1,74 @@
+//! Filesystem UUIDs
+//!
+//! [`Uuid`] formats and parses the usual `8-4-4-4-12` hex form shown by
+//! `blkid` and accepted by `tune2fs -U`.
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::Ext4Error;
+
+/// Positions of the dashes in the text form
+const DASHES: [usize; 4] = [8, 13, 18, 23];
+
+/// A 16-byte UUID as stored in the superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// The all-zero UUID
+    pub const NIL: Self = Self([0; 16]);
+
+    /// Raw bytes, in on-disk order
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Check if every byte is zero
+    pub fn is_nil(&self) -> bool {
+        *self == Self::NIL
+    }
+}
+
+impl From<[u8; 16]> for Uuid {
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Uuid {
+    type Err = Ext4Error;
+
+    /// Parse the `8-4-4-4-12` hex form, in either case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.as_bytes();
+        if text.len() != 36 || DASHES.iter().any(|&i| text[i] != b'-') {
+            return Err(Ext4Error::InvalidArg);
+        }
+
+        let mut digits = text
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !DASHES.contains(i))
+            .map(|(_, &c)| (c as char).to_digit(16).ok_or(Ext4Error::InvalidArg));
+        let mut bytes = [0u8; 16];
+        for byte in &mut bytes {
+            let high = digits.next().ok_or(Ext4Error::InvalidArg)??;
+            let low = digits.next().ok_or(Ext4Error::InvalidArg)??;
+            *byte = ((high << 4) | low) as u8;
+        }
+        Ok(Self(bytes))
+    }
+}
@@ -0,0 +1,301 @@
+//! Verification of metadata on every read
+//!
+//! Metadata is normally checked when it is first read and then served from
+//! the caches. On flaky flash a block that read back fine once may not the
+//! next time, so the `verify_reads` mount option reads inodes, extent tree
+//! nodes and directory blocks from the device on every access and checks
+//! them again: their `metadata_csum` checksums, and the invariants the rest
+//! of the code relies on. Bitmaps are checked against the checksums in
+//! their descriptors when they are read into the bitmap cache, and the
+//! descriptors themselves when they are read at mount. Bad metadata fails
+//! the read with `BadChecksum` or `InvalidState` before it is used.
+
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::directory::{DIRENT_TAIL_FT, DIRENT_TAIL_SIZE};
+use crate::extent::{ExtentHeader, EXT4_MAX_EXTENT_DEPTH};
+use crate::{crc32c, Ext4Error, Ext4FileSystem, Ext4Result, Inode, InodeFlags};
+
+/// Offset of `l_i_checksum_lo` in the on-disk inode
+const INODE_CSUM_LO: usize = 0x7C;
+/// Offset of `i_checksum_hi`, within the extra inode fields
+const INODE_CSUM_HI: usize = 0x82;
+/// Size of the base inode
+const GOOD_OLD_INODE_SIZE: usize = 128;
+/// Size of an extent tree header and of each of its entries
+const EXTENT_ENTRY_SIZE: usize = 12;
+/// Size of the fixed part of a directory entry
+const DIRENT_HEADER_SIZE: usize = 8;
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Check if metadata is verified on every read
+    pub fn verifies_reads(&self) -> bool {
+        self.mount_options.verify_reads
+    }
+
+    /// Checksum of the on-disk inode `ino` held in `raw`, as stored in its
+    /// checksum fields: the high half is only kept when `i_extra_isize`
+    /// covers `i_checksum_hi`
+    pub(crate) fn inode_checksum(&self, raw: &[u8], ino: u32) -> u32 {
+        let zero = [0u8; 2];
+        let generation = &raw[100..104];
+        let mut csum = crc32c(self.superblock.csum_seed(), &ino.to_le_bytes());
+        csum = crc32c(csum, generation);
+
+        csum = crc32c(csum, &raw[..INODE_CSUM_LO]);
+        csum = crc32c(csum, &zero);
+        csum = crc32c(csum, &raw[INODE_CSUM_LO + 2..GOOD_OLD_INODE_SIZE]);
+        if raw.len() <= GOOD_OLD_INODE_SIZE {
+            return csum & 0xFFFF;
+        }
+        csum = crc32c(csum, &raw[GOOD_OLD_INODE_SIZE..INODE_CSUM_HI]);
+        if !has_csum_hi(raw) {
+            return crc32c(csum, &raw[INODE_CSUM_HI..]) & 0xFFFF;
+        }
+        csum = crc32c(csum, &zero);
+        crc32c(csum, &raw[INODE_CSUM_HI + 2..])
+    }
+
+    /// Store the checksum of the on-disk inode `ino` held in `raw`, under
+    /// `metadata_csum`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn set_inode_csum(&self, raw: &mut [u8], ino: u32) {
+        if !self.superblock.has_metadata_csum() {
+            return;
+        }
+        let csum = self.inode_checksum(raw, ino);
+        raw[INODE_CSUM_LO..INODE_CSUM_LO + 2].copy_from_slice(&(csum as u16).to_le_bytes());
+        if raw.len() > GOOD_OLD_INODE_SIZE && has_csum_hi(raw) {
+            let hi = ((csum >> 16) as u16).to_le_bytes();
+            raw[INODE_CSUM_HI..INODE_CSUM_HI + 2].copy_from_slice(&hi);
+        }
+    }
+
+    /// Check the on-disk inode `ino` held in `raw`, if `verify_reads` is set
+    ///
+    /// Slots that were never used are all zeros and have no checksum.
+    pub(crate) fn verify_inode(&self, raw: &[u8], ino: u32) -> Ext4Result<()> {
+        if !self.mount_options.verify_reads || raw.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let corrupt = |line: u32, error: Ext4Error| {
+            self.record_error("verify_inode", line, ino, 0, &error);
+            error
+        };
+
+        if self.superblock.has_metadata_csum() {
+            let read_u16 = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+            let mut stored = read_u16(INODE_CSUM_LO) as u32;
+            if raw.len() > GOOD_OLD_INODE_SIZE && has_csum_hi(raw) {
+                stored |= (read_u16(INODE_CSUM_HI) as u32) << 16;
+            }
+            let calculated = self.inode_checksum(raw, ino);
+            if stored != calculated {
+                warn!(
+                    "Inode {} has checksum {:#x}, expected {:#x}",
+                    ino, stored, calculated
+                );
+                return Err(corrupt(line!(), Ext4Error::BadChecksum));
+            }
+        }
+
+        if raw.len() > GOOD_OLD_INODE_SIZE {
+            let extra_isize = u16::from_le_bytes([raw[128], raw[129]]) as usize;
+            if GOOD_OLD_INODE_SIZE + extra_isize > raw.len() || !extra_isize.is_multiple_of(4) {
+                warn!("Inode {} has bad i_extra_isize {}", ino, extra_isize);
+                return Err(corrupt(line!(), Ext4Error::InvalidState));
+            }
+        }
+
+        let flags = InodeFlags::from_bits_retain(u32::from_le_bytes([
+            raw[32], raw[33], raw[34], raw[35],
+        ]));
+        let root = &raw[40..100];
+        // Some tools leave the tree of an inode without blocks all zeros
+        if flags.contains(InodeFlags::EXTENTS)
+            && !flags.contains(InodeFlags::INLINE_DATA)
+            && root.iter().any(|&b| b != 0)
+        {
+            let valid = ExtentHeader::from_bytes(root).is_ok_and(|header| {
+                (header.max_entries as usize) < root.len() / EXTENT_ENTRY_SIZE
+                    && header.entries <= header.max_entries
+                    && header.depth <= EXT4_MAX_EXTENT_DEPTH
+            });
+            if !valid {
+                warn!("Inode {} has a bad extent tree root", ino);
+                return Err(corrupt(line!(), Ext4Error::InvalidState));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read extent tree node `block` of an inode whose metadata checksums
+    /// use `csum_seed`, from the device and checked if `verify_reads` is set
+    ///
+    /// Without `metadata_csum`, `csum_seed` is ignored; `None` skips the
+    /// checksum for callers that don't know the inode.
+    pub(crate) fn read_extent_block(
+        &self,
+        block: u64,
+        csum_seed: Option<u32>,
+        buf: &mut [u8],
+    ) -> Ext4Result<()> {
+        if !self.mount_options.verify_reads {
+            return self.read_block(block, buf);
+        }
+        self.read_blocks(block, buf)?;
+        let corrupt = |line: u32, error: Ext4Error| {
+            self.record_error("read_extent_block", line, 0, block, &error);
+            error
+        };
+
+        let Ok(header) = ExtentHeader::from_bytes(buf) else {
+            warn!("Extent tree node at block {} has a bad magic", block);
+            return Err(corrupt(line!(), Ext4Error::InvalidState));
+        };
+        let tail = EXTENT_ENTRY_SIZE * (header.max_entries as usize + 1);
+        if header.entries > header.max_entries || tail + 4 > buf.len() {
+            warn!(
+                "Extent tree node at block {} has {} of {} entries",
+                block, header.entries, header.max_entries
+            );
+            return Err(corrupt(line!(), Ext4Error::InvalidState));
+        }
+
+        if let Some(seed) = csum_seed.filter(|_| self.superblock.has_metadata_csum()) {
+            let stored = u32::from_le_bytes(buf[tail..tail + 4].try_into().unwrap());
+            let calculated = crc32c(seed, &buf[..tail]);
+            if stored != calculated {
+                warn!(
+                    "Extent tree node at block {} has checksum {:#x}, expected {:#x}",
+                    block, stored, calculated
+                );
+                return Err(corrupt(line!(), Ext4Error::BadChecksum));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read block `block` of directory `dir`, from the device and checked if
+    /// `verify_reads` is set
+    ///
+    /// Every record must stay inside the block. Under `metadata_csum`, a
+    /// block ending with a dirent tail must also match its checksum; blocks
+    /// without one, such as hashed index nodes, only have their records
+    /// checked.
+    pub(crate) fn read_dir_data_block(
+        &self,
+        dir: &Inode,
+        block: u64,
+        buf: &mut [u8],
+    ) -> Ext4Result<()> {
+        if !self.mount_options.verify_reads {
+            return self.read_block(block, buf);
+        }
+        self.read_blocks(block, buf)?;
+        let corrupt = |line: u32, error: Ext4Error| {
+            self.record_error("read_dir_data_block", line, dir.ino, block, &error);
+            error
+        };
+
+        let mut offset = 0;
+        while offset < buf.len() {
+            let rec_len = match buf.get(offset + 4..offset + 6) {
+                Some(raw) => u16::from_le_bytes([raw[0], raw[1]]) as usize,
+                None => 0,
+            };
+            let name_len = buf.get(offset + 6).copied().unwrap_or_default() as usize;
+            if rec_len < DIRENT_HEADER_SIZE
+                || !rec_len.is_multiple_of(4)
+                || offset + rec_len > buf.len()
+                || DIRENT_HEADER_SIZE + name_len > rec_len
+            {
+                warn!(
+                    "Directory {} has a bad record at offset {} of block {}",
+                    dir.ino, offset, block
+                );
+                return Err(corrupt(line!(), Ext4Error::InvalidState));
+            }
+            offset += rec_len;
+        }
+
+        if self.superblock.has_metadata_csum() && has_dirent_tail(buf) {
+            let tail = buf.len() - DIRENT_TAIL_SIZE;
+            let stored = u32::from_le_bytes(buf[tail + 8..tail + 12].try_into().unwrap());
+            let calculated = crc32c(self.inode_csum_seed(dir), &buf[..tail]);
+            if stored != calculated {
+                warn!(
+                    "Directory block {} has checksum {:#x}, expected {:#x}",
+                    block, stored, calculated
+                );
+                return Err(corrupt(line!(), Ext4Error::BadChecksum));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checksum of the first `bits` bits of `bitmap`, as stored in a group
+    /// descriptor: 32-byte descriptors keep the low half only
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn bitmap_checksum(&self, bitmap: &[u8], bits: usize) -> u32 {
+        let csum = crc32c(self.superblock.csum_seed(), &bitmap[..bits / 8]);
+        match self.superblock.group_desc_size() >= 64 {
+            true => csum,
+            false => csum & 0xFFFF,
+        }
+    }
+
+    /// Read the inode bitmap of `group` if `inodes` is set, else its block
+    /// bitmap, from the device and checked against the checksum in its
+    /// descriptor if `verify_reads` is set
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn read_bitmap(
+        &self,
+        group: usize,
+        inodes: bool,
+        buf: &mut [u8],
+    ) -> Ext4Result<()> {
+        let bg = &self.block_groups[group];
+        let sb = &self.superblock;
+        let (block, bits, stored) = match inodes {
+            true => (bg.inode_bitmap(), sb.inodes_per_group(), bg.inode_bitmap_csum()),
+            false => (bg.block_bitmap(), sb.clusters_per_group(), bg.block_bitmap_csum()),
+        };
+        if !self.mount_options.verify_reads {
+            return self.read_block(block, buf);
+        }
+        self.read_blocks(block, buf)?;
+        if !sb.has_metadata_csum() {
+            return Ok(());
+        }
+
+        let calculated = self.bitmap_checksum(buf, bits as usize);
+        if stored != calculated {
+            warn!(
+                "Bitmap at block {} of group {} has checksum {:#x}, expected {:#x}",
+                block, group, stored, calculated
+            );
+            let error = Ext4Error::BadChecksum;
+            self.record_error("read_bitmap", line!(), 0, block, &error);
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+/// Check if the extra fields of the on-disk inode `raw` cover
+/// `i_checksum_hi`
+fn has_csum_hi(raw: &[u8]) -> bool {
+    let extra_isize = u16::from_le_bytes([raw[128], raw[129]]) as usize;
+    GOOD_OLD_INODE_SIZE + extra_isize >= INODE_CSUM_HI + 2 && raw.len() >= INODE_CSUM_HI + 2
+}
+
+/// Check if directory block `block` ends with a dirent tail
+fn has_dirent_tail(block: &[u8]) -> bool {
+    let tail = &block[block.len() - DIRENT_TAIL_SIZE..];
+    tail[..4] == [0; 4]
+        && u16::from_le_bytes([tail[4], tail[5]]) as usize == DIRENT_TAIL_SIZE
+        && tail[6] == 0
+        && tail[7] == DIRENT_TAIL_FT
+}
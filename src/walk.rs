@@ -0,0 +1,237 @@
+//! Recursive directory tree traversal
+//!
+//! [`Walk`] visits a directory tree depth-first, yielding the path, directory
+//! entry, and inode of everything below the starting directory.
+//! [`Ext4FileSystem::disk_usage`] builds on it to sum the space used by a
+//! tree, as `du` does.
+
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{DirectoryEntry, Ext4Error, Ext4FileSystem, Ext4Result, Inode};
+
+/// How symbolic links are treated during a walk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Yield the link itself and never descend through it
+    NoFollow,
+    /// Yield the link target's inode and descend into it if it is a directory
+    Follow,
+    /// Do not yield symbolic links at all
+    Skip,
+}
+
+/// Options controlling a tree walk
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// Maximum depth to descend; entries of the starting directory are at depth 1
+    pub max_depth: Option<usize>,
+    /// Symbolic link handling
+    pub symlinks: SymlinkPolicy,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            symlinks: SymlinkPolicy::NoFollow,
+        }
+    }
+}
+
+/// Space used by a directory tree, from [`Ext4FileSystem::disk_usage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Bytes allocated to the inodes, from their block counts
+    pub bytes: u64,
+    /// Distinct inodes counted, the starting directory included
+    pub inodes: u64,
+    /// Entries not counted because their inode already was
+    pub hard_links: u64,
+}
+
+/// A directory whose entries are still being visited
+struct Frame {
+    path: String,
+    entries: Vec<DirectoryEntry>,
+    index: usize,
+    depth: usize,
+}
+
+/// Depth-first iterator over a directory tree, created by
+/// [`Ext4FileSystem::walk`]
+pub struct Walk<'a, D: BlockDriverOps> {
+    fs: &'a Ext4FileSystem<D>,
+    options: WalkOptions,
+    stack: Vec<Frame>,
+    /// Directories already entered, used to break symlink cycles
+    visited: BTreeSet<u32>,
+}
+
+/// Join a directory path and an entry name
+fn join_path(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir);
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    path.push_str(name);
+    path
+}
+
+impl<'a, D: BlockDriverOps> Walk<'a, D> {
+    pub(crate) fn new(
+        fs: &'a Ext4FileSystem<D>,
+        path: &str,
+        options: WalkOptions,
+    ) -> Ext4Result<Self> {
+        let root = fs.find_inode(path)?;
+        if !root.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+
+        let mut visited = BTreeSet::new();
+        visited.insert(root.ino);
+
+        let root_path = if path.is_empty() {
+            String::from("/")
+        } else {
+            String::from(path)
+        };
+        let entries = fs.read_dir(root.ino)?;
+
+        Ok(Self {
+            fs,
+            options,
+            stack: vec![Frame {
+                path: root_path,
+                entries,
+                index: 0,
+                depth: 1,
+            }],
+            visited,
+        })
+    }
+
+    /// Resolve a symbolic link found in `dir` to the inode it points at
+    fn resolve_symlink(&self, dir: &str, link: &Inode) -> Ext4Result<Inode> {
+        let target = crate::symlink::read_target(self.fs, link)?;
+        let path = if target.starts_with('/') {
+            target
+        } else {
+            join_path(dir, &target)
+        };
+        self.fs.find_inode(&path)
+    }
+
+    /// Visit the next entry of the top frame
+    fn step(&mut self) -> Option<Ext4Result<(String, DirectoryEntry, Inode)>> {
+        let frame = self.stack.last_mut()?;
+        if frame.index >= frame.entries.len() {
+            self.stack.pop();
+            return None;
+        }
+
+        let entry = frame.entries[frame.index].clone();
+        frame.index += 1;
+        let depth = frame.depth;
+        let path = join_path(&frame.path, &entry.name.to_string_lossy());
+        let dir_path = frame.path.clone();
+
+        if entry.name == "." || entry.name == ".." {
+            return None;
+        }
+
+        let mut inode = match self.fs.get_inode(entry.ino) {
+            Ok(inode) => inode,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if inode.is_symlink() {
+            match self.options.symlinks {
+                SymlinkPolicy::Skip => return None,
+                SymlinkPolicy::NoFollow => {}
+                SymlinkPolicy::Follow => match self.resolve_symlink(&dir_path, &inode) {
+                    Ok(target) => inode = target,
+                    Err(e) => {
+                        warn!("Failed to resolve symlink {}: {:?}", path, e);
+                    }
+                },
+            }
+        }
+
+        let descend = inode.is_dir() && self.options.max_depth.is_none_or(|max| depth < max);
+        if descend {
+            if self.visited.insert(inode.ino) {
+                match self.fs.read_dir(inode.ino) {
+                    Ok(entries) => self.stack.push(Frame {
+                        path: path.clone(),
+                        entries,
+                        index: 0,
+                        depth: depth + 1,
+                    }),
+                    Err(e) => return Some(Err(e)),
+                }
+            } else {
+                debug!("Not descending into {} again (inode {})", path, inode.ino);
+            }
+        }
+
+        Some(Ok((path, entry, inode)))
+    }
+}
+
+impl<'a, D: BlockDriverOps> Iterator for Walk<'a, D> {
+    type Item = Ext4Result<(String, DirectoryEntry, Inode)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.stack.is_empty() {
+            if let Some(item) = self.step() {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Sum the space allocated to the tree below `path`, as `du -s` does
+    ///
+    /// Space comes from the inodes' block counts, not their sizes, so holes
+    /// are free and metadata blocks count. An inode reached through several
+    /// hard links is counted once, and symbolic links are not followed.
+    /// With `max_depth`, only entries down to that depth are counted, those
+    /// of `path` itself being at depth 1.
+    pub fn disk_usage(&self, path: &str, max_depth: Option<usize>) -> Ext4Result<DiskUsage> {
+        let block_size = self.superblock.block_size();
+        let root = self.find_inode(path)?;
+        let mut usage = DiskUsage {
+            bytes: root.sectors(block_size) * 512,
+            inodes: 1,
+            hard_links: 0,
+        };
+        if !root.is_dir() {
+            return Ok(usage);
+        }
+
+        let mut seen = BTreeSet::new();
+        seen.insert(root.ino);
+        let options = WalkOptions {
+            max_depth,
+            symlinks: SymlinkPolicy::NoFollow,
+        };
+        for item in Walk::new(self, path, options)? {
+            let (_, _, inode) = item?;
+            if !seen.insert(inode.ino) {
+                usage.hard_links += 1;
+                continue;
+            }
+            usage.bytes += inode.sectors(block_size) * 512;
+            usage.inodes += 1;
+        }
+        debug!("{} uses {} bytes in {} inodes", path, usage.bytes, usage.inodes);
+        Ok(usage)
+    }
+}
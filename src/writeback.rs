@@ -0,0 +1,74 @@
+//! Background writeback
+//!
+//! Blocks are written through to the device as soon as they change, but a
+//! device with a volatile write cache only makes them durable when flushed.
+//! Besides the flushes of explicit sync points and ordered data, the owner of
+//! the filesystem can call [`Ext4FileSystem::poll_flush`] or
+//! [`Ext4FileSystem::flush_some`] from a timer or worker task, so that
+//! unflushed writes never pile up for long.
+
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::{Ext4FileSystem, Ext4Result, Timestamp};
+
+/// Writes not flushed to the device yet
+#[derive(Debug, Default)]
+pub(crate) struct Unflushed {
+    /// Blocks written since the last flush
+    blocks: u64,
+    /// Time of the first of them
+    since: Option<Timestamp>,
+}
+
+impl Unflushed {
+    /// Count `blocks` more blocks written at `now`
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn record(&mut self, blocks: u64, now: Timestamp) {
+        self.blocks = self.blocks.saturating_add(blocks);
+        self.since.get_or_insert(now);
+    }
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// Number of blocks written since the device was last flushed
+    pub fn unflushed_blocks(&self) -> u64 {
+        self.unflushed.borrow().blocks
+    }
+
+    /// Flush the device if a write made at or before `deadline` is still
+    /// unflushed, returning how many blocks the flush made durable
+    ///
+    /// Meant to be polled with a deadline of the current time minus the
+    /// longest a write may stay in the device's cache, as Linux's
+    /// `dirty_expire_centisecs`.
+    pub fn poll_flush(&self, deadline: Timestamp) -> Ext4Result<u64> {
+        let expired = self.unflushed.borrow().since.is_some_and(|since| since <= deadline);
+        if !expired {
+            return Ok(0);
+        }
+        self.flush_unflushed()
+    }
+
+    /// Flush the device once at least `max_blocks` blocks are unflushed,
+    /// returning how many blocks the flush made durable
+    ///
+    /// A device cache is flushed as a whole, so `max_blocks` bounds the
+    /// amount of data left at risk rather than the work of one call, as
+    /// Linux's `dirty_background_bytes`. 0 flushes any unflushed write.
+    pub fn flush_some(&self, max_blocks: u64) -> Ext4Result<u64> {
+        let blocks = self.unflushed_blocks();
+        if blocks == 0 || blocks < max_blocks {
+            return Ok(0);
+        }
+        self.flush_unflushed()
+    }
+
+    /// Flush the device, returning the number of blocks that were unflushed
+    fn flush_unflushed(&self) -> Ext4Result<u64> {
+        let blocks = self.unflushed_blocks();
+        self.flush()?;
+        debug!("Background flush of {} blocks", blocks);
+        Ok(blocks)
+    }
+}
@@ -0,0 +1,398 @@
+//! Extended attributes
+//!
+//! Attributes are stored in the space left after the fixed part of large
+//! inodes and in a block pointed to by `i_file_acl`. With the `ea_inode`
+//! feature, a value too large for either lives in the data of an inode of its
+//! own, named by the entry's `e_value_inum`; that inode is flagged
+//! `EA_INODE` and counts the entries referencing it.
+//!
+//! Inodes with inline data keep what doesn't fit in `i_block` in the value
+//! of `system.data`, in the inode's own area. That value is the only one
+//! written here, in place.
+
+use alloc::vec::Vec;
+use axdriver_block::BlockDriverOps;
+use log::*;
+
+use crate::inode::EXT4_GOOD_OLD_INODE_SIZE;
+#[cfg(not(feature = "read-only"))]
+use crate::crc32c;
+use crate::{Ext4Error, Ext4FileSystem, Ext4Result, FeatureIncompat, Inode, InodeFlags};
+
+/// Magic number of an extended attribute block or in-inode area
+pub(crate) const EXT4_XATTR_MAGIC: u32 = 0xEA02_0000;
+
+/// Size of the header of an extended attribute block
+const BLOCK_HEADER_SIZE: usize = 32;
+
+/// Offset of `h_checksum` in the header of an extended attribute block
+#[cfg(not(feature = "read-only"))]
+const BLOCK_CSUM_OFFSET: usize = 0x10;
+
+/// Size of an entry before its name
+const ENTRY_HEADER_SIZE: usize = 16;
+
+/// Name prefixes by `e_name_index`
+const PREFIXES: [(u8, &[u8]); 7] = [
+    (1, b"user."),
+    (2, b"system.posix_acl_access"),
+    (3, b"system.posix_acl_default"),
+    (4, b"trusted."),
+    (6, b"security."),
+    (7, b"system."),
+    (8, b"system.richacl"),
+];
+
+/// An extended attribute of an inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    /// Full name, including the namespace prefix such as `user.`
+    pub name: Vec<u8>,
+    /// Value
+    pub value: Vec<u8>,
+}
+
+/// Name index and name of `system.data`
+const INLINE_DATA_XATTR: (u8, &[u8]) = (7, b"data");
+
+/// An entry as stored on disk, before its value is fetched
+#[derive(Debug)]
+struct RawEntry {
+    /// Offset of the entry in its area
+    #[cfg(not(feature = "read-only"))]
+    offset: usize,
+    name_index: u8,
+    /// Name without the prefix given by `name_index`
+    suffix: Vec<u8>,
+    /// Offset of the value from the start of the value area
+    value_offs: usize,
+    /// Inode holding the value, or 0 if it is in the area itself
+    value_inum: u32,
+    value_size: usize,
+}
+
+/// Parse the entries starting at `entries` in `area`
+fn parse_entries(area: &[u8], entries: usize) -> Ext4Result<Vec<RawEntry>> {
+    let mut result = Vec::new();
+    let mut pos = entries;
+    loop {
+        let Some(header) = area.get(pos..pos + ENTRY_HEADER_SIZE) else {
+            // The list may end with the area when it has no room for the
+            // four zero bytes of the end marker
+            if area.get(pos..pos + 4).is_none_or(|end| end == [0; 4]) {
+                return Ok(result);
+            }
+            return Err(Ext4Error::InvalidInput);
+        };
+        if header[0..4] == [0; 4] {
+            return Ok(result);
+        }
+
+        let name_len = header[0] as usize;
+        let suffix = area
+            .get(pos + ENTRY_HEADER_SIZE..pos + ENTRY_HEADER_SIZE + name_len)
+            .ok_or(Ext4Error::InvalidInput)?;
+        result.push(RawEntry {
+            #[cfg(not(feature = "read-only"))]
+            offset: pos,
+            name_index: header[1],
+            suffix: suffix.to_vec(),
+            value_offs: u16::from_le_bytes([header[2], header[3]]) as usize,
+            value_inum: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+            value_size: u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize,
+        });
+        pos += (ENTRY_HEADER_SIZE + name_len + 3) & !3;
+    }
+}
+
+impl RawEntry {
+    /// Size of the entry, padded
+    #[cfg(not(feature = "read-only"))]
+    fn size(&self) -> usize {
+        (ENTRY_HEADER_SIZE + self.suffix.len() + 3) & !3
+    }
+
+    /// Check if this is `system.data`
+    fn is_inline_data(&self) -> bool {
+        (self.name_index, &self.suffix[..]) == INLINE_DATA_XATTR
+    }
+}
+
+/// Hash of an entry named `suffix` with a value of `value`, as computed by
+/// Linux's `ext4_xattr_hash_entry()`
+#[cfg(not(feature = "read-only"))]
+fn entry_hash(suffix: &[u8], value: &[u8]) -> u32 {
+    let mut hash = 0u32;
+    for &c in suffix {
+        hash = (hash << 5) ^ (hash >> 27) ^ c as u32;
+    }
+    for word in value.chunks(4) {
+        let mut bytes = [0u8; 4];
+        bytes[..word.len()].copy_from_slice(word);
+        hash = (hash << 16) ^ (hash >> 16) ^ u32::from_le_bytes(bytes);
+    }
+    hash
+}
+
+/// Store in attribute block `block` its checksum under `metadata_csum`,
+/// which covers the block number `block_num` it is written at
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn set_block_csum(seed: u32, block_num: u64, block: &mut [u8]) {
+    let field = BLOCK_CSUM_OFFSET..BLOCK_CSUM_OFFSET + 4;
+    block[field.clone()].fill(0);
+    let csum = crc32c(crc32c(seed, &block_num.to_le_bytes()), block);
+    block[field].copy_from_slice(&csum.to_le_bytes());
+}
+
+/// Inodes holding the values of the entries of attribute block `block`
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn block_value_inodes(block: &[u8]) -> Ext4Result<Vec<u32>> {
+    value_inodes(block, BLOCK_HEADER_SIZE)
+}
+
+/// Inodes holding the values of an in-inode attribute area, starting with
+/// its magic number
+#[cfg(not(feature = "read-only"))]
+pub(crate) fn inline_value_inodes(area: &[u8]) -> Ext4Result<Vec<u32>> {
+    value_inodes(&area[4..], 0)
+}
+
+#[cfg(not(feature = "read-only"))]
+fn value_inodes(area: &[u8], entries: usize) -> Ext4Result<Vec<u32>> {
+    Ok(parse_entries(area, entries)?
+        .iter()
+        .map(|entry| entry.value_inum)
+        .filter(|&inum| inum != 0)
+        .collect())
+}
+
+impl<D: BlockDriverOps> Ext4FileSystem<D> {
+    /// List the extended attributes of inode `ino` with their values
+    ///
+    /// Attributes stored in the inode come first, then those of its
+    /// attribute block. Attributes in namespaces unknown to Linux are
+    /// skipped, as Linux does.
+    pub fn list_xattrs(&self, ino: u32) -> Ext4Result<Vec<Xattr>> {
+        let inode = self.get_inode(ino)?;
+        let mut areas = Vec::new();
+        if let Some(area) = self.inline_xattr_area(&inode)? {
+            areas.push((area, 0));
+        }
+        if let Some(block) = self.xattr_block(&inode)? {
+            areas.push((block, BLOCK_HEADER_SIZE));
+        }
+
+        let mut xattrs = Vec::new();
+        for (area, entries) in areas {
+            let entries = parse_entries(&area, entries).inspect_err(|_| {
+                warn!("Corrupt extended attribute entries in inode {}", ino);
+            })?;
+            for entry in entries {
+                let Some(&(_, prefix)) = PREFIXES.iter().find(|(i, _)| *i == entry.name_index)
+                else {
+                    debug!("Skipping xattr with unknown name index {}", entry.name_index);
+                    continue;
+                };
+                let mut name = prefix.to_vec();
+                name.extend_from_slice(&entry.suffix);
+                let value = self.xattr_value(&inode, &area, &entry)?;
+                xattrs.push(Xattr { name, value });
+            }
+        }
+        Ok(xattrs)
+    }
+
+    /// Get the value of extended attribute `name` of inode `ino`
+    ///
+    /// Fails with `NoAttribute` if the inode has no such attribute.
+    pub fn get_xattr(&self, ino: u32, name: &[u8]) -> Ext4Result<Vec<u8>> {
+        self.list_xattrs(ino)?
+            .into_iter()
+            .find(|xattr| xattr.name == name)
+            .map(|xattr| xattr.value)
+            .ok_or(Ext4Error::NoAttribute)
+    }
+
+    /// Add a reference from `owner` to attribute value inode `ino`
+    ///
+    /// As in Linux, the count of references is split between `i_ctime`, for
+    /// the high half, and `i_version`, for the low half, and `owner` is
+    /// charged for the blocks of the value.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn ref_xattr_value_inode(&mut self, ino: u32, owner: &mut Inode) -> Ext4Result<()> {
+        let mut inode = self.get_inode(ino)?;
+        if !inode.inode_flags().contains(InodeFlags::EA_INODE) {
+            return Err(Ext4Error::InvalidInput);
+        }
+        let refs = ((inode.ctime as u64) << 32 | inode.version as u64) + 1;
+        inode.ctime = (refs >> 32) as u32;
+        inode.version = refs as u32;
+        self.write_inode(&inode)?;
+
+        let block_size = self.superblock().block_size();
+        for _ in 0..inode.block_count(block_size) {
+            owner.charge_block(block_size);
+        }
+        Ok(())
+    }
+
+    /// Value of the `system.data` attribute of `inode`, holding the inline
+    /// data past `i_block`, if it has one
+    pub(crate) fn inline_data_xattr(&self, inode: &Inode) -> Ext4Result<Option<Vec<u8>>> {
+        let Some(area) = self.inline_xattr_area(inode)? else {
+            return Ok(None);
+        };
+        let entries = parse_entries(&area, 0)?;
+        match entries.iter().find(|entry| entry.is_inline_data()) {
+            Some(entry) => self.xattr_value(inode, &area, entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Overwrite the value of the `system.data` attribute of `inode` with
+    /// `value`, of the same size, or remove the attribute if `value` is
+    /// `None`
+    ///
+    /// The inode table block is written with the inode checksum updated, so
+    /// the rest of the inode is left as it is on disk.
+    #[cfg(not(feature = "read-only"))]
+    pub(crate) fn write_inline_data_xattr(
+        &self,
+        inode: &Inode,
+        value: Option<&[u8]>,
+    ) -> Ext4Result<()> {
+        let inode_size = self.superblock().inode_size() as usize;
+        let start = EXT4_GOOD_OLD_INODE_SIZE + inode.extra_isize as usize;
+        let (block, offset) = self.inode_location(inode.ino)?;
+        let mut buf = vec![0u8; self.superblock().block_size() as usize];
+        self.read_block(block, &mut buf)?;
+        let slot = &mut buf[offset..offset + inode_size];
+        if start + 4 > inode_size
+            || u32::from_le_bytes(slot[start..start + 4].try_into().unwrap()) != EXT4_XATTR_MAGIC
+        {
+            return Err(Ext4Error::NoAttribute);
+        }
+
+        let area = &mut slot[start + 4..];
+        let entries = parse_entries(area, 0)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.is_inline_data())
+            .ok_or(Ext4Error::NoAttribute)?;
+        let value_range = entry.value_offs..entry.value_offs + entry.value_size;
+        if value_range.end > area.len() {
+            warn!("Xattr value of inode {} is out of bounds", inode.ino);
+            return Err(Ext4Error::InvalidInput);
+        }
+        match value {
+            Some(value) => {
+                if value.len() != entry.value_size {
+                    return Err(Ext4Error::InvalidInput);
+                }
+                area[value_range].copy_from_slice(value);
+                let hash = entry_hash(&entry.suffix, value);
+                area[entry.offset + 12..entry.offset + 16].copy_from_slice(&hash.to_le_bytes());
+            }
+            None => {
+                // The following entries move up over the removed one
+                let end = entries.last().map_or(0, |last| last.offset + last.size());
+                area[value_range].fill(0);
+                area.copy_within(entry.offset + entry.size()..end, entry.offset);
+                area[end - entry.size()..end].fill(0);
+            }
+        }
+
+        self.set_inode_csum(slot, inode.ino);
+        self.write_block(block, &buf)
+    }
+
+    /// The attribute area inside `inode`, after its magic number
+    fn inline_xattr_area(&self, inode: &Inode) -> Ext4Result<Option<Vec<u8>>> {
+        let inode_size = self.superblock().inode_size() as usize;
+        let start = EXT4_GOOD_OLD_INODE_SIZE + inode.extra_isize as usize;
+        if start + 4 > inode_size {
+            return Ok(None);
+        }
+
+        let (block, offset) = self.inode_location(inode.ino)?;
+        let mut buf = vec![0u8; self.superblock().block_size() as usize];
+        self.read_block(block, &mut buf)?;
+        let area = &buf[offset + start..offset + inode_size];
+        if u32::from_le_bytes(area[0..4].try_into().unwrap()) != EXT4_XATTR_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(area[4..].to_vec()))
+    }
+
+    /// The attribute block of `inode`
+    fn xattr_block(&self, inode: &Inode) -> Ext4Result<Option<Vec<u8>>> {
+        let block = inode.xattr_block();
+        if block == 0 {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0u8; self.superblock().block_size() as usize];
+        self.read_block(block, &mut buf)?;
+        let magic = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let blocks = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if magic != EXT4_XATTR_MAGIC || blocks != 1 {
+            warn!("Inode {} has an invalid xattr block {}", inode.ino, block);
+            return Err(Ext4Error::InvalidInput);
+        }
+        Ok(Some(buf))
+    }
+
+    /// Fetch the value of `entry`, from `area` or from its own inode
+    fn xattr_value(&self, inode: &Inode, area: &[u8], entry: &RawEntry) -> Ext4Result<Vec<u8>> {
+        if entry.value_inum == 0 {
+            return match area.get(entry.value_offs..entry.value_offs + entry.value_size) {
+                Some(value) => Ok(value.to_vec()),
+                None => {
+                    warn!("Xattr value of inode {} is out of bounds", inode.ino);
+                    Err(Ext4Error::InvalidInput)
+                }
+            };
+        }
+
+        if !self
+            .superblock()
+            .feature_incompat()
+            .contains(FeatureIncompat::EA_INODE)
+        {
+            warn!("Inode {} has an xattr value inode without ea_inode", inode.ino);
+            return Err(Ext4Error::InvalidInput);
+        }
+        self.read_xattr_value_inode(entry.value_inum, entry.value_size)
+    }
+
+    /// Read the `size` bytes of value held by attribute value inode `ino`
+    fn read_xattr_value_inode(&self, ino: u32, size: usize) -> Ext4Result<Vec<u8>> {
+        let inode = self.get_inode(ino)?;
+        if !inode.inode_flags().contains(InodeFlags::EA_INODE) || inode.size != size as u64 {
+            warn!(
+                "Inode {} is not a valid xattr value inode of {} bytes",
+                ino, size
+            );
+            return Err(Ext4Error::InvalidInput);
+        }
+
+        let block_size = self.superblock().block_size();
+        let mut value = crate::try_with_capacity(size)?;
+        let mut block_buf = vec![0u8; block_size as usize];
+        for i in 0..inode.block_count(block_size) {
+            let block_num = inode.get_block_number(i * block_size as u64, block_size, self)?;
+            if block_num == 0 {
+                block_buf.fill(0);
+            } else {
+                self.read_block(block_num, &mut block_buf)?;
+            }
+            let len = (size - value.len()).min(block_size as usize);
+            value.extend_from_slice(&block_buf[..len]);
+        }
+        if value.len() != size {
+            warn!("Xattr value inode {} is shorter than its size", ino);
+            return Err(Ext4Error::InvalidInput);
+        }
+        Ok(value)
+    }
+}
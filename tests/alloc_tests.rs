@@ -0,0 +1,518 @@
+//! Tests of block and inode allocation and of the block group descriptors
+//! and counts that track it
+
+mod images;
+
+#[cfg(not(feature = "read-only"))]
+use std::collections::BTreeMap;
+
+#[cfg(not(feature = "read-only"))]
+use axdriver_block::{BaseDriverOps, BlockDriverOps, DevResult, DeviceType};
+#[cfg(not(feature = "read-only"))]
+use ext4rs::{
+    crc32c, BlockGroupDescriptor, FeatureIncompat, File, InodeFlags, InodeMode, OpenFlags,
+    RenameFlags,
+};
+use ext4rs::{Ext4Error, Ext4FileSystem, FeatureRoCompat, MountOptions, VecBlockDevice};
+use images::{image, Image};
+
+#[cfg(not(feature = "read-only"))]
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT3: Image = image!("images/ext3.img.packed");
+static EXT4_LAZY_ITABLE: Image = image!("images/ext4_lazy_itable.img.packed");
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_64BIT: Image = image!("images/ext4_64bit.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_LARGE_DIR: Image = image!("images/ext4_large_dir.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_META_BG: Image = image!("images/ext4_meta_bg.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+/// 4 KiB-block device of `num_blocks` blocks that only stores the blocks
+/// written to it and reads zeros elsewhere
+#[cfg(not(feature = "read-only"))]
+struct SparseDevice {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    num_blocks: u64,
+}
+
+#[cfg(not(feature = "read-only"))]
+impl BaseDriverOps for SparseDevice {
+    fn device_name(&self) -> &str {
+        "sparse"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+impl BlockDriverOps for SparseDevice {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        4096
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        for (block, chunk) in (block_id..).zip(buf.chunks_mut(4096)) {
+            match self.blocks.get(&block) {
+                Some(data) => chunk.copy_from_slice(data),
+                None => chunk.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        for (block, chunk) in (block_id..).zip(buf.chunks(4096)) {
+            self.blocks.insert(block, chunk.to_vec());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_disk_usage() {
+    let fs = mount(&EXT2_HARD_LINKS);
+    // Two directory blocks, three blocks of /a/f counted once, and twenty
+    // blocks of /a/b/big with their indirect block
+    let usage = fs.disk_usage("/a", None).expect("Failed to sum usage");
+    assert_eq!(usage.bytes, (2 + 3 + 21) * 1024);
+    assert_eq!(usage.inodes, 5);
+    assert_eq!(usage.hard_links, 2);
+
+    let usage = fs.disk_usage("/a", Some(1)).expect("Failed to sum usage");
+    assert_eq!(usage.bytes, (2 + 3) * 1024);
+    assert_eq!(usage.inodes, 4);
+    assert_eq!(usage.hard_links, 1);
+
+    let usage = fs.disk_usage("/a/b/h", None).expect("Failed to sum usage");
+    assert_eq!(usage.bytes, 3 * 1024);
+    assert_eq!(usage.inodes, 1);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_write_block_group() {
+    // No checksums, crc32c (metadata_csum) and crc16 (gdt_csum)
+    for (image, desc_size) in [(&EXT2_REV0, 32), (&EXT4_64BIT, 64), (&EXT4_LAZY_ITABLE, 32)] {
+        let mut fs = mount(image);
+        let groups = fs.groups_count();
+        let group = groups - 1;
+        let used_dirs = fs.block_group(group).unwrap().used_dirs_count();
+        fs.block_group_mut(group).unwrap().set_used_dirs_count(7);
+        fs.write_block_group(group).expect("Failed to write descriptor");
+        assert_eq!(fs.write_block_group(groups), Err(Ext4Error::InvalidArg));
+
+        // Only the used directories count and the checksum of the slot
+        // changed
+        let has_csum = fs.superblock().has_group_csum();
+        let mut table = vec![0u8; 1024];
+        fs.read_block(2, &mut table).unwrap();
+        let slot = group as usize * desc_size;
+        let before = &image[2 * 1024..3 * 1024];
+        for (i, &byte) in table.iter().enumerate() {
+            let expected = match i.checked_sub(slot) {
+                Some(16) => 7,
+                Some(17) => 0,
+                Some(30 | 31) if has_csum => continue,
+                _ => before[i],
+            };
+            assert_eq!(byte, expected, "byte {}", i);
+        }
+        let checksum = u16::from_le_bytes([table[slot + 30], table[slot + 31]]);
+        assert_eq!(checksum, fs.block_group(group).unwrap().checksum());
+        if has_csum {
+            assert_ne!(checksum, u16::from_le_bytes([before[slot + 30], before[slot + 31]]));
+        }
+        if fs.superblock().has_metadata_csum() {
+            let mut desc = table[slot..slot + desc_size].to_vec();
+            desc[30..32].fill(0);
+            let seed = crc32c(fs.superblock().csum_seed(), &group.to_le_bytes());
+            assert_eq!(checksum, crc32c(seed, &desc) as u16);
+        }
+
+        // Writing the old count back gives the checksum mke2fs wrote
+        fs.block_group_mut(group).unwrap().set_used_dirs_count(used_dirs);
+        fs.write_block_group(group).expect("Failed to write descriptor");
+        fs.read_block(2, &mut table).unwrap();
+        assert_eq!(table, before);
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_metadata_csum_after_writes() {
+    let mut fs = mount(&EXT4_64BIT);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let dir = fs.create_dir(2, "dir", mode).expect("Failed to create directory");
+    for n in 0..40 {
+        fs.create_file(dir, &format!("file_with_a_long_name_{:03}", n), mode)
+            .expect("Failed to create file");
+    }
+    let mut file = fs.open("/dir/file_with_a_long_name_000", OpenFlags::WRITE).unwrap();
+    file.write(&[7u8; 5000], &mut fs).expect("Failed to write");
+    file.close(&mut fs).unwrap();
+    fs.rename(dir, b"file_with_a_long_name_001", 2, b"moved", RenameFlags::empty())
+        .expect("Failed to rename");
+    fs.flush().unwrap();
+
+    // Bitmap checksums in the descriptors, as 64-bit descriptors keep them
+    let sb = fs.superblock().clone();
+    let mut table = vec![0u8; 1024];
+    fs.read_block(2, &mut table).unwrap();
+    let mut bitmap = vec![0u8; 1024];
+    for group in 0..fs.groups_count() {
+        let desc = BlockGroupDescriptor::from_bytes(&table[group as usize * 64..][..64]).unwrap();
+        fs.read_block(desc.block_bitmap(), &mut bitmap).unwrap();
+        let len = sb.clusters_per_group() as usize / 8;
+        assert_eq!(desc.block_bitmap_csum(), crc32c(sb.csum_seed(), &bitmap[..len]));
+        fs.read_block(desc.inode_bitmap(), &mut bitmap).unwrap();
+        let len = sb.inodes_per_group() as usize / 8;
+        assert_eq!(desc.inode_bitmap_csum(), crc32c(sb.csum_seed(), &bitmap[..len]));
+    }
+
+    // Every directory block ends with a dirent tail holding its checksum
+    assert!(fs.get_inode(dir).unwrap().size > 1024);
+    let mut block = vec![0u8; 1024];
+    for ino in [2, dir] {
+        let inode = fs.get_inode(ino).unwrap();
+        let seed = crc32c(sb.csum_seed(), &ino.to_le_bytes());
+        let seed = crc32c(seed, &inode.generation.to_le_bytes());
+        for offset in (0..inode.size).step_by(1024) {
+            let block_num = inode.get_block_number(offset, 1024, &fs).unwrap();
+            fs.read_block(block_num, &mut block).unwrap();
+            assert_eq!(block[1012..1020], [0, 0, 0, 0, 12, 0, 0, 0xDE]);
+            let csum = u32::from_le_bytes(block[1020..].try_into().unwrap());
+            assert_eq!(csum, crc32c(seed, &block[..1012]), "block {} of {}", offset / 1024, ino);
+        }
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_accounting_drift() {
+    // One more free block in the descriptor than in the bitmap
+    let mut image = EXT2_REV0.to_vec();
+    let count = 2 * 1024 + 12;
+    let free = u16::from_le_bytes([image[count], image[count + 1]]) + 1;
+    image[count..count + 2].copy_from_slice(&free.to_le_bytes());
+
+    let options = MountOptions {
+        verify_accounting: false,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(VecBlockDevice::new(image.clone(), 512).unwrap(), options)
+        .expect("Failed to mount image");
+    fs.alloc_block().expect("Failed to allocate block");
+
+    let options = MountOptions {
+        verify_accounting: true,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(VecBlockDevice::new(image, 512).unwrap(), options)
+        .expect("Failed to mount image");
+    assert_eq!(fs.alloc_block(), Err(Ext4Error::CorruptGroupDescriptor(0)));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_failed_create_rolls_back() {
+    let mut fs = mount(&EXT2_REV0);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    // Fill the single block of the root directory
+    for n in 0..4 {
+        fs.create_file(2, &format!("{:0>200}", n), mode).expect("Failed to create file");
+    }
+    assert_eq!(fs.get_inode(2).unwrap().size, 1024);
+
+    // Leave one free block: enough for the new directory, not for the
+    // second block its entry needs in the root
+    while fs.group_stats(0).unwrap().free_blocks > 1 {
+        fs.alloc_block().expect("Failed to allocate block");
+    }
+    let before = fs.group_stats(0).unwrap();
+    assert_eq!(
+        fs.create_dir(2, &"d".repeat(200), InodeMode::from_bits_truncate(0o755)),
+        Err(Ext4Error::NoSpaceLeft)
+    );
+    let after = fs.group_stats(0).unwrap();
+    assert_eq!(after.free_blocks, before.free_blocks);
+    assert_eq!(after.free_inodes, before.free_inodes);
+    assert_eq!(after.used_dirs, before.used_dirs);
+    assert_eq!(fs.get_inode(2).unwrap().size, 1024);
+    assert_eq!(fs.lookup(2, &b"d".repeat(200)), Err(Ext4Error::InodeNotFound));
+
+    // The inode and block given back are reused
+    fs.create_dir(2, "d", InodeMode::from_bits_truncate(0o755))
+        .expect("Failed to create directory");
+    assert_eq!(fs.group_stats(0).unwrap().free_blocks, 0);
+}
+
+/// Check the crc16 checksum of every descriptor of a `gdt_csum`
+/// filesystem with its 32-byte descriptors in block 2
+#[cfg(not(feature = "read-only"))]
+fn assert_gdt_csums<D: BlockDriverOps>(fs: &Ext4FileSystem<D>) {
+    let crc16 = |mut crc: u16, data: &[u8]| {
+        for &byte in data {
+            crc ^= byte as u16;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+            }
+        }
+        crc
+    };
+    let mut table = vec![0u8; 1024];
+    fs.read_block(2, &mut table).unwrap();
+    for group in 0..fs.groups_count() {
+        let desc = &table[group as usize * 32..][..32];
+        let crc = crc16(0xFFFF, fs.superblock().uuid());
+        let crc = crc16(crc, &group.to_le_bytes());
+        let crc = crc16(crc, &desc[..30]);
+        assert_eq!(desc[30..32], crc.to_le_bytes(), "checksum of group {}", group);
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_lazy_itable_init() {
+    let mut fs = mount(&EXT4_LAZY_ITABLE);
+    assert!(!fs.group_stats(0).unwrap().itable_zeroed());
+    assert!(fs.group_stats(1).unwrap().inode_uninit());
+
+    // Inodes past those in use are zeroed as they are allocated, and the
+    // garbage bitmap of an uninitialized group is ignored
+    let mode = InodeMode::from_bits_truncate(0o644);
+    for n in 0..6 {
+        fs.create_file(2, &format!("f{}", n), mode).expect("Failed to create file");
+    }
+    assert_eq!(fs.group_stats(0).unwrap().itable_unused, 0);
+    let group1 = fs.group_stats(1).unwrap();
+    assert!(!group1.inode_uninit());
+    assert_eq!(group1.itable_unused, 15);
+    let ino = fs.lookup(2, b"f5").unwrap();
+    assert_eq!(ino, 17);
+    assert_eq!(fs.get_inode(ino).unwrap().file_acl, 0);
+    // Descriptors keep valid checksums as their flags and counts change
+    assert_gdt_csums(&fs);
+
+    // The rest of the tables is zeroed a group at a time
+    assert_eq!(fs.zero_inode_tables(1), Ok(1));
+    assert!(fs.group_stats(0).unwrap().itable_zeroed());
+    assert!(!fs.group_stats(1).unwrap().itable_zeroed());
+    assert_eq!(fs.zero_inode_tables(8), Ok(1));
+    assert_eq!(fs.zero_inode_tables(8), Ok(0));
+    assert_gdt_csums(&fs);
+    let mut buf = vec![0u8; 1024];
+    fs.read_block(264, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+    assert_eq!(fs.find_inode("/f5").unwrap().ino, 17);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_mkdir_accounting() {
+    let mut fs = mount(&EXT3);
+    let mode = InodeMode::from_bits_truncate(0o755);
+    let root_links = fs.get_inode(2).unwrap().links_count;
+    let used_dirs = fs.group_stats(0).unwrap().used_dirs;
+
+    let d = fs.create_dir(2, "d", mode).expect("Failed to create directory");
+    fs.create_dir(d, "e", mode).expect("Failed to create directory");
+    fs.create_dir(2, "f", mode).expect("Failed to create directory");
+    assert_eq!(fs.get_inode(2).unwrap().links_count, root_links + 2);
+    assert_eq!(fs.get_inode(d).unwrap().links_count, 3);
+    assert_eq!(fs.group_stats(0).unwrap().used_dirs, used_dirs + 3);
+
+    // Replacing a directory frees it, moving one keeps the count
+    fs.rename(2, b"f", d, b"e", RenameFlags::empty()).expect("Failed to rename");
+    assert_eq!(fs.get_inode(2).unwrap().links_count, root_links + 1);
+    assert_eq!(fs.get_inode(d).unwrap().links_count, 3);
+    assert_eq!(fs.group_stats(0).unwrap().used_dirs, used_dirs + 2);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_alloc_inode_stays_in_range() {
+    // Revision 0 reserves the first 10 inodes whatever s_first_ino says
+    let mut image = EXT2_REV0.to_vec();
+    image[1024 + 84..1024 + 88].copy_from_slice(&3u32.to_le_bytes());
+    let options = MountOptions {
+        verify_accounting: false,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(VecBlockDevice::new(image, 512).unwrap(), options).unwrap();
+    assert_eq!(fs.superblock().first_inode(), 11);
+
+    // Clear the bits of the reserved inodes and of the padding past the
+    // group, and claim more free inodes than there are
+    let inodes = fs.superblock().inodes_count() as usize;
+    let bitmap = fs.block_group(0).unwrap().inode_bitmap();
+    let mut block = vec![0u8; 1024];
+    fs.read_block(bitmap, &mut block).unwrap();
+    for i in (0..10).chain(inodes..8192) {
+        block[i / 8] &= !(1 << (i % 8));
+    }
+    let free = (10..inodes).filter(|&i| block[i / 8] & (1 << (i % 8)) == 0).count();
+    fs.write_block(bitmap, &block).unwrap();
+    fs.block_group_mut(0).unwrap().set_free_inodes_count(u16::MAX as u32);
+    fs.write_block_group(0).unwrap();
+
+    let mut allocated = Vec::new();
+    while let Ok(ino) = fs.alloc_inode() {
+        allocated.push(ino);
+    }
+    assert_eq!(allocated.len(), free);
+    assert!(allocated.iter().all(|&ino| (11..=inodes as u32).contains(&ino)), "{:?}", allocated);
+}
+
+/// Grow `ext4_meta_bg.img` into a sparse filesystem two groups past block
+/// 2^32, with no free blocks below it
+///
+/// The added groups get generated descriptors and uninitialized bitmaps, so
+/// only the descriptor blocks of the new meta groups need writing.
+#[cfg(not(feature = "read-only"))]
+fn mount_past_2_32(extents: bool) -> Ext4FileSystem<SparseDevice> {
+    const GROUP_BLOCKS: u64 = 32768;
+    const LOW_GROUPS: u64 = (1 << 32) / GROUP_BLOCKS;
+    let groups = LOW_GROUPS + 2;
+    let total = groups * GROUP_BLOCKS;
+
+    let mut blocks = BTreeMap::new();
+    for (block, data) in EXT4_META_BG.chunks(4096).enumerate() {
+        blocks.insert(block as u64, data.to_vec());
+    }
+
+    let sb = &mut blocks.get_mut(&0).unwrap()[1024..2048];
+    sb[0x00..0x04].copy_from_slice(&(groups as u32 * 16).to_le_bytes());
+    sb[0x04..0x08].copy_from_slice(&(total as u32).to_le_bytes());
+    sb[0x150..0x154].copy_from_slice(&((total >> 32) as u32).to_le_bytes());
+    sb[0x0C..0x10].copy_from_slice(&(2 * (GROUP_BLOCKS as u32 - 4)).to_le_bytes());
+    sb[0x10..0x14].copy_from_slice(&(5 + (groups as u32 - 1) * 16).to_le_bytes());
+    if !extents {
+        sb[0x60] &= !(FeatureIncompat::EXTENTS.bits() as u8);
+    }
+    let csum = crc32c(!0, &sb[..0x3FC]);
+    sb[0x3FC..].copy_from_slice(&csum.to_le_bytes());
+
+    // Group 0 keeps its descriptor but loses its free blocks. Every other
+    // group has its bitmaps and inode table in blocks 2 to 4 and all its
+    // inodes free. The descriptor block of each later meta group of 64
+    // starts its first group, which has no superblock backup.
+    blocks.get_mut(&2).unwrap().fill(0xFF);
+    for group in 0..groups {
+        let block = match group / 64 {
+            0 => 1,
+            meta_group => meta_group * 64 * GROUP_BLOCKS,
+        };
+        let data = blocks.entry(block).or_insert_with(|| vec![0; 4096]);
+        let desc = &mut data[(group % 64) as usize * 64..][..64];
+        if group != 0 {
+            let first = group * GROUP_BLOCKS;
+            for (at, block) in [(0x00, first + 2), (0x04, first + 3), (0x08, first + 4)] {
+                desc[at..at + 4].copy_from_slice(&(block as u32).to_le_bytes());
+                desc[at + 0x20..at + 0x24].copy_from_slice(&((block >> 32) as u32).to_le_bytes());
+            }
+            desc[0x0E..0x10].copy_from_slice(&16u16.to_le_bytes());
+            // INODE_UNINIT | BLOCK_UNINIT | ITABLE_ZEROED
+            desc[0x12..0x14].copy_from_slice(&7u16.to_le_bytes());
+            desc[0x1C..0x1E].copy_from_slice(&16u16.to_le_bytes());
+        }
+        let free = if group < LOW_GROUPS { 0 } else { GROUP_BLOCKS as u16 - 4 };
+        desc[0x0C..0x0E].copy_from_slice(&free.to_le_bytes());
+    }
+
+    let device = SparseDevice {
+        blocks,
+        num_blocks: total,
+    };
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_alloc_past_2_32() {
+    // Extent-mapped files get blocks past 2^32
+    let mut fs = mount_past_2_32(true);
+    let ino = fs.create_file(2, "f", InodeMode::from_bits_truncate(0o644)).unwrap();
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&[7; 4096], &mut fs).unwrap();
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.get_block_number(0, 4096, &fs), Ok((1 << 32) + 1));
+    let mut file = File::new(inode);
+    let mut buf = [0; 4096];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(4096));
+    assert_eq!(buf, [7; 4096]);
+
+    // Block-mapped files can't map them, so they find no space instead of
+    // taking a block and leaking it
+    let mut fs = mount_past_2_32(false);
+    let ino = fs.create_file(2, "f", InodeMode::from_bits_truncate(0o644)).unwrap();
+    let inode = fs.get_inode(ino).unwrap();
+    assert!(!inode.inode_flags().contains(InodeFlags::EXTENTS));
+    let free = fs.superblock().free_blocks_count();
+    let mut file = File::new(inode);
+    assert_eq!(file.write(&[7; 4096], &mut fs), Err(Ext4Error::NoSpaceLeft));
+    assert_eq!(fs.superblock().free_blocks_count(), free);
+    assert_eq!(fs.group_stats(1 << 17).unwrap().free_blocks, 32764);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_alloc_failure() {
+    // A directory claiming 4 EiB, as large_dir allows, fails to read
+    // rather than aborting
+    let fs = mount(&EXT4_LARGE_DIR);
+    let inode_size = fs.superblock().inode_size() as usize;
+    let table = fs.block_group(0).unwrap().inode_table();
+    let mut block = vec![0u8; fs.superblock().block_size() as usize];
+    fs.read_block(table, &mut block).unwrap();
+    let root = inode_size;
+    block[root + 4..root + 8].fill(0);
+    block[root + 108..root + 112].copy_from_slice(&(1u32 << 30).to_le_bytes());
+    fs.write_block(table, &block).unwrap();
+    assert_eq!(fs.get_inode(2).unwrap().size, 1 << 62);
+    assert_eq!(fs.read_dir(2).err(), Some(Ext4Error::NoMemory));
+}
+
+#[test]
+fn test_usage_report() {
+    // The values `dumpe2fs` prints for the image
+    let fs = mount(&EXT4_LAZY_ITABLE);
+    let report = fs.usage_report().expect("Failed to get report");
+    assert_eq!((report.block_size, report.inode_size, report.first_data_block), (1024, 256, 1));
+    assert_eq!((report.blocks_count, report.reserved_blocks, report.free_blocks), (512, 25, 482));
+    assert_eq!((report.inodes_count, report.free_inodes, report.used_dirs), (32, 21, 2));
+    assert_eq!((report.blocks_per_group, report.inodes_per_group), (256, 16));
+    assert!(report.feature_ro_compat.contains(FeatureRoCompat::GDT_CSUM));
+    assert_eq!(report.uuid, *fs.superblock().uuid());
+
+    let [group0, group1] = &report.groups[..] else {
+        panic!("Expected two groups, got {:?}", report.groups);
+    };
+    assert_eq!((group0.group, group0.first_block, group0.last_block), (0, 1, 256));
+    assert_eq!((group0.block_bitmap, group0.inode_bitmap, group0.inode_table), (3, 4, 5));
+    assert_eq!((group0.free_blocks, group0.free_inodes, group0.used_dirs), (235, 5, 2));
+    assert_eq!(group0.itable_unused, 5);
+    assert!(!group0.inode_uninit() && !group0.block_uninit());
+    assert_eq!((group1.group, group1.first_block, group1.last_block), (1, 257, 511));
+    assert_eq!((group1.block_bitmap, group1.inode_bitmap, group1.inode_table), (259, 260, 261));
+    assert_eq!((group1.free_blocks, group1.free_inodes, group1.used_dirs), (247, 16, 0));
+    assert!(group1.inode_uninit() && !group1.block_uninit());
+    assert_eq!(fs.group_stats(1).unwrap(), *group1);
+    assert_eq!(fs.group_stats(2).err(), Some(Ext4Error::InvalidArg));
+}
@@ -3,6 +3,7 @@
 mod common;
 use common::MockBlockDevice;
 
+#[cfg(not(feature = "read-only"))]
 #[test]
 fn test_mock_block_device() {
     // Create a mock block device
@@ -86,4 +87,110 @@ fn test_invalid_block_access() {
     // Should fail when reading from invalid block
     let mut read_buffer = vec![0u8; test_data.len()];
     assert!(device.read_block(invalid_block, &mut read_buffer).is_err(), "Reading from invalid block should fail");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_vec_block_device() {
+    use axdriver_block::BlockDriverOps;
+    use ext4rs::{Ext4Error, VecBlockDevice};
+
+    let mut device = VecBlockDevice::zeroed(16, 1024).unwrap();
+    assert_eq!(device.num_blocks(), 16);
+    assert_eq!(device.block_size(), 1024);
+
+    let test_data = vec![0x5Au8; 2048];
+    device.write_block(14, &test_data).expect("Failed to write blocks");
+
+    let mut read_buffer = vec![0u8; 2048];
+    device.read_block(14, &mut read_buffer).expect("Failed to read blocks");
+    assert_eq!(test_data, read_buffer);
+    assert_eq!(&device.as_bytes()[14 * 1024..], &test_data[..]);
+
+    // Transfers running past the end of the device fail
+    assert!(device.write_block(15, &test_data).is_err());
+    assert!(device.read_block(16, &mut read_buffer[..1024]).is_err());
+
+    // Block sizes must be powers of two
+    assert_eq!(VecBlockDevice::new(vec![0; 3000], 1000).err(), Some(Ext4Error::InvalidArg));
+    assert_eq!(VecBlockDevice::zeroed(4, 0).err(), Some(Ext4Error::InvalidArg));
+    assert_eq!(VecBlockDevice::zeroed(usize::MAX, 1024).err(), Some(Ext4Error::InvalidArg));
+}
+
+#[test]
+fn test_slice_block_device() {
+    use axdriver_block::BlockDriverOps;
+    use ext4rs::{Ext4Error, SliceBlockDevice};
+
+    static IMAGE: [u8; 4096 + 100] = [0xA5; 4096 + 100];
+    let mut device = SliceBlockDevice::new(&IMAGE, 1024).unwrap();
+
+    // The trailing partial block is not exposed
+    assert_eq!(device.num_blocks(), 4);
+
+    let mut read_buffer = vec![0u8; 1024];
+    device.read_block(3, &mut read_buffer).expect("Failed to read block");
+    assert!(read_buffer.iter().all(|&b| b == 0xA5));
+    assert!(device.read_block(4, &mut read_buffer).is_err());
+
+    // The device is read-only
+    assert!(device.write_block(0, &read_buffer).is_err());
+    assert_eq!(SliceBlockDevice::new(&IMAGE, 1536).err(), Some(Ext4Error::InvalidArg));
+}
+
+#[test]
+fn test_offset_device_partition() {
+    use axdriver_block::BlockDriverOps;
+    use ext4rs::{read_partitions, OffsetDevice, PartitionKind, VecBlockDevice};
+
+    // MBR with a Linux partition at blocks 8..24
+    let mut disk = vec![0u8; 32 * 512];
+    disk[446 + 4] = 0x83;
+    disk[446 + 8..446 + 12].copy_from_slice(&8u32.to_le_bytes());
+    disk[446 + 12..446 + 16].copy_from_slice(&16u32.to_le_bytes());
+    disk[510] = 0x55;
+    disk[511] = 0xAA;
+    disk[8 * 512] = 0xAB;
+    let mut device = VecBlockDevice::new(disk, 512).unwrap();
+
+    let partitions = read_partitions(&mut device).expect("Failed to read partition table");
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].number, 1);
+    assert_eq!(partitions[0].start, 8);
+    assert_eq!(partitions[0].num_blocks, 16);
+    assert_eq!(partitions[0].kind, PartitionKind::Mbr(0x83));
+    assert!(partitions[0].is_linux());
+
+    let mut partition = OffsetDevice::partition(device, 1).expect("Failed to open partition");
+    assert_eq!(partition.num_blocks(), 16);
+
+    let mut read_buffer = vec![0u8; 512];
+    partition.read_block(0, &mut read_buffer).expect("Failed to read block");
+    assert_eq!(read_buffer[0], 0xAB);
+
+    partition.write_block(15, &[0xCD; 512]).expect("Failed to write block");
+    assert!(partition.write_block(16, &[0xCD; 512]).is_err());
+    assert!(partition.read_block(15, &mut vec![0u8; 1024]).is_err());
+
+    let device = partition.into_inner();
+    assert!(device.as_bytes()[23 * 512..24 * 512].iter().all(|&b| b == 0xCD));
+    assert!(OffsetDevice::partition(device, 2).is_err());
+}
+
+#[test]
+fn test_superblock_on_any_sector_size() {
+    use ext4rs::{SuperBlock, VecBlockDevice};
+
+    let mut image = vec![0u8; 8192];
+    let sb = &mut image[1024..2048];
+    sb[0..4].copy_from_slice(&128u32.to_le_bytes());
+    sb[4..8].copy_from_slice(&1024u32.to_le_bytes());
+    sb[56..58].copy_from_slice(&0xEF53u16.to_le_bytes());
+
+    for sector_size in [512, 1024, 4096] {
+        let mut device = VecBlockDevice::new(image.clone(), sector_size).unwrap();
+        let superblock = SuperBlock::read_from_device(&mut device).expect("Failed to read superblock");
+        assert_eq!(superblock.magic(), 0xEF53, "sector size {}", sector_size);
+        assert_eq!(superblock.inodes_count(), 128);
+        assert_eq!(superblock.blocks_count(), 1024);
+    }
+}
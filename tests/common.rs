@@ -23,11 +23,13 @@ impl MockBlockDevice {
     }
 
     /// Get the total size of the device
+    #[cfg(not(feature = "read-only"))]
     pub fn size(&self) -> usize {
         self.data.len()
     }
 
     /// Write data directly to the device (for setup)
+    #[cfg(not(feature = "read-only"))]
     pub fn write_direct(&mut self, offset: usize, data: &[u8]) {
         let end = offset + data.len();
         assert!(end <= self.data.len(), "Write beyond device bounds");
@@ -35,6 +37,7 @@ impl MockBlockDevice {
     }
 
     /// Read data directly from the device (for verification)
+    #[cfg(not(feature = "read-only"))]
     pub fn read_direct(&self, offset: usize, buf: &mut [u8]) {
         let end = offset + buf.len();
         assert!(end <= self.data.len(), "Read beyond device bounds");
@@ -76,17 +79,20 @@ impl MockBlockDevice {
     }
 
     /// Get the number of blocks
+    #[cfg(not(feature = "read-only"))]
     pub fn num_blocks(&self) -> u32 {
         self.total_blocks
     }
 
     /// Get the block size
+    #[cfg(not(feature = "read-only"))]
     pub fn block_size(&self) -> u32 {
         self.block_size
     }
 }
 
 /// Create a minimal ext4 superblock for testing
+#[cfg(not(feature = "read-only"))]
 pub fn create_test_superblock() -> Vec<u8> {
     let mut sb = vec![0u8; 1024]; // Standard superblock size
     
@@ -121,6 +127,7 @@ pub fn create_test_superblock() -> Vec<u8> {
 }
 
 /// Create a minimal block group descriptor for testing
+#[cfg(not(feature = "read-only"))]
 pub fn create_test_block_group_descriptor() -> Vec<u8> {
     let mut bgd = vec![0u8; 32]; // Standard block group descriptor size
     
@@ -0,0 +1,135 @@
+//! Compatibility tests for ext2 and ext3 images
+//!
+//! The images in `tests/images`, packed as `images/mod.rs` describes, were
+//! created with mke2fs using 1 KiB blocks and 128-byte inodes:
+//!
+//! - `ext2_rev0.img`: revision 0, no features
+//! - `ext2_nofiletype.img`: revision 1 without the filetype feature
+//! - `ext3.img`: revision 1 with a journal and the filetype feature
+
+mod images;
+
+use ext4rs::{Ext4FileSystem, MountOptions, VecBlockDevice};
+#[cfg(not(feature = "read-only"))]
+use ext4rs::{File, InodeMode};
+use images::{image, Image};
+
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT2_NOFILETYPE: Image = image!("images/ext2_nofiletype.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT3: Image = image!("images/ext3.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+#[cfg(not(feature = "read-only"))]
+fn root_names(fs: &Ext4FileSystem<VecBlockDevice>) -> Vec<(String, u8)> {
+    fs.read_dir(2)
+        .expect("Failed to read root directory")
+        .iter()
+        .map(|e| (e.name.to_string(), e.file_type))
+        .collect()
+}
+
+/// Create, write, and read back a file large enough to need an indirect block
+#[cfg(not(feature = "read-only"))]
+fn check_write_read(fs: &mut Ext4FileSystem<VecBlockDevice>) {
+    let ino = fs
+        .create_file(2, "data.bin", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+
+    let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+    let mut file = File::new(fs.get_inode(ino).expect("Failed to get inode"));
+    assert_eq!(file.write(&data, fs).expect("Failed to write"), data.len());
+
+    let inode = fs.get_inode(ino).expect("Failed to get inode");
+    assert_eq!(inode.size, 20000);
+    // 20 data blocks plus one indirect block, in 512-byte sectors
+    assert_eq!(inode.blocks, 42);
+
+    let mut file = File::new(inode);
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(file.read(&mut buf, fs).expect("Failed to read"), data.len());
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn test_ext2_rev0_superblock() {
+    let fs = mount(&EXT2_REV0);
+    let sb = fs.superblock();
+    assert_eq!(sb.rev_level(), 0);
+    assert_eq!(sb.inode_size(), 128);
+    assert_eq!(sb.first_inode(), 11);
+    assert_eq!(sb.group_desc_size(), 32);
+    assert_eq!(sb.block_size(), 1024);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_ext2_rev0_read_write() {
+    let mut fs = mount(&EXT2_REV0);
+    assert_eq!(
+        root_names(&fs),
+        vec![
+            (".".to_string(), 0),
+            ("..".to_string(), 0),
+            ("lost+found".to_string(), 0)
+        ]
+    );
+
+    check_write_read(&mut fs);
+    let ino = fs
+        .create_dir(2, "dir", InodeMode::from_bits_truncate(0o755))
+        .expect("Failed to create directory");
+    assert_eq!(fs.lookup(2, b"dir").expect("Failed to look up"), ino);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_ext2_without_filetype() {
+    let mut fs = mount(&EXT2_NOFILETYPE);
+    check_write_read(&mut fs);
+    fs.create_dir(2, "dir", InodeMode::from_bits_truncate(0o755))
+        .expect("Failed to create directory");
+
+    // Without the filetype feature the type byte must stay zero
+    let names = root_names(&fs);
+    assert!(names.iter().all(|(_, file_type)| *file_type == 0));
+    assert!(names.iter().any(|(name, _)| name == "data.bin"));
+
+    let dir = fs.find_inode("/dir").expect("Failed to find directory");
+    let entries = fs.read_dir(dir.ino).expect("Failed to read directory");
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|e| e.file_type == 0));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_ext3_read_write() {
+    let mut fs = mount(&EXT3);
+    check_write_read(&mut fs);
+
+    let dir = fs
+        .create_dir(2, "dir", InodeMode::from_bits_truncate(0o755))
+        .expect("Failed to create directory");
+    fs.create_file(dir, "nested", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create nested file");
+
+    let meta = fs.stat_path("/data.bin").expect("Failed to stat file");
+    assert!(meta.is_file());
+    assert_eq!((meta.size, meta.blocks, meta.nlink), (20000, 42, 1));
+    assert_eq!(meta.block_size, 1024);
+    assert!(fs.stat(dir).expect("Failed to stat directory").is_dir());
+
+    let names = root_names(&fs);
+    assert!(names.contains(&("data.bin".to_string(), 1)));
+    assert!(names.contains(&("dir".to_string(), 2)));
+
+    // Directory blocks match the 1 KiB filesystem block size
+    let dir_inode = fs.get_inode(dir).expect("Failed to get inode");
+    assert_eq!(dir_inode.size, 1024);
+    assert!(fs.find_inode("/dir/nested").is_ok());
+}
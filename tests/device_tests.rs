@@ -0,0 +1,279 @@
+//! Tests of block device errors and of the devices layered over another
+
+#![cfg(not(feature = "read-only"))]
+
+mod images;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axdriver_block::{BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceType};
+use ext4rs::{
+    DeviceErrorKind, Ext4Error, Ext4FileSystem, File, InodeMode, MountOptions, OpenFlags,
+    RetryDevice, RetryPolicy, RetryStats, SliceBlockDevice, VecBlockDevice,
+};
+use images::{image, Image};
+
+#[cfg(feature = "test-utils")]
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+static EXT3: Image = image!("images/ext3.img.packed");
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+
+/// Device whose next requests fail with queued errors
+struct FaultyDevice {
+    inner: VecBlockDevice,
+    faults: Arc<Mutex<VecDeque<DevError>>>,
+}
+
+impl FaultyDevice {
+    fn fault(&self) -> DevResult {
+        match self.faults.lock().unwrap().pop_front() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BaseDriverOps for FaultyDevice {
+    fn device_name(&self) -> &str {
+        "faulty"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriverOps for FaultyDevice {
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.fault()?;
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.fault()?;
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.fault()?;
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_short_write() {
+    let options = MountOptions {
+        max_dirty_blocks: 4,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT3.to_vec(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let free_blocks: u32 = (0..fs.groups_count())
+        .map(|group| fs.group_stats(group).unwrap().free_blocks)
+        .sum();
+    let ino = fs
+        .create_file(2, "fill", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+
+    // Running out of space after the first commits ends the write short
+    let data = vec![7u8; (free_blocks as usize + 64) * 1024];
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    let written = file.write(&data, &mut fs).expect("Failed to write");
+    assert!(written > 0 && written < data.len());
+    assert_eq!(written % (4 * 1024), 0);
+    assert_eq!(file.position(), written as u64);
+    assert_eq!(fs.get_inode(ino).unwrap().size, written as u64);
+
+    // With nothing written, the error is returned
+    assert_eq!(file.write(&data, &mut fs), Err(Ext4Error::NoSpaceLeft));
+    assert_eq!(file.position(), written as u64);
+}
+
+#[test]
+#[cfg(feature = "test-utils")]
+fn test_copy_on_write_device() {
+    use ext4rs::CopyOnWriteDevice;
+
+    let device = CopyOnWriteDevice::new(VecBlockDevice::new(EXT2_REV0.to_vec(), 1024).unwrap());
+    let mut fs = Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount");
+    assert!(fs.changed_blocks().is_empty());
+    fs.create_file(2, "file", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    // Changed bitmaps reach the device on flush
+    fs.flush().expect("Failed to flush");
+
+    // The inode bitmap gained inode 12 in its second byte
+    let inode_bitmap = fs.group_stats(0).unwrap().inode_bitmap;
+    let diff = fs.diff().expect("Failed to diff");
+    let bitmap = diff.iter().find(|d| d.block == inode_bitmap).expect("No bitmap change");
+    assert_eq!(bitmap.changed_ranges(), vec![1..2]);
+    assert_eq!(bitmap.current[1] & !bitmap.original[1], 0x08);
+    let changed = fs.changed_blocks();
+    assert!(diff.iter().all(|d| changed.contains(&d.block)));
+
+    let device = fs.rollback().expect("Failed to roll back");
+    assert!(device.changed_blocks().is_empty());
+    assert_eq!(device.into_inner().as_bytes(), *EXT2_REV0);
+}
+
+#[test]
+fn test_device_error_kinds() {
+    let faults = Arc::new(Mutex::new(VecDeque::new()));
+    let device = FaultyDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        faults: faults.clone(),
+    };
+    let options = MountOptions {
+        journaling: false,
+        ..MountOptions::default()
+    };
+    let fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let mut buf = vec![0u8; 1024];
+
+    for (err, kind) in [
+        (DevError::Again, DeviceErrorKind::Timeout),
+        (DevError::ResourceBusy, DeviceErrorKind::Timeout),
+        (DevError::Io, DeviceErrorKind::Media),
+        (DevError::Unsupported, DeviceErrorKind::Unsupported),
+        (DevError::NoMemory, DeviceErrorKind::Other),
+    ] {
+        faults.lock().unwrap().push_back(err);
+        assert_eq!(fs.read_block(60, &mut buf), Err(Ext4Error::Device(kind)));
+        assert_eq!(kind.is_transient(), kind == DeviceErrorKind::Timeout);
+    }
+    assert_eq!(fs.read_block(60, &mut buf), Ok(()));
+    assert_eq!(
+        fs.read_block(1 << 40, &mut buf),
+        Err(Ext4Error::Device(DeviceErrorKind::OutOfRange))
+    );
+    assert_eq!(fs.read_block(u64::MAX, &mut buf), Err(Ext4Error::InvalidArg));
+
+    // Failed writes report the kind too
+    faults.lock().unwrap().push_back(DevError::Io);
+    assert_eq!(fs.write_block(60, &buf), Err(Ext4Error::Device(DeviceErrorKind::Media)));
+}
+
+#[test]
+fn test_file_read_errors() {
+    let faults = Arc::new(Mutex::new(VecDeque::new()));
+    let device = FaultyDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        faults: faults.clone(),
+    };
+    let options = MountOptions {
+        journaling: false,
+        cache_budget: 0,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let ino = fs
+        .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    File::new(fs.get_inode(ino).unwrap()).write(&[7; 2048], &mut fs).unwrap();
+
+    // A failed block read fails the read, which can be retried
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    let mut buf = vec![0u8; 2048];
+    faults.lock().unwrap().push_back(DevError::Again);
+    assert_eq!(file.read(&mut buf, &mut fs), Err(Ext4Error::Device(DeviceErrorKind::Timeout)));
+    assert_eq!(file.position(), 0);
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(2048));
+    assert_eq!(buf, vec![7; 2048]);
+
+    // A partial block write fails too, rather than zeroing the rest of the
+    // block
+    file.seek(5).unwrap();
+    faults.lock().unwrap().push_back(DevError::Io);
+    assert_eq!(file.write(b"data", &mut fs), Err(Ext4Error::Device(DeviceErrorKind::Media)));
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(2048));
+    assert_eq!(buf, vec![7; 2048]);
+}
+
+#[test]
+fn test_retry_device() {
+    static BACKOFFS: AtomicI64 = AtomicI64::new(0);
+    fn backoff(attempt: u32) {
+        BACKOFFS.fetch_add(attempt as i64, Ordering::Relaxed);
+    }
+    let faults = Arc::new(Mutex::new(VecDeque::new()));
+    let device = FaultyDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        faults: faults.clone(),
+    };
+    let policy = RetryPolicy {
+        attempts: 3,
+        backoff: Some(backoff),
+    };
+    let options = MountOptions {
+        journaling: false,
+        ..MountOptions::default()
+    };
+    let fs = Ext4FileSystem::new(RetryDevice::new(device, policy), options)
+        .expect("Failed to mount image");
+    assert_eq!(fs.retry_stats(), RetryStats::default());
+    let mut buf = vec![0u8; 1024];
+
+    // Two transient failures are retried away, with a backoff before each
+    faults.lock().unwrap().extend([DevError::Again, DevError::ResourceBusy]);
+    assert_eq!(fs.read_block(60, &mut buf), Ok(()));
+    assert_eq!(BACKOFFS.load(Ordering::Relaxed), 1 + 2);
+    let stats = fs.retry_stats();
+    assert_eq!((stats.retries, stats.recovered, stats.exhausted), (2, 1, 0));
+
+    // A third one is returned
+    faults.lock().unwrap().extend([DevError::Again; 3]);
+    assert_eq!(
+        fs.write_block(60, &buf),
+        Err(Ext4Error::Device(DeviceErrorKind::Timeout))
+    );
+    let stats = fs.retry_stats();
+    assert_eq!((stats.retries, stats.recovered, stats.exhausted), (4, 1, 1));
+
+    // Hard errors are not retried
+    faults.lock().unwrap().extend([DevError::Io, DevError::Again]);
+    assert_eq!(
+        fs.read_block(60, &mut buf),
+        Err(Ext4Error::Device(DeviceErrorKind::Media))
+    );
+    assert_eq!(fs.retry_stats().retries, 4);
+    faults.lock().unwrap().clear();
+}
+
+#[test]
+fn test_overlay_mount() {
+    // Writes succeed on a device that can't be written at all
+    let device = SliceBlockDevice::new(&EXT2_HARD_LINKS, 512).unwrap();
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new_overlay(device, options.clone()).expect("Failed to mount");
+    assert_eq!(fs.overlay_blocks(), 0);
+    let ino = fs.create_file(2, "new", InodeMode::from_bits_truncate(0o644)).unwrap();
+    let mut file = fs.open_inode(ino, OpenFlags::WRITE).unwrap();
+    file.write(&[7; 3000], &mut fs).unwrap();
+    file.close(&mut fs).unwrap();
+    let mut file = fs.open("/new", OpenFlags::empty()).unwrap();
+    let mut buf = vec![0; 3000];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(3000));
+    assert_eq!(buf, [7; 3000]);
+    file.close(&mut fs).unwrap();
+    assert!(fs.overlay_blocks() > 0);
+
+    // Discarding leaves the image as it was
+    let device = fs.discard_overlay();
+    assert_eq!(device.as_bytes(), *EXT2_HARD_LINKS);
+    let fs = Ext4FileSystem::new(device, options).unwrap();
+    assert_eq!(fs.find_inode("/new").err(), Some(Ext4Error::InodeNotFound));
+}
@@ -0,0 +1,548 @@
+//! Tests of directories: renames, link counts, path resolution, indexed,
+//! large and inline directories, and walking trees
+
+mod images;
+
+use ext4rs::{Ext4Error, Ext4FileSystem, MountOptions, SymlinkPolicy, VecBlockDevice, WalkOptions};
+#[cfg(not(feature = "read-only"))]
+use ext4rs::{
+    FeatureIncompat, FeatureRoCompat, File, InodeFlags, InodeMode, OpenFlags, RenameFlags,
+    ResolveFlags, EXT4_LINK_MAX,
+};
+use images::{image, Image};
+
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT3: Image = image!("images/ext3.img.packed");
+static EXT4_HTREE: Image = image!("images/ext4_htree.img.packed");
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_LARGE_DIR: Image = image!("images/ext4_large_dir.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_INLINE_DIR: Image = image!("images/ext4_inline_dir.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+/// Add a fast symbolic link `name` -> `target` to the root directory of
+/// `ext2_rev0.img`, using the free inode `ino`
+fn add_symlink(image: &mut [u8], ino: usize, name: &str, target: &str) {
+    let read_u16 = |image: &[u8], at: usize| {
+        u16::from_le_bytes([image[at], image[at + 1]]) as usize
+    };
+    let read_u32 = |image: &[u8], at: usize| {
+        u32::from_le_bytes(image[at..at + 4].try_into().unwrap()) as usize
+    };
+    // Single group, descriptor in block 2, 128-byte inodes
+    let inode_bitmap = read_u32(image, 2 * 1024 + 4) * 1024;
+    let inode_table = read_u32(image, 2 * 1024 + 8) * 1024;
+    image[inode_bitmap + (ino - 1) / 8] |= 1 << ((ino - 1) % 8);
+    let free_inodes = read_u16(image, 2 * 1024 + 14) - 1;
+    image[2 * 1024 + 14..2 * 1024 + 16].copy_from_slice(&(free_inodes as u16).to_le_bytes());
+
+    let inode = &mut image[inode_table + (ino - 1) * 128..][..128];
+    inode.fill(0);
+    inode[0..2].copy_from_slice(&0o120777u16.to_le_bytes());
+    inode[4..8].copy_from_slice(&(target.len() as u32).to_le_bytes());
+    inode[26..28].copy_from_slice(&1u16.to_le_bytes());
+    inode[40..40 + target.len()].copy_from_slice(target.as_bytes());
+
+    // Split the last entry of the root directory
+    let dir = read_u32(image, inode_table + 128 + 40) * 1024;
+    let mut pos = dir;
+    while pos + read_u16(image, pos + 4) < dir + 1024 {
+        pos += read_u16(image, pos + 4);
+    }
+    let used = (8 + image[pos + 6] as usize + 3) & !3;
+    let rec_len = read_u16(image, pos + 4) - used;
+    assert!(rec_len >= 8 + name.len());
+    image[pos + 4..pos + 6].copy_from_slice(&(used as u16).to_le_bytes());
+    let entry = pos + used;
+    image[entry..entry + 4].copy_from_slice(&(ino as u32).to_le_bytes());
+    image[entry + 4..entry + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    image[entry + 6] = name.len() as u8;
+    image[entry + 7] = 0;
+    image[entry + 8..entry + 8 + name.len()].copy_from_slice(name.as_bytes());
+}
+
+#[test]
+fn test_directory_cycle() {
+    // Rename the ".." entry of lost+found (block 10) to "up", so that a
+    // regular entry points back at the root
+    let mut image = EXT2_REV0.to_vec();
+    let dotdot_name = 10 * 1024 + 12 + 8;
+    assert_eq!(&image[dotdot_name..dotdot_name + 2], b"..");
+    image[dotdot_name..dotdot_name + 2].copy_from_slice(b"up");
+    let fs = mount(&image);
+
+    assert_eq!(fs.find_inode("/lost+found/up").err(), Some(Ext4Error::Loop));
+    assert_eq!(
+        fs.find_inode("/lost+found/up/lost+found/up").err(),
+        Some(Ext4Error::Loop)
+    );
+
+    // Genuine ".." entries and deep but finite paths still resolve
+    let fs = mount(&EXT2_REV0);
+    assert_eq!(fs.find_inode("/lost+found/..").unwrap().ino, 2);
+    assert!(fs.find_inode("/lost+found/../lost+found/.").is_ok());
+    let deep = "/lost+found/..".repeat(300);
+    assert_eq!(fs.find_inode(&deep).err(), Some(Ext4Error::InvalidPath));
+    let deep = "/.".repeat(1025);
+    assert_eq!(fs.find_inode(&deep).err(), Some(Ext4Error::Loop));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_rename_flags() {
+    let mut fs = mount(&EXT3);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let a = fs.create_file(2, "a", mode).expect("Failed to create file");
+    let b = fs.create_file(2, "b", mode).expect("Failed to create file");
+    let none = RenameFlags::empty();
+
+    fs.rename(2, b"a", 2, b"c", none).expect("Failed to rename");
+    assert_eq!(fs.lookup(2, b"a").err(), Some(Ext4Error::InodeNotFound));
+    assert_eq!(fs.lookup(2, b"c"), Ok(a));
+
+    // NOREPLACE refuses an existing target, EXCHANGE needs one
+    assert_eq!(
+        fs.rename(2, b"c", 2, b"b", RenameFlags::NOREPLACE).err(),
+        Some(Ext4Error::FileExists)
+    );
+    assert_eq!(
+        fs.rename(2, b"c", 2, b"missing", RenameFlags::EXCHANGE).err(),
+        Some(Ext4Error::InodeNotFound)
+    );
+    fs.rename(2, b"c", 2, b"b", RenameFlags::EXCHANGE).expect("Failed to exchange");
+    assert_eq!(fs.lookup(2, b"b"), Ok(a));
+    assert_eq!(fs.lookup(2, b"c"), Ok(b));
+
+    // Replacing drops the last link of the old target
+    let handle = fs.encode_fh(b).unwrap();
+    fs.rename(2, b"b", 2, b"c", none).expect("Failed to replace");
+    assert_eq!(fs.lookup(2, b"c"), Ok(a));
+    assert_eq!(fs.decode_fh(&handle).err(), Some(Ext4Error::StaleHandle));
+
+    // Moving a directory updates ".." and both parents' link counts
+    let root_links = fs.get_inode(2).unwrap().links_count;
+    let d1 = fs.create_dir(2, "d1", mode).expect("Failed to create dir");
+    let d2 = fs.create_dir(2, "d2", mode).expect("Failed to create dir");
+    let d2_links = fs.get_inode(d2).unwrap().links_count;
+    fs.rename(2, b"d1", d2, b"sub", RenameFlags::NOREPLACE).expect("Failed to move dir");
+    assert_eq!(fs.lookup(d1, b".."), Ok(d2));
+    assert_eq!(fs.get_inode(d2).unwrap().links_count, d2_links + 1);
+    assert_eq!(fs.get_inode(2).unwrap().links_count, root_links + 1);
+    assert_eq!(
+        fs.rename(2, b"d2", d1, b"loop", none).err(),
+        Some(Ext4Error::InvalidArg)
+    );
+    assert_eq!(fs.rename(2, b"c", 2, b"d2", none).err(), Some(Ext4Error::IsADirectory));
+
+    // Immutable inodes stay where they are
+    fs.set_flags(a, InodeFlags::IMMUTABLE).unwrap();
+    assert_eq!(
+        fs.rename(2, b"c", d2, b"c", none).err(),
+        Some(Ext4Error::PermissionDenied)
+    );
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_rename_dir_parent() {
+    let mut fs = mount(&EXT3);
+    let mode = InodeMode::from_bits_truncate(0o755);
+    let d1 = fs.create_dir(2, "d1", mode).expect("Failed to create dir");
+    let d2 = fs.create_dir(2, "d2", mode).expect("Failed to create dir");
+    let d3 = fs.create_dir(d1, "d3", mode).expect("Failed to create dir");
+
+    // Exchanging directories in different parents swaps their ".."
+    let d1_links = fs.get_inode(d1).unwrap().links_count;
+    let d2_links = fs.get_inode(d2).unwrap().links_count;
+    fs.rename(d1, b"d3", 2, b"d2", RenameFlags::EXCHANGE).expect("Failed to exchange");
+    assert_eq!(fs.lookup(d3, b".."), Ok(2));
+    assert_eq!(fs.lookup(d2, b".."), Ok(d1));
+    assert_eq!(fs.get_inode(d1).unwrap().links_count, d1_links);
+    assert_eq!(fs.get_inode(d2).unwrap().links_count, d2_links);
+
+    // A ".." naming another directory than the parent is corruption
+    let d4 = fs.create_dir(d1, "d4", mode).expect("Failed to create dir");
+    let d1_links = fs.get_inode(d1).unwrap().links_count;
+    let inode = fs.get_inode(d4).unwrap();
+    let block = inode.get_block_number(0, 1024, &fs).unwrap();
+    let mut buf = vec![0u8; 1024];
+    fs.read_block(block, &mut buf).unwrap();
+    let dotdot = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+    buf[dotdot..dotdot + 4].copy_from_slice(&d3.to_le_bytes());
+    fs.write_block(block, &buf).unwrap();
+    assert_eq!(
+        fs.rename(d1, b"d4", 2, b"moved", RenameFlags::empty()).err(),
+        Some(Ext4Error::InvalidState)
+    );
+    assert_eq!(fs.lookup(d1, b"d4"), Ok(d4));
+    assert_eq!(fs.get_inode(d1).unwrap().links_count, d1_links);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_dir_prealloc() {
+    // Enable dir_prealloc with 4 blocks per new directory
+    let mut image = EXT3.to_vec();
+    image[1024 + 92] |= 0x01;
+    image[1024 + 205] = 4;
+    let device = VecBlockDevice::new(image, 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount");
+    assert_eq!(fs.superblock().dir_prealloc_blocks(), 4);
+
+    let mode = InodeMode::from_bits_truncate(0o755);
+    let dir = fs.create_dir(2, "big", mode).expect("Failed to create dir");
+    let other = fs.create_dir(2, "other", mode).expect("Failed to create dir");
+    for i in 0..60 {
+        fs.create_file(dir, &format!("file-with-a-long-name-{:03}", i), mode)
+            .expect("Failed to create file");
+        fs.create_file(other, &format!("f{}", i), mode)
+            .expect("Failed to create file");
+    }
+
+    // Both directories grew into their own reserved blocks
+    for ino in [dir, other] {
+        let file = File::new(fs.get_inode(ino).unwrap());
+        let runs: Vec<_> = file.blocks(&fs).map(|r| r.unwrap()).collect();
+        assert_eq!(runs.len(), 1, "directory {} is fragmented: {:?}", ino, runs);
+    }
+    let file = File::new(fs.get_inode(dir).unwrap());
+    assert!(file.blocks(&fs).next().unwrap().unwrap().len >= 3);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_dir_nlink() {
+    // Root directory inode of `ext3.img` is at block 12, offset 128
+    let root_links = 12 * 1024 + 128 + 26;
+    let mode = InodeMode::from_bits_truncate(0o755);
+    let mut image = EXT3.to_vec();
+    image[root_links..root_links + 2].copy_from_slice(&EXT4_LINK_MAX.to_le_bytes());
+
+    let device = VecBlockDevice::new(image.clone(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount");
+    assert_eq!(fs.create_dir(2, "d", mode).err(), Some(Ext4Error::TooManyLinks));
+
+    // With dir_nlink the count saturates to 1 instead
+    image[1024 + 100] |= 0x20;
+    let device = VecBlockDevice::new(image, 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount");
+    for name in ["d1", "d2"] {
+        fs.create_dir(2, name, mode).expect("Failed to create dir");
+        assert_eq!(fs.get_inode(2).unwrap().links_count, 1);
+    }
+    fs.rename(2, b"d1", 2, b"d2", RenameFlags::empty())
+        .expect("Failed to rename");
+    assert_eq!(fs.get_inode(2).unwrap().links_count, 1);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_path_normalization() {
+    let mut fs = mount(&EXT2_REV0);
+    let file = fs
+        .create_file(2, "file", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let lost_found = fs.find_inode("/lost+found").unwrap().ino;
+
+    for path in ["//lost+found//", "/./lost+found/.", "lost+found", "/lost+found/../lost+found/"] {
+        assert_eq!(fs.find_inode(path).unwrap().ino, lost_found, "{}", path);
+    }
+    for path in ["/..", "/../..", "/lost+found/..", "//.//", ""] {
+        assert_eq!(fs.find_inode(path).unwrap().ino, 2, "{}", path);
+    }
+    assert_eq!(fs.find_inode("/lost+found/../file").unwrap().ino, file);
+    assert_eq!(fs.find_inode("//file").unwrap().ino, file);
+
+    // Only directories can be followed by "/", "." or ".."
+    for path in ["/file/", "/file/.", "/file/..", "/file//", "/file/../file", "/file/./x"] {
+        assert_eq!(fs.find_inode(path).err(), Some(Ext4Error::NotADirectory), "{}", path);
+    }
+    assert_eq!(fs.find_inode("/missing/..").err(), Some(Ext4Error::InodeNotFound));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_resolve_at() {
+    let mut image = EXT2_REV0.to_vec();
+    add_symlink(&mut image, 28, "dirlink", "lost+found");
+    add_symlink(&mut image, 29, "abs", "/lost+found/");
+    add_symlink(&mut image, 30, "loop", "loop/x");
+    add_symlink(&mut image, 31, "dangling", "missing");
+    add_symlink(&mut image, 32, "up", "lost+found/..");
+    let mut fs = mount(&image);
+    let lost_found = 11;
+    let file = fs
+        .create_file(lost_found, "file", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let none = ResolveFlags::empty();
+    let resolve =
+        |dir, path: &str, flags| fs.resolve_at(dir, path.as_bytes(), flags).map(|i| i.ino);
+
+    // Relative to the directory, or to the root for absolute paths
+    assert_eq!(resolve(lost_found, "file", none), Ok(file));
+    assert_eq!(resolve(lost_found, "../lost+found/./file", none), Ok(file));
+    assert_eq!(resolve(lost_found, "/dirlink/file", none), Ok(file));
+    assert_eq!(resolve(file, "x", none), Err(Ext4Error::NotADirectory));
+    assert_eq!(resolve(lost_found, "", none), Err(Ext4Error::InodeNotFound));
+    assert_eq!(resolve(file, "", ResolveFlags::EMPTY_PATH), Ok(file));
+
+    // Symbolic links are followed, except a last one with NOFOLLOW
+    assert_eq!(resolve(2, "dirlink", none), Ok(lost_found));
+    assert_eq!(resolve(2, "abs/file", none), Ok(file));
+    assert_eq!(resolve(2, "up", none), Ok(2));
+    assert_eq!(resolve(2, "dirlink", ResolveFlags::NOFOLLOW), Ok(28));
+    assert_eq!(resolve(2, "dirlink/", ResolveFlags::NOFOLLOW), Ok(lost_found));
+    assert_eq!(resolve(2, "dirlink/..", none), Ok(2));
+    assert_eq!(resolve(2, "dangling", none), Err(Ext4Error::InodeNotFound));
+    assert_eq!(resolve(2, "dangling", ResolveFlags::NOFOLLOW), Ok(31));
+    assert_eq!(resolve(2, "loop", none), Err(Ext4Error::Loop));
+    assert_eq!(resolve(2, "dirlink", ResolveFlags::NO_SYMLINKS), Err(Ext4Error::Loop));
+    let flags = ResolveFlags::NO_SYMLINKS | ResolveFlags::NOFOLLOW;
+    assert_eq!(resolve(2, "dirlink", flags), Ok(28));
+
+    // BENEATH keeps the lookup inside the starting directory
+    let beneath = ResolveFlags::BENEATH;
+    assert_eq!(resolve(2, "lost+found/../dirlink/file", beneath), Ok(file));
+    assert_eq!(resolve(lost_found, "..", beneath), Err(Ext4Error::CrossDevice));
+    assert_eq!(resolve(lost_found, "/lost+found", beneath), Err(Ext4Error::CrossDevice));
+    assert_eq!(resolve(2, "abs", beneath), Err(Ext4Error::CrossDevice));
+
+    // find_inode never follows links
+    assert_eq!(fs.find_inode("/dirlink").unwrap().ino, 28);
+    assert_eq!(fs.find_inode("/dirlink/file").err(), Some(Ext4Error::NotADirectory));
+}
+
+#[test]
+fn test_read_link() {
+    let mut image = EXT2_REV0.to_vec();
+    add_symlink(&mut image, 31, "dirlink", "lost+found");
+    add_symlink(&mut image, 32, "up", "lost+found/..");
+    let fs = mount(&image);
+
+    assert_eq!(fs.read_link("/dirlink").as_deref(), Ok("lost+found"));
+    assert_eq!(fs.read_link("up").as_deref(), Ok("lost+found/.."));
+    // Links before the last component are followed
+    assert_eq!(fs.read_link("/up/dirlink").as_deref(), Ok("lost+found"));
+    assert_eq!(fs.read_link_bytes(b"/dirlink"), Ok(b"lost+found".to_vec()));
+
+    assert_eq!(fs.read_link("/lost+found"), Err(Ext4Error::InvalidArg));
+    assert_eq!(fs.read_link("/dirlink/"), Err(Ext4Error::InvalidArg));
+    assert_eq!(fs.read_link("/missing"), Err(Ext4Error::InodeNotFound));
+
+    // A short target may still be stored in a block, here free block 200
+    // (inode 32 is the last one of the table at block 5)
+    let mut image = EXT2_REV0.to_vec();
+    add_symlink(&mut image, 32, "slow", "target");
+    let inode_table = 5 * 1024 + 31 * 128;
+    image[inode_table + 28..inode_table + 32].copy_from_slice(&2u32.to_le_bytes());
+    image[inode_table + 40..inode_table + 100].fill(0);
+    image[inode_table + 40..inode_table + 44].copy_from_slice(&200u32.to_le_bytes());
+    image[200 * 1024..200 * 1024 + 6].copy_from_slice(b"target");
+    let fs = mount(&image);
+    assert_eq!(fs.read_link("/slow").as_deref(), Ok("target"));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_large_dir() {
+    let mut fs = mount(&EXT4_LARGE_DIR);
+    assert!(fs.superblock().has_large_dir());
+    assert_eq!(
+        fs.superblock().write_blockers(),
+        (FeatureIncompat::empty(), FeatureRoCompat::empty())
+    );
+
+    // Lookups go down both levels of interior nodes
+    let stats = fs.dir_stats(12).expect("Failed to get directory stats");
+    assert!(stats.indexed);
+    assert_eq!(stats.index_leaves, Some(2));
+    for i in 0..24 {
+        let name = format!("file_{:02}", i);
+        assert_eq!(fs.lookup(12, name.as_bytes()), Ok(13 + i));
+    }
+
+    // Inserting goes through the index, splitting leaves that fill up
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(12, "new", mode).expect("Failed to create file");
+    assert_eq!(fs.lookup(12, b"new"), Ok(ino));
+    assert_eq!(fs.lookup(12, b"file_07"), Ok(20));
+    assert_eq!(fs.read_dir(12).unwrap().len(), 27);
+    let long_name = |i: usize| format!("{:a>200}", i);
+    let inodes: Vec<u32> = (0..20)
+        .map(|i| fs.create_file(12, &long_name(i), mode).expect("Failed to create file"))
+        .collect();
+    let stats = fs.dir_stats(12).expect("Failed to get directory stats");
+    assert!(fs.get_inode(12).unwrap().inode_flags().contains(InodeFlags::INDEX));
+    assert!(stats.index_leaves.unwrap() >= 5);
+    for (i, &ino) in inodes.iter().enumerate() {
+        assert_eq!(fs.lookup(12, long_name(i).as_bytes()), Ok(ino));
+    }
+    for i in 0..24 {
+        let name = format!("file_{:02}", i);
+        assert_eq!(fs.lookup(12, name.as_bytes()), Ok(13 + i));
+    }
+    assert_eq!(fs.read_dir(12).unwrap().len(), 47);
+    assert_eq!(fs.create_file(12, &long_name(3), mode), Err(Ext4Error::FileExists));
+
+    // Without large_dir the index is too deep to use
+    let mut image = EXT4_LARGE_DIR.to_vec();
+    image[1024 + 97] &= !(FeatureIncompat::LARGEDIR.bits() >> 8) as u8;
+    let fs = mount(&image);
+    assert_eq!(fs.dir_stats(12).unwrap().index_leaves, None);
+    assert_eq!(fs.lookup(12, b"file_07"), Ok(20));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_inline_dir() {
+    let mut fs = mount(&EXT4_INLINE_DIR);
+    assert_eq!(
+        fs.superblock().write_blockers(),
+        (FeatureIncompat::empty(), FeatureRoCompat::empty())
+    );
+
+    let names = |fs: &Ext4FileSystem<VecBlockDevice>, dir: u32| {
+        let mut names: Vec<String> = fs
+            .read_dir(dir)
+            .expect("Failed to read directory")
+            .iter()
+            .map(|e| e.name.to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&fs, 12), [".", "..", "a", "bb"]);
+    assert_eq!(names(&fs, 22), [".", "..", "a1", "in_xattr"]);
+    assert_eq!(fs.lookup(22, b"in_xattr"), Ok(24));
+    assert_eq!(fs.lookup(22, b".."), Ok(2));
+    assert_eq!(fs.find_inode("/mid/a1").unwrap().ino, 23);
+    let stats = fs.dir_stats(22).expect("Failed to get directory stats");
+    assert_eq!(stats.entries, 4);
+    assert_eq!(stats.size, 92);
+
+    // Entries that fit stay in the inode, in i_block or in system.data
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(22, "new", mode).expect("Failed to create file");
+    assert_eq!(fs.lookup(22, b"new"), Ok(ino));
+    assert!(fs.get_inode(22).unwrap().has_inline_data());
+    fs.rename(22, b"a1", 2, b"a1", RenameFlags::empty())
+        .expect("Failed to rename");
+    assert_eq!(names(&fs, 22), [".", "..", "in_xattr", "new"]);
+    assert_eq!(fs.lookup(2, b"a1"), Ok(23));
+
+    // Once they don't, the directory moves to a block
+    for i in 0..4 {
+        let name = format!("file_with_a_long_name_{}", i);
+        fs.create_file(12, &name, mode).expect("Failed to create file");
+    }
+    let dir = fs.get_inode(12).unwrap();
+    assert!(!dir.has_inline_data());
+    assert_eq!(dir.size, 1024);
+    assert_eq!(names(&fs, 12).len(), 8);
+    assert_eq!(fs.lookup(12, b"bb"), Ok(14));
+    assert!(fs.lookup(12, b"file_with_a_long_name_3").is_ok());
+
+    // Inline regular files read from i_block, then system.data, but can't
+    // be written
+    let expected: Vec<u8> = (0..100).map(|i| ((i * 7 + 3) % 251) as u8).collect();
+    let mut file = fs.open("/text", OpenFlags::empty()).expect("Failed to open file");
+    assert!(file.inode().has_inline_data());
+    let mut buf = vec![0u8; 50];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(50));
+    assert_eq!(buf, expected[..50]);
+    let mut rest = Vec::new();
+    assert_eq!(file.read_to_end(&mut rest, &mut fs), Ok(50));
+    assert_eq!(rest, expected[50..]);
+    assert_eq!(fs.open("/text", OpenFlags::WRITE).err(), Some(Ext4Error::NotSupported));
+}
+
+#[test]
+fn test_dir_stats() {
+    let fs = mount(&EXT4_HTREE);
+    let stats = fs.dir_stats(12).expect("Failed to get directory stats");
+    assert_eq!(stats.entries, fs.read_dir(12).unwrap().len() as u64);
+    assert_eq!(stats.size, 7168);
+    assert!(stats.indexed);
+    assert_eq!(stats.index_leaves, Some(6));
+
+    let stats = fs.dir_stats(13).expect("Failed to get directory stats");
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.size, 1024);
+    assert!(!stats.indexed);
+    assert_eq!(stats.index_leaves, None);
+
+    let file = fs.find_inode("/d/file_with_a_longish_name_77").unwrap().ino;
+    assert_eq!(fs.dir_stats(file), Err(Ext4Error::NotADirectory));
+}
+
+#[test]
+fn test_find_matching() {
+    let fs = mount(&EXT4_HTREE);
+    let names = |pattern: &str| {
+        let mut names: Vec<String> = fs
+            .find_matching(12, pattern.as_bytes())
+            .expect("Failed to match")
+            .iter()
+            .map(|e| e.name.to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names("*").len(), 120);
+    let teens: Vec<String> = (10..20)
+        .map(|n| format!("file_with_a_longish_name_{}", n))
+        .collect();
+    assert_eq!(names("*_1?"), teens);
+    assert_eq!(names("*_[2-3]").len(), 2);
+    assert_eq!(names("*_[!0-9]"), Vec::<String>::new());
+    assert_eq!(names(".*"), vec![".", ".."]);
+    assert_eq!(names("file_with_a_longish_name_77"), vec!["file_with_a_longish_name_77"]);
+
+    let file = fs.find_inode("/d/file_with_a_longish_name_77").unwrap().ino;
+    assert_eq!(fs.find_matching(file, b"*").err(), Some(Ext4Error::NotADirectory));
+}
+
+#[test]
+fn test_walk() {
+    let fs = mount(&EXT2_HARD_LINKS);
+    let walk = |max_depth, symlinks| {
+        let options = WalkOptions { max_depth, symlinks };
+        let walk = fs.walk_with("/a", options).expect("Failed to walk");
+        walk.map(|r| r.map(|(path, entry, inode)| (path, entry.ino, inode.ino)).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let entry = |path: &str, ino, target| (String::from(path), ino, target);
+
+    // Depth first, with the link itself yielded and not descended into
+    let tree = [
+        entry("/a/b", 13, 13),
+        entry("/a/b/big", 15, 15),
+        entry("/a/b/h", 14, 14),
+        entry("/a/f", 14, 14),
+        entry("/a/g", 14, 14),
+        entry("/a/s", 16, 16),
+    ];
+    assert_eq!(walk(None, SymlinkPolicy::NoFollow), tree);
+    let paths: Vec<String> = fs.walk("/a").unwrap().map(|r| r.unwrap().0).collect();
+    assert!(paths.iter().eq(tree.iter().map(|(path, _, _)| path)));
+
+    // Followed links yield their target, a directory already visited
+    // isn't entered again
+    let mut followed = tree.clone();
+    followed[5].2 = 13;
+    assert_eq!(walk(None, SymlinkPolicy::Follow), followed);
+    assert_eq!(walk(None, SymlinkPolicy::Skip), tree[..5]);
+    assert_eq!(
+        walk(Some(1), SymlinkPolicy::Skip),
+        [entry("/a/b", 13, 13), entry("/a/f", 14, 14), entry("/a/g", 14, 14)]
+    );
+
+    assert_eq!(fs.walk("/a/f").err(), Some(Ext4Error::NotADirectory));
+    assert_eq!(fs.walk("/missing").err(), Some(Ext4Error::InodeNotFound));
+}
@@ -0,0 +1,148 @@
+//! Tests of extent trees
+
+#![cfg(not(feature = "read-only"))]
+
+mod images;
+
+use ext4rs::{
+    Ext4Error, Ext4FileSystem, File, Inode, InodeMode, MountOptions, VecBlockDevice,
+    EXT4_MAX_EXTENT_DEPTH,
+};
+use images::{image, Image};
+
+static EXT4_EXTENTS: Image = image!("images/ext4_extents.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+/// Extent tree node of a 1 KiB block with a single index to `child`
+fn extent_index_node(depth: u16, child: u64) -> Vec<u8> {
+    let mut node = vec![0u8; 1024];
+    node[0..2].copy_from_slice(&0xF30Au16.to_le_bytes());
+    node[2..4].copy_from_slice(&1u16.to_le_bytes());
+    node[4..6].copy_from_slice(&84u16.to_le_bytes());
+    node[6..8].copy_from_slice(&depth.to_le_bytes());
+    node[16..20].copy_from_slice(&(child as u32).to_le_bytes());
+    node[20..22].copy_from_slice(&((child >> 32) as u16).to_le_bytes());
+    node
+}
+
+/// Make the root of the extent tree in `inode` an index of depth `depth`
+/// with a single index to `child`
+fn set_extent_index_root(inode: &mut Inode, depth: u16, child: u64) {
+    let node = extent_index_node(depth, child);
+    for (word, chunk) in inode.block.iter_mut().zip(node.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    inode.block[1] = 4 | (depth as u32) << 16;
+}
+
+#[test]
+fn test_extent_tree_corruption() {
+    let mut fs = mount(&EXT4_EXTENTS);
+    let ino = fs
+        .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+        .unwrap();
+    let mut inode = fs.get_inode(ino).unwrap();
+    let node = fs.alloc_block().unwrap();
+    let leaf = fs.alloc_block().unwrap();
+
+    // A well-formed tree: the root in the inode, one index node and a leaf
+    // mapping block 0
+    let mut leaf_data = vec![0u8; 1024];
+    leaf_data[0..2].copy_from_slice(&0xF30Au16.to_le_bytes());
+    leaf_data[2..4].copy_from_slice(&1u16.to_le_bytes());
+    leaf_data[4..6].copy_from_slice(&84u16.to_le_bytes());
+    leaf_data[16..18].copy_from_slice(&1u16.to_le_bytes());
+    leaf_data[20..24].copy_from_slice(&200u32.to_le_bytes());
+    fs.write_block(leaf, &leaf_data).unwrap();
+    fs.write_block(node, &extent_index_node(1, leaf)).unwrap();
+    set_extent_index_root(&mut inode, 2, node);
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Ok(200));
+    assert_eq!(inode.get_block_number(1024, 1024, &fs), Ok(0));
+
+    // An index pointing at its own node
+    fs.write_block(node, &extent_index_node(1, node)).unwrap();
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Err(Ext4Error::InvalidState));
+    // A child that isn't one level down
+    fs.write_block(node, &extent_index_node(2, leaf)).unwrap();
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Err(Ext4Error::InvalidState));
+    // A child outside the filesystem
+    fs.write_block(node, &extent_index_node(1, 1 << 20)).unwrap();
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Err(Ext4Error::InvalidState));
+    assert_eq!(fs.error_log().last.unwrap().func, "find_block_in_extent_node");
+    // Deeper than any tree Linux builds
+    set_extent_index_root(&mut inode, EXT4_MAX_EXTENT_DEPTH + 1, node);
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Err(Ext4Error::InvalidState));
+
+    // Copying into the file fails the same way, rather than mapping a new
+    // block over the corrupt tree
+    let src_ino = fs.create_file(2, "src", InodeMode::from_bits_truncate(0o644)).unwrap();
+    let mut src = File::new(fs.get_inode(src_ino).unwrap());
+    src.write(&[1; 1024], &mut fs).unwrap();
+    let free = fs.group_stats(0).unwrap().free_blocks;
+    let mut dst = File::new(inode);
+    assert_eq!(fs.copy_file_range(&src, 0, &mut dst, 0, 1024), Err(Ext4Error::InvalidState));
+    assert_eq!(fs.group_stats(0).unwrap().free_blocks, free);
+}
+
+#[test]
+fn test_extent_start_hi() {
+    let mut fs = mount(&EXT4_EXTENTS);
+    let ino = fs
+        .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+        .unwrap();
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&[7u8; 100], &mut fs).unwrap();
+    let mut inode = fs.get_inode(ino).unwrap();
+    let start = inode.block[5] as u64;
+
+    // ee_start_hi is the upper 16 bits of a 48-bit block, not part of the
+    // low 32
+    inode.block[4] |= 1 << 16;
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Ok((1 << 32) + start));
+    inode.block[4] &= 0xFFFF;
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Ok(start));
+
+    // So is ei_leaf_hi of an index, taking the child past the filesystem
+    set_extent_index_root(&mut inode, 1, start);
+    inode.block[5] = 1;
+    assert_eq!(inode.get_block_number(0, 1024, &fs), Err(Ext4Error::InvalidState));
+}
+
+#[test]
+fn test_unwritten_extent() {
+    let mut fs = mount(&EXT4_EXTENTS);
+    let ino = fs
+        .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+        .unwrap();
+    File::new(fs.get_inode(ino).unwrap()).write(&[7u8; 3 * 1024], &mut fs).unwrap();
+    let mut inode = fs.get_inode(ino).unwrap();
+    let start = inode.block[5] as u64;
+
+    // Lengths past 32768 mark the extent unwritten: still mapped, but read
+    // as zeros rather than as the old contents of its blocks
+    inode.block[4] |= 0x8000;
+    assert_eq!(inode.get_block_number(2 * 1024, 1024, &fs), Ok(start + 2));
+    assert_eq!(inode.get_block_number(3 * 1024, 1024, &fs), Ok(0));
+    let mut file = File::new(inode);
+    let mut buf = vec![1u8; 3 * 1024];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(buf.len()));
+    assert!(buf.iter().all(|&b| b == 0));
+
+    // Writing into it uses its block, and the rest of it stays unwritten
+    let free = fs.group_stats(0).unwrap().free_blocks;
+    file.seek(1024 + 10).unwrap();
+    file.write(b"data", &mut fs).unwrap();
+    file.sync(&mut fs).unwrap();
+    assert_eq!(fs.group_stats(0).unwrap().free_blocks, free);
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.get_block_number(1024, 1024, &fs), Ok(start + 1));
+    let mut file = File::new(inode);
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(buf.len()));
+    let mut expected = vec![0u8; 3 * 1024];
+    expected[1024 + 10..1024 + 14].copy_from_slice(b"data");
+    assert_eq!(buf, expected);
+}
@@ -0,0 +1,664 @@
+//! Tests of file operations on images: opening, copying, sparse and direct
+//! I/O, locks and files unlinked while open
+
+mod images;
+
+#[cfg(not(feature = "read-only"))]
+use ext4rs::{
+    crc32c, BlockRun, FeatureRoCompat, File, FileLock, Inode, InodeBuilder, InodeFlags, InodeMode,
+    LockKind, RenameFlags, SparseSegment, EXT4_RESIZE_INO,
+};
+use ext4rs::{Ext4Error, Ext4FileSystem, MountOptions, OpenFlags, VecBlockDevice};
+use images::{image, Image};
+
+#[cfg(not(feature = "read-only"))]
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT3: Image = image!("images/ext3.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_ORPHAN_FILE: Image = image!("images/ext4_orphan_file.img.packed");
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_EXTENTS: Image = image!("images/ext4_extents.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_INLINE_DIR: Image = image!("images/ext4_inline_dir.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_XATTR_BLOCK: Image = image!("images/ext4_xattr_block.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_verity_file() {
+    let mut fs = mount(&EXT3);
+    assert!(!fs.superblock().has_verity());
+    let builder = InodeBuilder::file().mode(0o644).flags(InodeFlags::VERITY);
+    let ino = fs.mknod(2, b"v", &builder).expect("Failed to create file");
+    assert!(fs.get_inode(ino).unwrap().is_verity());
+
+    // The data is fixed, the metadata and the name are not
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    assert_eq!(file.write(b"data", &mut fs).err(), Some(Ext4Error::PermissionDenied));
+    assert_eq!(file.truncate(1024, &mut fs).err(), Some(Ext4Error::PermissionDenied));
+    fs.set_flags(ino, InodeFlags::NOATIME).expect("Failed to set flags");
+    fs.rename(2, b"v", 2, b"w", RenameFlags::empty()).expect("Failed to rename");
+    assert!(fs.get_inode(ino).unwrap().is_verity());
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_copy_file_range() {
+    let mut fs = mount(&EXT3);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let data: Vec<u8> = (0..3 * 1024).map(|i| (i % 251) as u8).collect();
+    let src_ino = fs.create_file(2, "src", mode).expect("Failed to create file");
+    let mut src = File::new(fs.get_inode(src_ino).unwrap());
+    src.write(&data, &mut fs).expect("Failed to write");
+
+    // Block-aligned copy past the end of the destination leaves a hole
+    let dst_ino = fs.create_file(2, "dst", mode).expect("Failed to create file");
+    let mut dst = File::new(fs.get_inode(dst_ino).unwrap());
+    let copied = fs
+        .copy_file_range(&src, 0, &mut dst, 2048, 10_000)
+        .expect("Failed to copy");
+    assert_eq!(copied, data.len() as u64);
+    assert_eq!(dst.size(), 2048 + data.len() as u64);
+    let runs: Vec<_> = dst.blocks(&fs).map(|r| r.unwrap()).collect();
+    assert_eq!(runs.iter().map(|r| r.logical).min(), Some(2));
+    assert_eq!(runs.iter().map(|r| r.len).sum::<u32>(), 3);
+
+    let mut buf = vec![0xff; dst.size() as usize];
+    dst.read(&mut buf, &mut fs).expect("Failed to read");
+    assert!(buf[..2048].iter().all(|&b| b == 0));
+    assert_eq!(&buf[2048..], &data[..]);
+
+    // Unaligned copy into existing data
+    let copied = fs
+        .copy_file_range(&src, 100, &mut dst, 2055, 2500)
+        .expect("Failed to copy");
+    assert_eq!(copied, 2500);
+    let mut buf = vec![0; dst.size() as usize];
+    let mut dst = File::new(fs.get_inode(dst_ino).unwrap());
+    dst.read(&mut buf, &mut fs).expect("Failed to read");
+    assert_eq!(&buf[2055..2055 + 2500], &data[100..2600]);
+    assert_eq!(&buf[2048..2055], &data[..7]);
+
+    // Overlapping ranges of the same file are rejected
+    let mut same = File::new(fs.get_inode(src_ino).unwrap());
+    assert_eq!(
+        fs.copy_file_range(&src, 0, &mut same, 512, 1024).err(),
+        Some(Ext4Error::InvalidArg)
+    );
+
+    // So is a destination with inline data, which has no blocks to copy to
+    let mut inline = fs.get_inode(dst_ino).unwrap();
+    inline.flags |= InodeFlags::INLINE_DATA.bits();
+    assert_eq!(
+        fs.copy_file_range(&src, 0, &mut File::new(inline), 0, 10).err(),
+        Some(Ext4Error::NotSupported)
+    );
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_copy_file() {
+    let mut fs = mount(&EXT3);
+    let data: Vec<u8> = (0..5000).map(|i| (i % 253) as u8).collect();
+    let ino = fs
+        .create_file(2, "orig", InodeMode::from_bits_truncate(0o600))
+        .expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&data, &mut fs).expect("Failed to write");
+    fs.create_dir(2, "dir", InodeMode::from_bits_truncate(0o755))
+        .expect("Failed to create dir");
+
+    let copy = fs.copy("/orig", "/dir/copy").expect("Failed to copy");
+    let (orig, copied) = (fs.stat(ino).unwrap(), fs.stat(copy).unwrap());
+    assert_eq!(fs.find_inode("/dir/copy").unwrap().ino, copy);
+    assert_eq!(copied.mode, orig.mode);
+    assert_eq!((copied.uid, copied.gid), (orig.uid, orig.gid));
+    assert_eq!(copied.times.mtime, orig.times.mtime);
+    assert_eq!(copied.size, orig.size);
+
+    let mut buf = vec![0; data.len()];
+    File::new(fs.get_inode(copy).unwrap())
+        .read(&mut buf, &mut fs)
+        .expect("Failed to read");
+    assert_eq!(buf, data);
+
+    assert_eq!(fs.copy("/orig", "/dir/copy").err(), Some(Ext4Error::FileExists));
+    assert_eq!(fs.copy("/dir", "/dir2").err(), Some(Ext4Error::IsADirectory));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_copy_xattr_block_csum() {
+    let mut fs = mount(&EXT4_XATTR_BLOCK);
+    let copy = fs.copy("/f", "/g").expect("Failed to copy");
+    let (orig, copied) = (fs.get_inode(12).unwrap(), fs.get_inode(copy).unwrap());
+    assert_ne!(copied.xattr_block(), orig.xattr_block());
+    assert_eq!(fs.list_xattrs(copy).unwrap(), fs.list_xattrs(12).unwrap());
+
+    // The checksum covers the new block number, with h_checksum zeroed
+    let mut block = vec![0u8; 1024];
+    fs.read_block(copied.xattr_block(), &mut block).unwrap();
+    let stored = u32::from_le_bytes(block[0x10..0x14].try_into().unwrap());
+    block[0x10..0x14].fill(0);
+    let seed = fs.superblock().csum_seed();
+    let csum = crc32c(crc32c(seed, &copied.xattr_block().to_le_bytes()), &block);
+    assert_eq!(stored, csum);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_sparse_export_import() {
+    let mut fs = mount(&EXT2_REV0);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let segments = vec![
+        SparseSegment::Data(vec![0x11; 1500]),
+        SparseSegment::Hole(5000),
+        SparseSegment::Data(vec![0x22; 100]),
+        SparseSegment::Hole(3000),
+    ];
+    let ino = fs.create_file(2, "sparse", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    let size = file
+        .import_sparse(segments.into_iter().map(Ok), &mut fs)
+        .expect("Failed to import");
+    assert_eq!(size, 9600);
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.size, 9600);
+    assert_eq!(inode.blocks, 3 * 2);
+
+    // Holes come back block-aligned, data padded to whole blocks
+    let exported: Vec<SparseSegment> = file
+        .export_sparse(&fs)
+        .collect::<Result<_, _>>()
+        .expect("Failed to export");
+    let mut first = vec![0x11; 1500];
+    first.resize(2048, 0);
+    let mut second = vec![0; 1024];
+    second[356..456].fill(0x22);
+    assert_eq!(
+        exported,
+        vec![
+            SparseSegment::Data(first),
+            SparseSegment::Hole(4096),
+            SparseSegment::Data(second),
+            SparseSegment::Hole(2432),
+        ]
+    );
+
+    let copy = fs.create_file(2, "copy", mode).expect("Failed to create file");
+    let mut copy_file = File::new(fs.get_inode(copy).unwrap());
+    copy_file
+        .import_sparse(exported.clone().into_iter().map(Ok), &mut fs)
+        .expect("Failed to import");
+    assert_eq!(fs.get_inode(copy).unwrap().blocks, inode.blocks);
+    let mut original = vec![0u8; 9600];
+    let mut copied = vec![0xffu8; 9600];
+    File::new(inode).read(&mut original, &mut fs).unwrap();
+    File::new(fs.get_inode(copy).unwrap()).read(&mut copied, &mut fs).unwrap();
+    assert_eq!(original, copied);
+    let again: Vec<SparseSegment> = copy_file.export_sparse(&fs).map(Result::unwrap).collect();
+    assert_eq!(again, exported);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_sparse_export_unwritten_and_inline() {
+    let mut fs = mount(&EXT4_EXTENTS);
+    let ino = fs
+        .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+        .unwrap();
+    File::new(fs.get_inode(ino).unwrap()).write(&[7u8; 3 * 1024], &mut fs).unwrap();
+    let mut inode = fs.get_inode(ino).unwrap();
+    let start = inode.block[5] as u64;
+
+    // Mark the extent unwritten, then write its middle block only
+    inode.block[4] |= 0x8000;
+    let mut file = File::new(inode);
+    file.seek(1024).unwrap();
+    file.write(&[9u8; 1024], &mut fs).unwrap();
+    file.sync(&mut fs).unwrap();
+    let file = File::new(fs.get_inode(ino).unwrap());
+    let runs: Vec<BlockRun> = file.blocks(&fs).map(Result::unwrap).collect();
+    assert_eq!(
+        runs,
+        vec![BlockRun {
+            logical: 1,
+            physical: start + 1,
+            len: 1
+        }]
+    );
+    let exported: Vec<SparseSegment> = file.export_sparse(&fs).map(Result::unwrap).collect();
+    assert_eq!(
+        exported,
+        vec![
+            SparseSegment::Hole(1024),
+            SparseSegment::Data(vec![9u8; 1024]),
+            SparseSegment::Hole(1024),
+        ]
+    );
+
+    // Inline data has no blocks, and comes out whole
+    let fs = mount(&EXT4_INLINE_DIR);
+    let file = File::new(fs.find_inode("/text").unwrap());
+    assert_eq!(file.blocks(&fs).count(), 0);
+    let text: Vec<u8> = (0..100).map(|i| ((i * 7 + 3) % 251) as u8).collect();
+    let exported: Vec<SparseSegment> = file.export_sparse(&fs).map(Result::unwrap).collect();
+    assert_eq!(exported, vec![SparseSegment::Data(text)]);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_read_contiguous_hint() {
+    let mut fs = mount(&EXT2_REV0);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let segments = vec![
+        SparseSegment::Data(vec![0x11; 2048]),
+        SparseSegment::Hole(4096),
+        SparseSegment::Data(vec![0x22; 1500]),
+    ];
+    let ino = fs.create_file(2, "sparse", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.import_sparse(segments.into_iter().map(Ok), &mut fs)
+        .expect("Failed to import");
+    let runs: Vec<_> = file.blocks(&fs).map(|r| r.unwrap()).collect();
+    assert_eq!(runs.len(), 2, "{:?}", runs);
+
+    assert_eq!(file.read_contiguous_hint(0, &fs), Ok(2048));
+    assert_eq!(file.read_contiguous_hint(100, &fs), Ok(1948));
+    assert_eq!(file.read_contiguous_hint(3000, &fs), Ok(0));
+    // The last run stops at the end of the file, not of its last block
+    assert_eq!(file.read_contiguous_hint(6144, &fs), Ok(1500));
+    assert_eq!(file.read_contiguous_hint(7000, &fs), Ok(644));
+    assert_eq!(file.read_contiguous_hint(7644, &fs), Ok(0));
+
+    // Unwritten blocks read as zeros, so runs stop where they start even if
+    // the blocks are physically contiguous
+    let mut fs = mount(&EXT4_EXTENTS);
+    let ino = fs.create_file(2, "f", mode).unwrap();
+    File::new(fs.get_inode(ino).unwrap()).write(&[7u8; 3 * 1024], &mut fs).unwrap();
+    let mut inode = fs.get_inode(ino).unwrap();
+    inode.block[4] |= 0x8000;
+    let mut file = File::new(inode);
+    file.write(&[9u8; 1024], &mut fs).unwrap();
+    file.sync(&mut fs).unwrap();
+    let file = File::new(fs.get_inode(ino).unwrap());
+    assert_eq!(file.read_contiguous_hint(0, &fs), Ok(1024));
+    assert_eq!(file.read_contiguous_hint(1024, &fs), Ok(0));
+    assert_eq!(file.read_contiguous_hint(2500, &fs), Ok(0));
+
+    // Nor is there a run for data stored in the inode
+    let fs = mount(&EXT4_INLINE_DIR);
+    let file = File::new(fs.find_inode("/text").unwrap());
+    assert_eq!(file.read_contiguous_hint(0, &fs), Ok(0));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_direct_io() {
+    let options = MountOptions {
+        cache_budget: 1 << 20,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT3.to_vec(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "stream", mode).expect("Failed to create file");
+    let data: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+
+    // Buffered data is cached, direct data isn't
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&vec![0xAA; 40000], &mut fs).expect("Failed to write");
+    let cached = fs.cache_usage().blocks;
+    let mut direct = File::direct(fs.get_inode(ino).unwrap());
+    direct.seek(100).unwrap();
+    direct.write(&data[100..39000], &mut fs).expect("Failed to write");
+    assert!(fs.cache_usage().blocks < cached - 30 * 1024);
+
+    // Buffered reads see the direct writes, and direct reads match them
+    let mut expected = vec![0xAA; 40000];
+    expected[100..39000].copy_from_slice(&data[100..39000]);
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    let mut buf = vec![0u8; 40000];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(40000));
+    assert_eq!(buf, expected);
+
+    let mut direct = File::direct(fs.get_inode(ino).unwrap());
+    direct.seek(50).unwrap();
+    let mut buf = vec![0u8; 50000];
+    assert_eq!(direct.read(&mut buf, &mut fs), Ok(39950));
+    assert_eq!(buf[..39950], expected[50..]);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_large_file_size() {
+    // Start without the large_file feature
+    let mut image = EXT3.to_vec();
+    image[1024 + 100] &= !0x02;
+    let mut fs = mount(&image);
+    assert!(!fs.superblock().feature_ro_compat().contains(FeatureRoCompat::LARGE_FILE));
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "large", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    let size = (5 << 30) + 4;
+    let segments = vec![SparseSegment::Data(b"head".to_vec()), SparseSegment::Hole(5 << 30)];
+    assert_eq!(file.import_sparse(segments.into_iter().map(Ok), &mut fs), Ok(size));
+
+    // Both halves of the size make it to disk and back
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.size, size);
+    let mut raw = [0u8; 128];
+    inode.write_to(&mut raw);
+    assert_eq!(raw[4..8], (size as u32).to_le_bytes());
+    assert_eq!(raw[108..112], 1u32.to_le_bytes());
+    assert_eq!(Inode::from_bytes(&raw, ino).unwrap().size, size);
+
+    // The file sets large_file, on disk too
+    assert!(fs.superblock().feature_ro_compat().contains(FeatureRoCompat::LARGE_FILE));
+    let mut block = vec![0u8; 1024];
+    fs.read_block(1, &mut block).unwrap();
+    assert_eq!(block[100] & 0x02, 0x02);
+
+    // Shrinking below 4 GiB clears the high half
+    file.truncate(3, &mut fs).expect("Failed to truncate");
+    assert_eq!(fs.get_inode(ino).unwrap().size, 3);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_open() {
+    let mut fs = mount(&EXT2_HARD_LINKS);
+    let ino = fs.find_inode("/a/f").unwrap().ino;
+
+    // Symbolic links are followed, and every name of the file opens it
+    let mut file = fs.open("/a/s/h", OpenFlags::empty()).expect("Failed to open file");
+    assert_eq!(file.inode().ino, ino);
+    let mut buf = vec![0u8; 3000];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(3000));
+    let other = fs.open_inode(ino, OpenFlags::empty()).expect("Failed to open inode");
+    assert!(fs.is_open(ino));
+    file.close(&mut fs).unwrap();
+    assert!(fs.is_open(ino));
+    other.close(&mut fs).unwrap();
+    assert!(!fs.is_open(ino));
+
+    // Only regular files open
+    assert_eq!(fs.open("/a/b", OpenFlags::empty()).err(), Some(Ext4Error::IsADirectory));
+    assert_eq!(fs.open("/a/missing", OpenFlags::empty()).err(), Some(Ext4Error::InodeNotFound));
+    let fifo = fs.mknod(2, b"pipe", &InodeBuilder::fifo()).unwrap();
+    assert_eq!(fs.open_inode(fifo, OpenFlags::empty()).err(), Some(Ext4Error::InvalidArg));
+    assert_eq!(
+        fs.open_inode(EXT4_RESIZE_INO, OpenFlags::empty()).err(),
+        Some(Ext4Error::InodeNotFound)
+    );
+    assert_eq!(fs.open_inode(0, OpenFlags::empty()).err(), Some(Ext4Error::InodeNotFound));
+    let free = fs.alloc_inode().unwrap();
+    assert_eq!(fs.open_inode(free, OpenFlags::empty()).err(), Some(Ext4Error::InodeNotFound));
+    assert!(!fs.is_open(fifo));
+
+    // Immutable files only open for reading, append-only ones for appending
+    fs.set_flags(ino, InodeFlags::IMMUTABLE).unwrap();
+    assert_eq!(fs.open_inode(ino, OpenFlags::WRITE).err(), Some(Ext4Error::PermissionDenied));
+    fs.open_inode(ino, OpenFlags::empty()).unwrap().close(&mut fs).unwrap();
+    fs.set_flags(ino, InodeFlags::APPEND).unwrap();
+    assert_eq!(fs.open_inode(ino, OpenFlags::WRITE).err(), Some(Ext4Error::PermissionDenied));
+    let file = fs.open_inode(ino, OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+
+    // Dropping a file closes it too
+    assert!(fs.is_open(ino));
+    drop(file);
+    assert!(!fs.is_open(ino));
+}
+
+#[test]
+fn test_read_to_end() {
+    let mut fs = mount(&EXT2_HARD_LINKS);
+    let mut file = fs.open("/a/b/big", OpenFlags::empty()).unwrap();
+    let mut expected = vec![0u8; 20000];
+    assert_eq!(file.read(&mut expected, &mut fs), Ok(20000));
+
+    // Appends after what the vector holds, from the current position
+    file.seek(5000).unwrap();
+    let mut buf = b"head".to_vec();
+    assert_eq!(file.read_to_end(&mut buf, &mut fs), Ok(15000));
+    assert_eq!(&buf[..4], b"head");
+    assert_eq!(&buf[4..], &expected[5000..]);
+    assert_eq!(file.read_to_end(&mut buf, &mut fs), Ok(0));
+
+    // Too much to read leaves the vector and position alone
+    file.seek(0).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(file.read_to_end_limit(&mut buf, 19999, &mut fs), Err(Ext4Error::FileTooLarge));
+    assert!(buf.is_empty());
+    assert_eq!(file.position(), 0);
+    assert_eq!(file.read_to_end_limit(&mut buf, 20000, &mut fs), Ok(20000));
+    assert_eq!(buf, expected);
+    file.close(&mut fs).unwrap();
+
+    let options = MountOptions {
+        max_read_to_end: 10000,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT2_HARD_LINKS.to_vec(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options).unwrap();
+    let mut file = fs.open("/a/b/big", OpenFlags::empty()).unwrap();
+    assert_eq!(file.read_to_end(&mut buf, &mut fs), Err(Ext4Error::FileTooLarge));
+    file.seek(10000).unwrap();
+    assert_eq!(file.read_to_end(&mut Vec::new(), &mut fs), Ok(10000));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_unlink_open_file() {
+    let device = VecBlockDevice::new(EXT4_ORPHAN_FILE.to_vec(), 1024).unwrap();
+    let mut fs =
+        Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image");
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "old", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&[7; 3000], &mut fs).expect("Failed to write");
+    file.close(&mut fs).unwrap();
+    let before = fs.group_stats(0).unwrap();
+
+    // Replacing the last name of an open file keeps its blocks
+    let mut reader = fs.open("/old", OpenFlags::WRITE).expect("Failed to open file");
+    let other = fs.open_inode(ino, OpenFlags::empty()).expect("Failed to open inode");
+    fs.create_file(2, "new", mode).expect("Failed to create file");
+    fs.rename(2, b"new", 2, b"old", RenameFlags::empty()).expect("Failed to rename");
+    assert_eq!(fs.get_inode(ino).unwrap().links_count, 0);
+    assert_eq!(fs.orphans(), Ok(vec![ino]));
+    assert_eq!(fs.open_inode(ino, OpenFlags::empty()).err(), Some(Ext4Error::InodeNotFound));
+    let mut buf = vec![0u8; 3000];
+    assert_eq!(reader.read(&mut buf, &mut fs), Ok(3000));
+    assert_eq!(buf, vec![7; 3000]);
+
+    // Writing through an open file doesn't bring its links back
+    reader.write(&[8; 10], &mut fs).expect("Failed to write");
+    assert_eq!(fs.get_inode(ino).unwrap().links_count, 0);
+    reader.close(&mut fs).unwrap();
+    assert_eq!(fs.group_stats(0).unwrap().free_blocks, before.free_blocks);
+
+    // The last close frees it
+    other.close(&mut fs).unwrap();
+    assert!(!fs.is_open(ino));
+    assert_eq!(fs.orphans(), Ok(vec![]));
+    let after = fs.group_stats(0).unwrap();
+    assert_eq!(after.free_blocks, before.free_blocks + 3);
+    assert_eq!(after.free_inodes, before.free_inodes);
+
+    // An inode whose last file is dropped is freed by the next unlink
+    let ino = fs.lookup(2, b"old").unwrap();
+    let file = fs.open_inode(ino, OpenFlags::empty()).unwrap();
+    fs.create_file(2, "new", mode).expect("Failed to create file");
+    fs.rename(2, b"new", 2, b"old", RenameFlags::empty()).expect("Failed to rename");
+    drop(file);
+    assert!(!fs.is_open(ino));
+    assert_eq!(fs.orphans(), Ok(vec![ino]));
+    fs.create_file(2, "new", mode).expect("Failed to create file");
+    fs.rename(2, b"new", 2, b"old", RenameFlags::empty()).expect("Failed to rename");
+    assert_eq!(fs.orphans(), Ok(vec![]));
+    assert_eq!(fs.group_stats(0).unwrap().free_inodes, before.free_inodes);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_release_frees_block_map() {
+    let mut fs = mount(&EXT3);
+    let free_blocks = |fs: &Ext4FileSystem<VecBlockDevice>| {
+        (0..fs.groups_count())
+            .map(|group| fs.group_stats(group).unwrap().free_blocks)
+            .sum::<u32>()
+    };
+    let before = free_blocks(&fs);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "big", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    // 300 blocks need the single and the double indirect block, and one
+    // indirect block below the latter
+    file.write(&[1; 300 * 1024], &mut fs).expect("Failed to write");
+    file.close(&mut fs).unwrap();
+    assert_eq!(free_blocks(&fs), before - 303);
+
+    fs.create_file(2, "new", mode).expect("Failed to create file");
+    fs.rename(2, b"new", 2, b"big", RenameFlags::empty()).expect("Failed to rename");
+    assert_eq!(free_blocks(&fs), before);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_file_locks() {
+    let mut fs = mount(&EXT2_REV0);
+    let lock = |owner, kind, start, len| FileLock { owner, kind, start, len };
+
+    // Shared locks of different owners coexist, an exclusive one doesn't
+    assert_eq!(fs.set_lock(12, lock(1, LockKind::Shared, 0, 100)), Ok(()));
+    assert_eq!(fs.set_lock(12, lock(2, LockKind::Shared, 50, 100)), Ok(()));
+    assert_eq!(fs.set_lock(12, lock(3, LockKind::Exclusive, 120, 0)), Err(Ext4Error::WouldBlock));
+    assert_eq!(
+        fs.test_lock(12, lock(3, LockKind::Exclusive, 120, 0)),
+        Ok(Some(lock(2, LockKind::Shared, 50, 100)))
+    );
+    assert_eq!(fs.test_lock(12, lock(3, LockKind::Exclusive, 150, 0)), Ok(None));
+    assert_eq!(fs.test_lock(13, lock(3, LockKind::Exclusive, 0, 0)), Ok(None));
+    assert_eq!(fs.set_lock(12, lock(1, LockKind::Shared, u64::MAX, 2)), Err(Ext4Error::InvalidArg));
+
+    // An owner's locks replace each other: upgrading the middle of its
+    // range splits it, and unlocking and relocking merges it again
+    assert_eq!(fs.set_lock(12, lock(1, LockKind::Exclusive, 10, 20)), Ok(()));
+    let mut held = fs.locks(12);
+    held.retain(|held| held.owner == 1);
+    held.sort_by_key(|held| held.start);
+    assert_eq!(
+        held,
+        [
+            lock(1, LockKind::Shared, 0, 10),
+            lock(1, LockKind::Exclusive, 10, 20),
+            lock(1, LockKind::Shared, 30, 70),
+        ]
+    );
+    fs.unlock(12, 1, 10, 20).unwrap();
+    assert_eq!(fs.set_lock(12, lock(1, LockKind::Shared, 10, 20)), Ok(()));
+    let mut held = fs.locks(12);
+    held.sort_by_key(|held| held.owner);
+    assert_eq!(held, [lock(1, LockKind::Shared, 0, 100), lock(2, LockKind::Shared, 50, 100)]);
+
+    // Waiting on an owner that waits on us is refused
+    assert_eq!(fs.wait_lock(12, lock(1, LockKind::Exclusive, 60, 10)), Ok(false));
+    assert_eq!(fs.wait_lock(12, lock(2, LockKind::Exclusive, 0, 10)), Err(Ext4Error::Deadlock));
+    fs.release_locks(12, 2);
+    assert_eq!(fs.wait_lock(12, lock(1, LockKind::Exclusive, 60, 10)), Ok(true));
+    fs.release_locks(12, 1);
+    assert_eq!(fs.locks(12), []);
+    assert_eq!(fs.set_lock(12, lock(3, LockKind::Exclusive, 0, 0)), Ok(()));
+
+    // An owner that got a lock elsewhere, or released its locks, no longer
+    // waits, so waiting on it is no deadlock
+    assert_eq!(fs.wait_lock(12, lock(4, LockKind::Shared, 0, 10)), Ok(false));
+    assert_eq!(fs.set_lock(13, lock(4, LockKind::Exclusive, 0, 10)), Ok(()));
+    assert_eq!(fs.wait_lock(13, lock(3, LockKind::Shared, 0, 10)), Ok(false));
+    fs.release_locks(13, 3);
+    assert_eq!(fs.wait_lock(12, lock(4, LockKind::Shared, 0, 10)), Ok(false));
+    fs.release_locks(12, 3);
+    fs.release_locks(13, 4);
+
+    // Locks go away with the inode even if it was never opened
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "locked", mode).expect("Failed to create file");
+    fs.create_file(2, "other", mode).unwrap();
+    fs.set_lock(ino, lock(1, LockKind::Exclusive, 0, 0)).unwrap();
+    fs.rename(2, b"other", 2, b"locked", RenameFlags::empty()).unwrap();
+    assert_eq!(fs.locks(ino), []);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_file_blocks() {
+    // The indirect block of `/a/b/big` splits its 20 blocks in two runs
+    let mut fs = mount(&EXT2_HARD_LINKS);
+    let file = fs.open("/a/b/big", OpenFlags::empty()).unwrap();
+    let runs: Vec<_> = file.blocks(&fs).map(|r| r.unwrap()).collect();
+    assert_eq!(
+        runs,
+        [
+            BlockRun { logical: 0, physical: 31, len: 12 },
+            BlockRun { logical: 12, physical: 44, len: 8 },
+        ]
+    );
+    file.close(&mut fs).unwrap();
+
+    // Holes are skipped, and runs match the blocks that were read
+    let ino = fs.create_file(2, "sparse", InodeMode::from_bits_truncate(0o644)).unwrap();
+    let mut file = fs.open_inode(ino, OpenFlags::WRITE).unwrap();
+    file.write(&[1; 2048], &mut fs).unwrap();
+    file.seek_from_end(8 * 1024).unwrap();
+    file.write(&[2; 1024], &mut fs).unwrap();
+    let runs: Vec<_> = file.blocks(&fs).map(|r| r.unwrap()).collect();
+    let extents: Vec<_> = runs.iter().map(|run| (run.logical, run.len)).collect();
+    assert_eq!(extents, [(0, 2), (10, 1)]);
+    let mut block = vec![0; 1024];
+    fs.read_block(runs[1].physical, &mut block).unwrap();
+    assert_eq!(block, [2; 1024]);
+    file.close(&mut fs).unwrap();
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_reused_buffers_are_zeroed() {
+    // Block buffers are reused from one operation to the next, and must
+    // not carry data from the last one into partial blocks or holes
+    let mut fs = mount(&EXT2_HARD_LINKS);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let full = fs.create_file(2, "full", mode).unwrap();
+    let mut file = fs.open_inode(full, OpenFlags::WRITE).unwrap();
+    file.write(&[0xff; 3072], &mut fs).unwrap();
+    file.seek(0).unwrap();
+    let mut buf = vec![0; 3072];
+    file.read(&mut buf, &mut fs).unwrap();
+    file.close(&mut fs).unwrap();
+
+    let short = fs.create_file(2, "short", mode).unwrap();
+    let mut file = fs.open_inode(short, OpenFlags::WRITE).unwrap();
+    file.write(b"short", &mut fs).unwrap();
+    file.seek_from_end(2048).unwrap();
+    file.write(b"tail", &mut fs).unwrap();
+    let runs: Vec<_> = file.blocks(&fs).map(|r| r.unwrap()).collect();
+    let mut block = vec![0xff; 1024];
+    fs.read_block(runs[0].physical, &mut block).unwrap();
+    assert_eq!(&block[..5], b"short");
+    assert!(block[5..].iter().all(|&b| b == 0));
+
+    file.seek(0).unwrap();
+    let mut buf = vec![0xff; 2057];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(2057));
+    assert_eq!(&buf[..5], b"short");
+    assert!(buf[5..2053].iter().all(|&b| b == 0));
+    assert_eq!(&buf[2053..], b"tail");
+    file.close(&mut fs).unwrap();
+}
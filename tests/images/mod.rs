@@ -0,0 +1,75 @@
+//! Test images, stored packed
+//!
+//! Images are mostly zeros, so each `*.img.packed` file keeps only their
+//! non-zero runs: the image length as a little-endian u64, then each run as
+//! its offset (u64), its length (u32) and its bytes. `pack.py` packs raw
+//! images made with mke2fs and debugfs.
+//!
+//! Besides the ext2 and ext3 images of `compat_tests.rs`:
+//!
+//! - `ext2_hard_links.img`: made by mke2fs and debugfs: `/a/f` (3000 bytes)
+//!   hard linked as `/a/g` and `/a/b/h`, `/a/b/big` (20000 bytes) and a
+//!   symbolic link `/a/s` to `/a/b`
+//! - `ext4_ea_inode.img`: made by mke2fs with ea_inode, then debugfs
+//!   `ea_set` on file `/f`: a 1000-byte `user.big` value in inode 13 and
+//!   three short values, one of them in the attribute block
+//! - `ext4_htree.img`: metadata_csum image whose directory `/d` (inode 12)
+//!   was indexed by `e2fsck -D`: 120 files in six leaves below a root at
+//!   block 38
+//! - `ext4_orphan_file.img`: made by mke2fs with orphan_file, then debugfs:
+//!   file `/victim` (inode 13, three blocks) unlinked with its link count
+//!   cleared, and inode 13 written into the first block of the orphan file,
+//!   as a crash would leave them
+//! - `ext4_lazy_itable.img`: made by mke2fs with uninit_bg and
+//!   lazy_itable_init: 16 inodes per group, 5 unused in group 0 and group 1
+//!   `INODE_UNINIT`, with 0xaa garbage in the unused inode table blocks and
+//!   in the inode bitmap of group 1
+//! - `ext4_64bit.img`: made by mke2fs with 64bit and metadata_csum: two
+//!   groups of 512 blocks, 64-byte group descriptors in block 2
+//! - `ext4_extents.img`: made by mke2fs with extent but without
+//!   metadata_csum, so that it can be written: 256 blocks, root directory
+//!   mapped by an extent
+//! - `ext4_large_dir.img`: made by mke2fs with large_dir but without
+//!   metadata_csum, then debugfs: 24 files `/d/file_00` to `/d/file_23`
+//!   (inodes 13 to 36) behind an index with two levels of interior nodes,
+//!   written by hand and checked by e2fsck
+//! - `ext4_inline_dir.img`: made by mke2fs with inline_data and debugfs:
+//!   inline directory `/small` (inode 12) with files `a` and `bb`, inline
+//!   directory `/mid` (inode 22) with `a1` in `i_block` and `in_xattr`
+//!   (inode 24) in `system.data`, a block directory `/big`, and a 100-byte
+//!   inline file `/text` (inode 25) holding `(i * 7 + 3) % 251` at byte `i`
+//! - `ext4_meta_bg.img`: made by mke2fs with 4 KiB blocks, 64bit and meta_bg
+//!   but no resize inode: a single group of 1024 blocks and 16 inodes, with
+//!   its descriptor block at block 1
+//! - `ext4_xattr_block.img`: `ext4_64bit.img` with a file `/f` (inode 12)
+//!   added by debugfs, whose 300-byte `user.big` attribute and 3-byte
+//!   `user.small` live in its attribute block
+
+use std::sync::LazyLock;
+
+/// Image unpacked on first use
+pub type Image = LazyLock<Vec<u8>>;
+
+/// Image unpacked from the packed file at `path`, relative to the file
+/// using the macro
+macro_rules! image {
+    ($path:literal) => {
+        $crate::images::Image::new(|| $crate::images::unpack(include_bytes!($path)))
+    };
+}
+pub(crate) use image;
+
+/// Rebuild the raw image from `packed`
+pub fn unpack(packed: &[u8]) -> Vec<u8> {
+    let u64_at = |at: usize| u64::from_le_bytes(packed[at..at + 8].try_into().unwrap()) as usize;
+    let mut image = vec![0u8; u64_at(0)];
+    let mut at = 8;
+    while at < packed.len() {
+        let offset = u64_at(at);
+        let len = u32::from_le_bytes(packed[at + 8..at + 12].try_into().unwrap()) as usize;
+        at += 12;
+        image[offset..offset + len].copy_from_slice(&packed[at..at + len]);
+        at += len;
+    }
+    image
+}
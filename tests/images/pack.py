@@ -0,0 +1,34 @@
+#!/usr/bin/env python3
+"""Pack raw test images into the format `tests/images/mod.rs` unpacks.
+
+    pack.py IMAGE...
+
+Writes IMAGE.packed next to each IMAGE: the image length as a little-endian
+u64, then every run of non-zero 64-byte chunks as its offset (u64), its
+length (u32) and its bytes.
+"""
+
+import struct
+import sys
+
+CHUNK = 64
+
+
+def pack(image):
+    out = [struct.pack("<Q", len(image))]
+    start = None
+    for offset in range(0, len(image) + CHUNK, CHUNK):
+        zero = not any(image[offset:offset + CHUNK])
+        if start is None and not zero:
+            start = offset
+        elif start is not None and zero:
+            end = min(offset, len(image))
+            out.append(struct.pack("<QI", start, end - start))
+            out.append(image[start:end])
+            start = None
+    return b"".join(out)
+
+
+for path in sys.argv[1:]:
+    with open(path, "rb") as raw, open(path + ".packed", "wb") as packed:
+        packed.write(pack(raw.read()))
@@ -0,0 +1,438 @@
+//! Tests of inodes: flags, timestamps, handles, ID mapping, extended
+//! attributes and inode allocation
+
+#![cfg(not(feature = "read-only"))]
+
+mod images;
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use ext4rs::{
+    AtimeMode, Ext4Error, Ext4FileSystem, File, FileHandle, IdMap, Inode, InodeBuilder, InodeFlags,
+    InodeMode, InodeType, MountOptions, RenameFlags, Timestamp, VecBlockDevice, EXT4_JOURNAL_INO,
+    EXT4_RESIZE_INO,
+};
+use images::{image, Image};
+
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+static EXT3: Image = image!("images/ext3.img.packed");
+static EXT4_EA_INODE: Image = image!("images/ext4_ea_inode.img.packed");
+static EXT4_LAZY_ITABLE: Image = image!("images/ext4_lazy_itable.img.packed");
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+static EXT4_EXTENTS: Image = image!("images/ext4_extents.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+#[test]
+fn test_file_handles() {
+    let mut fs = mount(&EXT3);
+    let ino = fs
+        .create_file(2, "exported", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+
+    let handle = fs.encode_fh(ino).expect("Failed to encode handle");
+    let decoded = FileHandle::from_bytes(&handle.to_bytes()).expect("Failed to decode bytes");
+    assert_eq!(decoded, handle);
+    assert_eq!(fs.decode_fh(&decoded).expect("Failed to resolve handle").ino, ino);
+
+    // A different generation means the inode was reused
+    let reused = FileHandle {
+        generation: handle.generation.wrapping_add(1),
+        ..handle
+    };
+    assert_eq!(fs.decode_fh(&reused).err(), Some(Ext4Error::StaleHandle));
+
+    // Unallocated, reserved and out-of-range inodes
+    for ino in [ino + 1, 0, 7, 1_000_000] {
+        let handle = FileHandle { ino, generation: 0 };
+        assert_eq!(fs.decode_fh(&handle).err(), Some(Ext4Error::StaleHandle));
+    }
+    assert!(fs.decode_fh(&fs.encode_fh(2).unwrap()).is_ok());
+    // There is no inode 0 to encode
+    assert_eq!(fs.encode_fh(0).err(), Some(Ext4Error::InodeNotFound));
+    assert_eq!(fs.get_inode(0).err(), Some(Ext4Error::InodeNotFound));
+}
+
+#[test]
+fn test_inode_flags() {
+    let mut fs = mount(&EXT3);
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "flagged", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(b"hello", &mut fs).expect("Failed to write");
+
+    // Immutable: no writes, no truncation, no new entries in the directory
+    let dir = fs.create_dir(2, "frozen", mode).expect("Failed to create dir");
+    fs.set_flags(ino, InodeFlags::IMMUTABLE).expect("Failed to set flags");
+    fs.set_flags(dir, InodeFlags::IMMUTABLE).expect("Failed to set flags");
+    assert!(fs.get_flags(ino).unwrap().contains(InodeFlags::IMMUTABLE));
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    assert_eq!(file.write(b"x", &mut fs).err(), Some(Ext4Error::PermissionDenied));
+    assert_eq!(file.truncate(0, &mut fs).err(), Some(Ext4Error::PermissionDenied));
+    assert_eq!(
+        fs.create_file(dir, "new", mode).err(),
+        Some(Ext4Error::PermissionDenied)
+    );
+
+    // Append-only: writes only at end of file
+    fs.set_flags(ino, InodeFlags::APPEND).expect("Failed to set flags");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    assert_eq!(file.write(b"x", &mut fs).err(), Some(Ext4Error::PermissionDenied));
+    assert_eq!(file.truncate(0, &mut fs).err(), Some(Ext4Error::PermissionDenied));
+    file.seek(5).unwrap();
+    file.write(b" world", &mut fs).expect("Failed to append");
+    assert_eq!(fs.get_inode(ino).unwrap().size, 11);
+
+    // Flags outside the user-modifiable set are kept
+    let before = fs.get_flags(ino).unwrap() - InodeFlags::USER_MODIFIABLE;
+    fs.set_flags(ino, InodeFlags::all()).expect("Failed to set flags");
+    assert_eq!(fs.get_flags(ino).unwrap() - InodeFlags::USER_MODIFIABLE, before);
+
+    // Clearing the flags allows changes again
+    fs.set_flags(ino, InodeFlags::empty()).expect("Failed to clear flags");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.truncate(0, &mut fs).expect("Failed to truncate");
+}
+
+#[test]
+fn test_atime_updates() {
+    #[cfg(not(feature = "read-only"))]
+    static CLOCK: AtomicI64 = AtomicI64::new(1000);
+    let set_clock = |sec: i64| CLOCK.store(sec, Ordering::Relaxed);
+    let options = MountOptions {
+        time_source: Some(|| Timestamp::new(CLOCK.load(Ordering::Relaxed), 0)),
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT3.to_vec(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options.clone())
+        .expect("Failed to mount image");
+    let ino = fs
+        .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    File::new(fs.get_inode(ino).unwrap()).write(b"hello", &mut fs).unwrap();
+    let atime = |fs: &Ext4FileSystem<VecBlockDevice>| fs.get_inode(ino).unwrap().times().atime.sec;
+    let read = |fs: &mut Ext4FileSystem<VecBlockDevice>| {
+        let mut buf = [0u8; 5];
+        File::new(fs.get_inode(ino).unwrap()).read(&mut buf, fs).unwrap();
+    };
+    assert_eq!(atime(&fs), 1000);
+
+    // relatime: updated while not after mtime, then once a day
+    set_clock(2000);
+    read(&mut fs);
+    assert_eq!(atime(&fs), 2000);
+    set_clock(3000);
+    read(&mut fs);
+    assert_eq!(atime(&fs), 2000);
+    set_clock(2000 + 24 * 60 * 60);
+    read(&mut fs);
+    assert_eq!(atime(&fs), 2000 + 24 * 60 * 60);
+
+    // NOATIME inodes keep their access time
+    fs.set_flags(ino, InodeFlags::NOATIME | InodeFlags::DAX).expect("Failed to set flags");
+    assert!(fs.get_flags(ino).unwrap().contains(InodeFlags::DAX));
+    set_clock(500_000);
+    read(&mut fs);
+    assert_eq!(atime(&fs), 2000 + 24 * 60 * 60);
+    fs.set_flags(ino, InodeFlags::empty()).unwrap();
+
+    // A read through an older handle changes only the access time
+    let mut old = File::new(fs.get_inode(ino).unwrap());
+    File::new(fs.get_inode(ino).unwrap()).append(b" world", &mut fs).unwrap();
+    fs.set_flags(ino, InodeFlags::IMMUTABLE).expect("Failed to set flags");
+    set_clock(600_000);
+    old.read(&mut [0u8; 5], &mut fs).unwrap();
+    assert_eq!(atime(&fs), 600_000);
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.size, 11);
+    assert!(inode.inode_flags().contains(InodeFlags::IMMUTABLE));
+    fs.set_flags(ino, InodeFlags::empty()).unwrap();
+
+    // strictatime updates on every read, noatime never
+    for (mode, expected) in [(AtimeMode::Strict, 3000), (AtimeMode::NoAtime, 1000)] {
+        set_clock(1000);
+        let options = MountOptions {
+            atime: mode,
+            ..options.clone()
+        };
+        let mut fs = Ext4FileSystem::new(VecBlockDevice::new(EXT3.to_vec(), 512).unwrap(), options)
+            .expect("Failed to mount image");
+        let ino = fs
+            .create_file(2, "f", InodeMode::from_bits_truncate(0o644))
+            .expect("Failed to create file");
+        let mut file = File::new(fs.get_inode(ino).unwrap());
+        file.write(b"hello", &mut fs).unwrap();
+        for sec in [2000, 3000] {
+            set_clock(sec);
+            let mut buf = [0u8; 5];
+            File::new(fs.get_inode(ino).unwrap()).read(&mut buf, &mut fs).unwrap();
+        }
+        assert_eq!(fs.get_inode(ino).unwrap().times().atime.sec, expected);
+    }
+}
+
+#[test]
+fn test_generation_on_reuse() {
+    let mut fs = mount(&EXT3);
+    let old = fs
+        .create_file(2, "old", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let other = fs
+        .create_file(2, "other", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    assert_ne!(fs.encode_fh(old).unwrap().generation, fs.encode_fh(other).unwrap().generation);
+    let handle = fs.encode_fh(old).expect("Failed to encode handle");
+
+    // Replacing "old" frees its inode, which the next file gets again
+    fs.rename(2, b"other", 2, b"old", RenameFlags::empty())
+        .expect("Failed to rename");
+    let new = fs
+        .create_file(2, "new", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    assert_eq!(new, old);
+
+    assert_ne!(fs.get_inode(new).unwrap().generation, handle.generation);
+    assert_eq!(fs.decode_fh(&handle).err(), Some(Ext4Error::StaleHandle));
+    assert!(fs.decode_fh(&fs.encode_fh(new).unwrap()).is_ok());
+}
+
+#[test]
+fn test_xattr_value_inodes() {
+    let options = MountOptions {
+        journaling: false,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT4_EA_INODE.to_vec(), 1024).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options.clone()).expect("Failed to mount image");
+    let ino = fs.find_inode("/f").expect("Failed to find file").ino;
+
+    let xattrs = fs.list_xattrs(ino).expect("Failed to list xattrs");
+    let names: Vec<&[u8]> = xattrs.iter().map(|x| x.name.as_slice()).collect();
+    assert_eq!(
+        names,
+        [&b"user.small"[..], b"trusted.mid", b"user.big", b"user.padding"]
+    );
+    assert_eq!(fs.get_xattr(ino, b"user.small").unwrap(), b"hello");
+    assert_eq!(fs.get_xattr(ino, b"user.padding").unwrap(), [b'a'; 84]);
+    let big = fs.get_xattr(ino, b"user.big").expect("Failed to get value inode xattr");
+    assert_eq!(big.len(), 1000);
+    assert!(big.iter().all(|b| b.is_ascii_alphanumeric() || b"+/".contains(b)));
+    assert_eq!(fs.get_xattr(ino, b"user.none"), Err(Ext4Error::NoAttribute));
+    assert_eq!(fs.list_xattrs(2).unwrap(), []);
+
+    // A copy shares the value inode, which counts one more reference
+    let copy = fs.copy("/f", "/g").expect("Failed to copy");
+    assert_eq!(fs.get_xattr(copy, b"user.big").unwrap(), big);
+    assert_eq!(fs.get_inode(13).unwrap().version, 2);
+    assert_eq!(fs.stat(copy).unwrap().blocks, fs.stat(ino).unwrap().blocks);
+
+    // Value inodes are only valid with the ea_inode feature
+    let mut image = EXT4_EA_INODE.to_vec();
+    image[1024 + 0x61] &= !0x04;
+    let fs = Ext4FileSystem::new(VecBlockDevice::new(image, 1024).unwrap(), options)
+        .expect("Failed to mount image");
+    assert_eq!(fs.get_xattr(ino, b"user.big"), Err(Ext4Error::InvalidInput));
+}
+
+#[test]
+fn test_id_map() {
+    let squash = IdMap::root_squash();
+    assert_eq!((squash.uid_to_visible(0), squash.uid_to_visible(1000)), (65534, 1000));
+    assert_eq!((squash.gid_to_disk(0), squash.gid_to_disk(1000)), (65534, 1000));
+
+    // Ranges must end at u32::MAX at the latest
+    let top = IdMap::new().with_uids(10, u32::MAX - 9, 10).unwrap();
+    assert_eq!(top.uid_to_visible(19), u32::MAX);
+    assert_eq!(top.uid_to_disk(u32::MAX), 19);
+    assert_eq!(IdMap::new().with_uids(10, u32::MAX - 9, 11), Err(Ext4Error::InvalidArg));
+    assert_eq!(IdMap::new().with_gids(u32::MAX, 0, 2), Err(Ext4Error::InvalidArg));
+
+    let id_map = IdMap::new().with_uids(0, 1000, 1).and_then(|map| map.with_gids(0, 2000, 1));
+    let options = MountOptions {
+        id_map: Some(id_map.unwrap()),
+        cache_budget: 64 * 1024,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT2_HARD_LINKS.to_vec(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options)
+        .expect("Failed to mount image");
+    let raw_inode = |fs: &Ext4FileSystem<VecBlockDevice>, ino: u32| {
+        let inode_size = fs.superblock().inode_size() as usize;
+        let offset = (ino as usize - 1) * inode_size;
+        let table = fs.group_stats(0).unwrap().inode_table;
+        let mut buf = vec![0u8; 1024];
+        fs.read_block(table + (offset / 1024) as u64, &mut buf).unwrap();
+        Inode::from_bytes(&buf[offset % 1024..][..inode_size], ino).unwrap()
+    };
+
+    // Root-owned files are seen as 1000:2000 but stay root-owned on disk
+    let ino = fs.find_inode("/a/f").unwrap().ino;
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!((inode.full_uid(), inode.full_gid()), (1000, 2000));
+    assert_eq!(fs.get_inodes(&[ino]).unwrap()[0].full_uid(), 1000);
+    fs.set_flags(ino, InodeFlags::NODUMP).expect("Failed to set flags");
+    let raw = raw_inode(&fs, ino);
+    assert_eq!((raw.full_uid(), raw.full_gid()), (0, 0));
+
+    // A file created by the unmapped visible root is stored as the overflow ID
+    let ino = fs
+        .create_file(2, "new", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    assert_eq!(fs.get_inode(ino).unwrap().full_uid(), 65534);
+    assert_eq!(raw_inode(&fs, ino).full_uid(), 65534);
+}
+
+#[test]
+fn test_reserved_inodes() {
+    let mut fs = mount(&EXT3);
+    assert!(fs.is_reserved_inode(EXT4_RESIZE_INO));
+    assert!(!fs.is_reserved_inode(2));
+    assert!(!fs.is_reserved_inode(11));
+
+    // The resize inode maps the 7 reserved blocks after the descriptor
+    // table; with a single group there are no backups
+    assert!(fs.resize_inode().unwrap().is_file());
+    let reserved = fs.reserved_gdt_blocks().expect("Failed to read resize inode");
+    let primaries: Vec<u64> = reserved.iter().map(|r| r.primary).collect();
+    assert_eq!(primaries, (3..=9).collect::<Vec<u64>>());
+    assert!(reserved.iter().all(|r| r.backups.is_empty()));
+    assert_eq!(mount(&EXT2_REV0).reserved_gdt_blocks(), Err(Ext4Error::NotSupported));
+
+    // Neither listing nor iteration shows reserved inodes
+    let inos: Vec<u32> = fs.iter_inodes().map(|r| r.unwrap().0).collect();
+    assert_eq!(inos[0], 2);
+    assert!(inos[1..].iter().all(|&ino| ino >= 11));
+
+    let root = fs.root_inode().unwrap();
+    let mut block = vec![0u8; 1024];
+    fs.read_block(root.block[0] as u64, &mut block).unwrap();
+    let entry = block.windows(10).position(|w| w == b"lost+found").unwrap() - 8;
+    block[entry..entry + 4].copy_from_slice(&EXT4_JOURNAL_INO.to_le_bytes());
+    fs.write_block(root.block[0] as u64, &block).unwrap();
+    let names: Vec<String> = fs.read_dir(2).unwrap().iter().map(|e| e.name.to_string()).collect();
+    assert!(!names.contains(&"lost+found".to_string()), "{:?}", names);
+
+    // Allocation skips reserved inodes even when their bits are clear
+    let bitmap = fs.block_group(0).unwrap().inode_bitmap();
+    fs.read_block(bitmap, &mut block).unwrap();
+    block[0] = 0b11;
+    fs.write_block(bitmap, &block).unwrap();
+    let free = fs.block_group(0).unwrap().free_inodes_count();
+    fs.block_group_mut(0).unwrap().set_free_inodes_count(free + 6);
+    fs.write_block_group(0).unwrap();
+    assert!(fs.alloc_inode().unwrap() >= 11);
+}
+
+#[test]
+fn test_inode_builder() {
+    let device = VecBlockDevice::new(EXT3.to_vec(), 512).unwrap();
+    let mut fs =
+        Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image");
+
+    let fifo = InodeBuilder::fifo().mode(0o600).uid(70000).gid(5);
+    let ino = fs.mknod(2, b"pipe", &fifo).expect("Failed to make fifo");
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.inode_type(), InodeType::Fifo);
+    assert_eq!(inode.mode.bits() & 0o7777, 0o600);
+    assert_eq!((inode.full_uid(), inode.full_gid()), (70000, 5));
+    assert_eq!(inode.links_count, 1);
+
+    // Small device numbers use the old encoding, large ones the new one
+    for (name, major, minor) in [(&b"tty"[..], 4, 64), (&b"big"[..], 300, 70000)] {
+        let ino = fs.mknod(2, name, &InodeBuilder::char_device(major, minor)).unwrap();
+        let inode = fs.get_inode(ino).unwrap();
+        assert_eq!(inode.inode_type(), InodeType::CharDevice);
+        assert_eq!(inode.device_number(), (major, minor));
+    }
+    assert_eq!(fs.mknod(2, b"link", &InodeBuilder::symlink()), Err(Ext4Error::InvalidArg));
+    assert_eq!(fs.mknod(2, b"pipe", &InodeBuilder::file()), Err(Ext4Error::FileExists));
+
+    let dir = fs.mknod(2, b"dir", &InodeBuilder::dir()).expect("Failed to make directory");
+    assert_eq!(fs.get_inode(dir).unwrap().links_count, 2);
+
+    // Short targets live in the inode, long ones in a block
+    let long = vec![b'x'; 200];
+    let fast = fs.symlink(dir, b"fast", b"../pipe", &InodeBuilder::symlink()).unwrap();
+    let slow = fs.symlink(dir, b"slow", &long, &InodeBuilder::symlink()).unwrap();
+    assert_eq!(fs.get_inode(fast).unwrap().blocks, 0);
+    assert_ne!(fs.get_inode(slow).unwrap().blocks, 0);
+    assert_eq!(fs.read_link_bytes(b"/dir/fast"), Ok(b"../pipe".to_vec()));
+    assert_eq!(fs.read_link_bytes(b"/dir/slow"), Ok(long));
+    assert_eq!(
+        fs.symlink(dir, b"bad", b"x", &InodeBuilder::file()),
+        Err(Ext4Error::InvalidArg)
+    );
+}
+
+#[test]
+fn test_new_inode_extents() {
+    let mut fs = mount(&EXT4_EXTENTS);
+    let has_extents = |inode: &Inode| inode.inode_flags().contains(InodeFlags::EXTENTS);
+    let mode = InodeMode::from_bits_truncate(0o644);
+
+    // New files start with an empty extent tree and grow it as they are
+    // written
+    let ino = fs.create_file(2, "f", mode).expect("Failed to create file");
+    let inode = fs.get_inode(ino).unwrap();
+    assert!(has_extents(&inode));
+    assert_eq!(inode.block[0], 0xF30A);
+    assert_eq!(inode.block[1], 4);
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+    let mut file = File::new(inode);
+    file.write(&data, &mut fs).expect("Failed to write");
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.block[0], 0xF30A | 1 << 16);
+    assert_eq!(inode.block[4], 5);
+    let mut buf = vec![0u8; 5000];
+    assert_eq!(File::new(inode).read(&mut buf, &mut fs), Ok(5000));
+    assert_eq!(buf, data);
+
+    // Truncating shortens the extent
+    file.truncate(2000, &mut fs).unwrap();
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.block[4], 2);
+    assert_eq!(inode.get_block_number(3 * 1024, 1024, &fs), Ok(0));
+
+    let dir = fs.create_dir(2, "d", InodeMode::from_bits_truncate(0o755)).unwrap();
+    assert!(has_extents(&fs.get_inode(dir).unwrap()));
+    assert_eq!(fs.lookup(2, b"d"), Ok(dir));
+    assert_eq!(fs.lookup(dir, b".."), Ok(2));
+
+    // Only inodes holding blocks use extents
+    let fast = fs.symlink(dir, b"fast", b"../f", &InodeBuilder::symlink()).unwrap();
+    let slow = fs.symlink(dir, b"slow", &[b'y'; 100], &InodeBuilder::symlink()).unwrap();
+    let fifo = fs.mknod(dir, b"pipe", &InodeBuilder::fifo()).unwrap();
+    assert!(!has_extents(&fs.get_inode(fast).unwrap()));
+    assert!(has_extents(&fs.get_inode(slow).unwrap()));
+    assert!(!has_extents(&fs.get_inode(fifo).unwrap()));
+    assert_eq!(fs.read_link_bytes(b"/d/fast"), Ok(b"../f".to_vec()));
+    assert_eq!(fs.read_link_bytes(b"/d/slow"), Ok(vec![b'y'; 100]));
+}
+
+#[test]
+fn test_iter_inodes() {
+    // Every allocated inode once, in order, root included but no other
+    // reserved inode
+    let mut fs = mount(&EXT2_HARD_LINKS);
+    let inodes: Vec<(u32, Inode)> = fs.iter_inodes().map(|r| r.unwrap()).collect();
+    let inos: Vec<u32> = inodes.iter().map(|(ino, _)| *ino).collect();
+    assert_eq!(inos, [2, 11, 12, 13, 14, 15, 16]);
+    for (ino, inode) in &inodes {
+        assert_eq!(inode.ino, *ino);
+        assert_eq!(inode.size, fs.get_inode(*ino).unwrap().size);
+    }
+    let (_, linked) = &inodes[4];
+    assert_eq!((linked.links_count, linked.size), (3, 3000));
+
+    let ino = fs.create_file(2, "new", InodeMode::from_bits_truncate(0o644)).unwrap();
+    assert_eq!(fs.iter_inodes().last().unwrap().unwrap().0, ino);
+
+    // Garbage in the bitmap of an INODE_UNINIT group, and in the unused
+    // part of an inode table, yields nothing
+    let fs = mount(&EXT4_LAZY_ITABLE);
+    let inos: Vec<u32> = fs.iter_inodes().map(|r| r.unwrap().0).collect();
+    assert_eq!(inos, [2, 11]);
+}
@@ -0,0 +1,618 @@
+//! Tests of the device I/O behind filesystem calls: write ordering, caching,
+//! readahead, batching and flushing
+
+mod images;
+
+#[cfg(not(feature = "read-only"))]
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axdriver_block::{BaseDriverOps, BlockDriverOps, DevResult, DeviceType};
+#[cfg(not(feature = "read-only"))]
+use ext4rs::{DataMode, Ext4Error, File, InodeFlags, InodeMode, RenameFlags, Timestamp};
+use ext4rs::{Ext4FileSystem, InodeType, MountOptions, VecBlockDevice};
+use images::{image, Image};
+
+#[cfg(not(feature = "read-only"))]
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT3: Image = image!("images/ext3.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_HTREE: Image = image!("images/ext4_htree.img.packed");
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+
+/// Device operation seen by [`RecordingDevice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceOp {
+    Read(u64),
+    Write(u64),
+    Flush,
+}
+
+/// Device that logs reads, writes and flushes
+struct RecordingDevice {
+    inner: VecBlockDevice,
+    log: Arc<Mutex<Vec<DeviceOp>>>,
+}
+
+impl BaseDriverOps for RecordingDevice {
+    fn device_name(&self) -> &str {
+        "recording"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriverOps for RecordingDevice {
+    fn num_blocks(&self) -> u64 {
+        self.inner.num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.log.lock().unwrap().push(DeviceOp::Read(block_id));
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.log.lock().unwrap().push(DeviceOp::Write(block_id));
+        self.inner.write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.log.lock().unwrap().push(DeviceOp::Flush);
+        self.inner.flush()
+    }
+}
+
+/// Write a file in `mode` and return the device operations it caused
+#[cfg(not(feature = "read-only"))]
+fn write_with_data_mode(mode: DataMode) -> Vec<DeviceOp> {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let options = MountOptions {
+        data_mode: mode,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let ino = fs
+        .create_file(2, "data.bin", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+
+    log.lock().unwrap().clear();
+    let mut file = File::new(fs.get_inode(ino).expect("Failed to get inode"));
+    file.write(&[0x42; 3000], &mut fs).expect("Failed to write");
+    let ops = log.lock().unwrap().clone();
+    ops
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_data_ordered_flushes_before_inode() {
+    let ops = write_with_data_mode(DataMode::Ordered);
+    // Three data blocks, then a flush, then the inode table block
+    let flush = ops
+        .iter()
+        .position(|op| *op == DeviceOp::Flush)
+        .expect("Ordered mode must flush");
+    let data_writes = ops[..flush]
+        .iter()
+        .filter(|op| matches!(op, DeviceOp::Write(_)))
+        .count();
+    assert!(data_writes >= 3);
+    assert!(matches!(ops.last(), Some(DeviceOp::Write(_))));
+
+    let ops = write_with_data_mode(DataMode::Writeback);
+    assert!(!ops.contains(&DeviceOp::Flush));
+
+    let options = MountOptions {
+        data_mode: DataMode::Journal,
+        ..MountOptions::default()
+    };
+    let result = Ext4FileSystem::new(VecBlockDevice::new(EXT3.to_vec(), 512).unwrap(), options);
+    assert_eq!(result.err(), Some(Ext4Error::NotSupported));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_write_throttling() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let options = MountOptions {
+        max_dirty_blocks: 2,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let ino = fs
+        .create_file(2, "data.bin", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+
+    // Six 1 KiB blocks are committed two at a time
+    log.lock().unwrap().clear();
+    let data: Vec<u8> = (0..6144u32).map(|i| (i % 251) as u8).collect();
+    let mut file = File::new(fs.get_inode(ino).expect("Failed to get inode"));
+    assert_eq!(file.write(&data, &mut fs).expect("Failed to write"), data.len());
+    let ops = log.lock().unwrap().clone();
+    let flushes = ops.iter().filter(|op| **op == DeviceOp::Flush).count();
+    assert_eq!(flushes, 3);
+    // Every commit ends with a write of the inode table block
+    let Some(&inode_block) = ops.last() else {
+        panic!("No device operations");
+    };
+    for (i, op) in ops.iter().enumerate() {
+        if *op == DeviceOp::Flush {
+            let next = ops[i + 1..].iter().find(|op| matches!(op, DeviceOp::Write(_)));
+            assert_eq!(next, Some(&inode_block));
+        }
+    }
+
+    // Appends flush in steps too, but publish the size only once
+    log.lock().unwrap().clear();
+    file.append(&data, &mut fs).expect("Failed to append");
+    let ops = log.lock().unwrap().clone();
+    assert!(ops.iter().filter(|op| **op == DeviceOp::Flush).count() >= 3);
+
+    let inode = fs.get_inode(ino).expect("Failed to get inode");
+    assert_eq!(inode.size, 2 * data.len() as u64);
+    let mut file = File::new(inode);
+    let mut buf = vec![0u8; 2 * data.len()];
+    assert_eq!(file.read(&mut buf, &mut fs).expect("Failed to read"), buf.len());
+    assert_eq!(buf[..data.len()], data[..]);
+    assert_eq!(buf[data.len()..], data[..]);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_get_inodes_batched() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let mut fs = Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount");
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let mut inos: Vec<u32> = (0..40)
+        .map(|i| fs.create_file(2, &format!("f{}", i), mode).unwrap())
+        .collect();
+    inos.reverse();
+    inos.push(2);
+    inos.push(inos[0]);
+
+    log.lock().unwrap().clear();
+    let inodes = fs.get_inodes(&inos).expect("Failed to get inodes");
+    let reads = log.lock().unwrap().len();
+    // 128-byte inodes: eight per 1 KiB table block, all in one run
+    assert_eq!(reads, 1);
+
+    for (inode, &ino) in inodes.iter().zip(&inos) {
+        assert_eq!(inode.ino, ino);
+        assert_eq!(inode.mode, fs.get_inode(ino).unwrap().mode);
+    }
+    assert_eq!(fs.get_inodes(&[0]).err(), Some(Ext4Error::InodeNotFound));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_cache_budget() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let options = MountOptions {
+        cache_budget: 64 * 1024,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount");
+    let mode = InodeMode::from_bits_truncate(0o755);
+    let dir = fs.create_dir(2, "dir", mode).expect("Failed to create dir");
+    let ino = fs.create_file(dir, "file", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&[7; 5000], &mut fs).expect("Failed to write");
+
+    let read_all = |fs: &mut Ext4FileSystem<RecordingDevice>| {
+        let inode = fs.find_inode("/dir/file").expect("Failed to find file");
+        let mut buf = vec![0; 5000];
+        File::new(inode).read(&mut buf, fs).expect("Failed to read");
+        buf
+    };
+    let device_reads = || {
+        let ops = log.lock().unwrap();
+        ops.iter().filter(|op| matches!(op, DeviceOp::Read(_))).count()
+    };
+
+    // Everything just written is served from the caches
+    log.lock().unwrap().clear();
+    assert_eq!(read_all(&mut fs), vec![7; 5000]);
+    assert_eq!(device_reads(), 0);
+
+    let usage = fs.cache_usage();
+    assert_eq!(usage.budget, 64 * 1024);
+    assert!(usage.total() <= usage.budget);
+    assert!(usage.blocks > 0 && usage.inodes > 0 && usage.dentries > 0);
+
+    // Entries of a changed directory are not served stale
+    fs.rename(dir, b"file", dir, b"renamed", RenameFlags::empty())
+        .expect("Failed to rename");
+    assert_eq!(fs.find_inode("/dir/file").err(), Some(Ext4Error::InodeNotFound));
+    assert_eq!(fs.find_inode("/dir/renamed").unwrap().ino, ino);
+
+    fs.shrink(0);
+    assert_eq!(fs.cache_usage().total(), 0);
+    fs.rename(dir, b"renamed", dir, b"file", RenameFlags::empty())
+        .expect("Failed to rename");
+    log.lock().unwrap().clear();
+    assert_eq!(read_all(&mut fs), vec![7; 5000]);
+    assert!(device_reads() > 0);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_atomic_append() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let options = MountOptions {
+        data_mode: DataMode::Writeback,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    let ino = fs
+        .create_file(2, "log", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let mut first = File::new(fs.get_inode(ino).unwrap());
+    let mut second = File::new(fs.get_inode(ino).unwrap());
+
+    // Data is flushed before the inode publishing the new size, whatever
+    // the data mode
+    log.lock().unwrap().clear();
+    assert_eq!(first.append(&[b'a'; 1500], &mut fs), Ok(1500));
+    let ops = log.lock().unwrap().clone();
+    let flush = ops.iter().position(|op| *op == DeviceOp::Flush).expect("Append must flush");
+    assert!(ops[..flush].iter().any(|op| matches!(op, DeviceOp::Write(_))));
+    // The inode goes through the log, whose blocks, commit block and
+    // checkpoint are flushed in turn
+    assert!(ops.iter().filter(|op| **op == DeviceOp::Flush).count() >= 4);
+    assert_eq!(ops.last(), Some(&DeviceOp::Flush));
+
+    // A handle with a stale size still appends at the real end of file
+    assert_eq!(second.append(b"bbb", &mut fs), Ok(1503));
+    assert_eq!(first.append(b"cc", &mut fs), Ok(1505));
+    assert_eq!(first.position(), 1505);
+
+    let mut data = vec![0u8; 1505];
+    let mut reader = File::new(fs.get_inode(ino).unwrap());
+    assert_eq!(reader.read(&mut data, &mut fs), Ok(1505));
+    assert!(data[..1500].iter().all(|&b| b == b'a'));
+    assert_eq!(&data[1500..], b"bbbcc");
+
+    // Append-only files accept appends, immutable ones don't
+    fs.set_flags(ino, InodeFlags::APPEND).expect("Failed to set flags");
+    assert_eq!(first.append(b"d", &mut fs), Ok(1506));
+    fs.set_flags(ino, InodeFlags::IMMUTABLE).expect("Failed to set flags");
+    assert_eq!(first.append(b"e", &mut fs), Err(Ext4Error::PermissionDenied));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_buffered_small_writes() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let mut fs =
+        Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image");
+    let ino = fs
+        .create_file(2, "small.log", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let mut file = File::buffered(fs.get_inode(ino).unwrap());
+
+    // Writes are staged until they fill a block
+    log.lock().unwrap().clear();
+    for i in 0..15u8 {
+        assert_eq!(file.write(&[i; 64], &mut fs), Ok(64));
+    }
+    assert!(log.lock().unwrap().is_empty());
+    assert_eq!(file.size(), 960);
+    assert_eq!(fs.get_inode(ino).unwrap().size, 0);
+    assert_eq!(file.write(&[15; 64], &mut fs), Ok(64));
+    assert!(log.lock().unwrap().iter().any(|op| matches!(op, DeviceOp::Write(_))));
+    assert_eq!(fs.get_inode(ino).unwrap().size, 1024);
+
+    // A seek writes out what was staged before the next operation
+    file.write(b"tail", &mut fs).unwrap();
+    assert_eq!(file.seek(0), Ok(0));
+    let mut data = vec![0u8; 1028];
+    assert_eq!(file.read(&mut data, &mut fs), Ok(1028));
+    assert!(data[..1024].chunks(64).enumerate().all(|(i, c)| c.iter().all(|&b| b == i as u8)));
+    assert_eq!(&data[1024..1028], b"tail");
+
+    // Close writes out the rest
+    file.write(b"!!", &mut fs).unwrap();
+    assert_eq!(fs.get_inode(ino).unwrap().size, 1028);
+    file.close(&mut fs).expect("Failed to close");
+    assert_eq!(fs.get_inode(ino).unwrap().size, 1030);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_inode_readahead() {
+    let stat_after_readdir = |readahead: u32| {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let device = RecordingDevice {
+            inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+            log: log.clone(),
+        };
+        let options = MountOptions {
+            cache_budget: 256 * 1024,
+            inode_readahead_blks: readahead,
+            ..MountOptions::default()
+        };
+        let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount");
+        let mode = InodeMode::from_bits_truncate(0o644);
+        for i in 0..24 {
+            fs.create_file(2, &format!("f{}", i), mode).expect("Failed to create file");
+        }
+        fs.shrink(0);
+
+        let entries = fs.read_dir(2).expect("Failed to read directory");
+        log.lock().unwrap().clear();
+        for entry in &entries {
+            fs.get_inode(entry.ino).expect("Failed to get inode");
+        }
+        let ops = log.lock().unwrap();
+        ops.iter().filter(|op| matches!(op, DeviceOp::Read(_))).count()
+    };
+
+    // The stat storm after a readdir is served from the prefetched blocks
+    assert_eq!(stat_after_readdir(32), 0);
+    assert!(stat_after_readdir(0) > 1);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_htree_checksums() {
+    let options = MountOptions {
+        journaling: false,
+        ..MountOptions::default()
+    };
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT4_HTREE.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let mut fs = Ext4FileSystem::new(device, options.clone()).expect("Failed to mount image");
+    let d = fs.find_inode("/d").expect("Failed to find directory");
+    assert!(d.inode_flags().contains(InodeFlags::INDEX));
+
+    // A lookup reads the inode, the index root and a single leaf
+    log.lock().unwrap().clear();
+    let ino = fs.lookup(12, b"file_with_a_longish_name_77").expect("Failed to look up");
+    assert_eq!(log.lock().unwrap().len(), 3);
+    assert_eq!(fs.lookup(12, b"file_with_a_longish_name_0"), Err(Ext4Error::InodeNotFound));
+
+    // Moving the directory updates ".." in the root with a new checksum
+    let e = fs.find_inode("/e").unwrap().ino;
+    fs.rename(2, b"d", e, b"d", RenameFlags::empty()).expect("Failed to rename");
+    assert!(fs.get_inode(12).unwrap().inode_flags().contains(InodeFlags::INDEX));
+    assert_eq!(fs.lookup(12, b"..").unwrap(), e);
+    assert_eq!(fs.find_inode("/e/d/file_with_a_longish_name_77").unwrap().ino, ino);
+
+    // Changing the entries rebuilds the index with valid checksums
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let new = fs.create_file(12, "new", mode).expect("Failed to create file");
+    fs.rename(12, b"file_with_a_longish_name_5", 12, b"renamed", RenameFlags::empty())
+        .expect("Failed to rename");
+    assert!(fs.get_inode(12).unwrap().inode_flags().contains(InodeFlags::INDEX));
+    let stats = fs.dir_stats(12).expect("Failed to get directory stats");
+    assert_eq!(stats.size, 7168);
+    assert_eq!(stats.index_leaves, Some(6));
+    log.lock().unwrap().clear();
+    assert_eq!(fs.lookup(12, b"new"), Ok(new));
+    assert_eq!(log.lock().unwrap().len(), 3);
+    assert_eq!(fs.lookup(12, b"file_with_a_longish_name_5"), Err(Ext4Error::InodeNotFound));
+    assert!(fs.lookup(12, b"renamed").is_ok());
+    assert_eq!(fs.read_dir(12).unwrap().len(), 123);
+
+    // A corrupted index root fails lookups, not linear listings
+    let root_block = 38 * 1024;
+    let mut image = EXT4_HTREE.to_vec();
+    image[root_block + 0x30] ^= 0xff;
+    let fs = Ext4FileSystem::new(VecBlockDevice::new(image, 1024).unwrap(), options)
+        .expect("Failed to mount image");
+    assert_eq!(
+        fs.lookup(12, b"file_with_a_longish_name_77"),
+        Err(Ext4Error::BadChecksum)
+    );
+    assert_eq!(fs.read_dir(12).unwrap().len(), 122);
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_background_flush() {
+    static CLOCK: AtomicI64 = AtomicI64::new(1000);
+    let options = MountOptions {
+        time_source: Some(|| Timestamp::new(CLOCK.load(Ordering::Relaxed), 0)),
+        data_mode: DataMode::Writeback,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT2_REV0.to_vec(), 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    fs.flush().unwrap();
+    assert_eq!(fs.poll_flush(Timestamp::new(2000, 0)), Ok(0));
+
+    let mode = InodeMode::from_bits_truncate(0o644);
+    let ino = fs.create_file(2, "log", mode).expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&[1; 4096], &mut fs).expect("Failed to write");
+    let unflushed = fs.unflushed_blocks();
+    assert!(unflushed >= 4);
+
+    // Writes keep their age until flushed
+    CLOCK.store(1010, Ordering::Relaxed);
+    file.write(&[2; 1024], &mut fs).expect("Failed to write");
+    assert_eq!(fs.flush_some(unflushed + 100), Ok(0));
+    assert_eq!(fs.poll_flush(Timestamp::new(999, 0)), Ok(0));
+    let flushed = fs.poll_flush(Timestamp::new(1005, 0)).unwrap();
+    assert!(flushed > unflushed);
+    assert_eq!(fs.unflushed_blocks(), 0);
+
+    file.write(&[3; 2048], &mut fs).expect("Failed to write");
+    assert_eq!(fs.poll_flush(Timestamp::new(1005, 0)), Ok(0));
+    let unflushed = fs.unflushed_blocks();
+    assert_eq!(fs.flush_some(2), Ok(unflushed));
+    assert_eq!(fs.unflushed_blocks(), 0);
+}
+
+#[cfg(all(feature = "tracing", not(feature = "read-only")))]
+#[test]
+fn test_tracing() {
+    use ext4rs::{BlockCounts, TraceOp, Tracer};
+
+    #[derive(Debug)]
+    struct Recorder(Mutex<Vec<(TraceOp, u32, Option<BlockCounts>)>>);
+    impl Tracer for Recorder {
+        fn enter(&self, op: TraceOp, ino: u32) {
+            self.0.lock().unwrap().push((op, ino, None));
+        }
+        fn exit(&self, op: TraceOp, ino: u32, blocks: BlockCounts, ok: bool) {
+            assert!(ok);
+            self.0.lock().unwrap().push((op, ino, Some(blocks)));
+        }
+    }
+    static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+    let take = || core::mem::take(&mut *RECORDER.0.lock().unwrap());
+
+    let options = MountOptions {
+        tracer: Some(&RECORDER),
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(VecBlockDevice::new(EXT3.to_vec(), 512).unwrap(), options)
+        .expect("Failed to mount image");
+    let spans = take();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0], (TraceOp::Mount, 0, None));
+    assert!(spans[1].2.unwrap().read > 0);
+
+    let ino = fs
+        .create_file(2, "traced", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    take();
+    file.write(&[5; 3000], &mut fs).expect("Failed to write");
+    let spans = take();
+    assert_eq!(spans[0], (TraceOp::Write, ino, None));
+    let (op, _, blocks) = spans.last().unwrap();
+    assert_eq!(*op, TraceOp::Write);
+    assert!(blocks.unwrap().written >= 3);
+
+    // Appends on a journaled filesystem commit the inode update
+    file.append(&[6; 10], &mut fs).expect("Failed to append");
+    assert!(take().iter().any(|&(op, i, b)| op == TraceOp::Commit && i == ino && b.is_some()));
+
+    assert_eq!(fs.lookup(2, b"traced"), Ok(ino));
+    assert_eq!(take().len(), 2);
+    file.seek(0).unwrap();
+    file.read(&mut [0; 100], &mut fs).expect("Failed to read");
+    assert_eq!(take()[0], (TraceOp::Read, ino, None));
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_bitmap_cache() {
+    let create_files = |bitmap_cache_blocks: usize| {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let device = RecordingDevice {
+            inner: VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap(),
+            log: log.clone(),
+        };
+        let options = MountOptions {
+            bitmap_cache_blocks,
+            verify_accounting: false,
+            ..MountOptions::default()
+        };
+        let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+        let mode = InodeMode::from_bits_truncate(0o644);
+        for n in 0..10 {
+            let ino = fs.create_file(2, &format!("f{}", n), mode).expect("Failed to create file");
+            let mut file = File::new(fs.get_inode(ino).unwrap());
+            file.write(&[n as u8; 3000], &mut fs).expect("Failed to write");
+        }
+        let stats = fs.group_stats(0).unwrap();
+        let ops = log.lock().unwrap().clone();
+        let count = |op: DeviceOp| ops.iter().filter(|&&o| o == op).count();
+        let bitmaps = [stats.block_bitmap, stats.inode_bitmap];
+        let reads = bitmaps.map(|b| count(DeviceOp::Read(b)));
+        let writes = bitmaps.map(|b| count(DeviceOp::Write(b)));
+        (reads, writes, fs.stats().unwrap(), stats)
+    };
+
+    // Each bitmap is read once, and written once per flush, after each file
+    // is written, rather than after each of the three blocks of a file is
+    // allocated
+    let (reads, writes, fs_stats, group) = create_files(32);
+    assert_eq!(reads, [1, 1]);
+    assert_eq!(writes, [10, 10]);
+    let (uncached_reads, uncached_writes, uncached_stats, uncached_group) = create_files(0);
+    assert!(uncached_reads.iter().all(|&r| r >= 10));
+    assert_eq!(uncached_writes, [30, 10]);
+    assert_eq!(fs_stats.free_blocks, uncached_stats.free_blocks);
+    assert_eq!(group.free_inodes, uncached_group.free_inodes);
+}
+
+#[test]
+fn test_read_dir_plus() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let device = RecordingDevice {
+        inner: VecBlockDevice::new(EXT2_HARD_LINKS.to_vec(), 1024).unwrap(),
+        log: log.clone(),
+    };
+    let options = MountOptions {
+        cache_budget: 0,
+        ..MountOptions::default()
+    };
+    let fs = Ext4FileSystem::new(device, options).expect("Failed to mount");
+    log.lock().unwrap().clear();
+    let entries = fs.read_dir_plus(12).expect("Failed to read directory");
+    // The directory's inode and block, then the table blocks of inode 2 and
+    // of inodes 12 to 16, once each for all six entries
+    assert_eq!(log.lock().unwrap().len(), 4);
+
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|e| {
+            let name = e.entry.name.to_string_lossy().into_owned();
+            (name, e.entry.ino, e.inode_type, e.size, e.links_count)
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (".".into(), 12, InodeType::Directory, 1024, 3),
+            ("..".into(), 2, InodeType::Directory, 1024, 4),
+            ("b".into(), 13, InodeType::Directory, 1024, 2),
+            ("f".into(), 14, InodeType::File, 3000, 3),
+            ("g".into(), 14, InodeType::File, 3000, 3),
+            ("s".into(), 16, InodeType::SymLink, 4, 1),
+        ]
+    );
+    for plus in &entries {
+        let inode = fs.get_inode(plus.entry.ino).unwrap();
+        assert_eq!(plus.mode, inode.mode);
+        assert_eq!(plus.times, inode.times());
+    }
+}
@@ -0,0 +1,198 @@
+//! Tests of the journal of `ext3.img`: aborting it, mounting it corrupt or
+//! in need of recovery, and logging appends to it
+
+#![cfg(not(feature = "read-only"))]
+
+mod images;
+
+use std::sync::{Arc, Mutex};
+
+use axdriver_block::{BaseDriverOps, BlockDriverOps, DevError, DevResult, DeviceType};
+use ext4rs::{
+    Ext4Error, Ext4FileSystem, FeatureIncompat, FeatureRoCompat, File, InodeMode, MountOptions,
+    SuperBlock, VecBlockDevice,
+};
+use images::{image, Image};
+
+static EXT2_NOFILETYPE: Image = image!("images/ext2_nofiletype.img.packed");
+static EXT3: Image = image!("images/ext3.img.packed");
+/// First block of the journal inode in `ext3.img`, holding its superblock
+const EXT3_JOURNAL_BLOCK: usize = 58;
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+/// Device sharing its image with the test, whose writes to one block fail
+struct CrashingDevice {
+    inner: Arc<Mutex<VecBlockDevice>>,
+    failing: Arc<Mutex<Option<u64>>>,
+}
+
+impl BaseDriverOps for CrashingDevice {
+    fn device_name(&self) -> &str {
+        "crashing"
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriverOps for CrashingDevice {
+    fn num_blocks(&self) -> u64 {
+        self.inner.lock().unwrap().num_blocks()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.lock().unwrap().block_size()
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.inner.lock().unwrap().read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        if *self.failing.lock().unwrap() == Some(block_id) {
+            return Err(DevError::Io);
+        }
+        self.inner.lock().unwrap().write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+/// Check if the superblock of `image` has the `needs_recovery` feature
+fn recovering(image: &[u8]) -> bool {
+    let sb = SuperBlock::from_bytes(&image[1024..2048]).unwrap();
+    sb.feature_incompat().contains(FeatureIncompat::RECOVER)
+}
+
+fn read_be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn test_journal_abort() {
+    let mut fs = mount(&EXT3);
+    assert!(!fs.is_journal_aborted());
+    fs.abort_journal(-5).expect("Failed to abort journal");
+
+    assert!(fs.is_journal_aborted());
+    assert!(fs.superblock().has_errors());
+    // The superblock on disk says so too
+    let mut buf = vec![0u8; 1024];
+    fs.read_block(1, &mut buf).expect("Failed to read superblock");
+    assert!(SuperBlock::from_bytes(&buf).unwrap().has_errors());
+    assert_eq!(
+        fs.create_file(2, "file", InodeMode::from_bits_truncate(0o644)),
+        Err(Ext4Error::JournalAborted)
+    );
+    // Reads keep working
+    assert_eq!(fs.read_dir(2).expect("Failed to read root directory").len(), 3);
+
+    // Without a journal there is nothing to abort
+    let mut fs = mount(&EXT2_NOFILETYPE);
+    assert_eq!(fs.abort_journal(-5), Err(Ext4Error::NotSupported));
+}
+
+#[test]
+fn test_corrupt_journal_mounts_read_only() {
+    let mut image = EXT3.to_vec();
+    let offset = EXT3_JOURNAL_BLOCK * 1024;
+    assert_eq!(image[offset..offset + 4], [0xC0, 0x3B, 0x39, 0x98]);
+    image[offset] = 0;
+
+    let mut fs =
+        Ext4FileSystem::new(VecBlockDevice::new(image, 512).unwrap(), MountOptions::default())
+            .expect("Failed to mount image");
+    assert!(fs.is_journal_aborted());
+    assert_eq!(
+        fs.create_dir(2, "dir", InodeMode::from_bits_truncate(0o755)),
+        Err(Ext4Error::JournalAborted)
+    );
+    assert!(fs.find_inode("/lost+found").is_ok());
+
+    // Mounting without journaling ignores the log
+    let mut image = EXT3.to_vec();
+    image[offset] = 0;
+    let options = MountOptions {
+        journaling: false,
+        ..MountOptions::default()
+    };
+    let mut fs = Ext4FileSystem::new(VecBlockDevice::new(image, 512).unwrap(), options)
+        .expect("Failed to mount image");
+    assert!(!fs.is_journal_aborted());
+    assert!(fs
+        .create_dir(2, "dir", InodeMode::from_bits_truncate(0o755))
+        .is_ok());
+}
+
+#[test]
+fn test_needs_recovery_blocks_writes() {
+    let mut image = EXT3.to_vec();
+    image[1024 + 0x60] |= 0x04; // needs_recovery
+    let mut fs =
+        Ext4FileSystem::new(VecBlockDevice::new(image, 512).unwrap(), MountOptions::default())
+            .expect("Failed to mount image");
+
+    // The log isn't replayed, so only reads are allowed
+    assert!(fs.find_inode("/lost+found").is_ok());
+    assert_eq!(
+        fs.create_dir(2, "dir", InodeMode::from_bits_truncate(0o755)),
+        Err(Ext4Error::UnsupportedForWrite(FeatureIncompat::RECOVER, FeatureRoCompat::empty()))
+    );
+}
+
+#[test]
+fn test_append_logs_inode() {
+    let image = Arc::new(Mutex::new(VecBlockDevice::new(EXT3.to_vec(), 1024).unwrap()));
+    let failing = Arc::new(Mutex::new(None));
+    let device = CrashingDevice {
+        inner: image.clone(),
+        failing: failing.clone(),
+    };
+    let mut fs = Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount");
+    let ino = fs
+        .create_file(2, "log", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    let jsb = EXT3_JOURNAL_BLOCK * 1024;
+    let sequence = read_be32(&image.lock().unwrap().as_bytes()[jsb..], 0x18);
+
+    // The log holds a descriptor tagging the inode table block, its copy and
+    // a commit block, and is emptied once the inode is written in place
+    assert_eq!(file.append(&[b'a'; 100], &mut fs), Ok(100));
+    let bytes = image.lock().unwrap().as_bytes().to_vec();
+    assert_eq!(read_be32(&bytes[jsb..], 0x1C), 0);
+    assert_eq!(read_be32(&bytes[jsb..], 0x18), sequence + 1);
+    assert!(!recovering(&bytes));
+    let descriptor = &bytes[jsb + 1024..];
+    assert_eq!((read_be32(descriptor, 4), read_be32(descriptor, 8)), (1, sequence));
+    let itable_block = read_be32(descriptor, 12) as u64;
+    let commit = &bytes[jsb + 3 * 1024..];
+    assert_eq!((read_be32(commit, 4), read_be32(commit, 8)), (2, sequence));
+    let itable = &bytes[itable_block as usize * 1024..][..1024];
+    assert_eq!(bytes[jsb + 2 * 1024..jsb + 3 * 1024], *itable);
+
+    // Crashing before the inode is written in place leaves the committed
+    // transaction for the next mount to replay
+    *failing.lock().unwrap() = Some(itable_block);
+    assert!(file.append(&[b'b'; 100], &mut fs).is_err());
+    assert!(fs.is_journal_aborted());
+    let bytes = image.lock().unwrap().as_bytes().to_vec();
+    assert_eq!(read_be32(&bytes[jsb..], 0x1C), 1);
+    assert_eq!(read_be32(&bytes[jsb..], 0x18), sequence + 1);
+    assert!(recovering(&bytes));
+    let commit = &bytes[jsb + 3 * 1024..];
+    assert_eq!((read_be32(commit, 4), read_be32(commit, 8)), (2, sequence + 1));
+    let itable = &bytes[itable_block as usize * 1024..][..1024];
+    assert_ne!(bytes[jsb + 2 * 1024..jsb + 3 * 1024], *itable);
+
+    // Without a replay, the inode in place still has the old size
+    let fs = mount(&bytes);
+    assert_eq!(fs.get_inode(ino).unwrap().size, 100);
+}
@@ -0,0 +1,378 @@
+//! Tests of mounting: superblock fields, feature and group descriptor checks,
+//! the error log and the processing of orphans
+
+mod images;
+
+#[cfg(feature = "read-only")]
+use ext4rs::OpenFlags;
+use ext4rs::{crc32c, Ext4Error, Ext4FileSystem, MountOptions, VecBlockDevice, EXT4_RESIZE_INO};
+#[cfg(not(feature = "read-only"))]
+use ext4rs::{
+    ErrorLog, FeatureIncompat, FeatureRoCompat, File, InodeMode, RenameFlags, SuperBlock,
+    Timestamp, Uuid, EXT4_MAX_EXTENT_DEPTH,
+};
+use images::{image, Image};
+
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+static EXT3: Image = image!("images/ext3.img.packed");
+static EXT4_HTREE: Image = image!("images/ext4_htree.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_ORPHAN_FILE: Image = image!("images/ext4_orphan_file.img.packed");
+#[cfg(feature = "read-only")]
+static EXT2_HARD_LINKS: Image = image!("images/ext2_hard_links.img.packed");
+static EXT4_64BIT: Image = image!("images/ext4_64bit.img.packed");
+#[cfg(not(feature = "read-only"))]
+static EXT4_EXTENTS: Image = image!("images/ext4_extents.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+#[test]
+fn test_corrupt_group_descriptor() {
+    // The descriptor table of the 1 KiB images starts at block 2
+    let table = 2 * 1024;
+    for (field, value) in [(0, 1_000_000u32), (4, 1), (8, 8000)] {
+        let mut image = EXT2_REV0.to_vec();
+        image[table + field..table + field + 4].copy_from_slice(&value.to_le_bytes());
+        let device = VecBlockDevice::new(image, 512).unwrap();
+        let result = Ext4FileSystem::new(device, MountOptions::default());
+        assert_eq!(result.err(), Some(Ext4Error::CorruptGroupDescriptor(0)));
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_error_log() {
+    // Turn the ".." entry of lost+found into a loop, as in test_directory_cycle
+    let mut image = EXT2_REV0.to_vec();
+    let dotdot_name = 10 * 1024 + 12 + 8;
+    image[dotdot_name..dotdot_name + 2].copy_from_slice(b"up");
+    let options = MountOptions {
+        time_source: Some(|| Timestamp::new(1_700_000_000, 0)),
+        ..MountOptions::default()
+    };
+    let fs = Ext4FileSystem::new(VecBlockDevice::new(image, 512).unwrap(), options)
+        .expect("Failed to mount image");
+    assert_eq!(fs.error_log(), ErrorLog::default());
+
+    assert_eq!(fs.find_inode("/lost+found/up").err(), Some(Ext4Error::Loop));
+    assert_eq!(fs.find_inode("/lost+found/up/up").err(), Some(Ext4Error::Loop));
+    let log = fs.error_log();
+    assert_eq!(log.count, 2);
+    let first = log.first.as_ref().expect("First error not recorded");
+    assert_eq!(first.func, "resolve_path");
+    assert_eq!((first.time, first.ino, first.block, first.errcode), (1_700_000_000, 11, 0, 5));
+    assert_eq!(log.last.as_ref().unwrap().func, "resolve_path");
+
+    // The superblock on disk holds the same record
+    let mut buf = vec![0u8; 1024];
+    fs.read_block(1, &mut buf).expect("Failed to read superblock");
+    let sb = SuperBlock::from_bytes(&buf).expect("Failed to parse superblock");
+    assert!(sb.has_errors());
+    assert_eq!(sb.error_log(), &log);
+
+    // Without a clock, errors are recorded at time 0 and still read back
+    let mut image = EXT2_REV0.to_vec();
+    image[dotdot_name..dotdot_name + 2].copy_from_slice(b"up");
+    let device = VecBlockDevice::new(image, 512).unwrap();
+    let fs = Ext4FileSystem::new(device, MountOptions::default()).unwrap();
+    assert_eq!(fs.find_inode("/lost+found/up").err(), Some(Ext4Error::Loop));
+    fs.read_block(1, &mut buf).expect("Failed to read superblock");
+    let sb = SuperBlock::from_bytes(&buf).expect("Failed to parse superblock");
+    let first = sb.error_log().first.as_ref().expect("First error not read back");
+    assert_eq!((first.time, first.func.as_str()), (0, "resolve_path"));
+    assert_eq!(sb.error_log(), &fs.error_log());
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_label_and_uuid() {
+    let mut fs = mount(&EXT3);
+    assert_eq!(fs.superblock().label(), "");
+
+    fs.set_label("data").expect("Failed to set label");
+    let uuid: Uuid = "0123abcd-4567-89ef-0123-456789abcdef".parse().unwrap();
+    fs.set_uuid(uuid).expect("Failed to set UUID");
+    assert_eq!(fs.superblock().label(), "data");
+    assert_eq!(fs.superblock().volume_uuid(), uuid);
+
+    // The image has a single group, so only the primary superblock to update
+    let mut buf = vec![0u8; 1024];
+    fs.read_block(1, &mut buf).expect("Failed to read superblock");
+    let sb = SuperBlock::from_bytes(&buf).expect("Failed to parse superblock");
+    assert_eq!((sb.label(), sb.volume_uuid()), ("data", uuid));
+
+    assert_eq!(fs.set_label("seventeen-letters"), Err(Ext4Error::InvalidArg));
+    assert_eq!(fs.set_label("nul\0"), Err(Ext4Error::InvalidArg));
+    fs.set_label("sixteen-letters!").expect("Failed to set label");
+    assert_eq!(fs.superblock().label(), "sixteen-letters!");
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_orphan_file() {
+    let options = MountOptions {
+        time_source: Some(|| Timestamp::new(1_700_000_000, 0)),
+        ..MountOptions::default()
+    };
+    let mount_orphan = |options: MountOptions| {
+        let device = VecBlockDevice::new(EXT4_ORPHAN_FILE.to_vec(), 1024).unwrap();
+        Ext4FileSystem::new(device, options).expect("Failed to mount image")
+    };
+
+    // Read-only mounts leave orphans alone
+    let fs = mount_orphan(MountOptions {
+        read_only: true,
+        ..options.clone()
+    });
+    assert_eq!(fs.orphans(), Ok(vec![13]));
+    assert!(fs
+        .superblock()
+        .feature_ro_compat()
+        .contains(FeatureRoCompat::ORPHAN_PRESENT));
+    let before = fs.group_stats(0).unwrap();
+
+    // Others release them
+    let mut fs = mount_orphan(options);
+    assert_eq!(fs.orphans(), Ok(vec![]));
+    assert!(!fs
+        .superblock()
+        .feature_ro_compat()
+        .contains(FeatureRoCompat::ORPHAN_PRESENT));
+    let after = fs.group_stats(0).unwrap();
+    assert_eq!(after.free_inodes, before.free_inodes + 1);
+    assert_eq!(after.free_blocks, before.free_blocks + 3);
+
+    // Truncation frees the blocks past the new end
+    let ino = fs
+        .create_file(2, "t", InodeMode::from_bits_truncate(0o644))
+        .expect("Failed to create file");
+    let mut file = File::new(fs.get_inode(ino).unwrap());
+    file.write(&[7; 3000], &mut fs).expect("Failed to write");
+    file.truncate(1000, &mut fs).expect("Failed to truncate");
+    assert_eq!(fs.get_inode(ino).unwrap().size, 1000);
+    assert_eq!(fs.group_stats(0).unwrap().free_blocks, after.free_blocks - 1);
+    assert_eq!(fs.orphans(), Ok(vec![]));
+}
+
+#[test]
+fn test_checksum_seed() {
+    // Change the UUID of the htree image, keeping the checksums seeded from
+    // the old one in s_checksum_seed, as `tune2fs -U` does with csum_seed
+    let sb = 1024;
+    let mut image = EXT4_HTREE.to_vec();
+    let seed = crc32c(!0, &image[sb + 0x68..sb + 0x78]);
+    image[sb + 0x270..sb + 0x274].copy_from_slice(&seed.to_le_bytes());
+    image[sb + 0x68..sb + 0x78].fill(0x42);
+    image[sb + 0x61] |= 0x20;
+
+    let options = MountOptions {
+        journaling: false,
+        ..MountOptions::default()
+    };
+    let fs = Ext4FileSystem::new(VecBlockDevice::new(image, 1024).unwrap(), options)
+        .expect("Failed to mount image");
+    assert_eq!(fs.superblock().checksum_seed(), seed);
+    assert_eq!(fs.superblock().csum_seed(), seed);
+    assert!(fs.lookup(12, b"file_with_a_longish_name_77").is_ok());
+}
+
+#[test]
+fn test_superblock_late_fields() {
+    let sb = 1024;
+    let mut image = EXT4_64BIT.to_vec();
+    image[sb + 0x166..sb + 0x168].copy_from_slice(&0x0105u16.to_le_bytes());
+    image[sb + 0x168..sb + 0x170].copy_from_slice(&0x0002_0000_0003u64.to_le_bytes());
+    image[sb + 0x170..sb + 0x174].copy_from_slice(&0x0607_0809u32.to_le_bytes());
+    image[sb + 0x274..sb + 0x278].copy_from_slice(&[1, 2, 3, 4]);
+    let csum = crc32c(!0, &image[sb..sb + 0x3FC]);
+    image[sb + 0x3FC..sb + 0x400].copy_from_slice(&csum.to_le_bytes());
+
+    let fs = mount(&image);
+    let sb = fs.superblock();
+    assert_eq!(sb.mmp_interval(), 0x0105);
+    assert_eq!(sb.mmp_block(), 0x0002_0000_0003);
+    assert_eq!(sb.raid_stripe_width(), 0x0607_0809);
+    assert_eq!(sb.checksum_type(), 1);
+    assert_eq!(
+        (sb.wtime_hi(), sb.mtime_hi(), sb.mkfs_time_hi(), sb.lastcheck_hi()),
+        (1, 2, 3, 4)
+    );
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_sync_backups() {
+    // Leave the backup in group 1 with stale geometry, features and table
+    let mut image = EXT4_64BIT.to_vec();
+    let backup = 513 * 1024;
+    image[backup + 4..backup + 8].copy_from_slice(&512u32.to_le_bytes());
+    image[backup + 0x5C] &= !0x20;
+    image[514 * 1024..515 * 1024].fill(0);
+
+    let mut fs = mount(&image);
+    fs.block_group_mut(1).unwrap().set_used_dirs_count(3);
+    fs.write_block_group(1).expect("Failed to write descriptor");
+    fs.sync_backups().expect("Failed to sync backups");
+
+    let (mut primary, mut copy) = (vec![0u8; 1024], vec![0u8; 1024]);
+    fs.read_block(1, &mut primary).unwrap();
+    fs.read_block(513, &mut copy).unwrap();
+    for range in [0..12, 0x5C..0x68, 0x68..0x88, 0xCE..0xD0, 0x150..0x158] {
+        assert_eq!(copy[range.clone()], primary[range.clone()], "bytes {:?}", range);
+    }
+    // Each copy still names its own group
+    assert_eq!(copy[0x5A..0x5C], 1u16.to_le_bytes());
+
+    fs.read_block(2, &mut primary).unwrap();
+    fs.read_block(514, &mut copy).unwrap();
+    assert_eq!(copy, primary);
+    assert_eq!(copy[64 + 16], 3);
+}
+
+#[test]
+fn test_resize_inode_mismatch() {
+    // The superblock reserves 5 descriptor blocks, the resize inode maps 7
+    let mut image = EXT3.to_vec();
+    image[1024 + 0xCE..1024 + 0xD0].copy_from_slice(&5u16.to_le_bytes());
+
+    let fs = mount(&image);
+    let log = fs.error_log();
+    assert_eq!(log.first.as_ref().unwrap().func, "check_resize_inode");
+    assert_eq!(log.first.as_ref().unwrap().ino, EXT4_RESIZE_INO);
+    assert_eq!(fs.reserved_gdt_blocks(), Err(Ext4Error::InvalidState));
+    assert!(mount(&EXT3).error_log().first.is_none());
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_unsupported_write_features() {
+    let mut image = EXT3.to_vec();
+    image[1024 + 0x62] |= 0x01; // encrypt
+    image[1024 + 0x65] |= 0x01; // quota
+    let mode = InodeMode::from_bits_truncate(0o644);
+
+    // Reads still work, writes name the features in the way
+    let device = VecBlockDevice::new(image.clone(), 512).unwrap();
+    let mut fs =
+        Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image");
+    assert_eq!(
+        fs.superblock().write_blockers(),
+        (FeatureIncompat::ENCRYPT, FeatureRoCompat::QUOTA)
+    );
+    assert!(fs.lookup(2, b"lost+found").is_ok());
+    assert_eq!(
+        fs.create_file(2, "f", mode),
+        Err(Ext4Error::UnsupportedForWrite(FeatureIncompat::ENCRYPT, FeatureRoCompat::QUOTA))
+    );
+
+    let options = MountOptions {
+        read_only_if_unsupported: true,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(image, 512).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    assert_eq!(fs.create_file(2, "f", mode), Err(Ext4Error::ReadOnly));
+
+    // Features that writes keep up to date don't get in the way
+    let mut fs = mount(&EXT3);
+    assert_eq!(
+        fs.superblock().write_blockers(),
+        (FeatureIncompat::empty(), FeatureRoCompat::empty())
+    );
+    assert!(fs.create_file(2, "f", mode).is_ok());
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn test_verify_reads() {
+    let options = MountOptions {
+        journaling: false,
+        verify_reads: true,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT4_HTREE.to_vec(), 1024).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options.clone())
+        .expect("Failed to mount image");
+    assert!(fs.verifies_reads());
+    for entry in fs.read_dir(12).unwrap() {
+        fs.get_inode(entry.ino).expect("Failed to verify inode");
+    }
+
+    // Inodes written back keep a valid checksum
+    let e = fs.find_inode("/e").unwrap().ino;
+    fs.rename(2, b"d", e, b"d", RenameFlags::empty()).expect("Failed to rename");
+    assert!(fs.get_inode(12).is_ok());
+    assert!(fs.get_inode(e).is_ok());
+
+    // A flipped bit in inode 12 (block 5, 128-byte inodes) is only caught
+    // when verifying
+    let mut image = EXT4_HTREE.to_vec();
+    image[5 * 1024 + 11 * 128 + 16] ^= 1;
+    let device = VecBlockDevice::new(image.clone(), 1024).unwrap();
+    let fs = Ext4FileSystem::new(device, MountOptions::default())
+        .expect("Failed to mount image");
+    assert!(fs.get_inode(12).is_ok());
+    let fs = Ext4FileSystem::new(VecBlockDevice::new(image, 1024).unwrap(), options.clone())
+        .expect("Failed to mount image");
+    assert_eq!(fs.get_inode(12).err(), Some(Ext4Error::BadChecksum));
+    assert_eq!(fs.error_log().last.unwrap().func, "verify_inode");
+
+    // So are flipped bits in a directory block, a bitmap and a descriptor
+    let device = VecBlockDevice::new(EXT4_HTREE.to_vec(), 1024).unwrap();
+    let fs = Ext4FileSystem::new(device, options.clone()).expect("Failed to mount image");
+    let root = fs.get_inode(2).unwrap();
+    let dir_block = root.get_block_number(0, 1024, &fs).unwrap();
+    let bitmap_block = fs.block_group(0).unwrap().block_bitmap();
+    let bitmap_end = fs.superblock().clusters_per_group() as usize / 8;
+    let mut image = EXT4_HTREE.to_vec();
+    image[dir_block as usize * 1024 + 32] ^= 1;
+    image[bitmap_block as usize * 1024 + bitmap_end - 1] ^= 0x80;
+    let device = VecBlockDevice::new(image.clone(), 1024).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options.clone()).expect("Failed to mount image");
+    assert_eq!(fs.read_dir(2).err(), Some(Ext4Error::BadChecksum));
+    assert_eq!(fs.error_log().last.unwrap().func, "read_dir_data_block");
+    let mode = InodeMode::from_bits_truncate(0o755);
+    assert_eq!(fs.create_dir(12, "new", mode).err(), Some(Ext4Error::BadChecksum));
+    assert_eq!(fs.error_log().last.unwrap().func, "read_bitmap");
+    image[2 * 1024 + 16] ^= 1;
+    let device = VecBlockDevice::new(image.clone(), 1024).unwrap();
+    assert!(Ext4FileSystem::new(device, MountOptions::default()).is_ok());
+    let device = VecBlockDevice::new(image, 1024).unwrap();
+    let result = Ext4FileSystem::new(device, options.clone());
+    assert_eq!(result.err(), Some(Ext4Error::CorruptGroupDescriptor(0)));
+
+    // The inode is read again after it was cached, and its extent tree
+    // root checked
+    let device = VecBlockDevice::new(EXT4_EXTENTS.to_vec(), 512).unwrap();
+    let fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+    assert!(fs.get_inode(2).is_ok());
+    let inode_size = fs.superblock().inode_size() as usize;
+    let table = fs.block_group(0).unwrap().inode_table();
+    let mut block = vec![0u8; fs.superblock().block_size() as usize];
+    fs.read_block(table, &mut block).unwrap();
+    block[inode_size + 40 + 6] = EXT4_MAX_EXTENT_DEPTH as u8 + 1;
+    fs.write_block(table, &block).unwrap();
+    assert_eq!(fs.get_inode(2).err(), Some(Ext4Error::InvalidState));
+}
+
+#[cfg(feature = "read-only")]
+#[test]
+fn test_read_only_feature() {
+    let options = MountOptions {
+        read_only: false,
+        ..MountOptions::default()
+    };
+    let device = VecBlockDevice::new(EXT2_HARD_LINKS.to_vec(), 1024).unwrap();
+    let mut fs = Ext4FileSystem::new(device, options).expect("Failed to mount image");
+
+    // Files open and read, but never for writing
+    let mut file = fs.open("/a/s/h", OpenFlags::empty()).expect("Failed to open file");
+    let mut buf = vec![0u8; 3000];
+    assert_eq!(file.read(&mut buf, &mut fs), Ok(3000));
+    file.close(&mut fs).unwrap();
+    assert_eq!(fs.open("/a/f", OpenFlags::WRITE).err(), Some(Ext4Error::ReadOnly));
+    fs.flush().unwrap();
+}
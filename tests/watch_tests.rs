@@ -0,0 +1,97 @@
+//! Tests of change notifications
+
+#![cfg(not(feature = "read-only"))]
+
+mod images;
+
+use std::sync::{Arc, Mutex};
+
+use ext4rs::{
+    Change, Ext4FileSystem, InodeFlags, InodeMode, MountOptions, OpenFlags, RenameFlags,
+    VecBlockDevice, WatchId, WatchMask, Watcher,
+};
+use images::{image, Image};
+
+static EXT2_REV0: Image = image!("images/ext2_rev0.img.packed");
+
+fn mount(image: &[u8]) -> Ext4FileSystem<VecBlockDevice> {
+    let device = VecBlockDevice::new(image.to_vec(), 512).unwrap();
+    Ext4FileSystem::new(device, MountOptions::default()).expect("Failed to mount image")
+}
+
+/// Watcher keeping each change it sees as the watched inode and a summary
+#[derive(Debug, Default)]
+struct ChangeLog(Mutex<Vec<(u32, String)>>);
+
+impl Watcher for ChangeLog {
+    fn changed(&self, _watch: WatchId, ino: u32, change: &Change<'_>) {
+        let name = |name: &[u8]| String::from_utf8_lossy(name).into_owned();
+        let summary = match *change {
+            Change::Create { name: n, ino } => format!("create {} {}", name(n), ino),
+            Change::Unlink { name: n, ino } => format!("unlink {} {}", name(n), ino),
+            Change::Rename { old_dir, old_name, new_dir, new_name, .. } => {
+                format!("rename {}/{} {}/{}", old_dir, name(old_name), new_dir, name(new_name))
+            }
+            Change::Write => "write".into(),
+            Change::Attrib => "attrib".into(),
+            Change::Removed => "removed".into(),
+        };
+        self.0.lock().unwrap().push((ino, summary));
+    }
+}
+
+#[test]
+fn test_watch() {
+    let mut fs = mount(&EXT2_REV0);
+    let log = Arc::new(ChangeLog::default());
+    let take = || core::mem::take(&mut *log.0.lock().unwrap());
+    let root = fs.watch(2, WatchMask::all(), log.clone());
+    let mode = InodeMode::from_bits_truncate(0o644);
+
+    let ino = fs.create_file(2, "a", mode).expect("Failed to create file");
+    let dir = fs.create_dir(2, "d", InodeMode::from_bits_truncate(0o755)).unwrap();
+    assert_eq!(take(), [(2, format!("create a {}", ino)), (2, format!("create d {}", dir))]);
+    // A failed create reports nothing
+    assert!(fs.create_file(2, "a", mode).is_err());
+    assert_eq!(take(), []);
+
+    let file_watch = fs.watch(ino, WatchMask::WRITE, log.clone());
+    fs.watch(dir, WatchMask::RENAME, log.clone());
+    let mut file = fs.open_inode(ino, OpenFlags::WRITE).unwrap();
+    file.write(b"data", &mut fs).unwrap();
+    file.truncate(1, &mut fs).unwrap();
+    file.close(&mut fs).unwrap();
+    // Attribute changes aren't in the mask of the file's watch
+    fs.set_flags(ino, InodeFlags::NOATIME).unwrap();
+    assert_eq!(take(), [(ino, "write".into()), (ino, "write".into())]);
+
+    // Moving to another directory tells both, replacing unlinks the target
+    fs.rename(2, b"a", dir, b"b", RenameFlags::empty()).unwrap();
+    let other = fs.create_file(2, "c", mode).unwrap();
+    // The replaced target's watch ends with it, whatever its mask
+    fs.watch(other, WatchMask::WRITE, log.clone());
+    fs.rename(dir, b"b", 2, b"c", RenameFlags::empty()).unwrap();
+    let moved = format!("rename 2/a {}/b", dir);
+    let back = format!("rename {}/b 2/c", dir);
+    assert_eq!(
+        take(),
+        [
+            (2, moved.clone()),
+            (dir, moved),
+            (2, format!("create c {}", other)),
+            (2, format!("unlink c {}", other)),
+            (other, "removed".into()),
+            (dir, back.clone()),
+            (2, back),
+        ]
+    );
+
+    assert!(fs.unwatch(file_watch));
+    assert!(!fs.unwatch(file_watch));
+    assert!(fs.unwatch(root));
+    let mut file = fs.open_inode(ino, OpenFlags::WRITE).unwrap();
+    file.write(b"more", &mut fs).unwrap();
+    file.close(&mut fs).unwrap();
+    fs.create_file(2, "e", mode).unwrap();
+    assert_eq!(take(), []);
+}